@@ -1,9 +1,11 @@
 #!/bin/bash
 
-# the interval at which the sanitarr binary will be executed
-INTERVAL=${INTERVAL:-1h}
+# the interval at which the cleanup is repeated, as a humantime duration such
+# as `1h` or `30m` (a unit is required). Set it to an empty string to use the
+# `schedule` from the config instead
+INTERVAL=${INTERVAL-1h}
 
-while true; do
-  sanitarr ${@}
-  sleep $INTERVAL
-done
+if [ -n "$INTERVAL" ]; then
+  exec sanitarr --interval "$INTERVAL" "$@"
+fi
+exec sanitarr "$@"
@@ -0,0 +1,1315 @@
+use crate::{
+    cleaners::{
+        CleanupMode, RetentionDelta, Users, series,
+        utils::{self, Downloads},
+    },
+    config::SonarrConfig,
+    http::{
+        Episode, HttpClient, ITEM_FIELDS, Item as JellyfinItem, ItemsFilter, MediaServer,
+        SeriesInfo, SonarrClient,
+    },
+    report::{CleanerReport, labels},
+    services::{Clock, Confirmation, DeletionLimit, StateStore, Trash},
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    path::Path,
+    time::Duration,
+};
+
+/// EpisodesCleaner is responsible for cleaning up files of individual watched
+/// episodes from Sonarr. Unlike [`super::SeriesCleaner`] it doesn't wait for
+/// the whole series to be watched.
+pub struct EpisodesCleaner {
+    sonarr_client: SonarrClient,
+    media_server: MediaServer,
+    tags_to_keep: Vec<String>,
+    tags_to_delete: Vec<String>,
+    keep_quality_profiles: Vec<String>,
+    protected_genres: Vec<String>,
+    ignore_titles: Vec<String>,
+    ignore_tvdb_ids: Vec<u64>,
+    min_rating_to_keep: Option<f32>,
+    min_age_since_added: Option<Duration>,
+    min_free_space: Option<u64>,
+    retention_period: Option<Duration>,
+    users: Users,
+    unmonitor_watched: bool,
+    keep_latest_episodes: usize,
+    keep_current_season: bool,
+    /// deleting more items than this needs a confirmation even with `--yes`,
+    /// `None` with `--ignore-confirm-threshold`
+    confirm_threshold: Option<usize>,
+    episode_matching: EpisodeMatching,
+    trash: Option<Trash>,
+    clock: Clock,
+    deletion_limit: DeletionLimit,
+    state: StateStore,
+    max_concurrent_requests: usize,
+}
+
+impl EpisodesCleaner {
+    pub fn new(
+        sonarr_config: SonarrConfig,
+        http: &HttpClient,
+        media_server: MediaServer,
+        users: &Users,
+        clock: Clock,
+        deletion_limit: DeletionLimit,
+        state: StateStore,
+    ) -> anyhow::Result<Self> {
+        let sonarr_client = SonarrClient::new(&sonarr_config, http)?;
+        let SonarrConfig {
+            base_url: _,
+            api_key: _,
+            timeout: _,
+            basic_auth_username: _,
+            basic_auth_password: _,
+            cleanup_mode: _,
+            tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tvdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
+            retention_period,
+            unwatched_retention,
+            unmonitor_watched,
+            add_to_exclusion: _,
+            confirm_threshold,
+            keep_latest_episodes,
+            keep_current_season,
+            anime_absolute_matching,
+            include_specials,
+            deletion_mode,
+            trash_dir,
+        } = sonarr_config;
+        if unwatched_retention.is_some() {
+            warn!("`unwatched_retention` is not supported in episode cleanup mode, ignoring");
+        }
+
+        Ok(Self {
+            sonarr_client,
+            media_server,
+            tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tvdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
+            retention_period,
+            users: users.clone(),
+            unmonitor_watched,
+            keep_latest_episodes,
+            keep_current_season,
+            confirm_threshold,
+            episode_matching: EpisodeMatching {
+                absolute: anime_absolute_matching,
+                specials: include_specials,
+            },
+            trash: Trash::of(deletion_mode, trash_dir),
+            clock,
+            deletion_limit,
+            state,
+            max_concurrent_requests: http.max_concurrent_requests(),
+        })
+    }
+
+    /// unmonitor watched episodes (if configured) and delete files of watched
+    /// episodes from Sonarr along with their torrents in Download client
+    pub async fn cleanup(
+        &self,
+        mode: CleanupMode,
+        mut confirmation: Confirmation,
+    ) -> anyhow::Result<CleanerReport> {
+        let mut report = CleanerReport::new("episodes");
+        if let Some(server_time) = self.sonarr_client.server_time().await? {
+            self.clock.check_skew("Sonarr", server_time);
+        }
+
+        let candidates = self.candidates(true).await?;
+        self.state.prune(
+            report.cleaner,
+            &candidates.episodes().map(|ep| ep.episode.id).collect(),
+        );
+        if self.unmonitor_watched {
+            self.unmonitor(&candidates, mode).await?;
+        }
+        // only once their watched episodes are unmonitored
+        self.state
+            .record_not_yet_eligible(report.cleaner, candidates.not_yet_eligible.clone());
+        if candidates.is_empty() {
+            info!("no watched episodes found!");
+            return Ok(report);
+        }
+        report.considered = candidates.episodes().count();
+
+        let episodes_to_delete = candidates.for_deletion(self.retention_period);
+        let episodes_to_delete = self.skip_queued(episodes_to_delete, &mut report).await?;
+        let episodes_to_delete =
+            self.state
+                .without_deleted(episodes_to_delete, |ep| ep.episode.id, &mut report);
+        let episodes_to_delete = self.state.after_grace_period(
+            episodes_to_delete,
+            |ep| ep.episode.id,
+            self.clock.now(),
+            &mut report,
+        );
+        let episodes_to_delete = self
+            .until_min_free_space(episodes_to_delete, &mut report)
+            .await?;
+        if episodes_to_delete.is_empty() {
+            info!("no episodes found for deletion!");
+            return Ok(report);
+        }
+
+        let eligible = labels(&episodes_to_delete);
+        let episodes_to_delete = if mode == CleanupMode::Interactive {
+            confirmation
+                .select("episode files from Sonarr", episodes_to_delete, |ep| {
+                    utils::details(ep.last_played, ep.episode.size_on_disk())
+                })
+                .await?
+        } else {
+            episodes_to_delete
+        };
+        report.skip_missing(&eligible, &episodes_to_delete, "not selected");
+        let selected = labels(&episodes_to_delete);
+        let episodes_to_delete = confirmation
+            .limit(
+                episodes_to_delete,
+                |ep| ep.last_played,
+                |ep| ep.episode.size_on_disk(),
+            )
+            .await?;
+        report.skip_missing(
+            &selected,
+            &episodes_to_delete,
+            "`--max-delete` or `--max-free` limit reached",
+        );
+        if episodes_to_delete.is_empty() {
+            info!("no episodes selected for deletion");
+            return Ok(report);
+        }
+
+        let downloads = self.download_ids(&episodes_to_delete).await?;
+
+        if !utils::confirm_deletion(
+            mode,
+            self.confirm_threshold,
+            "episode files from Sonarr",
+            labels(&episodes_to_delete),
+            downloads.count(),
+            confirmation,
+        )
+        .await?
+        {
+            info!("deletion of episodes cancelled");
+            report.skip_all(&episodes_to_delete, "deletion not confirmed");
+            return Ok(report);
+        }
+
+        let episodes_to_delete = if mode != CleanupMode::DryRun {
+            debug!("trying to delete episodes {episodes_to_delete:?}");
+            let deleted = self
+                .delete_episode_files(episodes_to_delete, &mut report)
+                .await;
+            info!("successfully deleted episodes: {deleted:?}");
+            self.state.record_deleted(
+                report.cleaner,
+                deleted.iter().map(|ep| ep.episode.id),
+                self.clock.now(),
+            );
+            deleted
+        } else {
+            info!(
+                "no items will be deleted as no `--force-delete` flag is provided. Listing them instead: {episodes_to_delete:?}"
+            );
+            episodes_to_delete
+        };
+        report.deleted = labels(&episodes_to_delete);
+        let deleted = episodes_to_delete.iter().map(|ep| ep.episode.id).collect();
+        report.download_ids = downloads.of_deleted(&deleted);
+        report.size_on_disk = episodes_to_delete
+            .iter()
+            .map(|ep| ep.episode.size_on_disk())
+            .sum();
+        utils::log_freed_space(mode, "episodes", report.size_on_disk);
+
+        Ok(report)
+    }
+
+    /// leaves out episodes with a download in the Sonarr queue (e.g. an
+    /// upgrade in progress), as deleting them would orphan the download
+    async fn skip_queued<'a>(
+        &self,
+        episodes: Vec<EpisodeForDeletion<'a>>,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<EpisodeForDeletion<'a>>> {
+        if episodes.is_empty() {
+            return Ok(episodes);
+        }
+        let queue = self.sonarr_client.queue().await?;
+        let queued: HashSet<u64> = queue.into_iter().filter_map(|r| r.episode_id).collect();
+        let (in_queue, episodes): (Vec<_>, Vec<_>) = episodes
+            .into_iter()
+            .partition(|ep| queued.contains(&ep.episode.id));
+        if !in_queue.is_empty() {
+            info!("skipping episodes that are still in the Sonarr queue: {in_queue:?}");
+            report.skip_all(&in_queue, "still in the Sonarr queue");
+        }
+        Ok(episodes)
+    }
+
+    /// compares episodes eligible for deletion under the configured retention
+    /// period with those eligible under the given one. Nothing gets deleted
+    pub async fn simulate(
+        &self,
+        retention_period: Option<Duration>,
+    ) -> anyhow::Result<RetentionDelta> {
+        let candidates = self.candidates(false).await?;
+        let current = candidates.for_deletion(self.retention_period);
+        let simulated = candidates.for_deletion(retention_period.or(self.retention_period));
+        Ok(RetentionDelta::new(&current, &simulated))
+    }
+
+    /// gathers all the data needed to decide which episodes to delete. With
+    /// `skip_unchanged` series seen not yet eligible by an earlier run are
+    /// skipped until one of their episodes is played again, see
+    /// [`Self::watched_episodes`]
+    async fn candidates(&self, skip_unchanged: bool) -> anyhow::Result<EpisodeCandidates> {
+        let (watched, not_yet_eligible) = self.watched_episodes(skip_unchanged).await?;
+        if watched.is_empty() {
+            return Ok(EpisodeCandidates {
+                not_yet_eligible,
+                ..EpisodeCandidates::default()
+            });
+        }
+
+        Ok(EpisodeCandidates {
+            now: self.clock.now(),
+            watched,
+            not_yet_eligible,
+            forbidden_tags: self.forbidden_tags().await?,
+            required_tags: self.required_tags().await?,
+            forbidden_profiles: self.forbidden_quality_profiles().await?,
+            keep_current_season: self.keep_current_season,
+        })
+    }
+
+    async fn forbidden_tags(&self) -> anyhow::Result<Vec<u64>> {
+        debug!("forbidden tags configured: {:?}", self.tags_to_keep);
+
+        let tags = self.sonarr_client.tags().await?;
+        let forbidden_tags = tags
+            .iter()
+            .filter(|t| self.tags_to_keep.contains(&t.label))
+            .map(|t| t.id)
+            .collect();
+
+        debug!("forbidden tag ids: {forbidden_tags:?}");
+
+        Ok(forbidden_tags)
+    }
+
+    /// gets IDs of the tags an item needs to carry to be deleted, `None` if
+    /// `tags_to_delete` is not configured
+    async fn required_tags(&self) -> anyhow::Result<Option<Vec<u64>>> {
+        if self.tags_to_delete.is_empty() {
+            return Ok(None);
+        }
+        let tags = self.sonarr_client.tags().await?;
+        let required_tags: Vec<u64> = tags
+            .iter()
+            .filter(|t| self.tags_to_delete.contains(&t.label))
+            .map(|t| t.id)
+            .collect();
+        if required_tags.is_empty() {
+            warn!(
+                "none of the tags to delete {:?} exist in Sonarr, nothing will be deleted",
+                self.tags_to_delete
+            );
+        }
+        Ok(Some(required_tags))
+    }
+
+    async fn forbidden_quality_profiles(&self) -> anyhow::Result<Vec<u64>> {
+        if self.keep_quality_profiles.is_empty() {
+            return Ok(Vec::new());
+        }
+        debug!(
+            "forbidden quality profiles configured: {:?}",
+            self.keep_quality_profiles
+        );
+
+        let profiles = self.sonarr_client.quality_profiles().await?;
+        let forbidden_profiles = profiles
+            .iter()
+            .filter(|p| self.keep_quality_profiles.contains(&p.name))
+            .map(|p| p.id)
+            .collect();
+
+        debug!("forbidden quality profile ids: {forbidden_profiles:?}");
+
+        Ok(forbidden_profiles)
+    }
+
+    /// queries the media server for watched episodes and matches them with
+    /// the episodes in Sonarr that have a file on disk
+    async fn watched_episodes(
+        &self,
+        skip_unchanged: bool,
+    ) -> anyhow::Result<(Vec<SeriesWithWatchedEpisodes>, Vec<(String, DateTime<Utc>)>)> {
+        let watched_episodes = utils::watched_items_of_users(
+            &self.media_server,
+            &self.users,
+            ItemsFilter::watched().include_item_types(&["Episode"]),
+        )
+        .await?;
+        let watched_episodes = utils::without_recently_added(
+            watched_episodes,
+            self.clock.now(),
+            self.min_age_since_added,
+        );
+
+        let mut episodes_per_series: HashMap<String, Vec<JellyfinItem>> = HashMap::new();
+        for ep in watched_episodes {
+            if let Some(series_id) = ep.series_id.clone() {
+                episodes_per_series.entry(series_id).or_default().push(ep);
+            }
+        }
+        if episodes_per_series.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let all_episodes =
+            all_episodes_per_series(&self.media_server, &self.users, self.episode_matching).await?;
+        let series_ids: Vec<&str> = episodes_per_series.keys().map(String::as_str).collect();
+        let series = utils::items_of_users(
+            &self.media_server,
+            &self.users.ids,
+            self.users.policy,
+            ItemsFilter::new()
+                .ids(&series_ids)
+                .include_item_types(&["Series"])
+                .fields(ITEM_FIELDS),
+        )
+        .await?;
+        for (series_id, episodes) in &episodes_per_series {
+            if !series.iter().any(|s| &s.id == series_id) {
+                let series_name = episodes
+                    .iter()
+                    .find_map(|ep| ep.series_name.as_deref())
+                    .unwrap_or(series_id);
+                warn!(
+                    "series \"{series_name}\" of {} watched episode(s) not found in {}, skipping",
+                    episodes.len(),
+                    self.media_server.name()
+                );
+            }
+        }
+        let series =
+            utils::without_ignored(series, &self.ignore_titles, &self.ignore_tvdb_ids, |i| {
+                i.tvdb_id()
+            });
+        let series = utils::without_protected_genres(series, &self.protected_genres);
+        let series = utils::without_high_ratings(series, self.min_rating_to_keep);
+
+        // series none of whose episodes are eligible yet are remembered along
+        // with when they were last played. Their watched episodes were
+        // unmonitored already, so there is no need to look them up in Sonarr
+        // again until they are played or their retention period passes
+        let now = self.clock.now();
+        let mut not_yet_eligible = Vec::new();
+        let mut unchanged = Vec::new();
+        let series: Vec<JellyfinItem> = series
+            .into_iter()
+            .filter(|s| {
+                let episodes = episodes_per_series
+                    .get(&s.id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                let Some(last_played) =
+                    not_yet_eligible_since(episodes, self.retention_period, now)
+                else {
+                    return true;
+                };
+                not_yet_eligible.push((s.id.clone(), last_played));
+                if skip_unchanged && self.state.unchanged("episodes", &s.id, last_played) {
+                    unchanged.push(s.name.clone());
+                    return false;
+                }
+                true
+            })
+            .collect();
+        if !unchanged.is_empty() {
+            debug!(
+                "skipping series not played since an earlier run found none of their episodes eligible yet: {unchanged:?}"
+            );
+        }
+
+        let futs = series.into_iter().map(|jellyfin_series| {
+            let jellyfin_episodes = episodes_per_series
+                .get(&jellyfin_series.id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let all_episodes = all_episodes
+                .get(&jellyfin_series.id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            async move {
+                let series_name = &jellyfin_series.name;
+                let Some(tvdb_id) = jellyfin_series.tvdb_id() else {
+                    warn!("series \"{series_name}\" has no TVDB id, skipping");
+                    return Ok::<_, anyhow::Error>(None);
+                };
+                let Some(sonarr_series) =
+                    self.sonarr_client.series_by_tvdb_id(tvdb_id).await?.pop()
+                else {
+                    warn!("series {series_name} with TVDB id {tvdb_id} not found in Sonarr");
+                    return Ok(None);
+                };
+                let sonarr_episodes = self
+                    .sonarr_client
+                    .episodes_by_series_id(sonarr_series.id)
+                    .await?;
+
+                let latest = latest_aired(
+                    &sonarr_episodes,
+                    self.clock.now(),
+                    self.keep_latest_episodes,
+                );
+                let matching = self.episode_matching.for_series(&sonarr_series);
+                let episodes =
+                    match_episodes(jellyfin_episodes, all_episodes, sonarr_episodes, matching);
+                Ok(Some(SeriesWithWatchedEpisodes {
+                    series: sonarr_series,
+                    episodes,
+                    latest,
+                }))
+            }
+        });
+
+        let results = utils::try_join_limited(futs, self.max_concurrent_requests)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok((results, not_yet_eligible))
+    }
+
+    /// query Sonarr history for the series of the given episodes and get
+    /// download_ids per each client kind that only contain files of the
+    /// episodes being deleted (or episodes without a file anymore)
+    async fn download_ids(&self, episodes: &[EpisodeForDeletion<'_>]) -> anyhow::Result<Downloads> {
+        let series_ids = episodes.iter().map(|ep| ep.series.id).collect();
+        let deleted: HashSet<u64> = episodes.iter().map(|ep| ep.episode.id).collect();
+        let records = self.sonarr_client.history_records(&series_ids).await?;
+        let grabbed_for = records
+            .iter()
+            .filter(|r| deleted.contains(&r.episode_id))
+            .filter_map(|r| Some((r.episode_id, r.download_id.clone()?)))
+            .collect::<Vec<_>>();
+        let download_ids = series::releasable_download_ids(
+            &self.sonarr_client,
+            records,
+            &deleted,
+            self.max_concurrent_requests,
+        )
+        .await?;
+        Ok(Downloads::new(download_ids, grabbed_for))
+    }
+
+    /// unmonitor watched episodes that are still monitored
+    async fn unmonitor(
+        &self,
+        candidates: &EpisodeCandidates,
+        mode: CleanupMode,
+    ) -> anyhow::Result<()> {
+        let ids: HashSet<u64> = candidates
+            .episodes()
+            .filter(|ep| ep.episode.monitored)
+            .map(|ep| ep.episode.id)
+            .collect();
+        if ids.is_empty() {
+            debug!("no monitored episodes found for unmonitoring");
+            return Ok(());
+        }
+        if mode == CleanupMode::DryRun {
+            let episodes: Vec<String> = candidates
+                .watched
+                .iter()
+                .flat_map(|s| {
+                    s.episodes
+                        .iter()
+                        .filter(|ep| ep.episode.monitored)
+                        .map(|ep| {
+                            format!(
+                                "{} s{:02}e{:02}",
+                                s.series.title, ep.episode.season_number, ep.episode.episode_number
+                            )
+                        })
+                })
+                .collect();
+            info!(
+                "no episodes will be unmonitored as no `--force-delete` flag is provided. Listing them instead: {episodes:?}"
+            );
+            return Ok(());
+        }
+        let res = self.sonarr_client.unmonitor_episodes(&ids).await?;
+        let log_msg = res
+            .iter()
+            .map(|e| format!("  - {e}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        info!("unmonitored episodes:\n{log_msg}");
+        Ok(())
+    }
+
+    /// keeps only as many episodes as needed to reach `min_free_space` on the
+    /// disk holding them, if configured
+    async fn until_min_free_space<'a>(
+        &self,
+        episodes: Vec<EpisodeForDeletion<'a>>,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<EpisodeForDeletion<'a>>> {
+        let Some(min_free_space) = self.min_free_space else {
+            return Ok(episodes);
+        };
+        if episodes.is_empty() {
+            return Ok(episodes);
+        }
+        let disks = self.sonarr_client.disk_space().await?;
+        let paths = episodes.iter().filter_map(|ep| ep.series.path.as_deref());
+        let Some(disk) = utils::holding_disk(&disks, paths) else {
+            warn!(
+                "none of the disks reported by Sonarr holds the episodes, ignoring `min_free_space`"
+            );
+            return Ok(episodes);
+        };
+        let eligible = labels(&episodes);
+        let episodes = utils::until_disk_free_space(
+            "episodes",
+            episodes,
+            disk,
+            min_free_space,
+            &self.deletion_limit,
+            |ep| ep.episode.size_on_disk(),
+            |ep| ep.last_played,
+        );
+        report.skip_missing(&eligible, &episodes, "enough free space");
+        Ok(episodes)
+    }
+
+    /// deletes the files of the given episodes, continuing past failures, and
+    /// returns the episodes whose files were deleted. With a trash the
+    /// episodes are unmonitored first, so that Sonarr doesn't re-download
+    /// them, and their files are moved into the trash before being removed
+    /// from Sonarr, which then only drops its records of them
+    async fn delete_episode_files<'a>(
+        &self,
+        episodes: Vec<EpisodeForDeletion<'a>>,
+        report: &mut CleanerReport,
+    ) -> Vec<EpisodeForDeletion<'a>> {
+        utils::delete_each(
+            episodes,
+            |ep| ep.episode.episode_file_id,
+            |ep| {
+                let ep = *ep;
+                async move {
+                    let file_id = ep.episode.episode_file_id;
+                    let Some(trash) = &self.trash else {
+                        return self.sonarr_client.delete_episode_file(file_id).await;
+                    };
+                    let path = ep
+                        .episode
+                        .episode_file
+                        .as_ref()
+                        .and_then(|file| file.path.as_deref())
+                        .context("Sonarr reported no path")?;
+                    let path = Path::new(path);
+                    if ep.episode.monitored {
+                        let ids = HashSet::from([ep.episode.id]);
+                        self.sonarr_client.unmonitor_episodes(&ids).await?;
+                    }
+                    let trash_path = trash.move_item(&ep, path, self.clock.now())?;
+                    if let Err(e) = self.sonarr_client.delete_episode_file(file_id).await {
+                        trash
+                            .restore(&trash_path, path)
+                            .context("the file was left in the trash")?;
+                        return Err(e);
+                    }
+                    Ok(())
+                }
+            },
+            self.max_concurrent_requests,
+            report,
+        )
+        .await
+    }
+}
+
+/// when the latest of the watched episodes of a series was played, if none of
+/// them is eligible for deletion yet under the retention period. `None` if
+/// any of them is, or has no last played date to tell
+fn not_yet_eligible_since(
+    episodes: &[JellyfinItem],
+    retention_period: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let retention_date = now - retention_period?;
+    let mut latest = None;
+    for ep in episodes {
+        let last_played = ep.last_played_date()?;
+        if retention_date > last_played {
+            return None;
+        }
+        latest = latest.max(Some(last_played));
+    }
+    latest
+}
+
+/// how Sonarr episodes are matched with the media server ones
+#[derive(Clone, Copy, Default)]
+pub(super) struct EpisodeMatching {
+    /// match episodes numbered differently in the media server by Sonarr's
+    /// absolute number
+    pub absolute: bool,
+    /// match specials (season 0), falling back to their air date or title
+    pub specials: bool,
+}
+
+impl EpisodeMatching {
+    /// absolute numbering is only used for anime series
+    pub fn for_series(self, series: &SeriesInfo) -> Self {
+        Self {
+            absolute: self.absolute && series.is_anime(),
+            ..self
+        }
+    }
+}
+
+/// all the episodes of the media server grouped by series id. The fallbacks
+/// of `matching` need them to tell an episode numbered differently in the
+/// media server from one that just isn't watched, so they are only queried if
+/// a fallback is enabled
+pub(super) async fn all_episodes_per_series(
+    media_server: &MediaServer,
+    users: &Users,
+    matching: EpisodeMatching,
+) -> anyhow::Result<HashMap<String, Vec<JellyfinItem>>> {
+    let mut per_series: HashMap<String, Vec<JellyfinItem>> = HashMap::new();
+    let Some(user_id) = users.ids.first() else {
+        return Ok(per_series);
+    };
+    if !matching.absolute && !matching.specials {
+        return Ok(per_series);
+    }
+    let filter = ItemsFilter::new()
+        .include_item_types(&["Episode"])
+        .user_id(user_id.as_ref());
+    for ep in media_server.items(filter).await? {
+        if let Some(series_id) = ep.series_id.clone() {
+            per_series.entry(series_id).or_default().push(ep);
+        }
+    }
+    Ok(per_series)
+}
+
+/// finds the watched media server episode matching the given Sonarr one by
+/// season and episode number, or by the fallbacks enabled in `matching`. The
+/// fallbacks look the episode up among `all_episodes` of the series, so that
+/// an unwatched episode never matches some other watched one
+pub(super) fn find_watched<'a>(
+    watched_episodes: &'a [JellyfinItem],
+    all_episodes: &[JellyfinItem],
+    episode: &Episode,
+    matching: EpisodeMatching,
+) -> Option<&'a JellyfinItem> {
+    let special = episode.season_number == 0;
+    if special && !matching.specials {
+        return None;
+    }
+    let same_number = |jl_ep: &&JellyfinItem| {
+        jl_ep.parent_index_number == Some(episode.season_number)
+            && jl_ep.index_number == Some(episode.episode_number)
+    };
+    if let Some(jellyfin_ep) = watched_episodes.iter().find(same_number) {
+        debug!(
+            "matched s{:02}e{:02} with \"{}\" by season and episode number",
+            episode.season_number, episode.episode_number, jellyfin_ep.name
+        );
+        return Some(jellyfin_ep);
+    }
+    let (jellyfin_ep, strategy) = if special {
+        find_special(all_episodes, episode)?
+    } else {
+        if !matching.absolute || all_episodes.iter().any(|jl_ep| same_number(&jl_ep)) {
+            return None;
+        }
+        let absolute = episode.absolute_episode_number?;
+        let jellyfin_ep = all_episodes.iter().find(|jl_ep| {
+            jl_ep.parent_index_number != Some(episode.season_number)
+                && jl_ep.index_number == Some(absolute)
+        })?;
+        (jellyfin_ep, "absolute number")
+    };
+    let watched = watched_episodes
+        .iter()
+        .find(|jl_ep| jl_ep.id == jellyfin_ep.id)?;
+    debug!(
+        "matched s{:02}e{:02} with \"{}\" by {strategy}",
+        episode.season_number, episode.episode_number, watched.name
+    );
+    Some(watched)
+}
+
+/// specials are often numbered differently in the media server, so they are
+/// matched with the media server special aired on the same date, if there is
+/// exactly one. Otherwise, e.g. when the air date is unknown or several
+/// specials aired on that date, with the one of the same title (ignoring case)
+/// among them. Returns the matched special along with what it was matched by
+fn find_special<'a>(
+    all_episodes: &'a [JellyfinItem],
+    episode: &Episode,
+) -> Option<(&'a JellyfinItem, &'static str)> {
+    let specials = all_episodes
+        .iter()
+        .filter(|jl_ep| jl_ep.parent_index_number == Some(0));
+    let same_air_date: Vec<_> = match episode.air_date {
+        Some(air_date) => specials
+            .clone()
+            .filter(|jl_ep| jl_ep.premiere_date.map(|date| date.date_naive()) == Some(air_date))
+            .collect(),
+        None => Vec::new(),
+    };
+    if let [jellyfin_ep] = same_air_date[..] {
+        return Some((jellyfin_ep, "air date"));
+    }
+    let candidates = if same_air_date.is_empty() {
+        specials.collect()
+    } else {
+        same_air_date
+    };
+    let title = episode.title.to_lowercase();
+    let mut same_title = candidates
+        .into_iter()
+        .filter(|jl_ep| jl_ep.name.to_lowercase() == title);
+    let jellyfin_ep = same_title.next()?;
+    if same_title.next().is_some() {
+        debug!(
+            "several specials are named \"{}\", not matching s00e{:02}",
+            episode.title, episode.episode_number
+        );
+        return None;
+    }
+    Some((jellyfin_ep, "title"))
+}
+
+/// pairs Sonarr episodes having a file on disk with the matching watched
+/// episodes from the media server
+fn match_episodes(
+    jellyfin_episodes: &[JellyfinItem],
+    all_episodes: &[JellyfinItem],
+    sonarr_episodes: Vec<Episode>,
+    matching: EpisodeMatching,
+) -> Vec<WatchedEpisode> {
+    sonarr_episodes
+        .into_iter()
+        .filter(|ep| ep.episode_file_id != 0)
+        .filter_map(|episode| {
+            let jellyfin_ep = find_watched(jellyfin_episodes, all_episodes, &episode, matching)?;
+            Some(WatchedEpisode {
+                last_played: jellyfin_ep.last_played_date(),
+                episode,
+            })
+        })
+        .collect()
+}
+
+/// ids of the last `count` episodes of a series aired by `now`, ordered by
+/// season and episode number. Specials are not taken into account
+fn latest_aired(episodes: &[Episode], now: DateTime<Utc>, count: usize) -> HashSet<u64> {
+    let mut aired: Vec<&Episode> = episodes
+        .iter()
+        .filter(|ep| ep.season_number > 0)
+        .filter(|ep| ep.air_date_utc.is_some_and(|aired| aired <= now))
+        .collect();
+    aired.sort_by_key(|ep| (ep.season_number, ep.episode_number));
+    aired.iter().rev().take(count).map(|ep| ep.id).collect()
+}
+
+/// check if episodes of the given series are allowed to be deleted
+fn series_allows_deletion(
+    series: &SeriesInfo,
+    forbidden_tags: &[u64],
+    forbidden_profiles: &[u64],
+) -> bool {
+    let title = &series.title;
+    let has_forbidden_tags = series
+        .tags
+        .as_ref()
+        .is_some_and(|tags| tags.iter().any(|tag| forbidden_tags.contains(tag)));
+    if has_forbidden_tags {
+        debug!("{title}: series has forbidden tags, skipping its episodes");
+        return false;
+    }
+    if forbidden_profiles.contains(&series.quality_profile_id) {
+        debug!("{title}: series has forbidden quality profile, skipping its episodes");
+        return false;
+    }
+    true
+}
+
+/// a watched Sonarr episode along with the date it was last played
+struct WatchedEpisode {
+    episode: Episode,
+    last_played: Option<DateTime<Utc>>,
+}
+
+struct SeriesWithWatchedEpisodes {
+    series: SeriesInfo,
+    episodes: Vec<WatchedEpisode>,
+    /// ids of the latest aired episodes to keep
+    latest: HashSet<u64>,
+}
+
+/// an episode eligible for deletion, used for listing and selecting
+#[derive(Clone, Copy)]
+struct EpisodeForDeletion<'a> {
+    series: &'a SeriesInfo,
+    episode: &'a Episode,
+    last_played: Option<DateTime<Utc>>,
+}
+
+impl Debug for EpisodeForDeletion<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} s{:02}e{:02}({})",
+            self.series.title,
+            self.episode.season_number,
+            self.episode.episode_number,
+            self.episode.id
+        )
+    }
+}
+
+/// all the data needed to decide which episodes are eligible for deletion
+#[derive(Default)]
+struct EpisodeCandidates {
+    now: DateTime<Utc>,
+    watched: Vec<SeriesWithWatchedEpisodes>,
+    /// media server ids of the series none of whose watched episodes are
+    /// eligible yet, with when the latest of them was played
+    not_yet_eligible: Vec<(String, DateTime<Utc>)>,
+    forbidden_tags: Vec<u64>,
+    /// ids of `tags_to_delete`, if configured
+    required_tags: Option<Vec<u64>>,
+    forbidden_profiles: Vec<u64>,
+    keep_current_season: bool,
+}
+
+impl EpisodeCandidates {
+    fn is_empty(&self) -> bool {
+        self.watched.iter().all(|s| s.episodes.is_empty())
+    }
+
+    fn episodes(&self) -> impl Iterator<Item = &WatchedEpisode> {
+        self.watched.iter().flat_map(|s| s.episodes.iter())
+    }
+
+    /// episodes eligible for deletion under the given retention period
+    fn for_deletion(&self, retention_period: Option<Duration>) -> Vec<EpisodeForDeletion<'_>> {
+        if retention_period.is_none() && !self.is_empty() {
+            warn!(
+                "no retention period is set for Sonarr, will delete all watched episodes immediately"
+            );
+        }
+        let retention_date = retention_period.map(|period| self.now - period);
+
+        self.watched
+            .iter()
+            .filter(|s| {
+                series_allows_deletion(&s.series, &self.forbidden_tags, &self.forbidden_profiles)
+                    && utils::has_required_tag(
+                        &s.series.title,
+                        s.series.tags.as_deref(),
+                        self.required_tags.as_deref(),
+                    )
+            })
+            .flat_map(|s| {
+                let airing_season = s.series.airing_season().filter(|_| self.keep_current_season);
+                s.episodes
+                    .iter()
+                    .filter(move |ep| {
+                        if airing_season == Some(ep.episode.season_number) {
+                            debug!(
+                                "\"{}\" s{:02}e{:02} belongs to the season still airing, skipping",
+                                s.series.title, ep.episode.season_number, ep.episode.episode_number
+                            );
+                            return false;
+                        }
+                        if s.latest.contains(&ep.episode.id) {
+                            debug!(
+                                "\"{}\" s{:02}e{:02} is one of the latest episodes, skipping",
+                                s.series.title, ep.episode.season_number, ep.episode.episode_number
+                            );
+                            return false;
+                        }
+                        true
+                    })
+                    .map(move |ep| (&s.series, ep))
+            })
+            .filter(|(series, ep)| {
+                let Some(retention_date) = retention_date else {
+                    return true;
+                };
+                match ep.last_played {
+                    Some(last_played) if retention_date > last_played => true,
+                    Some(last_played) => {
+                        debug!(
+                            "retention period for \"{}\" s{:02}e{:02} is not yet passed ({} left), skipping",
+                            series.title,
+                            ep.episode.season_number,
+                            ep.episode.episode_number,
+                            utils::retention_str(&last_played, &retention_date)
+                        );
+                        false
+                    }
+                    None => false,
+                }
+            })
+            .map(|(series, ep)| EpisodeForDeletion {
+                series,
+                episode: &ep.episode,
+                last_played: ep.last_played,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Season, SeasonStatistics};
+
+    fn jellyfin_episode(season: u32, episode: u32) -> JellyfinItem {
+        serde_json::from_value(serde_json::json!({
+            "Name": format!("s{season}e{episode}"),
+            "Id": format!("{season}-{episode}"),
+            "ParentIndexNumber": season,
+            "IndexNumber": episode,
+            "UserData": {"Played": true, "LastPlayedDate": "2024-01-01T00:00:00Z"},
+        }))
+        .unwrap()
+    }
+
+    fn sonarr_episode(id: u64, season: u32, episode: u32, file_id: u64) -> Episode {
+        Episode {
+            id,
+            season_number: season,
+            episode_number: episode,
+            episode_file_id: file_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_not_yet_eligible_since() {
+        let played = "2024-01-01T00:00:00Z".parse().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let episodes = [jellyfin_episode(1, 1), jellyfin_episode(1, 2)];
+        let since = |retention, now| not_yet_eligible_since(&episodes, retention, now);
+
+        assert_eq!(since(Some(day * 7), played + day), Some(played));
+        // the retention period passed
+        assert_eq!(since(Some(day * 7), played + day * 8), None);
+        // every watched episode is eligible right away
+        assert_eq!(since(None, played), None);
+    }
+
+    #[test]
+    fn test_match_episodes() {
+        let jellyfin_episodes = [jellyfin_episode(1, 1), jellyfin_episode(1, 2)];
+        let sonarr_episodes = vec![
+            sonarr_episode(1, 1, 1, 10),
+            // watched but no file on disk
+            sonarr_episode(2, 1, 2, 0),
+            // has a file but not watched
+            sonarr_episode(3, 1, 3, 30),
+        ];
+
+        let matched = match_episodes(&jellyfin_episodes, &[], sonarr_episodes, Default::default());
+        let ids: Vec<u64> = matched.iter().map(|ep| ep.episode.id).collect();
+        assert_eq!(ids, vec![1]);
+    }
+
+    #[test]
+    fn test_match_episodes_by_absolute_number() {
+        // the media server lists all episodes in a single season
+        let jellyfin_episodes = [jellyfin_episode(1, 1), jellyfin_episode(1, 13)];
+        let absolute = |id, season, episode, absolute| Episode {
+            absolute_episode_number: Some(absolute),
+            ..sonarr_episode(id, season, episode, id * 10)
+        };
+        let sonarr_episodes = || vec![absolute(1, 1, 1, 1), absolute(2, 2, 1, 13)];
+        let matching = EpisodeMatching {
+            absolute: true,
+            ..Default::default()
+        };
+
+        let ids = |matched: Vec<WatchedEpisode>| -> Vec<u64> {
+            matched.iter().map(|ep| ep.episode.id).collect()
+        };
+        assert_eq!(
+            ids(match_episodes(
+                &jellyfin_episodes,
+                &jellyfin_episodes,
+                sonarr_episodes(),
+                matching
+            )),
+            vec![1, 2]
+        );
+        assert_eq!(
+            ids(match_episodes(
+                &jellyfin_episodes,
+                &jellyfin_episodes,
+                sonarr_episodes(),
+                Default::default()
+            )),
+            vec![1]
+        );
+
+        // s02e01 exists in the media server but is unwatched, so it doesn't
+        // fall back to the watched s01e13
+        let all_episodes = [
+            jellyfin_episode(1, 1),
+            jellyfin_episode(1, 13),
+            jellyfin_episode(2, 1),
+        ];
+        assert_eq!(
+            ids(match_episodes(
+                &jellyfin_episodes,
+                &all_episodes,
+                sonarr_episodes(),
+                matching
+            )),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_match_specials() {
+        let special = |episode, aired: &str| -> JellyfinItem {
+            let mut special = jellyfin_episode(0, episode);
+            special.premiere_date = Some(format!("{aired}T20:00:00Z").parse().unwrap());
+            special
+        };
+        let unwatched: JellyfinItem = serde_json::from_value(serde_json::json!({
+            "Name": "s0e7",
+            "Id": "0-7",
+            "ParentIndexNumber": 0,
+            "IndexNumber": 7,
+            "PremiereDate": "2021-06-01T20:00:00Z",
+        }))
+        .unwrap();
+        let all_episodes = [
+            special(5, "2020-12-25"),
+            special(1, "2019-01-01"),
+            unwatched,
+        ];
+        let watched_episodes = [special(5, "2020-12-25"), special(1, "2019-01-01")];
+        let sonarr_special = |id, episode, aired: &str| Episode {
+            air_date: Some(aired.parse().unwrap()),
+            ..sonarr_episode(id, 0, episode, id * 10)
+        };
+        // numbered differently than in the media server
+        let sonarr_episodes = || {
+            vec![
+                sonarr_special(1, 1, "2019-01-01"),
+                sonarr_special(2, 2, "2020-12-25"),
+                // aired on the date of the unwatched special only
+                sonarr_special(3, 3, "2021-06-01"),
+                // aired on no date of the media server specials
+                sonarr_special(4, 4, "2022-01-01"),
+            ]
+        };
+        let matching = EpisodeMatching {
+            specials: true,
+            ..Default::default()
+        };
+
+        let ids = |matched: Vec<WatchedEpisode>| -> Vec<u64> {
+            matched.iter().map(|ep| ep.episode.id).collect()
+        };
+        assert_eq!(
+            ids(match_episodes(
+                &watched_episodes,
+                &all_episodes,
+                sonarr_episodes(),
+                matching
+            )),
+            vec![1, 2]
+        );
+        assert!(
+            match_episodes(
+                &watched_episodes,
+                &all_episodes,
+                sonarr_episodes(),
+                Default::default()
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_match_specials_by_title() {
+        let special = |episode, name: &str, aired: Option<&str>| -> JellyfinItem {
+            let mut special = jellyfin_episode(0, episode);
+            special.name = name.to_owned();
+            special.premiere_date =
+                aired.map(|aired| format!("{aired}T20:00:00Z").parse().unwrap());
+            special
+        };
+        let all_episodes = [
+            special(5, "Christmas Special", None),
+            special(8, "Behind the Scenes", Some("2021-06-01")),
+            special(9, "The Making Of", Some("2021-06-01")),
+        ];
+        let sonarr_special = |id, episode, title: &str, aired: Option<&str>| Episode {
+            title: title.to_owned(),
+            air_date: aired.map(|aired| aired.parse().unwrap()),
+            ..sonarr_episode(id, 0, episode, id * 10)
+        };
+        let sonarr_episodes = vec![
+            // the air date is unknown
+            sonarr_special(1, 1, "christmas special", None),
+            // several specials aired on the same date
+            sonarr_special(2, 2, "THE MAKING OF", Some("2021-06-01")),
+            sonarr_special(3, 3, "Deleted Scenes", Some("2021-06-01")),
+        ];
+        let matching = EpisodeMatching {
+            specials: true,
+            ..Default::default()
+        };
+
+        let matched = match_episodes(&all_episodes, &all_episodes, sonarr_episodes, matching);
+        let ids: Vec<u64> = matched.iter().map(|ep| ep.episode.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_for_deletion_respects_retention_and_tags() {
+        let now = Utc::now();
+        let day = Duration::from_secs(60 * 60 * 24);
+        let watched = |id, days_ago: u32| WatchedEpisode {
+            episode: sonarr_episode(id, 1, id as u32, id * 10),
+            last_played: Some(now - day * days_ago),
+        };
+        let candidates = EpisodeCandidates {
+            now,
+            watched: vec![
+                SeriesWithWatchedEpisodes {
+                    series: SeriesInfo {
+                        title: "kept".to_string(),
+                        tags: Some(vec![5]),
+                        ..Default::default()
+                    },
+                    episodes: vec![watched(1, 30)],
+                    latest: HashSet::new(),
+                },
+                SeriesWithWatchedEpisodes {
+                    series: SeriesInfo {
+                        title: "show".to_string(),
+                        ..Default::default()
+                    },
+                    episodes: vec![watched(2, 30), watched(3, 1), watched(4, 30)],
+                    latest: HashSet::from([4]),
+                },
+            ],
+            not_yet_eligible: Vec::new(),
+            forbidden_tags: vec![5],
+            required_tags: None,
+            forbidden_profiles: vec![],
+            keep_current_season: false,
+        };
+
+        let ids = |episodes: Vec<EpisodeForDeletion>| -> Vec<u64> {
+            episodes.iter().map(|ep| ep.episode.id).collect()
+        };
+        assert_eq!(ids(candidates.for_deletion(Some(day * 7))), vec![2]);
+        assert_eq!(ids(candidates.for_deletion(None)), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_latest_aired() {
+        let now = Utc::now();
+        let day = Duration::from_secs(60 * 60 * 24);
+        let aired = |id, season, episode, air_date| Episode {
+            air_date_utc: air_date,
+            ..sonarr_episode(id, season, episode, 0)
+        };
+        let episodes = [
+            aired(1, 2, 1, Some(now - day * 3)),
+            aired(2, 1, 2, Some(now - day * 30)),
+            aired(3, 1, 1, Some(now - day * 40)),
+            // not aired yet
+            aired(4, 2, 2, Some(now + day)),
+            aired(5, 2, 3, None),
+            // specials
+            aired(6, 0, 1, Some(now - day)),
+        ];
+
+        assert_eq!(latest_aired(&episodes, now, 2), HashSet::from([1, 2]));
+        assert_eq!(latest_aired(&episodes, now, 10), HashSet::from([1, 2, 3]));
+        assert!(latest_aired(&episodes, now, 0).is_empty());
+    }
+
+    #[test]
+    fn test_for_deletion_keeps_current_season() {
+        let season = |season_number, next_airing| Season {
+            season_number,
+            monitored: true,
+            statistics: SeasonStatistics {
+                next_airing,
+                episode_file_count: 1,
+                total_episode_count: 2,
+            },
+        };
+        let watched = |id, season| WatchedEpisode {
+            episode: sonarr_episode(id, season, 1, id * 10),
+            last_played: None,
+        };
+        let mut candidates = EpisodeCandidates {
+            watched: vec![SeriesWithWatchedEpisodes {
+                series: SeriesInfo {
+                    title: "airing".to_string(),
+                    seasons: Some(vec![season(1, None), season(2, Some(Utc::now()))]),
+                    ..Default::default()
+                },
+                episodes: vec![watched(1, 1), watched(2, 2)],
+                latest: HashSet::new(),
+            }],
+            keep_current_season: true,
+            ..Default::default()
+        };
+
+        let ids = |episodes: Vec<EpisodeForDeletion>| -> Vec<u64> {
+            episodes.iter().map(|ep| ep.episode.id).collect()
+        };
+        assert_eq!(ids(candidates.for_deletion(None)), vec![1]);
+        candidates.keep_current_season = false;
+        assert_eq!(ids(candidates.for_deletion(None)), vec![1, 2]);
+    }
+}
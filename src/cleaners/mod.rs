@@ -1,6 +1,168 @@
+mod episodes;
 mod movies;
+mod music;
 mod series;
 mod utils;
 
+use crate::{
+    config::{RetentionAnchor, SonarrCleanupMode, SonarrConfig, WatchedPolicy},
+    http::{HttpClient, MediaServer, UserId},
+    report::CleanerReport,
+    services::{Clock, Confirmation, DeletionLimit, StateStore},
+};
+pub use episodes::EpisodesCleaner;
 pub use movies::MoviesCleaner;
+pub use music::MusicCleaner;
 pub use series::SeriesCleaner;
+pub use utils::{RetentionDelta, human_size, try_join_limited};
+
+/// defines what cleaners do with the items eligible for deletion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CleanupMode {
+    /// only list the items that would be deleted
+    DryRun,
+    /// delete all eligible items
+    Delete,
+    /// delete all eligible items once the user confirms the deletion
+    Confirm,
+    /// let the user pick which of the eligible items to delete
+    Interactive,
+}
+
+/// the users whose watch history decides what gets deleted
+#[derive(Clone)]
+pub struct Users {
+    pub ids: Vec<UserId>,
+    pub policy: WatchedPolicy,
+    /// percentage of an item played that counts as watched, besides items
+    /// marked as played
+    pub watched_threshold: Option<f64>,
+    /// how many times an item has to be played by a user
+    pub min_play_count: Option<u32>,
+    /// with `DateAdded` all items count as watched on the date they were added
+    pub retention_anchor: RetentionAnchor,
+}
+
+/// cleans up TV shows from Sonarr either per series or per episode, depending
+/// on `sonarr.cleanup_mode`. Only one of them runs so they don't fight each
+/// other
+pub enum TvCleaner {
+    Series(SeriesCleaner),
+    Episodes(EpisodesCleaner),
+}
+
+impl TvCleaner {
+    pub fn new(
+        sonarr_config: SonarrConfig,
+        http: &HttpClient,
+        media_server: MediaServer,
+        users: &Users,
+        clock: Clock,
+        deletion_limit: DeletionLimit,
+        state: StateStore,
+    ) -> anyhow::Result<Self> {
+        let cleaner = match sonarr_config.cleanup_mode {
+            SonarrCleanupMode::Series => Self::Series(SeriesCleaner::new(
+                sonarr_config,
+                http,
+                media_server,
+                users,
+                clock,
+                deletion_limit,
+                state,
+            )?),
+            SonarrCleanupMode::Episode => Self::Episodes(EpisodesCleaner::new(
+                sonarr_config,
+                http,
+                media_server,
+                users,
+                clock,
+                deletion_limit,
+                state,
+            )?),
+        };
+        Ok(cleaner)
+    }
+
+    pub async fn cleanup(
+        &self,
+        mode: CleanupMode,
+        confirmation: Confirmation,
+    ) -> anyhow::Result<CleanerReport> {
+        match self {
+            Self::Series(cleaner) => cleaner.cleanup(mode, confirmation).await,
+            Self::Episodes(cleaner) => cleaner.cleanup(mode, confirmation).await,
+        }
+    }
+
+    pub async fn simulate(
+        &self,
+        retention_period: Option<std::time::Duration>,
+    ) -> anyhow::Result<RetentionDelta> {
+        match self {
+            Self::Series(cleaner) => cleaner.simulate(retention_period).await,
+            Self::Episodes(cleaner) => cleaner.simulate(retention_period).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ClockConfig, HttpConfig, JellyfinConfig},
+        http::JellyfinClient,
+    };
+    use std::sync::Arc;
+
+    async fn tv_cleaner(cleanup_mode: &str) -> anyhow::Result<TvCleaner> {
+        let sonarr_config: SonarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "http://localhost:8989"
+            api_key = "api-key-foo"
+            cleanup_mode = "{cleanup_mode}"
+            "#
+        ))?;
+        let http = HttpClient::new(&HttpConfig::default())?;
+        let media_server = Arc::new(JellyfinClient::new(
+            &JellyfinConfig {
+                base_url: "http://localhost:8096".to_owned(),
+                api_key: "api-key-foo".to_owned(),
+                timeout: None,
+                basic_auth_username: None,
+                basic_auth_password: None,
+                refresh_after_cleanup: false,
+                libraries: Vec::new(),
+                protected_collections: Vec::new(),
+            },
+            &http,
+        )?);
+        let users = Users {
+            ids: vec![serde_json::from_str("\"user-id\"")?],
+            policy: WatchedPolicy::All,
+            watched_threshold: None,
+            min_play_count: None,
+            retention_anchor: RetentionAnchor::LastPlayed,
+        };
+        let clock = Clock::new(&ClockConfig::default());
+        TvCleaner::new(
+            sonarr_config,
+            &http,
+            media_server,
+            &users,
+            clock,
+            DeletionLimit::new(None, None),
+            StateStore::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tv_cleaner_selected_by_config() -> anyhow::Result<()> {
+        assert!(matches!(tv_cleaner("series").await?, TvCleaner::Series(_)));
+        assert!(matches!(
+            tv_cleaner("episode").await?,
+            TvCleaner::Episodes(_)
+        ));
+        Ok(())
+    }
+}
@@ -1,26 +1,48 @@
 use crate::{
-    cleaners::utils,
-    config::RadarrConfig,
+    cleaners::{
+        CleanupMode, RetentionDelta, Users,
+        utils::{self, Downloads, Grab},
+    },
+    config::{RadarrConfig, WatchedPolicy},
     http::{
-        Item as JellyfinItem, ItemsFilter, JellyfinClient, Movie, MovieEditor, RadarrClient,
-        TorrentClientKind, UserId,
+        Collection, HttpClient, Item as JellyfinItem, ItemsFilter, MediaServer, Movie, MovieEditor,
+        RadarrClient,
     },
-    services::DownloadService,
+    report::{CleanerReport, labels},
+    services::{Clock, Confirmation, DeletionLimit, StateStore, Trash},
 };
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use log::{debug, info, warn};
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
-};
+use std::{collections::HashSet, path::Path, time::Duration};
 
 pub struct MoviesCleaner {
     radarr_client: RadarrClient,
-    jellyfin: JellyfinClient,
-    download_service: DownloadService,
+    media_server: MediaServer,
     tags_to_keep: Vec<String>,
+    tags_to_delete: Vec<String>,
+    keep_quality_profiles: Vec<String>,
+    protected_genres: Vec<String>,
+    ignore_titles: Vec<String>,
+    ignore_tmdb_ids: Vec<u64>,
+    min_rating_to_keep: Option<f32>,
+    min_age_since_added: Option<Duration>,
+    min_free_space: Option<u64>,
     retention_period: Option<Duration>,
-    user_id: UserId,
+    unwatched_retention: Option<Duration>,
+    users: Users,
     unmonitor_watched: bool,
+    add_to_exclusion: bool,
+    delete_from_incomplete_collections: bool,
+    /// deleting more items than this needs a confirmation even with `--yes`,
+    /// `None` with `--ignore-confirm-threshold`
+    confirm_threshold: Option<usize>,
+    /// where the files of deleted movies are moved to instead of deleting them
+    trash: Option<Trash>,
+    clock: Clock,
+    deletion_limit: DeletionLimit,
+    state: StateStore,
+    max_concurrent_requests: usize,
 }
 
 /// MoviesCleaner is responsible for cleaning up watched movies from Radarr and
@@ -28,96 +50,395 @@ pub struct MoviesCleaner {
 impl MoviesCleaner {
     pub fn new(
         radarr_config: RadarrConfig,
-        jellyfin: JellyfinClient,
-        download_service: DownloadService,
-        user_id: &UserId,
+        http: &HttpClient,
+        media_server: MediaServer,
+        users: &Users,
+        clock: Clock,
+        deletion_limit: DeletionLimit,
+        state: StateStore,
     ) -> anyhow::Result<Self> {
+        let radarr_client = RadarrClient::new(&radarr_config, http)?;
         let RadarrConfig {
-            base_url,
-            api_key,
+            base_url: _,
+            api_key: _,
+            timeout: _,
+            basic_auth_username: _,
+            basic_auth_password: _,
             tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tmdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
             retention_period,
+            unwatched_retention,
             unmonitor_watched,
+            add_to_exclusion,
+            delete_from_incomplete_collections,
+            confirm_threshold,
+            deletion_mode,
+            trash_dir,
         } = radarr_config;
-        let radarr_client = RadarrClient::new(&base_url, &api_key)?;
 
         Ok(Self {
             radarr_client,
-            jellyfin,
-            download_service,
+            media_server,
             tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tmdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
             retention_period,
+            unwatched_retention,
             unmonitor_watched,
-            user_id: user_id.clone(),
+            add_to_exclusion,
+            delete_from_incomplete_collections,
+            confirm_threshold,
+            trash: Trash::of(deletion_mode, trash_dir),
+            users: users.clone(),
+            clock,
+            deletion_limit,
+            state,
+            max_concurrent_requests: http.max_concurrent_requests(),
         })
     }
 
     /// unmonitor watched movies (if configured) and cleanup movies from Radarr
     /// and Download client that are fully watched in Jellyfin
-    pub async fn cleanup(&self, force_delete: bool) -> anyhow::Result<()> {
-        let watched_movies = self.watched_movies(&self.user_id).await?;
-        if watched_movies.is_empty() {
-            log::info!("no movies found for deletion in Jellyfin!");
-            return Ok(());
+    pub async fn cleanup(
+        &self,
+        mode: CleanupMode,
+        mut confirmation: Confirmation,
+    ) -> anyhow::Result<CleanerReport> {
+        let mut report = CleanerReport::new("movies");
+        if let Some(server_time) = self.radarr_client.server_time().await? {
+            self.clock.check_skew("Radarr", server_time);
+        }
+
+        let candidates = self.candidates().await?;
+        self.state.prune(report.cleaner, &candidates.ids());
+        if candidates.is_empty() {
+            log::info!(
+                "no movies found for deletion in {}!",
+                self.media_server.name()
+            );
+            return Ok(report);
         }
+        report.considered = candidates.considered();
 
         if self.unmonitor_watched {
-            self.unmonitor(&watched_movies).await?;
+            self.unmonitor(&candidates.watched, mode).await?;
         }
 
-        let forbidden_tags = self.forbidden_tags().await?;
         let movies_for_deletion =
-            watched_movies.filter_for_deletion(self.retention_period, &forbidden_tags)?;
-
+            candidates.for_deletion(self.retention_period, self.unwatched_retention)?;
+        let movies_for_deletion = self.skip_queued(movies_for_deletion, &mut report).await?;
+        let movies_for_deletion = self
+            .skip_incomplete_collections(movies_for_deletion, &mut report)
+            .await?;
+        let movies_for_deletion =
+            self.state
+                .without_deleted(movies_for_deletion, |movie| movie.id, &mut report);
+        let movies_for_deletion = self.state.after_grace_period(
+            movies_for_deletion,
+            |movie| movie.id,
+            self.clock.now(),
+            &mut report,
+        );
+        let movies_for_deletion = self
+            .until_min_free_space(movies_for_deletion, &candidates, &mut report)
+            .await?;
         if movies_for_deletion.is_empty() {
             info!("no movies found for deletion in Radarr!");
-            return Ok(());
+            return Ok(report);
+        }
+
+        let eligible = labels(&movies_for_deletion);
+        let movies_for_deletion = if mode == CleanupMode::Interactive {
+            confirmation
+                .select("movies from Radarr", movies_for_deletion, |movie| {
+                    utils::details(candidates.last_played(movie.id), movie.size_on_disk)
+                })
+                .await?
+        } else {
+            movies_for_deletion
+        };
+        report.skip_missing(&eligible, &movies_for_deletion, "not selected");
+        let selected = labels(&movies_for_deletion);
+        let movies_for_deletion = confirmation
+            .limit(
+                movies_for_deletion,
+                |movie| candidates.last_played(movie.id),
+                |movie| movie.size_on_disk,
+            )
+            .await?;
+        report.skip_missing(
+            &selected,
+            &movies_for_deletion,
+            "`--max-delete` or `--max-free` limit reached",
+        );
+        if movies_for_deletion.is_empty() {
+            info!("no movies selected for deletion");
+            return Ok(report);
         }
 
         let movie_ids = movies_for_deletion.iter().map(|m| m.id).collect();
-        let download_ids = self.download_ids(&movie_ids).await?;
+        let downloads = self.download_ids(&movie_ids).await?;
+
+        if !utils::confirm_deletion(
+            mode,
+            self.confirm_threshold,
+            "movies from Radarr",
+            labels(&movies_for_deletion),
+            downloads.count(),
+            confirmation,
+        )
+        .await?
+        {
+            info!("deletion of movies cancelled");
+            report.skip_all(&movies_for_deletion, "deletion not confirmed");
+            return Ok(report);
+        }
 
-        if force_delete {
+        let movies_for_deletion = if mode != CleanupMode::DryRun {
             debug!("trying to delete items in Radarr: {movies_for_deletion:?}");
-            self.delete_movies(&movie_ids).await?;
-            info!("successfully deleted items from Radarr: {movies_for_deletion:?}");
-            self.download_service.delete(&download_ids).await?;
+            let deleted = self.delete_movies(movies_for_deletion, &mut report).await;
+            info!("successfully deleted items from Radarr: {deleted:?}");
+            self.state.record_deleted(
+                report.cleaner,
+                deleted.iter().map(|movie| movie.id),
+                self.clock.now(),
+            );
+            deleted
         } else {
             info!(
                 "no items will be deleted as no `--force-delete` flag is provided. Listing them instead: {movies_for_deletion:?}"
             );
-            self.download_service.list(&download_ids).await?;
+            movies_for_deletion
+        };
+        report.deleted = labels(&movies_for_deletion);
+        let deleted = movies_for_deletion.iter().map(|movie| movie.id).collect();
+        report.download_ids = downloads.of_deleted(&deleted);
+        report.size_on_disk = movies_for_deletion
+            .iter()
+            .map(|movie| movie.size_on_disk)
+            .sum();
+        utils::log_freed_space(mode, "movies", report.size_on_disk);
+
+        Ok(report)
+    }
+
+    /// compares movies eligible for deletion under the configured retention
+    /// period with those eligible under the given one. Nothing gets deleted
+    pub async fn simulate(
+        &self,
+        retention_period: Option<Duration>,
+    ) -> anyhow::Result<RetentionDelta> {
+        let candidates = self.candidates().await?;
+        let current = candidates.for_deletion(self.retention_period, self.unwatched_retention)?;
+        let simulated = candidates.for_deletion(
+            retention_period.or(self.retention_period),
+            self.unwatched_retention,
+        )?;
+        Ok(RetentionDelta::new(&current, &simulated))
+    }
+
+    /// gathers all the data needed to decide which movies to delete
+    async fn candidates(&self) -> anyhow::Result<MovieCandidates> {
+        let watched = self.watched_movies().await?;
+        let unwatched = match self.unwatched_retention {
+            Some(_) => self.unwatched_movies().await?,
+            None => Vec::new(),
+        };
+        if watched.is_empty() && unwatched.is_empty() {
+            return Ok(MovieCandidates::default());
         }
 
-        Ok(())
+        Ok(MovieCandidates {
+            now: self.clock.now(),
+            watched,
+            unwatched,
+            forbidden_tags: self.forbidden_tags().await?,
+            required_tags: self.required_tags().await?,
+            forbidden_profiles: self.forbidden_quality_profiles().await?,
+        })
     }
 
-    /// queries Jellyfin and returns all watched movies for the given user
-    async fn watched_jellyfin_items(&self, user_id: &UserId) -> anyhow::Result<Vec<JellyfinItem>> {
-        self.jellyfin
-            .items(
-                ItemsFilter::watched()
-                    .user_id(user_id.as_ref())
-                    .include_item_types(&["Movie", "Video"]),
-            )
-            .await
+    /// queries Jellyfin and returns all movies watched by all the users
+    async fn watched_jellyfin_items(&self) -> anyhow::Result<Vec<JellyfinItem>> {
+        let items = utils::watched_items_of_users(
+            &self.media_server,
+            &self.users,
+            ItemsFilter::watched().include_item_types(&["Movie", "Video"]),
+        )
+        .await?;
+        let items =
+            utils::without_ignored(items, &self.ignore_titles, &self.ignore_tmdb_ids, |i| {
+                i.tmdb_id()
+            });
+        let items = utils::without_protected_genres(items, &self.protected_genres);
+        let items = utils::without_high_ratings(items, self.min_rating_to_keep);
+        Ok(utils::without_recently_added(
+            items,
+            self.clock.now(),
+            self.min_age_since_added,
+        ))
     }
 
-    /// delete movies with given ids
-    async fn delete_movies(&self, movies_ids: &HashSet<u64>) -> anyhow::Result<()> {
-        let delete_futs = movies_ids
-            .iter()
-            .map(|id| self.radarr_client.delete_movie(*id));
-        let _ = futures::future::try_join_all(delete_futs).await?;
-        Ok(())
+    /// leaves out movies with a download in the Radarr queue (e.g. an upgrade
+    /// in progress), as deleting them would orphan the download
+    async fn skip_queued<'a>(
+        &self,
+        movies: Vec<&'a Movie>,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<&'a Movie>> {
+        if movies.is_empty() {
+            return Ok(movies);
+        }
+        let queue = self.radarr_client.queue().await?;
+        let queued: HashSet<u64> = queue.into_iter().filter_map(|r| r.movie_id).collect();
+        let (in_queue, movies): (Vec<_>, Vec<_>) =
+            movies.into_iter().partition(|m| queued.contains(&m.id));
+        if !in_queue.is_empty() {
+            info!("skipping movies that are still in the Radarr queue: {in_queue:?}");
+            report.skip_all(&in_queue, "still in the Radarr queue");
+        }
+        Ok(movies)
     }
 
-    /// unmonitor watched movies that are still monitored
-    async fn unmonitor(&self, watched: &WatchedMovies) -> anyhow::Result<()> {
-        let ids = watched.monitored_movie_ids();
-        if ids.is_empty() {
+    /// leaves out movies of monitored collections that are still being
+    /// completed, i.e. that miss movies in the library or their files, unless
+    /// `delete_from_incomplete_collections` is set
+    async fn skip_incomplete_collections<'a>(
+        &self,
+        movies: Vec<&'a Movie>,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<&'a Movie>> {
+        if self.delete_from_incomplete_collections || movies.iter().all(|m| m.collection.is_none())
+        {
+            return Ok(movies);
+        }
+        let collections = self.radarr_client.collections().await?;
+        let library = self.radarr_client.movies().await?;
+        let incomplete = incomplete_collections(&collections, &library);
+
+        let mut kept = Vec::with_capacity(movies.len());
+        for movie in movies {
+            let collection = movie
+                .collection
+                .as_ref()
+                .filter(|c| incomplete.contains(&c.tmdb_id));
+            match collection {
+                Some(collection) => {
+                    info!(
+                        "skipping {movie:?} as its collection \"{}\" is still being completed",
+                        collection.title
+                    );
+                    report.skip_all(
+                        &[movie],
+                        &format!("collection \"{}\" is incomplete", collection.title),
+                    );
+                }
+                None => kept.push(movie),
+            }
+        }
+        Ok(kept)
+    }
+
+    /// keeps only as many movies as needed to reach `min_free_space` on the
+    /// disk holding them, if configured
+    async fn until_min_free_space<'a>(
+        &self,
+        movies: Vec<&'a Movie>,
+        candidates: &MovieCandidates,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<&'a Movie>> {
+        let Some(min_free_space) = self.min_free_space else {
+            return Ok(movies);
+        };
+        if movies.is_empty() {
+            return Ok(movies);
+        }
+        let disks = self.radarr_client.disk_space().await?;
+        let paths = movies.iter().filter_map(|movie| movie.path.as_deref());
+        let Some(disk) = utils::holding_disk(&disks, paths) else {
+            warn!(
+                "none of the disks reported by Radarr holds the movies, ignoring `min_free_space`"
+            );
+            return Ok(movies);
+        };
+        let eligible = labels(&movies);
+        let movies = utils::until_disk_free_space(
+            "movies",
+            movies,
+            disk,
+            min_free_space,
+            &self.deletion_limit,
+            |movie| movie.size_on_disk,
+            |movie| candidates.last_played(movie.id),
+        );
+        report.skip_missing(&eligible, &movies, "enough free space");
+        Ok(movies)
+    }
+
+    /// deletes the given movies, continuing past failures, and returns those
+    /// that were deleted. With a trash only the movies are removed from Radarr
+    /// and their files are moved into the trash afterwards, so that Radarr
+    /// never re-downloads a movie whose files are gone
+    async fn delete_movies<'a>(
+        &self,
+        movies: Vec<&'a Movie>,
+        report: &mut CleanerReport,
+    ) -> Vec<&'a Movie> {
+        utils::delete_each(
+            movies,
+            |movie| movie.id,
+            |movie| {
+                let movie: &Movie = movie;
+                async move {
+                    let trash = match &self.trash {
+                        Some(trash) => {
+                            let path = movie.path.as_deref().context("Radarr reported no path")?;
+                            Some((trash, Path::new(path)))
+                        }
+                        None => None,
+                    };
+                    self.radarr_client
+                        .delete_movie(movie.id, trash.is_none(), self.add_to_exclusion)
+                        .await?;
+                    if let Some((trash, path)) = trash {
+                        trash
+                            .move_item(movie, path, self.clock.now())
+                            .context("removed from Radarr, but the files were left in place")?;
+                    }
+                    Ok(())
+                }
+            },
+            self.max_concurrent_requests,
+            report,
+        )
+        .await
+    }
+
+    /// unmonitor watched movies that are still monitored. In dry-run mode they
+    /// are only listed
+    async fn unmonitor(&self, watched: &WatchedMovies, mode: CleanupMode) -> anyhow::Result<()> {
+        let movies = watched.monitored_movies();
+        if movies.is_empty() {
             debug!("no monitored movies found for unmonitoring");
+        } else if mode == CleanupMode::DryRun {
+            info!(
+                "no movies will be unmonitored as no `--force-delete` flag is provided. Listing them instead: {movies:?}"
+            );
         } else {
+            let ids = movies.iter().map(|m| m.id).collect();
             let request = MovieEditor::new(ids).monitored(false);
             let response = self.radarr_client.bulk_edit(&request).await?;
             let log_msg = response
@@ -132,21 +453,30 @@ impl MoviesCleaner {
 
     /// queries Radarr history for given movie ids and gets corresponding
     /// download_id's per torrent client for each
-    async fn download_ids(
-        &self,
-        ids: &HashSet<u64>,
-    ) -> anyhow::Result<HashMap<TorrentClientKind, HashSet<String>>> {
-        let mut per_client_hashes = HashMap::new();
+    async fn download_ids(&self, ids: &HashSet<u64>) -> anyhow::Result<Downloads> {
         let records = self.radarr_client.history_records(ids).await?;
-        for record in records {
-            if let Some((kind, hash)) = record.download_id_per_client() {
-                per_client_hashes
-                    .entry(kind)
-                    .or_insert_with(HashSet::new)
-                    .insert(hash);
-            }
-        }
-        Ok(per_client_hashes)
+        let grabs: Vec<Grab> = records
+            .into_iter()
+            .filter_map(|record| {
+                let item_id = record.movie_id;
+                let date = record.date;
+                let (client, download_id) = record.download_id_per_client()?;
+                Some(Grab {
+                    item_id,
+                    date,
+                    client,
+                    download_id,
+                })
+            })
+            .collect();
+        let grabbed_for = grabs
+            .iter()
+            .map(|grab| (grab.item_id, grab.download_id.clone()))
+            .collect::<Vec<_>>();
+        Ok(Downloads::new(
+            utils::latest_download_ids(grabs),
+            grabbed_for,
+        ))
     }
 
     /// gets IDs of the tags that are configured to be kept
@@ -165,9 +495,85 @@ impl MoviesCleaner {
         Ok(forbidden_tags)
     }
 
+    /// gets IDs of the tags an item needs to carry to be deleted, `None` if
+    /// `tags_to_delete` is not configured
+    async fn required_tags(&self) -> anyhow::Result<Option<Vec<u64>>> {
+        if self.tags_to_delete.is_empty() {
+            return Ok(None);
+        }
+        let tags = self.radarr_client.tags().await?;
+        let required_tags: Vec<u64> = tags
+            .iter()
+            .filter(|t| self.tags_to_delete.contains(&t.label))
+            .map(|t| t.id)
+            .collect();
+        if required_tags.is_empty() {
+            warn!(
+                "none of the tags to delete {:?} exist in Radarr, nothing will be deleted",
+                self.tags_to_delete
+            );
+        }
+        Ok(Some(required_tags))
+    }
+
+    /// gets IDs of the quality profiles that are configured to be kept
+    async fn forbidden_quality_profiles(&self) -> anyhow::Result<Vec<u64>> {
+        if self.keep_quality_profiles.is_empty() {
+            return Ok(Vec::new());
+        }
+        debug!(
+            "forbidden movie quality profiles configured: {:?}",
+            self.keep_quality_profiles
+        );
+
+        let profiles = self.radarr_client.quality_profiles().await?;
+        let forbidden_profiles = profiles
+            .iter()
+            .filter(|p| self.keep_quality_profiles.contains(&p.name))
+            .map(|p| p.id)
+            .collect();
+
+        debug!("forbidden quality profile ids: {forbidden_profiles:?}");
+
+        Ok(forbidden_profiles)
+    }
+
     /// queries movies per Jellyfin items and returns a [`WatchedMovies`] object
-    async fn watched_movies(&self, user_id: &UserId) -> anyhow::Result<WatchedMovies> {
-        let items = self.watched_jellyfin_items(user_id).await?;
+    async fn watched_movies(&self) -> anyhow::Result<WatchedMovies> {
+        let items = self.watched_jellyfin_items().await?;
+        let movie_items = self.radarr_movies(items).await?;
+        Ok(WatchedMovies(movie_items))
+    }
+
+    /// queries Jellyfin for movies that have never been played by any of the
+    /// users and returns the corresponding Radarr movies
+    async fn unwatched_movies(&self) -> anyhow::Result<Vec<Movie>> {
+        let items = utils::items_of_users(
+            &self.media_server,
+            &self.users.ids,
+            // unwatched by every user, whatever `watched_policy` is
+            WatchedPolicy::All,
+            ItemsFilter::unwatched().include_item_types(&["Movie", "Video"]),
+        )
+        .await?
+        .into_iter()
+        .filter(|item| item.last_played_date().is_none())
+        .collect();
+        let items =
+            utils::without_ignored(items, &self.ignore_titles, &self.ignore_tmdb_ids, |i| {
+                i.tmdb_id()
+            });
+        let items = utils::without_protected_genres(items, &self.protected_genres);
+        let items = utils::without_high_ratings(items, self.min_rating_to_keep);
+        let items =
+            utils::without_recently_added(items, self.clock.now(), self.min_age_since_added);
+
+        let movie_items = self.radarr_movies(items).await?;
+        Ok(movie_items.into_iter().flat_map(|wm| wm.movies).collect())
+    }
+
+    /// looks up Radarr movies for each of the given Jellyfin items
+    async fn radarr_movies(&self, items: Vec<JellyfinItem>) -> anyhow::Result<Vec<WatchedMovie>> {
         let movies_futs = items.into_iter().map(|jellyfin_item| async move {
             let Some(tmdb_id) = jellyfin_item.tmdb_id() else {
                 warn!("movie \"{}\" has no TMDB id, skipping", jellyfin_item.name);
@@ -181,14 +587,13 @@ impl MoviesCleaner {
             Ok::<_, anyhow::Error>(Some(watched))
         });
 
-        let results = futures::future::try_join_all(movies_futs).await?;
-        let movie_items = results.into_iter().flatten().collect();
-        Ok(WatchedMovies(movie_items))
+        let results = utils::try_join_limited(movies_futs, self.max_concurrent_requests).await?;
+        Ok(results.into_iter().flatten().collect())
     }
 }
 
 /// check if it's safe to delete a movie.
-fn safe_to_delete(movie: &Movie, forbidden_tags: &[u64]) -> bool {
+fn safe_to_delete(movie: &Movie, forbidden_tags: &[u64], forbidden_profiles: &[u64]) -> bool {
     let has_forbidden_tags = movie
         .tags
         .as_ref()
@@ -198,14 +603,144 @@ fn safe_to_delete(movie: &Movie, forbidden_tags: &[u64]) -> bool {
         debug!("movie '{}' has forbidden tags, skipping", movie.title);
         return false;
     }
+    if forbidden_profiles.contains(&movie.quality_profile_id) {
+        debug!(
+            "movie '{}' has forbidden quality profile, skipping",
+            movie.title
+        );
+        return false;
+    }
+    if !movie.has_file || movie.size_on_disk == 0 {
+        debug!("movie '{}' not present on disk, skipping", movie.title);
+        return false;
+    }
     true
 }
 
+/// filter never watched movies that were added to Radarr longer than
+/// `unwatched_retention` ago
+fn unwatched_for_deletion<'a>(
+    movies: &'a [Movie],
+    now: DateTime<Utc>,
+    unwatched_retention: Duration,
+    forbidden_tags: &[u64],
+    forbidden_profiles: &[u64],
+) -> Vec<&'a Movie> {
+    let retention_date = now - unwatched_retention;
+    movies
+        .iter()
+        .filter(|movie| match movie.added {
+            Some(added) if retention_date > added => true,
+            Some(added) => {
+                debug!(
+                    "unwatched retention period for \"{}\" is not yet passed ({} left), skipping",
+                    movie.title,
+                    utils::retention_str(&added, &retention_date)
+                );
+                false
+            }
+            None => {
+                debug!("movie \"{}\" has no date added, skipping", movie.title);
+                false
+            }
+        })
+        .filter(|movie| safe_to_delete(movie, forbidden_tags, forbidden_profiles))
+        .collect()
+}
+
+/// all the data needed to decide which movies are eligible for deletion
+#[derive(Default)]
+struct MovieCandidates {
+    now: DateTime<Utc>,
+    watched: WatchedMovies,
+    unwatched: Vec<Movie>,
+    forbidden_tags: Vec<u64>,
+    /// ids of `tags_to_delete`, if configured
+    required_tags: Option<Vec<u64>>,
+    forbidden_profiles: Vec<u64>,
+}
+
+impl MovieCandidates {
+    fn is_empty(&self) -> bool {
+        self.watched.is_empty() && self.unwatched.is_empty()
+    }
+
+    /// movies eligible for deletion under the given retention periods
+    fn for_deletion(
+        &self,
+        retention_period: Option<Duration>,
+        unwatched_retention: Option<Duration>,
+    ) -> anyhow::Result<Vec<&Movie>> {
+        let mut movies = self.watched.filter_for_deletion(
+            self.now,
+            retention_period,
+            &self.forbidden_tags,
+            &self.forbidden_profiles,
+        )?;
+        if let Some(unwatched_retention) = unwatched_retention {
+            movies.extend(unwatched_for_deletion(
+                &self.unwatched,
+                self.now,
+                unwatched_retention,
+                &self.forbidden_tags,
+                &self.forbidden_profiles,
+            ));
+        }
+        movies.retain(|movie| {
+            utils::has_required_tag(
+                &movie.title,
+                movie.tags.as_deref(),
+                self.required_tags.as_deref(),
+            )
+        });
+        Ok(movies)
+    }
+
+    /// number of movies checked for eligibility
+    fn considered(&self) -> usize {
+        self.watched.movies().len() + self.unwatched.len()
+    }
+
+    /// Radarr ids of the movies checked for eligibility
+    fn ids(&self) -> HashSet<u64> {
+        let watched = self.watched.movies().into_iter().map(|movie| movie.id);
+        watched
+            .chain(self.unwatched.iter().map(|movie| movie.id))
+            .collect()
+    }
+
+    /// when the given movie was last played, `None` for never played ones
+    fn last_played(&self, movie_id: u64) -> Option<DateTime<Utc>> {
+        self.watched
+            .0
+            .iter()
+            .find(|wm| wm.movies.iter().any(|m| m.id == movie_id))
+            .and_then(|wm| wm.jellyfin_item.last_played_date())
+    }
+}
+
+/// TMDB ids of the monitored collections that miss movies, either not added
+/// to the library yet or without a file
+fn incomplete_collections(collections: &[Collection], library: &[Movie]) -> HashSet<u64> {
+    let without_file: HashSet<u64> = library
+        .iter()
+        .filter(|m| m.monitored && !m.has_file)
+        .filter_map(|m| m.collection.as_ref().map(|c| c.tmdb_id))
+        .collect();
+    collections
+        .iter()
+        .filter(|c| c.monitored)
+        .filter(|c| c.missing_movies > 0 || without_file.contains(&c.tmdb_id))
+        .map(|c| c.tmdb_id)
+        .collect()
+}
+
 struct WatchedMovie {
     jellyfin_item: JellyfinItem,
     movies: Vec<Movie>,
 }
 
+#[derive(Default)]
 struct WatchedMovies(Vec<WatchedMovie>);
 
 impl WatchedMovies {
@@ -217,22 +752,20 @@ impl WatchedMovies {
         self.0.iter().flat_map(|wm| wm.movies.iter()).collect()
     }
 
-    fn monitored_movie_ids(&self) -> HashSet<u64> {
-        self.0
-            .iter()
-            .flat_map(|wm| wm.movies.iter())
-            .filter_map(|m| m.monitored.then_some(m.id))
-            .collect()
+    fn monitored_movies(&self) -> Vec<&Movie> {
+        self.movies().into_iter().filter(|m| m.monitored).collect()
     }
 
     fn filter_for_deletion(
         &self,
+        now: DateTime<Utc>,
         retention_period: Option<Duration>,
         forbidden_tags: &[u64],
+        forbidden_profiles: &[u64],
     ) -> anyhow::Result<Vec<&Movie>> {
         let movies = match retention_period {
             Some(retention_period) => {
-                let retention_date = chrono::Utc::now() - retention_period;
+                let retention_date = now - retention_period;
                 let mut safe_to_delete_items = vec![];
 
                 for item in &self.0 {
@@ -262,7 +795,7 @@ impl WatchedMovies {
 
         let movies = movies
             .into_iter()
-            .filter(|movie| safe_to_delete(movie, forbidden_tags))
+            .filter(|movie| safe_to_delete(movie, forbidden_tags, forbidden_profiles))
             .collect();
 
         Ok(movies)
@@ -272,6 +805,83 @@ impl WatchedMovies {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http::MovieCollection;
+
+    #[test]
+    fn test_unwatched_for_deletion() {
+        let now = Utc::now();
+        let day = Duration::from_secs(60 * 60 * 24);
+        let movies = vec![
+            Movie {
+                id: 1,
+                title: "old".to_string(),
+                has_file: true,
+                size_on_disk: 1,
+                added: Some(now - day * 100),
+                ..Default::default()
+            },
+            Movie {
+                id: 2,
+                title: "recent".to_string(),
+                has_file: true,
+                size_on_disk: 1,
+                added: Some(now - day * 10),
+                ..Default::default()
+            },
+            Movie {
+                id: 3,
+                title: "unknown".to_string(),
+                has_file: true,
+                size_on_disk: 1,
+                added: None,
+                ..Default::default()
+            },
+            Movie {
+                id: 4,
+                title: "old but kept".to_string(),
+                has_file: true,
+                size_on_disk: 1,
+                tags: Some(vec![5]),
+                added: Some(now - day * 100),
+                ..Default::default()
+            },
+        ];
+
+        let result = unwatched_for_deletion(&movies, now, day * 30, &[5], &[]);
+        let ids: Vec<u64> = result.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1]);
+    }
+
+    #[test]
+    fn test_incomplete_collections() -> anyhow::Result<()> {
+        let collections: Vec<Collection> = serde_json::from_str(
+            r#"[
+                {"title": "Alien", "tmdbId": 8091, "monitored": true, "missingMovies": 1},
+                {"title": "Heat", "tmdbId": 1, "monitored": true, "missingMovies": 0},
+                {"title": "Matrix", "tmdbId": 2344, "monitored": true, "missingMovies": 0},
+                {"title": "Saw", "tmdbId": 656, "monitored": false, "missingMovies": 3}
+            ]"#,
+        )?;
+        let in_collection = |tmdb_id, has_file| Movie {
+            monitored: true,
+            has_file,
+            collection: Some(MovieCollection {
+                title: String::new(),
+                tmdb_id,
+            }),
+            ..Default::default()
+        };
+        let library = vec![
+            in_collection(1, true),
+            // still being downloaded
+            in_collection(2344, false),
+            in_collection(2344, true),
+        ];
+
+        let incomplete = incomplete_collections(&collections, &library);
+        assert_eq!(incomplete, HashSet::from([8091, 2344]));
+        Ok(())
+    }
 
     #[test]
     fn test_movie_safe_to_delete() {
@@ -279,9 +889,13 @@ mod tests {
             id: 1,
             monitored: false,
             tags: Some(vec![1, 2, 3]),
+            has_file: true,
+            size_on_disk: 1,
             title: "movie".to_string(),
+            quality_profile_id: 1,
+            ..Default::default()
         };
-        assert!(safe_to_delete(&movie, &[]));
+        assert!(safe_to_delete(&movie, &[], &[]));
     }
 
     #[test]
@@ -291,7 +905,72 @@ mod tests {
             monitored: false,
             tags: Some(vec![5]),
             title: "movie".to_string(),
+            quality_profile_id: 1,
+            has_file: true,
+            size_on_disk: 1,
+            ..Default::default()
+        };
+        assert!(!safe_to_delete(&movie, &[4, 5, 6], &[]));
+    }
+
+    #[test]
+    fn test_movie_not_safe_to_delete_forbidden_quality_profile() {
+        let movie = Movie {
+            id: 1,
+            monitored: false,
+            tags: None,
+            title: "movie".to_string(),
+            quality_profile_id: 7,
+            has_file: true,
+            size_on_disk: 1,
+            ..Default::default()
+        };
+        assert!(!safe_to_delete(&movie, &[], &[7]));
+        assert!(safe_to_delete(&movie, &[], &[1, 2]));
+    }
+
+    #[test]
+    fn test_movie_not_safe_to_delete_no_file() {
+        let movie = Movie {
+            id: 1,
+            title: "movie".to_string(),
+            has_file: false,
+            ..Default::default()
+        };
+        assert!(!safe_to_delete(&movie, &[], &[]));
+    }
+
+    #[test]
+    fn test_movie_not_safe_to_delete_zero_size() {
+        let movie = Movie {
+            id: 1,
+            title: "movie".to_string(),
+            has_file: true,
+            size_on_disk: 0,
+            ..Default::default()
+        };
+        assert!(!safe_to_delete(&movie, &[], &[]));
+    }
+
+    #[test]
+    fn test_monitored_movies() {
+        let movie = |id, monitored| Movie {
+            id,
+            monitored,
+            title: format!("movie {id}"),
+            ..Default::default()
         };
-        assert!(!safe_to_delete(&movie, &[4, 5, 6]));
+        let watched = WatchedMovies(vec![
+            WatchedMovie {
+                jellyfin_item: JellyfinItem::default(),
+                movies: vec![movie(1, true), movie(2, false)],
+            },
+            WatchedMovie {
+                jellyfin_item: JellyfinItem::default(),
+                movies: vec![movie(3, true)],
+            },
+        ]);
+        let ids: Vec<u64> = watched.monitored_movies().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 3]);
     }
 }
@@ -0,0 +1,377 @@
+use crate::{
+    cleaners::{
+        CleanupMode, Users,
+        utils::{self, Downloads, Grab},
+    },
+    config::LidarrConfig,
+    http::{Album, HttpClient, Item as JellyfinItem, ItemsFilter, LidarrClient, MediaServer},
+    report::{CleanerReport, labels},
+    services::{Clock, Confirmation, StateStore},
+};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use std::{collections::HashSet, time::Duration};
+
+pub struct MusicCleaner {
+    lidarr_client: LidarrClient,
+    media_server: MediaServer,
+    tags_to_keep: Vec<String>,
+    retention_period: Option<Duration>,
+    /// deleting more items than this needs a confirmation even with `--yes`,
+    /// `None` with `--ignore-confirm-threshold`
+    confirm_threshold: Option<usize>,
+    users: Users,
+    clock: Clock,
+    state: StateStore,
+    max_concurrent_requests: usize,
+}
+
+/// MusicCleaner is responsible for cleaning up fully played albums from Lidarr
+/// and Download client (e.g. qBittorrent).
+impl MusicCleaner {
+    pub fn new(
+        lidarr_config: LidarrConfig,
+        http: &HttpClient,
+        media_server: MediaServer,
+        users: &Users,
+        clock: Clock,
+        state: StateStore,
+    ) -> anyhow::Result<Self> {
+        let lidarr_client = LidarrClient::new(&lidarr_config, http)?;
+        let LidarrConfig {
+            base_url: _,
+            api_key: _,
+            timeout: _,
+            basic_auth_username: _,
+            basic_auth_password: _,
+            tags_to_keep,
+            retention_period,
+            confirm_threshold,
+        } = lidarr_config;
+
+        Ok(Self {
+            lidarr_client,
+            media_server,
+            tags_to_keep,
+            retention_period,
+            confirm_threshold,
+            users: users.clone(),
+            clock,
+            state,
+            max_concurrent_requests: http.max_concurrent_requests(),
+        })
+    }
+
+    /// cleanup albums from Lidarr and Download client that are fully played in
+    /// the media server
+    pub async fn cleanup(
+        &self,
+        mode: CleanupMode,
+        mut confirmation: Confirmation,
+    ) -> anyhow::Result<CleanerReport> {
+        let mut report = CleanerReport::new("albums");
+        if let Some(server_time) = self.lidarr_client.server_time().await? {
+            self.clock.check_skew("Lidarr", server_time);
+        }
+
+        let played = self.played_albums().await?;
+        self.state.prune(report.cleaner, &played.ids());
+        if played.is_empty() {
+            info!(
+                "no albums found for deletion in {}!",
+                self.media_server.name()
+            );
+            return Ok(report);
+        }
+        report.considered = played.considered();
+
+        let forbidden_tags = self.forbidden_tags().await?;
+        let albums_for_deletion =
+            played.filter_for_deletion(self.clock.now(), self.retention_period, &forbidden_tags);
+        let albums_for_deletion =
+            self.state
+                .without_deleted(albums_for_deletion, |album| album.id, &mut report);
+        let albums_for_deletion = self.state.after_grace_period(
+            albums_for_deletion,
+            |album| album.id,
+            self.clock.now(),
+            &mut report,
+        );
+        if albums_for_deletion.is_empty() {
+            info!("no albums found for deletion in Lidarr!");
+            return Ok(report);
+        }
+
+        let eligible = labels(&albums_for_deletion);
+        let albums_for_deletion = if mode == CleanupMode::Interactive {
+            confirmation
+                .select("albums from Lidarr", albums_for_deletion, |album| {
+                    utils::details(played.last_played(album.id), album.size_on_disk())
+                })
+                .await?
+        } else {
+            albums_for_deletion
+        };
+        report.skip_missing(&eligible, &albums_for_deletion, "not selected");
+        let selected = labels(&albums_for_deletion);
+        let albums_for_deletion = confirmation
+            .limit(
+                albums_for_deletion,
+                |album| played.last_played(album.id),
+                |album| album.size_on_disk(),
+            )
+            .await?;
+        report.skip_missing(
+            &selected,
+            &albums_for_deletion,
+            "`--max-delete` or `--max-free` limit reached",
+        );
+        if albums_for_deletion.is_empty() {
+            info!("no albums selected for deletion");
+            return Ok(report);
+        }
+
+        let album_ids = albums_for_deletion.iter().map(|a| a.id).collect();
+        let downloads = self.download_ids(&album_ids).await?;
+
+        if !utils::confirm_deletion(
+            mode,
+            self.confirm_threshold,
+            "albums from Lidarr",
+            labels(&albums_for_deletion),
+            downloads.count(),
+            confirmation,
+        )
+        .await?
+        {
+            info!("deletion of albums cancelled");
+            report.skip_all(&albums_for_deletion, "deletion not confirmed");
+            return Ok(report);
+        }
+
+        let albums_for_deletion = if mode != CleanupMode::DryRun {
+            debug!("trying to delete items in Lidarr: {albums_for_deletion:?}");
+            let deleted = self.delete_albums(albums_for_deletion, &mut report).await;
+            info!("successfully deleted items from Lidarr: {deleted:?}");
+            self.state.record_deleted(
+                report.cleaner,
+                deleted.iter().map(|album| album.id),
+                self.clock.now(),
+            );
+            deleted
+        } else {
+            info!(
+                "no items will be deleted as no `--force-delete` flag is provided. Listing them instead: {albums_for_deletion:?}"
+            );
+            albums_for_deletion
+        };
+        report.deleted = labels(&albums_for_deletion);
+        let deleted = albums_for_deletion.iter().map(|album| album.id).collect();
+        report.download_ids = downloads.of_deleted(&deleted);
+        report.size_on_disk = albums_for_deletion
+            .iter()
+            .map(|album| album.size_on_disk())
+            .sum();
+        utils::log_freed_space(mode, "albums", report.size_on_disk);
+
+        Ok(report)
+    }
+
+    /// deletes the given albums, continuing past failures, and returns those
+    /// that were deleted
+    async fn delete_albums<'a>(
+        &self,
+        albums: Vec<&'a Album>,
+        report: &mut CleanerReport,
+    ) -> Vec<&'a Album> {
+        utils::delete_each(
+            albums,
+            |album| album.id,
+            |album| self.lidarr_client.delete_album(album.id),
+            self.max_concurrent_requests,
+            report,
+        )
+        .await
+    }
+
+    /// queries Lidarr history for given album ids and gets corresponding
+    /// download_id's per torrent client for each
+    async fn download_ids(&self, ids: &HashSet<u64>) -> anyhow::Result<Downloads> {
+        let records = self.lidarr_client.history_records(ids).await?;
+        let grabs: Vec<Grab> = records
+            .into_iter()
+            .filter_map(|record| {
+                let item_id = record.album_id;
+                let date = record.date;
+                let (client, download_id) = record.download_id_per_client()?;
+                Some(Grab {
+                    item_id,
+                    date,
+                    client,
+                    download_id,
+                })
+            })
+            .collect();
+        let grabbed_for = grabs
+            .iter()
+            .map(|grab| (grab.item_id, grab.download_id.clone()))
+            .collect::<Vec<_>>();
+        Ok(Downloads::new(
+            utils::latest_download_ids(grabs),
+            grabbed_for,
+        ))
+    }
+
+    /// gets IDs of the tags that are configured to be kept
+    async fn forbidden_tags(&self) -> anyhow::Result<Vec<u64>> {
+        debug!("forbidden music tags configured: {:?}", self.tags_to_keep);
+
+        let tags = self.lidarr_client.tags().await?;
+        let forbidden_tags = tags
+            .iter()
+            .filter(|t| self.tags_to_keep.contains(&t.label))
+            .map(|t| t.id)
+            .collect();
+
+        debug!("forbidden tag ids: {forbidden_tags:?}");
+
+        Ok(forbidden_tags)
+    }
+
+    /// queries the media server for albums fully played by all the users and
+    /// looks up the corresponding Lidarr albums
+    async fn played_albums(&self) -> anyhow::Result<PlayedAlbums> {
+        let items = utils::watched_items_of_users(
+            &self.media_server,
+            &self.users,
+            ItemsFilter::watched().include_item_types(&["MusicAlbum"]),
+        )
+        .await?;
+
+        let albums_futs = items.into_iter().map(|jellyfin_item| async move {
+            let Some(mbid) = jellyfin_item.musicbrainz_release_group_id() else {
+                warn!(
+                    "album \"{}\" has no MusicBrainz release group id, skipping",
+                    jellyfin_item.name
+                );
+                return Ok(None);
+            };
+            let albums = self.lidarr_client.albums_by_foreign_id(mbid).await?;
+            let played = PlayedAlbum {
+                jellyfin_item,
+                albums,
+            };
+            Ok::<_, anyhow::Error>(Some(played))
+        });
+
+        let results = utils::try_join_limited(albums_futs, self.max_concurrent_requests).await?;
+        Ok(PlayedAlbums(results.into_iter().flatten().collect()))
+    }
+}
+
+/// check if it's safe to delete an album.
+fn safe_to_delete(album: &Album, forbidden_tags: &[u64]) -> bool {
+    if album.tags().iter().any(|tag| forbidden_tags.contains(tag)) {
+        debug!("album '{}' has forbidden tags, skipping", album.title);
+        return false;
+    }
+    true
+}
+
+struct PlayedAlbum {
+    jellyfin_item: JellyfinItem,
+    albums: Vec<Album>,
+}
+
+struct PlayedAlbums(Vec<PlayedAlbum>);
+
+impl PlayedAlbums {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// number of albums checked for eligibility
+    fn considered(&self) -> usize {
+        self.0.iter().map(|pa| pa.albums.len()).sum()
+    }
+
+    /// Lidarr ids of the albums checked for eligibility
+    fn ids(&self) -> HashSet<u64> {
+        self.0
+            .iter()
+            .flat_map(|pa| &pa.albums)
+            .map(|album| album.id)
+            .collect()
+    }
+
+    /// when the given album was last played
+    fn last_played(&self, album_id: u64) -> Option<DateTime<Utc>> {
+        self.0
+            .iter()
+            .find(|pa| pa.albums.iter().any(|a| a.id == album_id))
+            .and_then(|pa| pa.jellyfin_item.last_played_date())
+    }
+
+    fn filter_for_deletion(
+        &self,
+        now: DateTime<Utc>,
+        retention_period: Option<Duration>,
+        forbidden_tags: &[u64],
+    ) -> Vec<&Album> {
+        let albums: Vec<&Album> = match retention_period {
+            Some(retention_period) => {
+                let retention_date = now - retention_period;
+                let mut safe_to_delete_items = vec![];
+
+                for item in &self.0 {
+                    if let Some(last_played) = item.jellyfin_item.last_played_date() {
+                        if retention_date > last_played {
+                            safe_to_delete_items.extend(&item.albums);
+                        } else {
+                            debug!(
+                                "retention period for \"{}\" is not yet passed ({} left), skipping",
+                                item.jellyfin_item.name,
+                                utils::retention_str(&last_played, &retention_date)
+                            );
+                        }
+                    };
+                }
+                safe_to_delete_items
+            }
+            None => {
+                if !self.0.is_empty() {
+                    warn!(
+                        "no retention period is set for Lidarr, will delete all albums immediately"
+                    );
+                }
+                self.0.iter().flat_map(|pa| pa.albums.iter()).collect()
+            }
+        };
+
+        albums
+            .into_iter()
+            .filter(|album| safe_to_delete(album, forbidden_tags))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Artist;
+
+    #[test]
+    fn test_album_not_safe_to_delete_forbidden_tags() {
+        let album = Album {
+            id: 1,
+            title: "album".to_string(),
+            artist: Some(Artist {
+                artist_name: "artist".to_string(),
+                tags: vec![5],
+            }),
+            ..Default::default()
+        };
+        assert!(!safe_to_delete(&album, &[4, 5, 6]));
+        assert!(safe_to_delete(&album, &[1]));
+    }
+}
@@ -1,15 +1,23 @@
 use crate::{
-    cleaners::utils,
-    config::SonarrConfig,
+    cleaners::{
+        CleanupMode, RetentionDelta, Users,
+        episodes::{self, EpisodeMatching},
+        utils::{self, Downloads, Grab},
+    },
+    config::{SonarrConfig, WatchedPolicy},
     http::{
-        Episode, Item as JellyfinItem, ItemsFilter, JellyfinClient, SeriesInfo, SonarrClient,
-        TorrentClientKind, UserId,
+        DownloadClientKind, Episode, HistoryRecord, HttpClient, ITEM_FIELDS, Item as JellyfinItem,
+        ItemsFilter, MediaServer, SeriesInfo, SonarrClient,
     },
-    services::DownloadService,
+    report::{CleanerReport, labels},
+    services::{Clock, Confirmation, DeletionLimit, StateStore, Trash},
 };
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use log::{debug, info, warn};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
     time::Duration,
 };
 
@@ -17,93 +25,310 @@ use std::{
 /// Download client (e.g. qBittorrent).
 pub struct SeriesCleaner {
     sonarr_client: SonarrClient,
-    jellyfin: JellyfinClient,
-    download_client: DownloadService,
+    media_server: MediaServer,
     tags_to_keep: Vec<String>,
+    tags_to_delete: Vec<String>,
+    keep_quality_profiles: Vec<String>,
+    protected_genres: Vec<String>,
+    ignore_titles: Vec<String>,
+    ignore_tvdb_ids: Vec<u64>,
+    min_rating_to_keep: Option<f32>,
+    min_age_since_added: Option<Duration>,
+    min_free_space: Option<u64>,
     retention_period: Option<Duration>,
-    user_id: UserId,
+    unwatched_retention: Option<Duration>,
+    users: Users,
     unmonitor_watched: bool,
+    add_to_exclusion: bool,
+    /// deleting more items than this needs a confirmation even with `--yes`,
+    /// `None` with `--ignore-confirm-threshold`
+    confirm_threshold: Option<usize>,
+    keep_current_season: bool,
+    episode_matching: EpisodeMatching,
+    /// where the files of deleted series are moved to instead of deleting them
+    trash: Option<Trash>,
+    clock: Clock,
+    deletion_limit: DeletionLimit,
+    state: StateStore,
+    max_concurrent_requests: usize,
 }
 
 impl SeriesCleaner {
     pub fn new(
         sonarr_config: SonarrConfig,
-        jellyfin: JellyfinClient,
-        download_client: DownloadService,
-        user_id: &UserId,
+        http: &HttpClient,
+        media_server: MediaServer,
+        users: &Users,
+        clock: Clock,
+        deletion_limit: DeletionLimit,
+        state: StateStore,
     ) -> anyhow::Result<Self> {
+        let sonarr_client = SonarrClient::new(&sonarr_config, http)?;
         let SonarrConfig {
-            base_url,
-            api_key,
+            base_url: _,
+            api_key: _,
+            timeout: _,
+            basic_auth_username: _,
+            basic_auth_password: _,
+            cleanup_mode: _,
             tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tvdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
             retention_period,
+            unwatched_retention,
             unmonitor_watched,
+            add_to_exclusion,
+            confirm_threshold,
+            keep_latest_episodes,
+            keep_current_season,
+            anime_absolute_matching,
+            include_specials,
+            deletion_mode,
+            trash_dir,
         } = sonarr_config;
+        if keep_latest_episodes > 0 {
+            warn!("`keep_latest_episodes` is not supported in series cleanup mode, ignoring");
+        }
 
-        let sonarr_client = SonarrClient::new(&base_url, &api_key)?;
         Ok(Self {
             sonarr_client,
-            jellyfin,
-            download_client,
+            media_server,
             tags_to_keep,
+            tags_to_delete,
+            keep_quality_profiles,
+            protected_genres,
+            ignore_titles,
+            ignore_tvdb_ids,
+            min_rating_to_keep,
+            min_age_since_added,
+            min_free_space,
             retention_period,
-            user_id: user_id.clone(),
+            unwatched_retention,
+            users: users.clone(),
             unmonitor_watched,
+            add_to_exclusion,
+            confirm_threshold,
+            keep_current_season,
+            episode_matching: EpisodeMatching {
+                absolute: anime_absolute_matching,
+                specials: include_specials,
+            },
+            trash: Trash::of(deletion_mode, trash_dir),
+            clock,
+            deletion_limit,
+            state,
+            max_concurrent_requests: http.max_concurrent_requests(),
         })
     }
 
     /// unmonitor watched episodes (if configured) and cleanup fully watched
     /// series from Sonarr and Download client
-    pub async fn cleanup(&self, force_delete: bool) -> anyhow::Result<()> {
-        let series_with_watched_eps = self.shows_with_watched_episodes().await?;
+    pub async fn cleanup(
+        &self,
+        mode: CleanupMode,
+        mut confirmation: Confirmation,
+    ) -> anyhow::Result<CleanerReport> {
+        let mut report = CleanerReport::new("series");
+        if let Some(server_time) = self.sonarr_client.server_time().await? {
+            self.clock.check_skew("Sonarr", server_time);
+        }
 
-        if series_with_watched_eps.is_empty() {
+        let candidates = self.candidates().await?;
+        self.state.prune(report.cleaner, &candidates.ids());
+        if candidates.is_empty() {
             log::info!("no fully watched series found!");
-            return Ok(());
+            return Ok(report);
         }
+        report.considered = candidates.considered();
         if self.unmonitor_watched {
-            self.unmonitor_watched_episodes(&series_with_watched_eps)
+            self.unmonitor_watched_episodes(&candidates.watched, mode)
                 .await?;
         }
-        let forbidden_tags = self.forbidden_tags().await?;
-        let series_to_delete =
-            series_with_watched_eps.series_for_deletion(self.retention_period, &forbidden_tags)?;
 
+        let series_to_delete =
+            candidates.for_deletion(self.retention_period, self.unwatched_retention)?;
+        let series_to_delete = self.skip_queued(series_to_delete, &mut report).await?;
+        let series_to_delete =
+            self.state
+                .without_deleted(series_to_delete, |series| series.id, &mut report);
+        let series_to_delete = self.state.after_grace_period(
+            series_to_delete,
+            |series| series.id,
+            self.clock.now(),
+            &mut report,
+        );
+        let series_to_delete = self
+            .until_min_free_space(series_to_delete, &candidates, &mut report)
+            .await?;
         if series_to_delete.is_empty() {
             info!("no series found for deletion!");
-            return Ok(());
+            return Ok(report);
+        }
+
+        let eligible = labels(&series_to_delete);
+        let series_to_delete = if mode == CleanupMode::Interactive {
+            confirmation
+                .select("series from Sonarr", series_to_delete, |series| {
+                    utils::details(
+                        candidates.last_played(series.id),
+                        series.statistics.size_on_disk as u64,
+                    )
+                })
+                .await?
+        } else {
+            series_to_delete
+        };
+        report.skip_missing(&eligible, &series_to_delete, "not selected");
+        let selected = labels(&series_to_delete);
+        let series_to_delete = confirmation
+            .limit(
+                series_to_delete,
+                |series| candidates.last_played(series.id),
+                |series| series.statistics.size_on_disk as u64,
+            )
+            .await?;
+        report.skip_missing(
+            &selected,
+            &series_to_delete,
+            "`--max-delete` or `--max-free` limit reached",
+        );
+        if series_to_delete.is_empty() {
+            info!("no series selected for deletion");
+            return Ok(report);
         }
 
         let series_ids = series_to_delete
             .iter()
             .map(|s| s.id)
             .collect::<HashSet<u64>>();
-        let download_ids = self.download_ids(&series_ids).await?;
+        let downloads = self.download_ids(&series_ids).await?;
 
-        if force_delete {
-            debug!("trying to delete series {series_to_delete:?}");
-            self.delete_series(&series_ids).await?;
-            info!("successfully deleted series: {series_to_delete:?}");
+        let summary = series_to_delete
+            .iter()
+            .map(|series| format!("{series:?}: {} episode(s)", series.episode_file_count()))
+            .collect();
+        if !utils::confirm_deletion(
+            mode,
+            self.confirm_threshold,
+            "series from Sonarr",
+            summary,
+            downloads.count(),
+            confirmation,
+        )
+        .await?
+        {
+            info!("deletion of series cancelled");
+            report.skip_all(&series_to_delete, "deletion not confirmed");
+            return Ok(report);
+        }
 
-            self.download_client.delete(&download_ids).await?;
+        let series_to_delete = if mode != CleanupMode::DryRun {
+            debug!("trying to delete series {series_to_delete:?}");
+            let deleted = self.delete_series(series_to_delete, &mut report).await;
+            info!("successfully deleted series: {deleted:?}");
+            self.state.record_deleted(
+                report.cleaner,
+                deleted.iter().map(|series| series.id),
+                self.clock.now(),
+            );
+            deleted
         } else {
             info!(
                 "no items will be deleted as no `--force-delete` flag is provided. Listing them instead: {series_to_delete:?}"
             );
-            self.download_client.list(&download_ids).await?;
+            series_to_delete
+        };
+        report.deleted = labels(&series_to_delete);
+        let deleted = series_to_delete.iter().map(|series| series.id).collect();
+        report.download_ids = downloads.of_deleted(&deleted);
+        report.size_on_disk = series_to_delete
+            .iter()
+            .map(|series| series.statistics.size_on_disk as u64)
+            .sum();
+        utils::log_freed_space(mode, "series", report.size_on_disk);
+
+        Ok(report)
+    }
+
+    /// leaves out series with a download in the Sonarr queue (e.g. a new
+    /// episode or an upgrade), as deleting them would orphan the download
+    async fn skip_queued<'a>(
+        &self,
+        series: Vec<&'a SeriesInfo>,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<&'a SeriesInfo>> {
+        if series.is_empty() {
+            return Ok(series);
         }
+        let queue = self.sonarr_client.queue().await?;
+        let queued: HashSet<u64> = queue.into_iter().filter_map(|r| r.series_id).collect();
+        let (in_queue, series): (Vec<_>, Vec<_>) =
+            series.into_iter().partition(|s| queued.contains(&s.id));
+        if !in_queue.is_empty() {
+            info!("skipping series that are still in the Sonarr queue: {in_queue:?}");
+            report.skip_all(&in_queue, "still in the Sonarr queue");
+        }
+        Ok(series)
+    }
 
-        Ok(())
+    /// compares series eligible for deletion under the configured retention
+    /// period with those eligible under the given one. Nothing gets deleted
+    pub async fn simulate(
+        &self,
+        retention_period: Option<Duration>,
+    ) -> anyhow::Result<RetentionDelta> {
+        let candidates = self.candidates().await?;
+        let current = candidates.for_deletion(self.retention_period, self.unwatched_retention)?;
+        let simulated = candidates.for_deletion(
+            retention_period.or(self.retention_period),
+            self.unwatched_retention,
+        )?;
+        Ok(RetentionDelta::new(&current, &simulated))
+    }
+
+    /// gathers all the data needed to decide which series to delete
+    async fn candidates(&self) -> anyhow::Result<SeriesCandidates> {
+        let watched = self.shows_with_watched_episodes().await?;
+        let unwatched = match self.unwatched_retention {
+            Some(_) => self.unwatched_series(&watched).await?,
+            None => Vec::new(),
+        };
+        if watched.is_empty() && unwatched.is_empty() {
+            return Ok(SeriesCandidates::default());
+        }
+
+        Ok(SeriesCandidates {
+            now: self.clock.now(),
+            watched,
+            unwatched,
+            forbidden_tags: self.forbidden_tags().await?,
+            required_tags: self.required_tags().await?,
+            forbidden_profiles: self.forbidden_quality_profiles().await?,
+            keep_current_season: self.keep_current_season,
+        })
     }
 
     /// unmonitor watched episodes that are still monitored
     async fn unmonitor_watched_episodes(
         &self,
         shows: &ShowsWithWatchedEpisodes,
+        mode: CleanupMode,
     ) -> anyhow::Result<()> {
         let per_series_ep_ids = shows.monitored_ep_ids_per_series();
         if per_series_ep_ids.is_empty() {
             debug!("no monitored episodes found for unmonitoring");
+        } else if mode == CleanupMode::DryRun {
+            let series: BTreeSet<&str> = per_series_ep_ids.values().copied().collect();
+            info!(
+                "no episodes will be unmonitored as no `--force-delete` flag is provided. {} watched episode(s) of these series are monitored: {series:?}",
+                per_series_ep_ids.len()
+            );
         } else {
             let ids: HashSet<u64> = per_series_ep_ids.keys().copied().collect();
             let res = self.sonarr_client.unmonitor_episodes(&ids).await?;
@@ -123,14 +348,12 @@ impl SeriesCleaner {
 
     async fn shows_with_watched_episodes(&self) -> anyhow::Result<ShowsWithWatchedEpisodes> {
         // first query all watched episodes
-        let mut watched_episodes = self
-            .jellyfin
-            .items(
-                ItemsFilter::watched()
-                    .user_id(self.user_id.as_ref())
-                    .include_item_types(&["Episode"]),
-            )
-            .await?;
+        let mut watched_episodes = utils::watched_items_of_users(
+            &self.media_server,
+            &self.users,
+            ItemsFilter::watched().include_item_types(&["Episode"]),
+        )
+        .await?;
 
         let series_ids: HashSet<&str> = watched_episodes
             .iter()
@@ -139,16 +362,32 @@ impl SeriesCleaner {
 
         // then query all series for those episodes. Note that some series may
         // not be fully watched yet
-        let series = self
-            .jellyfin
-            .items(
-                ItemsFilter::new()
-                    .user_id(self.user_id.as_ref())
-                    .ids(series_ids.iter().copied().collect::<Vec<&str>>().as_slice())
-                    .include_item_types(&["Series"])
-                    .fields(&["ProviderIds"]),
-            )
-            .await?;
+        let series = utils::items_of_users(
+            &self.media_server,
+            &self.users.ids,
+            self.users.policy,
+            ItemsFilter::new()
+                .ids(series_ids.iter().copied().collect::<Vec<&str>>().as_slice())
+                .include_item_types(&["Series"])
+                .fields(ITEM_FIELDS),
+        )
+        .await?;
+        let series =
+            utils::without_ignored(series, &self.ignore_titles, &self.ignore_tvdb_ids, |i| {
+                i.tvdb_id()
+            });
+        let series = utils::without_protected_genres(series, &self.protected_genres);
+        let series = utils::without_high_ratings(series, self.min_rating_to_keep);
+        let series =
+            utils::without_recently_added(series, self.clock.now(), self.min_age_since_added);
+
+        let all_episodes = episodes::all_episodes_per_series(
+            &self.media_server,
+            &self.users,
+            self.episode_matching,
+        )
+        .await?;
+        let all_episodes = &all_episodes;
 
         // group watched episodes per series
         let mut episodes_per_series = Vec::with_capacity(series.len());
@@ -182,18 +421,14 @@ impl SeriesCleaner {
                     .await?;
 
                 // retain only those Sonarr episodes that are watched in Jellyfin
+                let matching = self.episode_matching.for_series(&sonarr_series);
+                let all_episodes = all_episodes
+                    .get(&jellyfin_series.id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
                 sonarr_episodes.retain(|sonar_ep| {
-                    jellyfin_episodes.iter().any(|jl_ep| {
-                        if !jl_ep.watched() {
-                            return false;
-                        }
-                        let (Some(season_nr), Some(ep_nr)) =
-                            (jl_ep.parent_index_number, jl_ep.index_number)
-                        else {
-                            return false;
-                        };
-                        sonar_ep.season_number == season_nr && sonar_ep.episode_number == ep_nr
-                    })
+                    episodes::find_watched(&jellyfin_episodes, all_episodes, sonar_ep, matching)
+                        .is_some()
                 });
 
                 let result = TvShowWithWatchedEpisodes {
@@ -206,7 +441,7 @@ impl SeriesCleaner {
             },
         );
 
-        let results = futures::future::try_join_all(futs)
+        let results = utils::try_join_limited(futs, self.max_concurrent_requests)
             .await?
             .into_iter()
             .flatten()
@@ -215,6 +450,51 @@ impl SeriesCleaner {
         Ok(ShowsWithWatchedEpisodes(results))
     }
 
+    /// queries Jellyfin for series none of whose episodes have ever been
+    /// played and returns the corresponding Sonarr series
+    async fn unwatched_series(
+        &self,
+        watched: &ShowsWithWatchedEpisodes,
+    ) -> anyhow::Result<Vec<SeriesInfo>> {
+        let series = utils::items_of_users(
+            &self.media_server,
+            &self.users.ids,
+            // unwatched by every user, whatever `watched_policy` is
+            WatchedPolicy::All,
+            ItemsFilter::unwatched().include_item_types(&["Series"]),
+        )
+        .await?;
+        let series =
+            utils::without_ignored(series, &self.ignore_titles, &self.ignore_tvdb_ids, |i| {
+                i.tvdb_id()
+            });
+        let series = utils::without_protected_genres(series, &self.protected_genres);
+        let series = utils::without_high_ratings(series, self.min_rating_to_keep);
+        let series =
+            utils::without_recently_added(series, self.clock.now(), self.min_age_since_added)
+                .into_iter()
+                .filter(|s| s.last_played_date().is_none() && !watched.contains(&s.id));
+
+        let futs = series.map(|jellyfin_series| async move {
+            let Some(tvdb_id) = jellyfin_series.tvdb_id() else {
+                warn!(
+                    "series \"{}\" has no TVDB id, skipping",
+                    jellyfin_series.name
+                );
+                return Ok::<_, anyhow::Error>(None);
+            };
+            let sonarr_series = self.sonarr_client.series_by_tvdb_id(tvdb_id).await?.pop();
+            Ok(sonarr_series)
+        });
+
+        let results = utils::try_join_limited(futs, self.max_concurrent_requests)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(results)
+    }
+
     async fn forbidden_tags(&self) -> anyhow::Result<Vec<u64>> {
         debug!("forbidden tags configured: {:?}", self.tags_to_keep);
 
@@ -230,37 +510,198 @@ impl SeriesCleaner {
         Ok(forbidden_tags)
     }
 
+    /// gets IDs of the tags an item needs to carry to be deleted, `None` if
+    /// `tags_to_delete` is not configured
+    async fn required_tags(&self) -> anyhow::Result<Option<Vec<u64>>> {
+        if self.tags_to_delete.is_empty() {
+            return Ok(None);
+        }
+        let tags = self.sonarr_client.tags().await?;
+        let required_tags: Vec<u64> = tags
+            .iter()
+            .filter(|t| self.tags_to_delete.contains(&t.label))
+            .map(|t| t.id)
+            .collect();
+        if required_tags.is_empty() {
+            warn!(
+                "none of the tags to delete {:?} exist in Sonarr, nothing will be deleted",
+                self.tags_to_delete
+            );
+        }
+        Ok(Some(required_tags))
+    }
+
+    async fn forbidden_quality_profiles(&self) -> anyhow::Result<Vec<u64>> {
+        if self.keep_quality_profiles.is_empty() {
+            return Ok(Vec::new());
+        }
+        debug!(
+            "forbidden quality profiles configured: {:?}",
+            self.keep_quality_profiles
+        );
+
+        let profiles = self.sonarr_client.quality_profiles().await?;
+        let forbidden_profiles = profiles
+            .iter()
+            .filter(|p| self.keep_quality_profiles.contains(&p.name))
+            .map(|p| p.id)
+            .collect();
+
+        debug!("forbidden quality profile ids: {forbidden_profiles:?}");
+
+        Ok(forbidden_profiles)
+    }
+
     /// query Sonarr history for given series ids and get download_ids per each
-    /// client kind for each
-    async fn download_ids(
-        &self,
-        ids: &HashSet<u64>,
-    ) -> anyhow::Result<HashMap<TorrentClientKind, HashSet<String>>> {
-        let mut per_client_hashes = HashMap::new();
+    /// client kind for each. All the episodes of these series are deleted
+    async fn download_ids(&self, ids: &HashSet<u64>) -> anyhow::Result<Downloads> {
         let records = self.sonarr_client.history_records(ids).await?;
-        for record in records {
-            if let Some((kind, hash)) = record.download_id_per_client() {
-                per_client_hashes
-                    .entry(kind)
-                    .or_insert_with(HashSet::new)
-                    .insert(hash);
-            }
+        let deleted = records.iter().map(|r| r.episode_id).collect();
+        let grabbed_for = records
+            .iter()
+            .filter_map(|r| Some((r.series_id, r.download_id.clone()?)))
+            .collect::<Vec<_>>();
+        let download_ids = releasable_download_ids(
+            &self.sonarr_client,
+            records,
+            &deleted,
+            self.max_concurrent_requests,
+        )
+        .await?;
+        Ok(Downloads::new(download_ids, grabbed_for))
+    }
+
+    /// keeps only as many series as needed to reach `min_free_space` on the
+    /// disk holding them, if configured
+    async fn until_min_free_space<'a>(
+        &self,
+        series: Vec<&'a SeriesInfo>,
+        candidates: &SeriesCandidates,
+        report: &mut CleanerReport,
+    ) -> anyhow::Result<Vec<&'a SeriesInfo>> {
+        let Some(min_free_space) = self.min_free_space else {
+            return Ok(series);
+        };
+        if series.is_empty() {
+            return Ok(series);
         }
-        Ok(per_client_hashes)
+        let disks = self.sonarr_client.disk_space().await?;
+        let paths = series.iter().filter_map(|series| series.path.as_deref());
+        let Some(disk) = utils::holding_disk(&disks, paths) else {
+            warn!(
+                "none of the disks reported by Sonarr holds the series, ignoring `min_free_space`"
+            );
+            return Ok(series);
+        };
+        let eligible = labels(&series);
+        let series = utils::until_disk_free_space(
+            "series",
+            series,
+            disk,
+            min_free_space,
+            &self.deletion_limit,
+            |series| series.statistics.size_on_disk as u64,
+            |series| candidates.last_played(series.id),
+        );
+        report.skip_missing(&eligible, &series, "enough free space");
+        Ok(series)
     }
 
-    /// delete series with given ids
-    async fn delete_series(&self, series_ids: &HashSet<u64>) -> anyhow::Result<()> {
-        let delete_futs = series_ids
-            .iter()
-            .map(|id| self.sonarr_client.delete_series(*id));
-        let _ = futures::future::try_join_all(delete_futs).await?;
-        Ok(())
+    /// deletes the given series, continuing past failures, and returns those
+    /// that were deleted. With a trash only the series are removed from Sonarr
+    /// and their files are moved into the trash afterwards, so that Sonarr
+    /// never re-downloads a series whose files are gone
+    async fn delete_series<'a>(
+        &self,
+        series: Vec<&'a SeriesInfo>,
+        report: &mut CleanerReport,
+    ) -> Vec<&'a SeriesInfo> {
+        utils::delete_each(
+            series,
+            |series| series.id,
+            |series| {
+                let series: &SeriesInfo = series;
+                async move {
+                    let trash = match &self.trash {
+                        Some(trash) => {
+                            let path = series.path.as_deref().context("Sonarr reported no path")?;
+                            Some((trash, Path::new(path)))
+                        }
+                        None => None,
+                    };
+                    self.sonarr_client
+                        .delete_series(series.id, trash.is_none(), self.add_to_exclusion)
+                        .await?;
+                    if let Some((trash, path)) = trash {
+                        trash
+                            .move_item(series, path, self.clock.now())
+                            .context("removed from Sonarr, but the files were left in place")?;
+                    }
+                    Ok(())
+                }
+            },
+            self.max_concurrent_requests,
+            report,
+        )
+        .await
     }
 }
 
+/// gets download ids of the `deleted` episodes that can be removed from the
+/// download clients. Since a single download (e.g. a season pack) may contain
+/// more episodes than those being deleted, all episodes grabbed with each
+/// download are looked up and the download is kept if any of them still has a
+/// file on disk
+pub(super) async fn releasable_download_ids(
+    sonarr_client: &SonarrClient,
+    records: HashSet<HistoryRecord>,
+    deleted: &HashSet<u64>,
+    max_concurrent_requests: usize,
+) -> anyhow::Result<HashMap<DownloadClientKind, HashSet<String>>> {
+    let mut grabs: Vec<Grab> = records.into_iter().filter_map(grab).collect();
+    let download_ids: HashSet<String> = grabs
+        .iter()
+        .filter(|g| deleted.contains(&g.item_id))
+        .map(|g| g.download_id.clone())
+        .collect();
+
+    let futs = download_ids
+        .iter()
+        .map(|id| sonarr_client.history_records_by_download_id(id));
+    for records in utils::try_join_limited(futs, max_concurrent_requests).await? {
+        grabs.extend(records.into_iter().filter_map(grab));
+    }
+
+    let others = grabs
+        .iter()
+        .map(|g| g.item_id)
+        .filter(|id| !deleted.contains(id))
+        .collect();
+    let with_file = sonarr_client
+        .episodes_by_ids(&others)
+        .await?
+        .into_iter()
+        .filter(|ep| ep.episode_file_id != 0)
+        .map(|ep| ep.id)
+        .collect();
+
+    Ok(utils::releasable_download_ids(grabs, deleted, &with_file))
+}
+
+fn grab(record: HistoryRecord) -> Option<Grab> {
+    let item_id = record.episode_id;
+    let date = record.date;
+    let (client, download_id) = record.download_id_per_client()?;
+    Some(Grab {
+        item_id,
+        date,
+        client,
+        download_id,
+    })
+}
+
 /// check if the series is safe to delete.
-fn safe_to_delete(series: &SeriesInfo, forbidden_tags: &[u64]) -> bool {
+fn safe_to_delete(series: &SeriesInfo, forbidden_tags: &[u64], forbidden_profiles: &[u64]) -> bool {
     let has_forbidden_tags = series
         .tags
         .as_ref()
@@ -272,6 +713,10 @@ fn safe_to_delete(series: &SeriesInfo, forbidden_tags: &[u64]) -> bool {
         debug!("{title}: series has forbidden tags, skipping");
         return false;
     }
+    if forbidden_profiles.contains(&series.quality_profile_id) {
+        debug!("{title}: series has forbidden quality profile, skipping");
+        return false;
+    }
     if series.statistics.size_on_disk == 0 {
         debug!("{title}: series not present on disk, skipping");
         return false;
@@ -288,10 +733,119 @@ fn safe_to_delete(series: &SeriesInfo, forbidden_tags: &[u64]) -> bool {
         let stats = &season.statistics;
         let fully_downloaded = stats.episode_file_count >= stats.total_episode_count;
         let wont_air = stats.next_airing.is_none();
-        fully_downloaded || wont_air
+        // an unmonitored season without files was never meant to be watched
+        let not_interested = !season.monitored && stats.episode_file_count == 0;
+        fully_downloaded || wont_air || not_interested
     })
 }
 
+/// filter never watched series that were added to Sonarr longer than
+/// `unwatched_retention` ago
+fn unwatched_for_deletion<'a>(
+    series: &'a [SeriesInfo],
+    now: DateTime<Utc>,
+    unwatched_retention: Duration,
+    forbidden_tags: &[u64],
+    forbidden_profiles: &[u64],
+) -> Vec<&'a SeriesInfo> {
+    let retention_date = now - unwatched_retention;
+    series
+        .iter()
+        .filter(|s| match s.added {
+            Some(added) if retention_date > added => true,
+            Some(added) => {
+                debug!(
+                    "unwatched retention period for \"{}\" is not yet passed ({} left), skipping",
+                    s.title,
+                    utils::retention_str(&added, &retention_date)
+                );
+                false
+            }
+            None => {
+                debug!("{}: series has no date added, skipping", s.title);
+                false
+            }
+        })
+        .filter(|s| safe_to_delete(s, forbidden_tags, forbidden_profiles))
+        .collect()
+}
+
+/// all the data needed to decide which series are eligible for deletion
+#[derive(Default)]
+struct SeriesCandidates {
+    now: DateTime<Utc>,
+    watched: ShowsWithWatchedEpisodes,
+    unwatched: Vec<SeriesInfo>,
+    forbidden_tags: Vec<u64>,
+    /// ids of `tags_to_delete`, if configured
+    required_tags: Option<Vec<u64>>,
+    forbidden_profiles: Vec<u64>,
+    keep_current_season: bool,
+}
+
+impl SeriesCandidates {
+    fn is_empty(&self) -> bool {
+        self.watched.is_empty() && self.unwatched.is_empty()
+    }
+
+    /// number of series checked for eligibility
+    fn considered(&self) -> usize {
+        self.watched.0.len() + self.unwatched.len()
+    }
+
+    /// Sonarr ids of the series checked for eligibility
+    fn ids(&self) -> HashSet<u64> {
+        let watched = self.watched.0.iter().map(|s| s.sonarr_series.id);
+        watched.chain(self.unwatched.iter().map(|s| s.id)).collect()
+    }
+
+    /// series eligible for deletion under the given retention periods
+    fn for_deletion(
+        &self,
+        retention_period: Option<Duration>,
+        unwatched_retention: Option<Duration>,
+    ) -> anyhow::Result<Vec<&SeriesInfo>> {
+        let mut series = self.watched.series_for_deletion(
+            self.now,
+            retention_period,
+            &self.forbidden_tags,
+            &self.forbidden_profiles,
+        )?;
+        if let Some(unwatched_retention) = unwatched_retention {
+            series.extend(unwatched_for_deletion(
+                &self.unwatched,
+                self.now,
+                unwatched_retention,
+                &self.forbidden_tags,
+                &self.forbidden_profiles,
+            ));
+        }
+        series.retain(|s| {
+            utils::has_required_tag(&s.title, s.tags.as_deref(), self.required_tags.as_deref())
+        });
+        if self.keep_current_season {
+            series.retain(|s| match s.airing_season() {
+                Some(season) => {
+                    debug!("{}: season {season} is still airing, skipping", s.title);
+                    false
+                }
+                None => true,
+            });
+        }
+        Ok(series)
+    }
+
+    /// when an episode of the given series was last played, `None` for never
+    /// played series
+    fn last_played(&self, series_id: u64) -> Option<DateTime<Utc>> {
+        self.watched
+            .0
+            .iter()
+            .find(|s| s.sonarr_series.id == series_id)
+            .and_then(TvShowWithWatchedEpisodes::latest_played_date)
+    }
+}
+
 /// a struct that represents a TV show with only watched Jellyfin episodes and
 /// the corresponding episodes in Sonarr. Here the TV show itself is not
 /// necessarily fully watched
@@ -303,7 +857,7 @@ struct TvShowWithWatchedEpisodes {
 }
 
 impl TvShowWithWatchedEpisodes {
-    fn latest_played_date(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+    fn latest_played_date(&self) -> Option<DateTime<Utc>> {
         self.watched_jellyfin_episodes
             .iter()
             .filter_map(JellyfinItem::last_played_date)
@@ -312,6 +866,7 @@ impl TvShowWithWatchedEpisodes {
 }
 
 /// a collection of [`TvShowWithWatchedEpisodes`] with some helper methods
+#[derive(Default)]
 struct ShowsWithWatchedEpisodes(Vec<TvShowWithWatchedEpisodes>);
 
 impl ShowsWithWatchedEpisodes {
@@ -319,6 +874,11 @@ impl ShowsWithWatchedEpisodes {
         self.0.is_empty()
     }
 
+    /// check if the collection contains a series with the given Jellyfin id
+    fn contains(&self, jellyfin_id: &str) -> bool {
+        self.0.iter().any(|s| s.jellyfin_series.id == jellyfin_id)
+    }
+
     /// get a map of monitored episode ids per series titles. Needed for further
     /// logging to map episode ids back to series titles
     fn monitored_ep_ids_per_series(&self) -> HashMap<u64, &str> {
@@ -342,12 +902,14 @@ impl ShowsWithWatchedEpisodes {
     /// forbidden tags
     fn series_for_deletion(
         &self,
+        now: DateTime<Utc>,
         retention_period: Option<Duration>,
         forbidden_tags: &[u64],
+        forbidden_profiles: &[u64],
     ) -> anyhow::Result<Vec<&SeriesInfo>> {
         let series = match retention_period {
             Some(retention_period) => {
-                let retention_date = chrono::Utc::now() - retention_period;
+                let retention_date = now - retention_period;
                 let mut safe_to_delete_items = vec![];
 
                 for item in &self.0 {
@@ -380,7 +942,7 @@ impl ShowsWithWatchedEpisodes {
 
         let result = series
             .into_iter()
-            .filter(|s| safe_to_delete(s, forbidden_tags))
+            .filter(|s| safe_to_delete(s, forbidden_tags, forbidden_profiles))
             .collect();
 
         Ok(result)
@@ -397,6 +959,8 @@ mod tests {
     #[test]
     fn test_safe_to_delete_as_fully_downloaded() {
         let season_1 = Season {
+            season_number: 1,
+            monitored: true,
             statistics: SeasonStatistics {
                 next_airing: None,
                 episode_file_count: 1,
@@ -404,6 +968,8 @@ mod tests {
             },
         };
         let season_2 = Season {
+            season_number: 2,
+            monitored: true,
             statistics: SeasonStatistics {
                 next_airing: None,
                 episode_file_count: 11,
@@ -416,12 +982,14 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(safe_to_delete(&series, &[]));
+        assert!(safe_to_delete(&series, &[], &[]));
     }
 
     #[test]
     fn test_safe_to_delete_as_wont_air() {
         let season = Season {
+            season_number: 1,
+            monitored: true,
             statistics: SeasonStatistics {
                 next_airing: None,
                 episode_file_count: 1,
@@ -434,12 +1002,14 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(safe_to_delete(&series, &[]));
+        assert!(safe_to_delete(&series, &[], &[]));
     }
 
     #[test]
     fn test_not_safe_to_delete_as_will_air() {
         let season_1 = Season {
+            season_number: 1,
+            monitored: true,
             statistics: SeasonStatistics {
                 next_airing: Some(Default::default()),
                 episode_file_count: 1,
@@ -447,19 +1017,50 @@ mod tests {
             },
         };
         let season_2 = Season {
+            season_number: 2,
+            monitored: true,
+            statistics: SeasonStatistics {
+                next_airing: None,
+                episode_file_count: 10,
+                total_episode_count: 10,
+            },
+        };
+        let series = SeriesInfo {
+            statistics: SeriesStatistics { size_on_disk: 1 },
+            seasons: Some(vec![season_1, season_2]),
+            ..Default::default()
+        };
+
+        assert!(!safe_to_delete(&series, &[], &[]));
+    }
+
+    #[test]
+    fn test_safe_to_delete_as_not_interested_in_airing_season() {
+        let season_1 = Season {
+            season_number: 1,
+            monitored: true,
             statistics: SeasonStatistics {
                 next_airing: None,
                 episode_file_count: 10,
                 total_episode_count: 10,
             },
         };
+        let season_2 = Season {
+            season_number: 2,
+            monitored: false,
+            statistics: SeasonStatistics {
+                next_airing: Some(Default::default()),
+                episode_file_count: 0,
+                total_episode_count: 10,
+            },
+        };
         let series = SeriesInfo {
             statistics: SeriesStatistics { size_on_disk: 1 },
             seasons: Some(vec![season_1, season_2]),
             ..Default::default()
         };
 
-        assert!(!safe_to_delete(&series, &[]));
+        assert!(safe_to_delete(&series, &[], &[]));
     }
 
     #[test]
@@ -470,7 +1071,7 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS));
+        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS, &[]));
     }
 
     #[test]
@@ -482,7 +1083,7 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(!safe_to_delete(&series, &[]));
+        assert!(!safe_to_delete(&series, &[], &[]));
     }
 
     #[test]
@@ -493,7 +1094,7 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS));
+        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS, &[]));
     }
 
     #[test]
@@ -505,6 +1106,83 @@ mod tests {
             ..Default::default()
         };
 
-        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS));
+        assert!(!safe_to_delete(&series, FORBIDDEN_TAGS, &[]));
+    }
+
+    #[test]
+    fn test_not_safe_to_delete_forbidden_quality_profile() {
+        let season = Season {
+            season_number: 1,
+            monitored: true,
+            statistics: SeasonStatistics {
+                next_airing: None,
+                episode_file_count: 1,
+                total_episode_count: 1,
+            },
+        };
+        let series = SeriesInfo {
+            quality_profile_id: 3,
+            statistics: SeriesStatistics { size_on_disk: 1 },
+            seasons: Some(vec![season]),
+            ..Default::default()
+        };
+
+        assert!(!safe_to_delete(&series, &[], &[3]));
+        assert!(safe_to_delete(&series, &[], &[1]));
+    }
+
+    #[test]
+    fn test_airing_season() {
+        let season = |season_number, next_airing| Season {
+            season_number,
+            monitored: true,
+            statistics: SeasonStatistics {
+                next_airing,
+                episode_file_count: 1,
+                total_episode_count: 1,
+            },
+        };
+        let mut series = SeriesInfo {
+            seasons: Some(vec![
+                season(1, None),
+                season(2, Some(Utc::now())),
+                season(3, None),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(series.airing_season(), Some(2));
+
+        series.seasons = Some(vec![season(1, None)]);
+        assert_eq!(series.airing_season(), None);
+    }
+
+    #[test]
+    fn test_unwatched_for_deletion() {
+        let now = Utc::now();
+        let day = Duration::from_secs(60 * 60 * 24);
+        let downloaded_series = |id: u64, added: Option<DateTime<Utc>>| SeriesInfo {
+            id,
+            added,
+            statistics: SeriesStatistics { size_on_disk: 1 },
+            seasons: Some(vec![Season {
+                season_number: 1,
+                monitored: true,
+                statistics: SeasonStatistics {
+                    next_airing: None,
+                    episode_file_count: 1,
+                    total_episode_count: 1,
+                },
+            }]),
+            ..Default::default()
+        };
+        let series = vec![
+            downloaded_series(1, Some(now - day * 200)),
+            downloaded_series(2, Some(now - day * 20)),
+            downloaded_series(3, None),
+        ];
+
+        let result = unwatched_for_deletion(&series, now, day * 180, &[], &[]);
+        let ids: Vec<u64> = result.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1]);
     }
 }
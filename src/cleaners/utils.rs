@@ -1,14 +1,741 @@
+use super::{CleanupMode, Users};
+use crate::config::{RetentionAnchor, WatchedPolicy};
+use crate::http::{
+    DiskSpace, DownloadClientKind, Item as JellyfinItem, ItemsFilter, MediaServer, UserId,
+};
+use crate::prompt;
+use crate::report::{CleanerReport, FailedItem};
+use crate::services::{Confirmation, DeletionLimit};
+use anyhow::bail;
 use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use log::{debug, error, info, warn};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+/// queries the media server for the items matching the filter for each of the
+/// users and combines them according to the policy:
+/// - `All` keeps only the items matching for every user, e.g. watched by all
+///   of them. The retention period counts from the last user who watched it
+/// - `Any` keeps the items matching for any of the users, e.g. watched by at
+///   least one of them. The retention period counts from the most recent play
+///   among the users who watched it
+///
+/// Either way the item as seen by the user who played it most recently is
+/// kept
+pub async fn items_of_users(
+    media_server: &MediaServer,
+    user_ids: &[UserId],
+    policy: WatchedPolicy,
+    filter: ItemsFilter<'_>,
+) -> anyhow::Result<Vec<JellyfinItem>> {
+    let mut items_per_user = Vec::with_capacity(user_ids.len());
+    for user_id in user_ids {
+        let filter = filter.clone().user_id(user_id.as_ref());
+        items_per_user.push(media_server.items(filter).await?);
+    }
+    Ok(merge_items(items_per_user, policy))
+}
+
+/// queries the media server for the items watched by the users, combined
+/// according to their `watched_policy` as [`items_of_users`] does. With a
+/// `watched_threshold` items played at least that far count as watched too.
+/// With a `min_play_count` items played fewer times by a user don't count as
+/// watched by them. With `retention_anchor = "date_added"` all the items count
+/// as watched on the date they were added instead
+pub async fn watched_items_of_users(
+    media_server: &MediaServer,
+    users: &Users,
+    filter: ItemsFilter<'_>,
+) -> anyhow::Result<Vec<JellyfinItem>> {
+    if users.retention_anchor == RetentionAnchor::DateAdded {
+        let items = items_of_users(
+            media_server,
+            &users.ids,
+            users.policy,
+            filter.played_or_not(),
+        )
+        .await?;
+        return Ok(played_on_date_added(items));
+    }
+    let mut items_per_user = Vec::with_capacity(users.ids.len());
+    for user_id in &users.ids {
+        let filter = filter.clone().user_id(user_id.as_ref());
+        let mut items = media_server.items(filter.clone()).await?;
+        if let Some(threshold) = users.watched_threshold {
+            let in_progress = media_server.items(filter.in_progress()).await?;
+            items.extend(watched_up_to(in_progress, threshold));
+        }
+        if let Some(min_play_count) = users.min_play_count {
+            items = played_at_least(items, min_play_count);
+        }
+        items_per_user.push(items);
+    }
+    Ok(merge_items(items_per_user, users.policy))
+}
+
+/// items marked as played on the date they were added. Items without the date
+/// are skipped, as there is nothing to count their retention period from
+fn played_on_date_added(items: Vec<JellyfinItem>) -> Vec<JellyfinItem> {
+    items
+        .into_iter()
+        .filter_map(|mut item| {
+            if item.date_created.is_none() {
+                warn!("\"{}\" has no date added, skipping", item.name);
+                return None;
+            }
+            item.mark_played_on_date_added();
+            Some(item)
+        })
+        .collect()
+}
+
+/// items played at least `threshold` percent, marked as played
+fn watched_up_to(items: Vec<JellyfinItem>, threshold: f64) -> Vec<JellyfinItem> {
+    items
+        .into_iter()
+        .filter(|item| item.played_percentage().is_some_and(|p| p >= threshold))
+        .map(|mut item| {
+            debug!(
+                "\"{}\" is played {:.0}%, counting it as watched",
+                item.name,
+                item.played_percentage().unwrap_or_default()
+            );
+            item.mark_played();
+            item
+        })
+        .collect()
+}
+
+/// items played at least `min_play_count` times
+fn played_at_least(items: Vec<JellyfinItem>, min_play_count: u32) -> Vec<JellyfinItem> {
+    let (items, skipped): (Vec<_>, Vec<_>) = items
+        .into_iter()
+        .partition(|item| item.play_count() >= min_play_count);
+    if !skipped.is_empty() {
+        let names: Vec<&str> = skipped.iter().map(|i| i.name.as_str()).collect();
+        debug!("skipping items played fewer than {min_play_count} times: {names:?}");
+    }
+    items
+}
+
+/// combines the items of several users, in the order they are first seen. With
+/// [`WatchedPolicy::All`] an item is kept if every user has it, however many
+/// times it is listed for each of them
+fn merge_items(items_per_user: Vec<Vec<JellyfinItem>>, policy: WatchedPolicy) -> Vec<JellyfinItem> {
+    let users = items_per_user.len();
+    let mut order = Vec::new();
+    let mut merged: HashMap<String, (JellyfinItem, HashSet<usize>)> = HashMap::new();
+    for (user, items) in items_per_user.into_iter().enumerate() {
+        for item in items {
+            match merged.entry(item.id.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let (kept, seen_by) = entry.get_mut();
+                    seen_by.insert(user);
+                    if item.last_played_date() > kept.last_played_date() {
+                        *kept = item;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    order.push(item.id.clone());
+                    entry.insert((item, HashSet::from([user])));
+                }
+            }
+        }
+    }
+    order
+        .into_iter()
+        .filter_map(|id| merged.remove(&id))
+        .filter(|(_, seen_by)| policy == WatchedPolicy::Any || seen_by.len() == users)
+        .map(|(item, _)| item)
+        .collect()
+}
+
+/// whether an item carries one of the `required_tags` (ids of
+/// `tags_to_delete`). Any item does if they are not configured
+pub fn has_required_tag(title: &str, tags: Option<&[u64]>, required_tags: Option<&[u64]>) -> bool {
+    let Some(required_tags) = required_tags else {
+        return true;
+    };
+    let tagged = tags.is_some_and(|tags| tags.iter().any(|tag| required_tags.contains(tag)));
+    if !tagged {
+        debug!("\"{title}\" has none of the tags to delete, skipping");
+    }
+    tagged
+}
+
+/// a single "grabbed" event from the *arr history
+pub struct Grab {
+    /// id of the grabbed item, e.g. a movie id in Radarr or an episode id in
+    /// Sonarr
+    pub item_id: u64,
+    pub date: DateTime<Utc>,
+    pub client: DownloadClientKind,
+    pub download_id: String,
+}
+
+/// groups download ids per torrent client, keeping only the download id of the
+/// most recent grab per (item, client) pair. Earlier grabs of the same item
+/// usually point to torrents that were replaced by an upgrade and have already
+/// been removed from the client
+pub fn latest_download_ids<I>(grabs: I) -> HashMap<DownloadClientKind, HashSet<String>>
+where
+    I: IntoIterator<Item = Grab>,
+{
+    let mut grabs: Vec<Grab> = grabs.into_iter().collect();
+    grabs.sort_by_key(|grab| std::cmp::Reverse(grab.date));
+
+    let mut seen = HashSet::new();
+    let mut per_client_hashes = HashMap::new();
+    for grab in grabs {
+        if seen.insert((grab.item_id, grab.client.clone())) {
+            per_client_hashes
+                .entry(grab.client)
+                .or_insert_with(HashSet::new)
+                .insert(grab.download_id);
+        }
+    }
+    per_client_hashes
+}
+
+/// leaves out items whose title is one of `titles` (compared
+/// case-insensitively) or whose provider id is one of `ids`
+pub fn without_ignored<F>(
+    items: Vec<JellyfinItem>,
+    titles: &[String],
+    ids: &[u64],
+    provider_id: F,
+) -> Vec<JellyfinItem>
+where
+    F: Fn(&JellyfinItem) -> Option<&str>,
+{
+    if titles.is_empty() && ids.is_empty() {
+        return items;
+    }
+    items
+        .into_iter()
+        .filter(|item| {
+            let by_title = titles.iter().any(|t| t.eq_ignore_ascii_case(&item.name));
+            let by_id = provider_id(item)
+                .and_then(|id| id.parse::<u64>().ok())
+                .is_some_and(|id| ids.contains(&id));
+            if by_title || by_id {
+                info!("\"{}\" is on the ignore list, skipping", item.name);
+            }
+            !by_title && !by_id
+        })
+        .collect()
+}
+
+/// leaves out media server items having one of the `protected` genres
+pub fn without_protected_genres(
+    items: Vec<JellyfinItem>,
+    protected: &[String],
+) -> Vec<JellyfinItem> {
+    items
+        .into_iter()
+        .filter(|item| match item.genre_in(protected) {
+            Some(genre) => {
+                debug!(
+                    "\"{}\" has protected genre \"{genre}\", skipping",
+                    item.name
+                );
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// leaves out media server items rated `min_rating` or higher
+pub fn without_high_ratings(
+    items: Vec<JellyfinItem>,
+    min_rating: Option<f32>,
+) -> Vec<JellyfinItem> {
+    let Some(min_rating) = min_rating else {
+        return items;
+    };
+    items
+        .into_iter()
+        .filter(|item| match item.rating() {
+            Some(rating) if rating >= min_rating => {
+                debug!("\"{}\" is rated {rating}, skipping", item.name);
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// leaves out media server items added less than `min_age` before `now`.
+/// Items without a known date added are kept for deletion
+pub fn without_recently_added(
+    items: Vec<JellyfinItem>,
+    now: DateTime<Utc>,
+    min_age: Option<Duration>,
+) -> Vec<JellyfinItem> {
+    let Some(min_age) = min_age else {
+        return items;
+    };
+    let added_before = now - min_age;
+    items
+        .into_iter()
+        .filter(|item| match item.date_created {
+            Some(added) if added > added_before => {
+                debug!(
+                    "\"{}\" was added too recently ({} left), skipping",
+                    item.name,
+                    retention_str(&added, &added_before)
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// like [`futures::future::try_join_all`] but polls at most `limit` of the
+/// futures at a time, so that a large library doesn't flood a service with
+/// requests. Results keep the order of the futures
+pub async fn try_join_limited<I, F, T>(futs: I, limit: usize) -> anyhow::Result<Vec<T>>
+where
+    I: IntoIterator<Item = F>,
+    F: Future<Output = anyhow::Result<T>>,
+{
+    futures::stream::iter(futs)
+        .buffered(limit.max(1))
+        .try_collect()
+        .await
+}
+
+/// deletes the items, at most `limit` at a time, continuing past failures so
+/// that a single bad item doesn't prevent deleting the rest. Items sharing an
+/// id (e.g. episodes of a multi-episode file) are deleted once. The failures
+/// are recorded in the report, the deleted items are returned
+pub async fn delete_each<T, I, D, F>(
+    items: Vec<T>,
+    id: I,
+    delete: D,
+    limit: usize,
+    report: &mut CleanerReport,
+) -> Vec<T>
+where
+    T: Debug,
+    I: Fn(&T) -> u64,
+    D: Fn(&T) -> F,
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let mut unique: BTreeMap<u64, &T> = BTreeMap::new();
+    for item in &items {
+        unique.entry(id(item)).or_insert(item);
+    }
+    let results: Vec<anyhow::Result<()>> =
+        futures::stream::iter(unique.values().map(|item| delete(item)))
+            .buffered(limit.max(1))
+            .collect()
+            .await;
+    let failed: HashMap<u64, anyhow::Error> = unique
+        .into_keys()
+        .zip(results)
+        .filter_map(|(id, result)| Some((id, result.err()?)))
+        .collect();
+
+    let (deleted, not_deleted): (Vec<T>, Vec<T>) = items
+        .into_iter()
+        .partition(|item| !failed.contains_key(&id(item)));
+    for item in not_deleted {
+        let e = &failed[&id(&item)];
+        error!("failed to delete {item:?}: {e:#}");
+        report.failed.push(FailedItem {
+            item: format!("{item:?}"),
+            error: format!("{e:#}"),
+        });
+    }
+    deleted
+}
+
+/// the disk holding the given paths, i.e. the one whose path is the longest
+/// prefix of each of them. If they span several disks the one with the least
+/// free space is returned, `None` if none of the paths is on a known disk
+pub fn holding_disk<'a, 'd, I>(disks: &'d [DiskSpace], paths: I) -> Option<&'d DiskSpace>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            disks
+                .iter()
+                .filter(|disk| is_within(path, &disk.path))
+                .max_by_key(|disk| disk.path.len())
+        })
+        .min_by_key(|disk| disk.free_space)
+}
+
+/// checks whether `path` is `dir` itself or is located under it
+fn is_within(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches(['/', '\\']);
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '\\']))
+}
+
+/// keeps only as many of the items as needed to get the free space of `disk`
+/// up to `min_free_space`, taking into account what other cleaners are
+/// already freeing on it. See [`until_free_space`]
+pub fn until_disk_free_space<T, S, F>(
+    kind: &str,
+    items: Vec<T>,
+    disk: &DiskSpace,
+    min_free_space: u64,
+    limit: &DeletionLimit,
+    size: S,
+    last_played: F,
+) -> Vec<T>
+where
+    S: Fn(&T) -> u64,
+    F: Fn(&T) -> Option<DateTime<Utc>>,
+{
+    limit.free_space_on(&disk.path, |freeing| {
+        if freeing > 0 {
+            info!(
+                "{} are already being freed on {} by other cleaners",
+                human_size(freeing),
+                disk.path
+            );
+        }
+        let free_space = disk.free_space + freeing;
+        let items = until_free_space(kind, items, free_space, min_free_space, &size, last_played);
+        let freed = items.iter().map(&size).sum();
+        (items, freed)
+    })
+}
+
+/// keeps only as many of the items as needed to get `free_space` up to
+/// `min_free_space`. Items last played the longest ago are preferred, never
+/// played ones come first
+pub fn until_free_space<T, S, F>(
+    kind: &str,
+    mut items: Vec<T>,
+    free_space: u64,
+    min_free_space: u64,
+    size: S,
+    last_played: F,
+) -> Vec<T>
+where
+    S: Fn(&T) -> u64,
+    F: Fn(&T) -> Option<DateTime<Utc>>,
+{
+    if free_space >= min_free_space {
+        info!(
+            "{} free already, which is not less than `min_free_space` of {}, no {kind} need deleting",
+            human_size(free_space),
+            human_size(min_free_space)
+        );
+        return Vec::new();
+    }
+    items.sort_by_key(|item| last_played(item));
+    let mut freed = 0;
+    let needed = items
+        .iter()
+        .take_while(|item| {
+            let enough = free_space + freed >= min_free_space;
+            freed += size(item);
+            !enough
+        })
+        .count();
+    items.truncate(needed);
+
+    let freed: u64 = items.iter().map(&size).sum();
+    if free_space + freed >= min_free_space {
+        info!(
+            "deleting {needed} {kind} frees {} reaching `min_free_space` of {}",
+            human_size(freed),
+            human_size(min_free_space)
+        );
+    } else {
+        warn!(
+            "deleting all {needed} eligible {kind} frees only {}, {} short of `min_free_space` of {}",
+            human_size(freed),
+            human_size(min_free_space - free_space - freed),
+            human_size(min_free_space)
+        );
+    }
+    items
+}
+
+/// asks to confirm the deletion unless `--yes` is given. The user is asked
+/// once the other cleaners asked too, see [`needs_confirmation`] for the
+/// `confirm_threshold` of the service. Without one, the items count towards
+/// the global `confirm_threshold` along with those of the other cleaners, see
+/// [`Confirmation::confirm`]
+pub async fn confirm_deletion(
+    mode: CleanupMode,
+    confirm_threshold: Option<usize>,
+    what: &str,
+    labels: Vec<String>,
+    downloads: usize,
+    confirmation: Confirmation,
+) -> anyhow::Result<bool> {
+    if mode == CleanupMode::DryRun {
+        return Ok(true);
+    }
+    let ask = needs_confirmation(
+        mode,
+        labels.len(),
+        confirm_threshold,
+        what,
+        prompt::is_terminal(),
+    )?;
+    let own_threshold = confirm_threshold.is_some();
+    confirmation
+        .confirm(what, labels, downloads, ask, own_threshold)
+        .await
+}
+
+/// whether the deletion of `count` items has to be confirmed, i.e. unless
+/// `--yes` is given. More items than `confirm_threshold` often means the media
+/// server reported wrong watch history, so `--yes` isn't enough for them and,
+/// without a terminal to confirm in, the deletion is refused
+fn needs_confirmation(
+    mode: CleanupMode,
+    count: usize,
+    confirm_threshold: Option<usize>,
+    what: &str,
+    terminal: bool,
+) -> anyhow::Result<bool> {
+    let above_threshold = confirm_threshold.filter(|&threshold| count > threshold);
+    match mode {
+        CleanupMode::DryRun => Ok(false),
+        CleanupMode::Delete => match above_threshold {
+            None => Ok(false),
+            Some(threshold) if terminal => {
+                warn!(
+                    "{count} {what} are about to be deleted, more than `confirm_threshold` ({threshold}), which has to be confirmed even with `--yes`"
+                );
+                Ok(true)
+            }
+            Some(threshold) => bail!(
+                "refusing to delete {count} {what}, more than `confirm_threshold` ({threshold}). Check the watch history reported by the media server, then confirm the deletion in a terminal or pass `--ignore-confirm-threshold`"
+            ),
+        },
+        // only chosen when running in a terminal, see `Cli::cleanup_mode`
+        CleanupMode::Confirm | CleanupMode::Interactive => {
+            if let Some(threshold) = above_threshold {
+                warn!(
+                    "{count} {what} are about to be deleted, more than `confirm_threshold` ({threshold})"
+                );
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// total number of download ids across all clients
+pub fn download_count(download_ids: &HashMap<DownloadClientKind, HashSet<String>>) -> usize {
+    download_ids.values().map(HashSet::len).sum()
+}
+
+/// the download ids of the items about to be deleted. They have to be looked
+/// up before the deletion, as the *arr drops the history of deleted items
+pub struct Downloads {
+    ids: HashMap<DownloadClientKind, HashSet<String>>,
+    /// pairs each of the items to delete with the download ids it was grabbed
+    /// with
+    grabbed_for: Vec<(u64, String)>,
+}
+
+impl Downloads {
+    pub fn new(
+        ids: HashMap<DownloadClientKind, HashSet<String>>,
+        grabbed_for: impl IntoIterator<Item = (u64, String)>,
+    ) -> Self {
+        Self {
+            ids,
+            grabbed_for: grabbed_for.into_iter().collect(),
+        }
+    }
+
+    /// number of downloads that may be removed along with the items
+    pub fn count(&self) -> usize {
+        download_count(&self.ids)
+    }
+
+    /// the download ids to remove once the items are deleted. Those grabbed
+    /// for any of the items that failed to be deleted, i.e. that are missing
+    /// from `deleted`, are kept as their files are still in use
+    pub fn of_deleted(
+        mut self,
+        deleted: &HashSet<u64>,
+    ) -> HashMap<DownloadClientKind, HashSet<String>> {
+        let in_use: HashSet<&String> = self
+            .grabbed_for
+            .iter()
+            .filter(|(item, _)| !deleted.contains(item))
+            .map(|(_, download_id)| download_id)
+            .collect();
+        for hashes in self.ids.values_mut() {
+            hashes.retain(|hash| {
+                let releasable = !in_use.contains(hash);
+                if !releasable {
+                    warn!("keeping download {hash} as some of its items failed to be deleted");
+                }
+                releasable
+            });
+        }
+        self.ids.retain(|_, hashes| !hashes.is_empty());
+        self.ids
+    }
+}
+
+/// like [`latest_download_ids`] but only for the grabs of the `deleted` items.
+/// A single download (e.g. a season pack) often contains several items, so a
+/// download id is only returned if none of the other items grabbed with it
+/// still has a file on disk (i.e. is in `with_file`) after the deletion
+pub fn releasable_download_ids<I>(
+    grabs: I,
+    deleted: &HashSet<u64>,
+    with_file: &HashSet<u64>,
+) -> HashMap<DownloadClientKind, HashSet<String>>
+where
+    I: IntoIterator<Item = Grab>,
+{
+    let grabs: Vec<Grab> = grabs.into_iter().collect();
+    let still_needed: HashSet<String> = grabs
+        .iter()
+        .filter(|grab| !deleted.contains(&grab.item_id) && with_file.contains(&grab.item_id))
+        .map(|grab| grab.download_id.clone())
+        .collect();
+
+    let deleted_grabs = grabs
+        .into_iter()
+        .filter(|grab| deleted.contains(&grab.item_id));
+    let mut per_client_hashes = latest_download_ids(deleted_grabs);
+    for hashes in per_client_hashes.values_mut() {
+        hashes.retain(|hash| {
+            let releasable = !still_needed.contains(hash);
+            if !releasable {
+                debug!("download {hash} still contains files of other items, keeping it");
+            }
+            releasable
+        });
+    }
+    per_client_hashes.retain(|_, hashes| !hashes.is_empty());
+    per_client_hashes
+}
+
+/// the difference between the sets of items eligible for deletion under the
+/// configured and a simulated retention period
+pub struct RetentionDelta {
+    current: usize,
+    simulated: usize,
+    /// items that only become eligible under the simulated retention period
+    added: Vec<String>,
+    /// items that are no longer eligible under the simulated retention period
+    removed: Vec<String>,
+}
+
+impl RetentionDelta {
+    pub fn new<T: Debug>(current: &[T], simulated: &[T]) -> Self {
+        let current: Vec<String> = current.iter().map(|i| format!("{i:?}")).collect();
+        let simulated: Vec<String> = simulated.iter().map(|i| format!("{i:?}")).collect();
+        let added = simulated
+            .iter()
+            .filter(|i| !current.contains(i))
+            .cloned()
+            .collect();
+        let removed = current
+            .iter()
+            .filter(|i| !simulated.contains(i))
+            .cloned()
+            .collect();
+        Self {
+            current: current.len(),
+            simulated: simulated.len(),
+            added,
+            removed,
+        }
+    }
+}
+
+impl Display for RetentionDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} item(s) eligible for deletion currently, {} with simulated retention (+{}/-{})",
+            self.current,
+            self.simulated,
+            self.added.len(),
+            self.removed.len()
+        )?;
+        for item in &self.added {
+            write!(f, "\n  + {item}")?;
+        }
+        for item in &self.removed {
+            write!(f, "\n  - {item}")?;
+        }
+        Ok(())
+    }
+}
+
+/// when an item was last played and its size, as shown when selecting the
+/// items to delete
+pub fn details(last_played: Option<DateTime<Utc>>, size: u64) -> String {
+    let last_played = match last_played {
+        Some(date) => format!("last played {}", date.format("%Y-%m-%d")),
+        None => "never played".to_owned(),
+    };
+    format!("{last_played}, {}", human_size(size))
+}
+
+/// formats a number of bytes as a human readable size, e.g. `1.5 GiB`
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// logs the disk space freed by deleting items of the given kind, or the one
+/// that would be freed in a "dry run" mode
+pub fn log_freed_space(mode: CleanupMode, kind: &str, bytes: u64) {
+    let size = human_size(bytes);
+    if mode == CleanupMode::DryRun {
+        info!("deleting these {kind} would free {size} of disk space");
+    } else {
+        info!("deleting {kind} freed {size} of disk space");
+    }
+}
+
+/// days in a month, approximately
+const DAYS_IN_MONTH: i64 = 30;
 
 /// a helper function that turns the difference between `last_played_dt` and
-/// `retention_dt` into a human readable string
+/// `retention_dt` into a human readable string. Long spans are given in
+/// (approximate) months and weeks, e.g. "1 month, 2 weeks"
 pub fn retention_str(last_played_dt: &DateTime<Utc>, retention_dt: &DateTime<Utc>) -> String {
     if retention_dt > last_played_dt {
         "0".to_string()
     } else {
         let delta = *last_played_dt - retention_dt;
         let days = delta.num_days();
-        if days > 0 {
+        if days >= DAYS_IN_MONTH {
+            let months = days / DAYS_IN_MONTH;
+            let weeks = days % DAYS_IN_MONTH / 7;
+            with_remainder(months, "month", weeks, "week")
+        } else if days >= 7 {
+            with_remainder(days / 7, "week", days % 7, "day")
+        } else if days > 0 {
             format!("{days} day{}", suffix(days))
         } else {
             let hours = delta.num_hours();
@@ -22,6 +749,16 @@ pub fn retention_str(last_played_dt: &DateTime<Utc>, retention_dt: &DateTime<Utc
     }
 }
 
+/// e.g. "1 month, 2 weeks", leaving out the remainder if there is none
+fn with_remainder(units: i64, unit: &str, remainder: i64, remainder_unit: &str) -> String {
+    let units = format!("{units} {unit}{}", suffix(units));
+    if remainder > 0 {
+        format!("{units}, {remainder} {remainder_unit}{}", suffix(remainder))
+    } else {
+        units
+    }
+}
+
 fn suffix(units: i64) -> String {
     if units > 1 {
         String::from("s")
@@ -33,7 +770,364 @@ fn suffix(units: i64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
+
+    fn grab(item_id: u64, hours_ago: u64, client: DownloadClientKind, id: &str) -> Grab {
+        Grab {
+            item_id,
+            date: chrono::Utc::now() - Duration::from_secs(hours_ago * 3600),
+            client,
+            download_id: id.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_each() {
+        let deleted = std::sync::Mutex::new(Vec::new());
+        let mut report = CleanerReport::new("episodes");
+        // "b" and "c" share a file
+        let items = vec![("a", 1), ("b", 2), ("c", 2), ("d", 3)];
+        let kept = delete_each(
+            items,
+            |item| item.1,
+            |&(_, id)| {
+                deleted.lock().unwrap().push(id);
+                async move {
+                    match id {
+                        2 => anyhow::bail!("not found"),
+                        _ => Ok(()),
+                    }
+                }
+            },
+            2,
+            &mut report,
+        )
+        .await;
+
+        assert_eq!(kept, vec![("a", 1), ("d", 3)]);
+        assert_eq!(*deleted.lock().unwrap(), vec![1, 2, 3]);
+        let failed: Vec<_> = report.failed.iter().map(|f| f.item.as_str()).collect();
+        assert_eq!(failed, vec![r#"("b", 2)"#, r#"("c", 2)"#]);
+        assert_eq!(report.failed[0].error, "not found");
+    }
+
+    fn disk(path: &str, free_space: u64) -> DiskSpace {
+        DiskSpace {
+            path: path.to_owned(),
+            free_space,
+        }
+    }
+
+    #[test]
+    fn test_holding_disk() {
+        let disks = [disk("/", 10), disk("/data", 100), disk("/media", 50)];
+        let path = |paths: &[&str]| holding_disk(&disks, paths.iter().copied()).map(|d| &d.path);
+        assert_eq!(path(&["/data/movies/Movie (2020)"]).unwrap(), "/data");
+        assert_eq!(path(&["/data/a", "/media/b"]).unwrap(), "/media");
+        // `/database` is not under `/data`
+        assert_eq!(path(&["/database/a"]).unwrap(), "/");
+        assert!(path(&[]).is_none());
+
+        // no disk holding the paths
+        let disks = [disk("/data", 100), disk("/media", 50)];
+        assert!(holding_disk(&disks, ["/tv/a"]).is_none());
+        assert!(holding_disk(&[], ["/data/a"]).is_none());
+    }
+
+    #[test]
+    fn test_until_free_space() {
+        let now = Utc::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        // (id, size, days since last played)
+        let items = vec![
+            (1, 30, Some(1)),
+            (2, 20, Some(10)),
+            (3, 40, None),
+            (4, 50, Some(5)),
+        ];
+        let last_played = |item: &(u64, u64, Option<u32>)| item.2.map(|days| now - day * days);
+        let size = |item: &(u64, u64, Option<u32>)| item.1;
+        let ids =
+            |items: Vec<(u64, u64, Option<u32>)>| items.iter().map(|i| i.0).collect::<Vec<_>>();
+
+        let kept = until_free_space("items", items.clone(), 100, 150, size, last_played);
+        assert_eq!(ids(kept), vec![3, 2]);
+
+        let kept = until_free_space("items", items.clone(), 100, 100, size, last_played);
+        assert!(kept.is_empty());
+
+        let kept = until_free_space("items", items.clone(), 0, 1000, size, last_played);
+        assert_eq!(ids(kept), vec![3, 2, 4, 1]);
+
+        // concurrent cleaners share the gap to `min_free_space` on a disk
+        let limit = DeletionLimit::new(None, None);
+        let disk = disk("/data", 100);
+        let kept = until_disk_free_space(
+            "movies",
+            items.clone(),
+            &disk,
+            150,
+            &limit,
+            size,
+            last_played,
+        );
+        assert_eq!(ids(kept), vec![3, 2]);
+        let kept = until_disk_free_space(
+            "series",
+            items.clone(),
+            &disk,
+            150,
+            &limit,
+            size,
+            last_played,
+        );
+        assert!(kept.is_empty());
+        let kept = until_disk_free_space("episodes", items, &disk, 170, &limit, size, last_played);
+        assert_eq!(ids(kept), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_try_join_limited() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let futs = (0..10).map(|i| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, anyhow::Error>(i)
+            }
+        });
+
+        let results = try_join_limited(futs, 3).await?;
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_recently_added() {
+        let now = Utc::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let item = |name: &str, date_created| {
+            let mut item = JellyfinItem::default();
+            item.name = name.to_owned();
+            item.date_created = date_created;
+            item
+        };
+        let items = vec![
+            item("old", Some(now - day * 30)),
+            item("fresh", Some(now - day)),
+            item("unknown", None),
+        ];
+
+        let kept = without_recently_added(items, now, Some(day * 14));
+        let names: Vec<&str> = kept.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["old", "unknown"]);
+    }
+
+    #[test]
+    fn test_merge_items() -> anyhow::Result<()> {
+        let item = |id: &str, last_played: &str| {
+            serde_json::from_str::<JellyfinItem>(&format!(
+                r#"{{"Name": "{id}", "Id": "{id}", "UserData": {{"LastPlayedDate": "{last_played}", "Played": true}}}}"#
+            ))
+        };
+        let items_per_user = || -> anyhow::Result<_> {
+            let john = vec![
+                item("1", "2024-01-10T00:00:00Z")?,
+                item("2", "2024-01-01T00:00:00Z")?,
+                item("3", "2024-01-01T00:00:00Z")?,
+            ];
+            let jane = vec![
+                item("3", "2024-01-01T00:00:00Z")?,
+                item("1", "2024-01-01T00:00:00Z")?,
+                item("4", "2024-01-01T00:00:00Z")?,
+            ];
+            Ok(vec![john, jane])
+        };
+        let ids = |items: &[JellyfinItem]| -> Vec<String> {
+            items.iter().map(|i| i.id.clone()).collect()
+        };
+
+        let all = merge_items(items_per_user()?, WatchedPolicy::All);
+        assert_eq!(ids(&all), vec!["1", "3"]);
+        // the latest play of any user counts
+        assert_eq!(
+            all[0].last_played_date(),
+            Some("2024-01-10T00:00:00Z".parse()?)
+        );
+
+        let any = merge_items(items_per_user()?, WatchedPolicy::Any);
+        assert_eq!(ids(&any), vec!["1", "2", "3", "4"]);
+        assert_eq!(
+            any[0].last_played_date(),
+            Some("2024-01-10T00:00:00Z".parse()?)
+        );
+
+        // a single user gets all of their items
+        let john = vec![
+            item("1", "2024-01-10T00:00:00Z")?,
+            item("2", "2024-01-01T00:00:00Z")?,
+        ];
+        assert_eq!(merge_items(vec![john], WatchedPolicy::All).len(), 2);
+
+        // an item listed twice for one user is still missing for the other
+        let john = vec![
+            item("1", "2024-01-10T00:00:00Z")?,
+            item("1", "2024-01-10T00:00:00Z")?,
+        ];
+        assert!(merge_items(vec![john, Vec::new()], WatchedPolicy::All).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watched_up_to() -> anyhow::Result<()> {
+        let item = |id: &str, position: u64| {
+            serde_json::from_str::<JellyfinItem>(&format!(
+                r#"{{"Name": "{id}", "Id": "{id}", "RunTimeTicks": 100, "UserData": {{"Played": false, "PlaybackPositionTicks": {position}}}}}"#
+            ))
+        };
+        let items = vec![item("1", 90)?, item("2", 50)?, item("3", 85)?];
+
+        let watched = watched_up_to(items, 85.0);
+        let ids: Vec<&str> = watched.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "3"]);
+        assert!(watched.iter().all(JellyfinItem::watched));
+        Ok(())
+    }
+
+    #[test]
+    fn test_played_at_least() -> anyhow::Result<()> {
+        let item = |id: &str, play_count: u32| {
+            serde_json::from_str::<JellyfinItem>(&format!(
+                r#"{{"Name": "{id}", "Id": "{id}", "UserData": {{"Played": true, "PlayCount": {play_count}}}}}"#
+            ))
+        };
+        let items = vec![item("1", 1)?, item("2", 3)?, item("3", 2)?];
+
+        let kept = played_at_least(items, 2);
+        let ids: Vec<&str> = kept.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_played_on_date_added() -> anyhow::Result<()> {
+        let items = vec![
+            serde_json::from_str::<JellyfinItem>(
+                r#"{"Name": "1", "Id": "1", "DateCreated": "2024-01-01T00:00:00Z"}"#,
+            )?,
+            serde_json::from_str::<JellyfinItem>(r#"{"Name": "2", "Id": "2"}"#)?,
+        ];
+
+        let items = played_on_date_added(items);
+        assert_eq!(items.len(), 1);
+        assert!(items[0].watched());
+        assert_eq!(items[0].last_played_date(), items[0].date_created);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_required_tag() {
+        assert!(has_required_tag("a", None, None));
+        assert!(has_required_tag("a", Some(&[1, 2]), Some(&[2, 3])));
+        assert!(!has_required_tag("a", Some(&[1]), Some(&[2, 3])));
+        assert!(!has_required_tag("a", None, Some(&[2])));
+    }
+
+    #[test]
+    fn test_without_ignored() -> anyhow::Result<()> {
+        let item = |name: &str, tmdb: &str| {
+            serde_json::from_str::<JellyfinItem>(&format!(
+                r#"{{"Name": "{name}", "Id": "{name}", "ProviderIds": {{"Tmdb": "{tmdb}"}}}}"#
+            ))
+        };
+        let items = vec![
+            item("The Matrix", "603")?,
+            item("Heat", "949")?,
+            item("Alien", "348")?,
+        ];
+
+        let kept = without_ignored(items, &["the matrix".to_owned()], &[348], |i| i.tmdb_id());
+        let names: Vec<&str> = kept.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["Heat"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_latest_download_ids_multiple_grabs() {
+        let grabs = vec![
+            grab(1, 48, DownloadClientKind::Qbittorrent, "OLD"),
+            grab(1, 1, DownloadClientKind::Qbittorrent, "NEW"),
+            grab(1, 24, DownloadClientKind::Qbittorrent, "OLDER"),
+            grab(1, 72, DownloadClientKind::Deluge, "OTHER_CLIENT"),
+            grab(2, 5, DownloadClientKind::Qbittorrent, "OTHER_MOVIE"),
+        ];
+        let result = latest_download_ids(grabs);
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result[&DownloadClientKind::Qbittorrent],
+            HashSet::from(["NEW".to_owned(), "OTHER_MOVIE".to_owned()])
+        );
+        assert_eq!(
+            result[&DownloadClientKind::Deluge],
+            HashSet::from(["OTHER_CLIENT".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_releasable_download_ids_season_pack() {
+        let grabs = vec![
+            // a season pack containing episodes 1-3
+            grab(1, 10, DownloadClientKind::Qbittorrent, "PACK"),
+            grab(2, 10, DownloadClientKind::Qbittorrent, "PACK"),
+            grab(3, 10, DownloadClientKind::Qbittorrent, "PACK"),
+            grab(4, 5, DownloadClientKind::Qbittorrent, "SINGLE"),
+        ];
+        let deleted = HashSet::from([1, 2, 4]);
+
+        // episode 3 still has a file, so the pack has to stay
+        let with_file = HashSet::from([1, 2, 3, 4]);
+        let result = releasable_download_ids(grabs, &deleted, &with_file);
+        assert_eq!(
+            result[&DownloadClientKind::Qbittorrent],
+            HashSet::from(["SINGLE".to_owned()])
+        );
+
+        // episode 3 has no file anymore, so the pack can go
+        let grabs = vec![
+            grab(1, 10, DownloadClientKind::Qbittorrent, "PACK"),
+            grab(2, 10, DownloadClientKind::Qbittorrent, "PACK"),
+            grab(3, 10, DownloadClientKind::Qbittorrent, "PACK"),
+        ];
+        let with_file = HashSet::from([1, 2]);
+        let result = releasable_download_ids(grabs, &deleted, &with_file);
+        assert_eq!(
+            result[&DownloadClientKind::Qbittorrent],
+            HashSet::from(["PACK".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_retention_delta() {
+        let delta = RetentionDelta::new(&["a", "b"], &["b", "c", "d"]);
+        assert_eq!(delta.current, 2);
+        assert_eq!(delta.simulated, 3);
+        assert_eq!(delta.added, vec![r#""c""#, r#""d""#]);
+        assert_eq!(delta.removed, vec![r#""a""#]);
+    }
 
     #[test]
     fn test_retention_str_less_than_zero() {
@@ -56,6 +1150,29 @@ mod tests {
         assert_eq!(retention_str(&last_played, &retention), "3 days");
     }
 
+    #[test]
+    fn test_retention_str_weeks() {
+        let retention = chrono::Utc::now() - Duration::from_secs(60 * 60 * 24 * 7 + 300);
+        let last_played = chrono::Utc::now();
+        assert_eq!(retention_str(&last_played, &retention), "1 week");
+
+        let retention = chrono::Utc::now() - Duration::from_secs(60 * 60 * 24 * 17 + 300);
+        assert_eq!(retention_str(&last_played, &retention), "2 weeks, 3 days");
+    }
+
+    #[test]
+    fn test_retention_str_months() {
+        let retention = chrono::Utc::now() - Duration::from_secs(60 * 60 * 24 * 30 + 300);
+        let last_played = chrono::Utc::now();
+        assert_eq!(retention_str(&last_played, &retention), "1 month");
+
+        let retention = chrono::Utc::now() - Duration::from_secs(60 * 60 * 24 * 45 + 300);
+        assert_eq!(retention_str(&last_played, &retention), "1 month, 2 weeks");
+
+        let retention = chrono::Utc::now() - Duration::from_secs(60 * 60 * 24 * 400 + 300);
+        assert_eq!(retention_str(&last_played, &retention), "13 months, 1 week");
+    }
+
     #[test]
     fn test_retention_str_one_hour() {
         let retention = chrono::Utc::now() - Duration::from_secs(60 * 60);
@@ -83,4 +1200,47 @@ mod tests {
         let last_played = chrono::Utc::now();
         assert_eq!(retention_str(&last_played, &retention), "2 minutes");
     }
+
+    #[test]
+    fn test_downloads_of_deleted() {
+        let ids = HashMap::from([(
+            DownloadClientKind::Qbittorrent,
+            HashSet::from(["a".to_owned(), "b".to_owned(), "pack".to_owned()]),
+        )]);
+        let grabbed_for = [(1, "a"), (2, "b"), (1, "pack"), (2, "pack")]
+            .map(|(item, download_id)| (item, download_id.to_owned()));
+        let downloads = Downloads::new(ids, grabbed_for);
+        assert_eq!(downloads.count(), 3);
+
+        // 2 failed to be deleted, so its downloads are still in use
+        let download_ids = downloads.of_deleted(&HashSet::from([1]));
+        let expected = HashSet::from(["a".to_owned()]);
+        assert_eq!(download_ids[&DownloadClientKind::Qbittorrent], expected);
+    }
+
+    #[test]
+    fn test_needs_confirmation() -> anyhow::Result<()> {
+        let needs = |mode, count, threshold, terminal| {
+            needs_confirmation(mode, count, threshold, "movies", terminal)
+        };
+        assert!(needs(CleanupMode::Confirm, 1, None, true)?);
+        // the selection is confirmed once for all cleaners
+        assert!(needs(CleanupMode::Interactive, 1, None, true)?);
+        assert!(!needs(CleanupMode::Delete, 100, None, false)?);
+        assert!(!needs(CleanupMode::Delete, 10, Some(10), false)?);
+        // `--yes` isn't enough above the threshold
+        assert!(needs(CleanupMode::Delete, 11, Some(10), true)?);
+        assert!(needs(CleanupMode::Delete, 11, Some(10), false).is_err());
+        assert!(needs(CleanupMode::Confirm, 11, Some(10), true)?);
+        // nothing gets deleted anyway
+        assert!(!needs(CleanupMode::DryRun, 11, Some(10), false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_details() {
+        let date = "2024-03-01T20:00:00Z".parse().ok();
+        assert_eq!(details(date, 1536), "last played 2024-03-01, 1.5 KiB");
+        assert_eq!(details(None, 0), "never played, 0 B");
+    }
 }
@@ -1,6 +1,17 @@
-use crate::logging::LoggingSettings;
-use clap::Parser;
-use std::path::PathBuf;
+use crate::{
+    cleaners::CleanupMode,
+    config::parse_size,
+    logging::{ColorChoice, LogFormat, LoggingSettings},
+    report::ReportFormat,
+};
+use anyhow::bail;
+use clap::{ArgAction, Parser, Subcommand};
+use log::warn;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -11,14 +22,452 @@ use std::path::PathBuf;
 pub struct Cli {
     /// Perform actual deletion of files. If not set the program will operate in
     /// a "dry run" mode
-    #[clap(short = 'd', long)]
+    #[clap(short = 'd', long, global = true)]
     pub force_delete: bool,
+    /// Only list the items that would be deleted. This is the default, passing
+    /// it makes the intent explicit. Can't be combined with `--force-delete`
+    /// or `--interactive`
+    #[clap(long, global = true, conflicts_with_all = ["force_delete", "interactive"])]
+    pub dry_run: bool,
+    /// Review the items eligible for deletion and select which of them to
+    /// delete, then confirm the selection. Requires `--force-delete` and only
+    /// takes effect when running in a terminal
+    #[clap(short, long, global = true)]
+    pub interactive: bool,
+    /// Delete without asking for confirmation. Required for `--force-delete`
+    /// when not running in a terminal
+    #[clap(short, long, global = true)]
+    pub yes: bool,
+    /// Let `--yes` delete more items than `confirm_threshold` in one run, e.g.
+    /// once the watch history reported by the media server was checked
+    #[clap(long, global = true)]
+    pub ignore_confirm_threshold: bool,
     /// You can either provide a single log level (like `info`) or use a more
     /// detailed syntax like `off,sanitarr=debug,reqwest=info` (similar to
-    /// `tracing_subscriber::filter::EnvFilter` syntax)
-    #[clap(short, long, env = "LOG_LEVEL")]
-    pub log_level: LoggingSettings,
-    /// Path to the config file
-    #[clap(short, long)]
-    pub config: PathBuf,
+    /// `tracing_subscriber::filter::EnvFilter` syntax). Defaults to `info`
+    #[clap(short, long, global = true, env = "LOG_LEVEL")]
+    pub log_level: Option<LoggingSettings>,
+    /// Log more of what sanitarr does: `-v` at debug and `-vv` at trace level.
+    /// Ignored if `--log-level` is given
+    #[clap(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Format of the log lines
+    #[clap(long, global = true, env = "LOG_FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Colorize the levels of the log lines printed to stdout. `auto` does
+    /// when stdout is a terminal and `NO_COLOR` is not set
+    #[clap(long, global = true, value_enum, default_value_t)]
+    pub color: ColorChoice,
+    /// Also write logs to the given file. It is rotated once it grows over
+    /// `--log-file-max-size`, keeping a few of the previous files as
+    /// `<file>.1`, `<file>.2` and so on
+    #[clap(long, global = true, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Size the log file is rotated at, e.g. `10MiB`
+    #[clap(long, global = true, value_parser = parse_size, default_value = "10MiB")]
+    pub log_file_max_size: u64,
+    /// Path to the config file. Required, either before or after the
+    /// subcommand
+    #[clap(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Override the configured `retention_period` of all services for this
+    /// run (e.g. `30d`)
+    #[clap(long, global = true, value_parser = humantime::parse_duration)]
+    pub since: Option<Duration>,
+    /// Maximum number of items (movies, series, episodes or albums) deleted
+    /// in this run across all services. Items last played the longest ago are
+    /// deleted first, the rest are skipped
+    #[clap(long, global = true)]
+    pub max_delete: Option<usize>,
+    /// Maximum disk space (e.g. `50GB`) freed in this run across all
+    /// services. Items last played the longest ago are deleted first, the
+    /// deletion stops once the next item would exceed it
+    #[clap(long, global = true, value_parser = parse_size)]
+    pub max_free: Option<u64>,
+    /// Format of the report of what was deleted (or would be deleted in a
+    /// "dry run" mode) printed at the end of the run
+    #[clap(long, global = true, value_enum, default_value_t)]
+    pub report_format: ReportFormat,
+    /// Write the report to the given file instead of stdout
+    #[clap(long, global = true)]
+    pub report_file: Option<PathBuf>,
+    /// Keep running and repeat the cleanup at the given interval (e.g. `6h`)
+    /// until SIGTERM or SIGINT is received. Takes precedence over the
+    /// configured `schedule`. Deletion requires `--yes` as nobody is there to
+    /// confirm it
+    #[clap(long, global = true, value_parser = humantime::parse_duration)]
+    pub interval: Option<Duration>,
+    /// In a "dry run" mode, exit with code 10 if anything would be deleted and
+    /// 0 otherwise, e.g. to only follow up with `--force-delete` when there is
+    /// work to do. Errors still exit with code 1. Ignored by the daemon
+    #[clap(long, global = true)]
+    pub fail_if_pending: bool,
+    /// When running as a daemon, serve Prometheus metrics (items, torrents
+    /// and bytes deleted, run durations) at `http://<addr>/metrics`, e.g.
+    /// `0.0.0.0:9090`
+    #[clap(long, global = true)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// When running as a daemon, serve its health at `http://<addr>/health`:
+    /// 200 while the last run succeeded and 503 if it failed or no run
+    /// completed within twice the time between runs, e.g. `0.0.0.0:8080`
+    #[clap(long, global = true)]
+    pub health_addr: Option<SocketAddr>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Debug, Default)]
+pub enum Command {
+    /// Clean up movies, series (or episodes, depending on `sonarr.cleanup_mode`)
+    /// and music. This is the default
+    #[default]
+    All,
+    /// Clean up movies from Radarr only
+    Movies,
+    /// Clean up fully watched series from Sonarr only, regardless of
+    /// `sonarr.cleanup_mode`
+    Series,
+    /// Clean up files of watched episodes from Sonarr only, regardless of
+    /// `sonarr.cleanup_mode`
+    Episodes,
+    /// Clean up albums from Lidarr only
+    Music,
+    /// Review the items every cleaner would delete on a single screen, with
+    /// when they were last played and their size, and check which of them to
+    /// delete. Nothing is deleted before the selection is confirmed. Requires
+    /// `--force-delete` and a terminal
+    Review,
+    /// Preview how a change of retention periods affects the set of items
+    /// eligible for deletion. Nothing gets deleted
+    Simulate {
+        /// Retention period to simulate for Radarr (e.g. `3d`). Defaults to the
+        /// configured one
+        #[clap(long, value_parser = humantime::parse_duration)]
+        radarr_retention: Option<Duration>,
+        /// Retention period to simulate for Sonarr (e.g. `3d`). Defaults to the
+        /// configured one
+        #[clap(long, value_parser = humantime::parse_duration)]
+        sonarr_retention: Option<Duration>,
+    },
+    /// Check the config and the connection to every configured service, then
+    /// exit. Nothing gets deleted
+    Validate,
+    /// Write a commented example config with every option, to be edited
+    /// before the first run
+    Init {
+        /// Where to write the config. Defaults to `--config`. An existing file
+        /// is never overwritten
+        path: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    /// whether the command cleans up, i.e. can be repeated by a daemon
+    pub fn is_cleanup(&self) -> bool {
+        match self {
+            Self::All | Self::Movies | Self::Series | Self::Episodes | Self::Music => true,
+            Self::Review | Self::Simulate { .. } | Self::Validate | Self::Init { .. } => false,
+        }
+    }
+}
+
+/// exit code of a dry run that found anything to delete, see
+/// [`Cli::fail_if_pending`]
+pub const PENDING_EXIT_CODE: i32 = 10;
+
+impl Cli {
+    /// the path given with `--config`. Clap can't require an option that is
+    /// accepted after the subcommand too, so it is checked here
+    pub fn config_path(&self) -> anyhow::Result<&Path> {
+        match &self.config {
+            Some(path) => Ok(path),
+            None => bail!("the config file is required, pass it with `--config <CONFIG>`"),
+        }
+    }
+
+    /// the explicit `--log-level` if given, otherwise the one `-v` stands for
+    pub fn logging_settings(&self) -> LoggingSettings {
+        match &self.log_level {
+            Some(settings) => settings.clone(),
+            None => LoggingSettings::verbosity(self.verbose),
+        }
+    }
+
+    /// resolves the cleanup mode from the provided flags. Interactive mode is
+    /// only enabled when attached to a terminal and, as it deletes the selected
+    /// items, requires `--force-delete` like any deletion. Deletion has to be
+    /// confirmed unless `--yes` is given, which is required outside of a
+    /// terminal and when running as a daemon. `terminal` tells whether
+    /// prompts can be answered, see [`crate::prompt::is_terminal`]
+    pub fn cleanup_mode(&self, daemon: bool, terminal: bool) -> anyhow::Result<CleanupMode> {
+        if let Some(Command::Review) = self.command {
+            if self.dry_run {
+                bail!("`review` deletes the selected items, it can't be combined with `--dry-run`");
+            }
+            if !self.force_delete {
+                bail!("`review` deletes the selected items, pass `--force-delete` along with it");
+            }
+            if daemon || !terminal {
+                bail!("`review` requires a terminal and can't run as a daemon");
+            }
+            return Ok(CleanupMode::Interactive);
+        }
+        if self.interactive {
+            if !daemon && !self.force_delete {
+                bail!(
+                    "`--interactive` deletes the selected items, pass `--force-delete` along with it"
+                );
+            }
+            if !daemon && terminal {
+                return Ok(CleanupMode::Interactive);
+            }
+            warn!(
+                "`--interactive` is ignored as sanitarr is not running in a terminal or runs as a daemon"
+            );
+        }
+        if !self.force_delete {
+            return Ok(CleanupMode::DryRun);
+        }
+        if self.yes {
+            return Ok(CleanupMode::Delete);
+        }
+        if daemon {
+            bail!("refusing to delete without confirmation, pass `--yes` when running as a daemon");
+        }
+        if terminal {
+            return Ok(CleanupMode::Confirm);
+        }
+        bail!(
+            "refusing to delete without confirmation, pass `--yes` when not running in a terminal"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::LevelFilter;
+
+    fn cli(args: &[&str]) -> Cli {
+        let base = ["sanitarr", "--config", "config.toml", "--log-level", "info"];
+        Cli::parse_from(base.iter().chain(args))
+    }
+
+    #[test]
+    fn test_cleanup_mode() -> anyhow::Result<()> {
+        assert_eq!(cli(&[]).cleanup_mode(false, false)?, CleanupMode::DryRun);
+        assert_eq!(
+            cli(&["--force-delete", "--yes"]).cleanup_mode(false, false)?,
+            CleanupMode::Delete
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> anyhow::Result<()> {
+        assert_eq!(
+            cli(&["--dry-run"]).cleanup_mode(false, false)?,
+            CleanupMode::DryRun
+        );
+        assert_eq!(
+            cli(&["movies", "--dry-run"]).cleanup_mode(false, false)?,
+            CleanupMode::DryRun
+        );
+        let base = ["sanitarr", "--config", "config.toml", "--log-level", "info"];
+        for conflicting in ["--force-delete", "--interactive"] {
+            let args = base.into_iter().chain(["--dry-run", conflicting]);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_subcommands() -> anyhow::Result<()> {
+        assert!(cli(&[]).command.is_none());
+        assert!(matches!(cli(&["movies"]).command, Some(Command::Movies)));
+
+        let args = cli(&["episodes", "--force-delete", "--yes"]);
+        assert!(matches!(args.command, Some(Command::Episodes)));
+        assert_eq!(args.cleanup_mode(false, false)?, CleanupMode::Delete);
+        Ok(())
+    }
+
+    #[test]
+    fn test_options_after_subcommand() {
+        let args = Cli::parse_from([
+            "sanitarr",
+            "movies",
+            "--config",
+            "config.toml",
+            "--log-level",
+            "warn",
+        ]);
+        assert!(matches!(args.command, Some(Command::Movies)));
+        assert_eq!(args.config_path().unwrap(), Path::new("config.toml"));
+        assert_eq!(args.logging_settings().root_level, LevelFilter::Warn);
+
+        let args = Cli::parse_from(["sanitarr", "review", "-c", "config.toml", "-l", "debug"]);
+        assert_eq!(args.config_path().unwrap(), Path::new("config.toml"));
+        assert_eq!(args.logging_settings().root_level, LevelFilter::Debug);
+
+        let args = Cli::parse_from(["sanitarr", "movies", "-vv"]);
+        assert_eq!(args.verbose, 2);
+
+        assert!(
+            Cli::parse_from(["sanitarr", "movies"])
+                .config_path()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verbose() {
+        let settings = |args: &[&str]| {
+            let base = ["sanitarr", "--config", "config.toml"];
+            Cli::parse_from(base.iter().chain(args)).logging_settings()
+        };
+        let sanitarr_level = |settings: LoggingSettings| {
+            settings
+                .other_levels
+                .into_iter()
+                .find(|(target, _)| target == "sanitarr")
+                .map(|(_, level)| level)
+        };
+        assert_eq!(settings(&[]).root_level, LevelFilter::Info);
+        assert_eq!(sanitarr_level(settings(&["-v"])), Some(LevelFilter::Debug));
+        assert_eq!(sanitarr_level(settings(&["-vv"])), Some(LevelFilter::Trace));
+        assert_eq!(settings(&["-vv"]).root_level, LevelFilter::Info);
+        // an explicit level wins
+        let explicit = settings(&["-vv", "--log-level", "warn"]);
+        assert_eq!(explicit.root_level, LevelFilter::Warn);
+        assert!(explicit.other_levels.is_empty());
+    }
+
+    #[test]
+    fn test_review() {
+        assert!(matches!(cli(&["review"]).command, Some(Command::Review)));
+        assert!(
+            cli(&["review", "--dry-run"])
+                .cleanup_mode(false, false)
+                .is_err()
+        );
+        // selecting what to delete doesn't make deletion implicit
+        assert!(cli(&["review"]).cleanup_mode(false, false).is_err());
+        assert!(cli(&["--interactive"]).cleanup_mode(false, false).is_err());
+        assert!(
+            cli(&["review", "--interval", "6h"])
+                .cleanup_mode(true, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_is_cleanup() {
+        assert!(Command::default().is_cleanup());
+        assert!(Command::Episodes.is_cleanup());
+        assert!(!Command::Review.is_cleanup());
+        assert!(!cli(&["simulate"]).command.unwrap().is_cleanup());
+    }
+
+    #[test]
+    fn test_init() {
+        let Some(Command::Init { path }) = cli(&["init", "new.toml"]).command else {
+            panic!("expected the init command");
+        };
+        assert_eq!(path, Some(PathBuf::from("new.toml")));
+        assert!(matches!(
+            cli(&["init"]).command,
+            Some(Command::Init { path: None })
+        ));
+    }
+
+    #[test]
+    fn test_max_free() {
+        assert_eq!(cli(&[]).max_free, None);
+        assert_eq!(cli(&["--max-free", "50GB"]).max_free, Some(50_000_000_000));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(cli(&[]).log_format, LogFormat::Text);
+        assert_eq!(cli(&["--log-format", "json"]).log_format, LogFormat::Json);
+        let args = cli(&["validate", "--log-format", "json"]);
+        assert_eq!(args.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_color() {
+        assert_eq!(cli(&[]).color, ColorChoice::Auto);
+        assert_eq!(cli(&["--color", "never"]).color, ColorChoice::Never);
+        assert_eq!(
+            cli(&["validate", "--color", "always"]).color,
+            ColorChoice::Always
+        );
+    }
+
+    #[test]
+    fn test_log_file() {
+        let args = cli(&[]);
+        assert_eq!(args.log_file, None);
+        assert_eq!(args.log_file_max_size, 10 << 20);
+
+        let args = cli(&["--log-file", "sanitarr.log", "--log-file-max-size", "1MB"]);
+        assert_eq!(args.log_file, Some(PathBuf::from("sanitarr.log")));
+        assert_eq!(args.log_file_max_size, 1_000_000);
+
+        let args = cli(&[
+            "validate",
+            "--log-file",
+            "sanitarr.log",
+            "--log-file-max-size",
+            "1MB",
+        ]);
+        assert_eq!(args.log_file, Some(PathBuf::from("sanitarr.log")));
+        assert_eq!(args.log_file_max_size, 1_000_000);
+    }
+
+    #[test]
+    fn test_fail_if_pending() {
+        assert!(!cli(&[]).fail_if_pending);
+        assert!(cli(&["movies", "--fail-if-pending"]).fail_if_pending);
+    }
+
+    #[test]
+    fn test_interval() -> anyhow::Result<()> {
+        assert!(cli(&[]).interval.is_none());
+
+        let args = cli(&["--interval", "6h", "--force-delete", "--yes"]);
+        assert_eq!(args.interval, Some(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(args.cleanup_mode(true, false)?, CleanupMode::Delete);
+
+        let args = cli(&["--interval", "6h", "--interactive"]);
+        assert_eq!(args.cleanup_mode(true, false)?, CleanupMode::DryRun);
+        assert!(cli(&["--force-delete"]).cleanup_mode(true, false).is_err());
+
+        // the Docker `INTERVAL` used to be passed to `sleep`, which takes plain
+        // seconds too
+        let args = cli(&["--interval", "1.5h"]);
+        assert_eq!(args.interval, Some(Duration::from_secs(90 * 60)));
+        let args = ["sanitarr", "--config", "config.toml", "--interval", "3600"];
+        assert!(Cli::try_parse_from(args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deletion_requires_confirmation_outside_terminal() -> anyhow::Result<()> {
+        assert!(cli(&["--force-delete"]).cleanup_mode(false, false).is_err());
+        assert_eq!(
+            cli(&["--force-delete"]).cleanup_mode(false, true)?,
+            CleanupMode::Confirm
+        );
+        assert_eq!(
+            cli(&["--force-delete", "--interactive"]).cleanup_mode(false, true)?,
+            CleanupMode::Interactive
+        );
+        assert_eq!(
+            cli(&["review", "--force-delete"]).cleanup_mode(false, true)?,
+            CleanupMode::Interactive
+        );
+        Ok(())
+    }
 }
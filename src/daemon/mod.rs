@@ -0,0 +1,300 @@
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
+use croner::Cron;
+use log::{error, info, warn};
+use std::time::Duration;
+
+/// when the cleanup is repeated in daemon mode
+pub enum Schedule {
+    /// right away and then every time the interval passes after a run
+    Interval(Duration),
+    /// at the times the cron expression fires, in the given timezone or the
+    /// local one
+    Cron {
+        cron: Box<Cron>,
+        timezone: Option<Tz>,
+    },
+}
+
+impl Schedule {
+    fn next_run(&self, now: DateTime<Local>) -> anyhow::Result<DateTime<Local>> {
+        match self {
+            Self::Interval(interval) => Ok(now + *interval),
+            Self::Cron { cron, timezone } => next_fire_time(cron, *timezone, &now)
+                .with_context(|| format!("cron schedule `{cron}` never fires")),
+        }
+    }
+
+    /// the longest time between two runs, for a cron schedule the longest gap
+    /// between its fire times over the next week or so
+    pub fn period(&self) -> Duration {
+        self.period_after(Local::now())
+    }
+
+    fn period_after(&self, now: DateTime<Local>) -> Duration {
+        let (cron, timezone) = match self {
+            Self::Interval(interval) => return *interval,
+            Self::Cron { cron, timezone } => (cron, *timezone),
+        };
+        let horizon = now + chrono::Duration::days(8);
+        let mut longest = Duration::ZERO;
+        let Some(mut previous) = next_fire_time(cron, timezone, &now) else {
+            return longest;
+        };
+        // bounded for schedules firing every minute
+        for _ in 0..10_000 {
+            let Some(next) = next_fire_time(cron, timezone, &previous) else {
+                break;
+            };
+            longest = longest.max((next - previous).to_std().unwrap_or_default());
+            previous = next;
+            if previous > horizon {
+                break;
+            }
+        }
+        longest
+    }
+}
+
+/// the first time the cron schedule fires after `after`, with the expression
+/// evaluated in `timezone` if given
+fn next_fire_time(
+    cron: &Cron,
+    timezone: Option<Tz>,
+    after: &DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    match timezone {
+        Some(timezone) => cron
+            .find_next_occurrence(&after.with_timezone(&timezone), false)
+            .ok()
+            .map(|time| time.with_timezone(&Local)),
+        None => cron.find_next_occurrence(after, false).ok(),
+    }
+}
+
+/// runs `cleanup` on the given schedule until SIGTERM or SIGINT is received.
+/// A failed run is logged and retried at the next scheduled time. A signal
+/// received in the middle of a run lets it finish before shutting down
+pub async fn run<F, Fut>(schedule: Schedule, mut cleanup: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // a cron schedule waits for its first fire time instead of running now
+    if let Schedule::Cron { .. } = schedule
+        && !wait_for_next_run(&schedule, &mut shutdown).await?
+    {
+        return Ok(());
+    }
+    loop {
+        let run = cleanup();
+        tokio::pin!(run);
+        let (result, stopping) = tokio::select! {
+            result = &mut run => (result, false),
+            _ = &mut shutdown => {
+                info!("shutdown requested, finishing the current cleanup first");
+                (run.await, true)
+            }
+        };
+        if let Err(e) = result {
+            error!("cleanup failed: {e:#}");
+        }
+        if stopping || !wait_for_next_run(&schedule, &mut shutdown).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// sleeps until the next scheduled run. Returns `false` if shutdown was
+/// requested in the meantime
+async fn wait_for_next_run<S>(schedule: &Schedule, shutdown: &mut S) -> anyhow::Result<bool>
+where
+    S: Future<Output = ()> + Unpin,
+{
+    let now = Local::now();
+    let next_run = schedule.next_run(now)?;
+    info!("next cleanup at {}", next_run.format("%Y-%m-%d %H:%M:%S"));
+    let delay = (next_run - now).to_std().unwrap_or_default();
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => Ok(true),
+        _ = shutdown => {
+            info!("shutting down");
+            Ok(false)
+        }
+    }
+}
+
+/// completes once SIGTERM or SIGINT (Ctrl+C) is received
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("unable to listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("unable to listen for SIGINT: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    /// the next fire time of `expression` evaluated in `timezone` after the
+    /// given UTC time
+    fn next_in(timezone: Tz, expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        let cron = expression.parse().unwrap();
+        next_fire_time(&cron, Some(timezone), &utc(after).with_timezone(&Local))
+            .map(|time| time.to_utc())
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        next_in(chrono_tz::UTC, expression, after)
+    }
+
+    fn period(expression: &str) -> Duration {
+        let now = Local.with_ymd_and_hms(2025, 3, 12, 12, 0, 0).unwrap();
+        let cron = expression.parse().unwrap();
+        Schedule::Cron {
+            cron: Box::new(cron),
+            timezone: None,
+        }
+        .period_after(now)
+    }
+
+    #[test]
+    fn test_period() {
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(Schedule::Interval(hour * 6).period(), hour * 6);
+        assert_eq!(period("0 4 * * *"), hour * 24);
+        assert_eq!(period("*/15 * * * *"), Duration::from_secs(15 * 60));
+        // the weekend is the longest gap of weekdays only
+        assert_eq!(period("0 4 * * 1-5"), hour * 24 * 3);
+    }
+
+    #[test]
+    fn test_next_run_in_timezone() -> anyhow::Result<()> {
+        let schedule = Schedule::Cron {
+            cron: Box::new("0 4 * * *".parse()?),
+            timezone: Some(chrono_tz::Asia::Tokyo),
+        };
+        // 4am in Tokyo is 7pm UTC the day before
+        let now = chrono::Utc.with_ymd_and_hms(2025, 3, 12, 12, 0, 0).unwrap();
+        let next_run = schedule.next_run(now.with_timezone(&Local))?;
+        assert_eq!(
+            next_run.to_utc(),
+            chrono::Utc.with_ymd_and_hms(2025, 3, 12, 19, 0, 0).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_fire_time() {
+        // every day at 4am
+        assert_eq!(
+            next("0 4 * * *", "2025-03-10T03:59:00Z"),
+            Some(utc("2025-03-10T04:00:00Z"))
+        );
+        assert_eq!(
+            next("0 4 * * *", "2025-03-10T04:00:00Z"),
+            Some(utc("2025-03-11T04:00:00Z"))
+        );
+        // every 15 minutes
+        assert_eq!(
+            next("*/15 * * * *", "2025-03-10T10:16:30Z"),
+            Some(utc("2025-03-10T10:30:00Z"))
+        );
+        // every minute from the 5th on
+        assert_eq!(
+            next("5/1 * * * *", "2025-03-10T10:07:30Z"),
+            Some(utc("2025-03-10T10:08:00Z"))
+        );
+        // Sundays at 2:30am, 2025-03-10 is a Monday
+        assert_eq!(
+            next("30 2 * * 7", "2025-03-10T00:00:00Z"),
+            Some(utc("2025-03-16T02:30:00Z"))
+        );
+        // weekdays at 6pm, skipping the weekend
+        assert_eq!(
+            next("0 18 * * MON-FRI", "2025-03-14T19:00:00Z"),
+            Some(utc("2025-03-17T18:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 1 JAN *", "2025-03-10T00:00:00Z"),
+            Some(utc("2026-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            next("@daily", "2025-03-10T10:00:00Z"),
+            Some(utc("2025-03-11T00:00:00Z"))
+        );
+        // the 13th or a Friday, not only Friday the 13th
+        assert_eq!(
+            next("0 0 13 * 5", "2025-06-10T00:00:00Z"),
+            Some(utc("2025-06-13T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 13 * 5", "2025-06-13T00:00:00Z"),
+            Some(utc("2025-06-20T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2025-03-10T00:00:00Z"),
+            Some(utc("2028-02-29T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_daylight_saving_time() {
+        use chrono_tz::Europe::Berlin;
+
+        // 2:30 doesn't exist on 2025-03-30, clocks jump from 2:00 to 3:00 and
+        // the run happens right after the jump
+        assert_eq!(
+            next_in(Berlin, "30 2 * * *", "2025-03-29T02:00:00Z"),
+            Some(utc("2025-03-30T01:00:00Z"))
+        );
+        // 2:30 happens twice on 2025-10-26, the schedule fires only once
+        assert_eq!(
+            next_in(Berlin, "30 2 * * *", "2025-10-25T23:00:00Z"),
+            Some(utc("2025-10-26T00:30:00Z"))
+        );
+        assert_eq!(
+            next_in(Berlin, "30 2 * * *", "2025-10-26T00:30:00Z"),
+            Some(utc("2025-10-27T01:30:00Z"))
+        );
+        // every 15 minutes across the jump
+        assert_eq!(
+            next_in(Berlin, "*/15 * * * *", "2025-03-30T00:50:00Z"),
+            Some(utc("2025-03-30T01:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expression in ["", "0 4 * *", "60 4 * * *", "0 4 0 * *", "a * * * *"] {
+            assert!(
+                expression.parse::<Cron>().is_err(),
+                "`{expression}` should be invalid"
+            );
+        }
+    }
+}
@@ -1,31 +1,106 @@
-use super::ResponseExt;
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{ResponseExt, WatchHistoryProvider, api_url};
 use crate::config::JellyfinConfig;
 use anyhow::Ok;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use log::debug;
+use reqwest::Url;
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
-use reqwest::{Client, ClientBuilder, Url};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 
+/// A client for Jellyfin API. Emby shares the same API apart from the
+/// authentication header, so the same client is used for both
 #[derive(Clone)]
 pub struct JellyfinClient {
-    client: Client,
+    client: ServiceClient,
     base_url: Url,
+    retry: RetryPolicy,
+    flavor: Flavor,
+    /// names or ids of the libraries items are queried from, all if empty
+    libraries: Vec<String>,
+    /// ids of `libraries`, resolved on the first query
+    library_ids: Arc<OnceCell<Vec<String>>>,
+    /// names or ids of the collections whose items are never returned
+    protected_collections: Vec<String>,
+    /// ids of the items in `protected_collections`, resolved on the first
+    /// query
+    protected_ids: Arc<OnceCell<HashSet<String>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flavor {
+    Jellyfin,
+    Emby,
 }
 
 impl JellyfinClient {
-    pub fn new(config: &JellyfinConfig) -> anyhow::Result<Self> {
-        let JellyfinConfig { base_url, api_key } = config;
-        let base_url = Url::parse(base_url)?;
-        let default_headers = auth_headers(api_key)?;
-        let client = ClientBuilder::new()
-            .default_headers(default_headers)
-            .build()?;
-        Ok(Self { client, base_url })
+    pub fn new(config: &JellyfinConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        Self::with_flavor(config, Flavor::Jellyfin, http)
+    }
+
+    /// creates a client for an Emby server. The base url may include the
+    /// `/emby` path prefix
+    pub fn emby(config: &JellyfinConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        Self::with_flavor(config, Flavor::Emby, http)
+    }
+
+    fn with_flavor(
+        config: &JellyfinConfig,
+        flavor: Flavor,
+        http: &HttpClient,
+    ) -> anyhow::Result<Self> {
+        let JellyfinConfig {
+            base_url,
+            api_key,
+            timeout,
+            basic_auth_username,
+            basic_auth_password,
+            refresh_after_cleanup: _,
+            libraries,
+            protected_collections,
+        } = config;
+        let base_url = api_url(base_url, "")?;
+        let default_headers = auth_headers(api_key, flavor, basic_auth_username.is_some())?;
+        let client = http
+            .service(default_headers, *timeout)
+            .with_basic_auth(basic_auth_username.as_ref(), basic_auth_password.as_ref());
+        Ok(Self {
+            client,
+            base_url,
+            flavor,
+            retry: http.retry(),
+            libraries: libraries.clone(),
+            library_ids: Arc::new(OnceCell::new()),
+            protected_collections: protected_collections.clone(),
+            protected_ids: Arc::new(OnceCell::new()),
+        })
     }
 
-    /// Get all items that match the given query filter
+    /// Get all users.
+    /// https://api.jellyfin.org/#tag/User
+    async fn users(&self) -> anyhow::Result<Vec<User>> {
+        let url = self.base_url.join("Users")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json::<Vec<User>>()
+            .await?;
+
+        Ok(response)
+    }
+
+    /// Get all items that match the given query filter, page by page
     /// https://api.jellyfin.org/#tag/Items
-    pub async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
+    async fn all_items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
         let url = self.base_url.join("Items")?;
 
         // pagination
@@ -39,7 +114,7 @@ impl JellyfinClient {
                 .get(url.clone())
                 .query(&items_filter)
                 .query(&[("startIndex", start_index), ("limit", limit)])
-                .send()
+                .send_with_retry(&self.retry)
                 .await?
                 .handle_error()
                 .await?
@@ -61,26 +136,137 @@ impl JellyfinClient {
         Ok(items)
     }
 
-    /// Get all users.
-    /// https://api.jellyfin.org/#tag/User
-    async fn users(&self) -> anyhow::Result<Vec<User>> {
-        let url = self.base_url.join("Users")?;
+    /// Collect the ids of the items in the protected collections, once
+    async fn protected_ids(&self) -> anyhow::Result<&HashSet<String>> {
+        self.protected_ids
+            .get_or_try_init(|| async {
+                let filter = ItemsFilter::new()
+                    .recursive()
+                    .include_item_types(&["BoxSet"]);
+                let collections = self.all_items(filter).await?;
+                let mut ids = HashSet::new();
+                for name in &self.protected_collections {
+                    let collection = collections
+                        .iter()
+                        .find(|c| c.id == *name || c.name.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("collection \"{name}\" not found in {}", self.name())
+                        })?;
+                    let members = self
+                        .all_items(ItemsFilter::new().parent_id(&collection.id))
+                        .await?;
+                    ids.extend(members.into_iter().map(|item| item.id));
+                }
+                Ok(ids)
+            })
+            .await
+    }
+
+    /// Resolve the configured library names (or ids) to ids, once
+    async fn library_ids(&self) -> anyhow::Result<&[String]> {
+        let ids = self
+            .library_ids
+            .get_or_try_init(|| async {
+                let filter = ItemsFilter::new()
+                    .recursive()
+                    .include_item_types(&["CollectionFolder"]);
+                let folders = self.all_items(filter).await?;
+                self.libraries
+                    .iter()
+                    .map(|library| {
+                        folders
+                            .iter()
+                            .find(|f| f.id == *library || f.name.eq_ignore_ascii_case(library))
+                            .map(|f| f.id.clone())
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "library \"{library}\" not found in {}",
+                                    self.name()
+                                )
+                            })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .await?;
+        Ok(ids)
+    }
+
+    /// Start a scan of all the libraries. The scan runs in the background,
+    /// so this doesn't wait for it to finish
+    /// https://api.jellyfin.org/#tag/Library/operation/RefreshLibrary
+    pub async fn refresh_library(&self) -> anyhow::Result<()> {
+        let url = self.base_url.join("Library/Refresh")?;
+        self.client
+            .post(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WatchHistoryProvider for JellyfinClient {
+    fn name(&self) -> &'static str {
+        match self.flavor {
+            Flavor::Jellyfin => "Jellyfin",
+            Flavor::Emby => "Emby",
+        }
+    }
+
+    /// Get all items that match the given query filter. With `libraries`
+    /// configured only items in those libraries are returned. Items queried
+    /// by their ids are not constrained any further, as they are looked up
+    /// for items already found in the libraries. Items in
+    /// `protected_collections` (or episodes of series in them) are left out
+    async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
+        let items = if self.libraries.is_empty() || items_filter.ids.is_some() {
+            self.all_items(items_filter).await?
+        } else {
+            let mut items = Vec::new();
+            for library_id in self.library_ids().await? {
+                let filter = items_filter.clone().parent_id(library_id);
+                items.extend(self.all_items(filter).await?);
+            }
+            items
+        };
+        if self.protected_collections.is_empty() {
+            return Ok(items);
+        }
+        let protected = self.protected_ids().await?;
+        let (protected_items, items): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| {
+            protected.contains(&item.id)
+                || item
+                    .series_id
+                    .as_ref()
+                    .is_some_and(|id| protected.contains(id))
+        });
+        if !protected_items.is_empty() {
+            let names: Vec<&str> = protected_items.iter().map(|i| i.name.as_str()).collect();
+            debug!("skipping items in protected collections: {names:?}");
+        }
+        Ok(items)
+    }
+
+    /// Get the current time of the Jellyfin server as reported by the `Date`
+    /// header of a public system info request.
+    /// https://api.jellyfin.org/#tag/System/operation/GetPublicSystemInfo
+    async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let url = self.base_url.join("System/Info/Public")?;
         let response = self
             .client
             .get(url)
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
-            .await?
-            .json::<Vec<User>>()
             .await?;
-
-        Ok(response)
+        Ok(response.server_date())
     }
 
     /// Get a user by it's username (not id). Throws an error if the user not
     /// found
-    pub async fn user(&self, user_name: &str) -> anyhow::Result<User> {
+    async fn user(&self, user_name: &str) -> anyhow::Result<User> {
         self.users()
             .await?
             .into_iter()
@@ -89,15 +275,40 @@ impl JellyfinClient {
     }
 }
 
-fn auth_headers(api_key: &str) -> Result<HeaderMap, anyhow::Error> {
+/// builds the headers authenticating requests with the API key. With basic auth
+/// the `Authorization` header is taken, so Jellyfin gets the key in its
+/// alternative `X-MediaBrowser-Token` header
+fn auth_headers(
+    api_key: &str,
+    flavor: Flavor,
+    basic_auth: bool,
+) -> Result<HeaderMap, anyhow::Error> {
     let mut auth_headers = HeaderMap::new();
-    let header_value = format!("MediaBrowser Token={api_key}");
-    let mut header_value = HeaderValue::from_str(&header_value)?;
-    header_value.set_sensitive(true);
-    auth_headers.insert(AUTHORIZATION, header_value);
+    match flavor {
+        Flavor::Jellyfin if basic_auth => {
+            let mut header_value = HeaderValue::from_str(api_key)?;
+            header_value.set_sensitive(true);
+            auth_headers.insert("X-MediaBrowser-Token", header_value);
+        }
+        Flavor::Jellyfin => {
+            let header_value = format!("MediaBrowser Token={api_key}");
+            let mut header_value = HeaderValue::from_str(&header_value)?;
+            header_value.set_sensitive(true);
+            auth_headers.insert(AUTHORIZATION, header_value);
+        }
+        Flavor::Emby => {
+            let mut header_value = HeaderValue::from_str(api_key)?;
+            header_value.set_sensitive(true);
+            auth_headers.insert("X-Emby-Token", header_value);
+        }
+    }
     Ok(auth_headers)
 }
 
+/// item fields the cleaners need to match items with the *arr services and to
+/// decide whether they may be deleted
+pub const ITEM_FIELDS: &[&str] = &["ProviderIds", "Genres", "CommunityRating", "DateCreated"];
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ItemsResponse {
@@ -111,10 +322,24 @@ pub struct Item {
     pub name: String,
     pub id: String,
     pub series_id: Option<String>,
+    /// name of the series an episode belongs to
+    pub series_name: Option<String>,
+    /// when the item was first released or aired
+    pub premiere_date: Option<DateTime<Utc>>,
     pub index_number: Option<u32>,
     pub parent_index_number: Option<u32>,
-    provider_ids: Option<ProviderIds>,
-    user_data: Option<ItemUserData>,
+    pub(super) provider_ids: Option<ProviderIds>,
+    pub(super) user_data: Option<ItemUserData>,
+    /// only included when requested with the `Genres` field
+    #[serde(default)]
+    pub genres: Vec<String>,
+    /// only included when requested with the `CommunityRating` field
+    pub(super) community_rating: Option<f32>,
+    /// when the item was added to the media server, only included when
+    /// requested with the `DateCreated` field
+    pub date_created: Option<DateTime<Utc>>,
+    /// length of the item in ticks (100 ns)
+    pub(super) run_time_ticks: Option<u64>,
 }
 
 impl Item {
@@ -126,36 +351,115 @@ impl Item {
         self.provider_ids.as_ref()?.tvdb.as_deref()
     }
 
+    /// MusicBrainz release group id of an album, which is what Lidarr uses as
+    /// the album's foreign id
+    pub fn musicbrainz_release_group_id(&self) -> Option<&str> {
+        self.provider_ids
+            .as_ref()?
+            .musicbrainz_release_group
+            .as_deref()
+    }
+
     pub fn last_played_date(&self) -> Option<DateTime<Utc>> {
         self.user_data.as_ref()?.last_played_date
     }
 
+    /// the first genre of the item that is one of the given `genres`, compared
+    /// case-insensitively
+    pub fn genre_in(&self, genres: &[String]) -> Option<&str> {
+        self.genres
+            .iter()
+            .find(|genre| genres.iter().any(|g| g.eq_ignore_ascii_case(genre)))
+            .map(String::as_str)
+    }
+
+    /// rating given by the user, or the community rating if the user hasn't
+    /// rated the item
+    pub fn rating(&self) -> Option<f32> {
+        self.user_data
+            .as_ref()
+            .and_then(|ud| ud.rating)
+            .or(self.community_rating)
+    }
+
     pub fn watched(&self) -> bool {
         self.user_data
             .as_ref()
             .map(|ud| ud.played)
             .unwrap_or_default()
     }
+
+    /// how much of the item the user has played so far, in percent. Taken
+    /// from the server if it reports it, computed from the playback position
+    /// otherwise
+    pub fn played_percentage(&self) -> Option<f64> {
+        let user_data = self.user_data.as_ref()?;
+        if let Some(percentage) = user_data.played_percentage {
+            return Some(percentage);
+        }
+        let position = user_data.playback_position_ticks?;
+        let run_time = self.run_time_ticks.filter(|ticks| *ticks > 0)?;
+        Some(position as f64 * 100.0 / run_time as f64)
+    }
+
+    pub fn play_count(&self) -> u32 {
+        self.user_data
+            .as_ref()
+            .map(|ud| ud.play_count)
+            .unwrap_or_default()
+    }
+
+    /// treats the item as played, e.g. once enough of it has been watched
+    /// even though the server hasn't marked it as played
+    pub fn mark_played(&mut self) {
+        if let Some(user_data) = &mut self.user_data {
+            user_data.played = true;
+        }
+    }
+
+    /// treats the item as played on the date it was added, so that the
+    /// retention period counts from it
+    pub fn mark_played_on_date_added(&mut self) {
+        let user_data = self.user_data.get_or_insert(ItemUserData {
+            last_played_date: None,
+            played: false,
+            rating: None,
+            playback_position_ticks: None,
+            played_percentage: None,
+            play_count: 0,
+        });
+        user_data.played = true;
+        user_data.last_played_date = self.date_created;
+    }
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 #[cfg_attr(test, derive(Default))]
 pub struct ProviderIds {
-    tmdb: Option<String>,
-    tvdb: Option<String>,
+    pub(super) tmdb: Option<String>,
+    pub(super) tvdb: Option<String>,
+    #[serde(rename = "MusicBrainzReleaseGroup")]
+    pub(super) musicbrainz_release_group: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "PascalCase")]
-#[cfg_attr(test, derive(Default))]
 pub struct ItemUserData {
-    last_played_date: Option<DateTime<Utc>>,
-    played: bool,
+    pub(super) last_played_date: Option<DateTime<Utc>>,
+    pub(super) played: bool,
+    pub(super) rating: Option<f32>,
+    /// where the playback stopped, in ticks (100 ns)
+    pub(super) playback_position_ticks: Option<u64>,
+    /// only reported for items that are partially played
+    pub(super) played_percentage: Option<f64>,
+    /// how many times the user has played the item
+    #[serde(default)]
+    pub(super) play_count: u32,
 }
 
 #[derive(Deserialize, Debug, Clone)]
-pub struct UserId(String);
+pub struct UserId(pub(super) String);
 
 impl AsRef<str> for UserId {
     fn as_ref(&self) -> &str {
@@ -167,30 +471,34 @@ impl AsRef<str> for UserId {
 #[serde(rename_all = "PascalCase")]
 pub struct User {
     pub id: UserId,
-    name: String,
+    pub(super) name: String,
 }
 
 /// Filter for querying items. Serializes into query parameters. Check [docs]
 /// for more details
 ///
 /// [docs]: https://api.jellyfin.org/#tag/Items/operation/GetItems
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ItemsFilter<'a> {
     #[serde(serialize_with = "to_comma_separated")]
     fields: Option<&'a [&'a str]>,
     #[serde(serialize_with = "to_comma_separated")]
-    include_item_types: Option<&'a [&'a str]>,
+    pub(super) include_item_types: Option<&'a [&'a str]>,
     #[serde(
         serialize_with = "to_comma_separated",
         skip_serializing_if = "Option::is_none"
     )]
-    ids: Option<&'a [&'a str]>,
-    is_favorite: Option<bool>,
-    is_played: Option<bool>,
+    pub(super) ids: Option<&'a [&'a str]>,
+    pub(super) is_favorite: Option<bool>,
+    pub(super) is_played: Option<bool>,
     recursive: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    user_id: Option<&'a str>,
+    pub(super) user_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filters: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<&'a str>,
 }
 
 impl<'a> ItemsFilter<'a> {
@@ -203,6 +511,8 @@ impl<'a> ItemsFilter<'a> {
             recursive: None,
             user_id: None,
             ids: None,
+            filters: None,
+            parent_id: None,
         }
     }
 
@@ -212,6 +522,13 @@ impl<'a> ItemsFilter<'a> {
         self
     }
 
+    /// only items within the given library or folder
+    #[must_use]
+    pub fn parent_id(mut self, parent_id: &'a str) -> Self {
+        self.parent_id = Some(parent_id);
+        self
+    }
+
     #[must_use]
     pub fn played(mut self) -> Self {
         self.is_played = Some(true);
@@ -247,13 +564,36 @@ impl<'a> ItemsFilter<'a> {
         self
     }
 
+    /// turns the filter into one for items whether they are played or not
+    #[must_use]
+    pub fn played_or_not(mut self) -> Self {
+        self.is_played = None;
+        self
+    }
+
     /// a convenience function to filter out watched items
     pub fn watched() -> Self {
         Self::new()
             .recursive()
             .played()
             .favorite(false)
-            .fields(&["ProviderIds"])
+            .fields(ITEM_FIELDS)
+    }
+
+    /// turns the filter into one for items whose playback was started but not
+    /// finished, e.g. to find items that are watched up to a threshold
+    #[must_use]
+    pub fn in_progress(mut self) -> Self {
+        self.is_played = Some(false);
+        self.filters = Some("IsResumable");
+        self
+    }
+
+    /// a convenience function to filter out items that are not fully watched
+    pub fn unwatched() -> Self {
+        let mut filter = Self::new().recursive().favorite(false).fields(ITEM_FIELDS);
+        filter.is_played = Some(false);
+        filter
     }
 }
 
@@ -292,12 +632,239 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_unwatched_items_filter() {
+        let filter = ItemsFilter::unwatched().include_item_types(&["Series"]);
+
+        let expected = r#"{"fields":"ProviderIds,Genres,CommunityRating,DateCreated","includeItemTypes":"Series","isFavorite":false,"isPlayed":false,"recursive":true}"#;
+        let actual = serde_json::to_string(&filter).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_genre_in() {
+        let item = Item {
+            genres: vec!["Drama".to_owned(), "Documentary".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            item.genre_in(&["documentary".to_owned()]),
+            Some("Documentary")
+        );
+        assert_eq!(item.genre_in(&["Kids".to_owned()]), None);
+        assert_eq!(Item::default().genre_in(&["Kids".to_owned()]), None);
+    }
+
+    #[test]
+    fn test_rating() {
+        let mut item = Item {
+            community_rating: Some(7.5),
+            user_data: Some(ItemUserData::default()),
+            ..Default::default()
+        };
+        assert_eq!(item.rating(), Some(7.5));
+
+        item.user_data = Some(ItemUserData {
+            rating: Some(9.0),
+            ..Default::default()
+        });
+        assert_eq!(item.rating(), Some(9.0));
+        assert_eq!(Item::default().rating(), None);
+    }
+
+    #[test]
+    fn test_in_progress_items_filter() {
+        let filter = ItemsFilter::watched()
+            .include_item_types(&["Movie"])
+            .in_progress();
+
+        let expected = r#"{"fields":"ProviderIds,Genres,CommunityRating,DateCreated","includeItemTypes":"Movie","isFavorite":false,"isPlayed":false,"recursive":true,"filters":"IsResumable"}"#;
+        let actual = serde_json::to_string(&filter).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_played_percentage() -> anyhow::Result<()> {
+        let item: Item = serde_json::from_str(
+            r#"{"Name": "a", "Id": "1", "RunTimeTicks": 1000, "UserData": {"Played": false, "PlaybackPositionTicks": 850}}"#,
+        )?;
+        assert_eq!(item.played_percentage(), Some(85.0));
+
+        let item: Item = serde_json::from_str(
+            r#"{"Name": "a", "Id": "1", "UserData": {"Played": false, "PlayedPercentage": 42.5}}"#,
+        )?;
+        assert_eq!(item.played_percentage(), Some(42.5));
+        assert_eq!(Item::default().played_percentage(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_play_count() -> anyhow::Result<()> {
+        let item: Item = serde_json::from_str(
+            r#"{"Name": "a", "Id": "1", "UserData": {"Played": true, "PlayCount": 3}}"#,
+        )?;
+        assert_eq!(item.play_count(), 3);
+
+        let item: Item =
+            serde_json::from_str(r#"{"Name": "a", "Id": "1", "UserData": {"Played": false}}"#)?;
+        assert_eq!(item.play_count(), 0);
+        Ok(())
+    }
+
     #[test]
     fn test_auth_headers() -> anyhow::Result<()> {
-        let headers = auth_headers("abc")?;
+        let headers = auth_headers("abc", Flavor::Jellyfin, false)?;
         let expected = "MediaBrowser Token=abc";
         let actual = headers.get(AUTHORIZATION).unwrap().to_str()?;
         assert_eq!(expected, actual);
         Ok(())
     }
+
+    #[test]
+    fn test_emby_auth_headers() -> anyhow::Result<()> {
+        let headers = auth_headers("abc", Flavor::Emby, true)?;
+        let actual = headers.get("X-Emby-Token").unwrap().to_str()?;
+        assert_eq!("abc", actual);
+        assert!(!headers.contains_key(AUTHORIZATION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_headers_with_basic_auth() -> anyhow::Result<()> {
+        let headers = auth_headers("abc", Flavor::Jellyfin, true)?;
+        let actual = headers.get("X-MediaBrowser-Token").unwrap().to_str()?;
+        assert_eq!("abc", actual);
+        assert!(!headers.contains_key(AUTHORIZATION));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_items_pages() -> anyhow::Result<()> {
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "a", "Id": "1"}], "TotalRecordCount": 2}"#),
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "b", "Id": "2"}], "TotalRecordCount": 2}"#),
+        ])?;
+        let config: JellyfinConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc"
+            "#,
+            server.url
+        ))?;
+        let client = JellyfinClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        let items = client.items(ItemsFilter::new().played()).await?;
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /Items?isPlayed=true&startIndex=0&limit=100 "));
+        assert!(requests[1].starts_with("GET /Items?isPlayed=true&startIndex=1&limit=100 "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_items_in_libraries() -> anyhow::Result<()> {
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200).body(
+                r#"{"Items": [{"Name": "Movies", "Id": "m"}, {"Name": "Temp", "Id": "t"}, {"Name": "Shows", "Id": "s"}], "TotalRecordCount": 3}"#,
+            ),
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "a", "Id": "1"}], "TotalRecordCount": 1}"#),
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "b", "Id": "2"}], "TotalRecordCount": 1}"#),
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "c", "Id": "3"}], "TotalRecordCount": 1}"#),
+            MockResponse::new(200)
+                .body(r#"{"Items": [{"Name": "d", "Id": "4"}], "TotalRecordCount": 1}"#),
+        ])?;
+        let config: JellyfinConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc"
+            libraries = ["temp", "s"]
+            "#,
+            server.url
+        ))?;
+        let client = JellyfinClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        let items = client.items(ItemsFilter::new().played()).await?;
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        // the libraries are resolved only once
+        let items = client.items(ItemsFilter::new().played()).await?;
+        assert_eq!(items.len(), 2);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].contains("includeItemTypes=CollectionFolder"));
+        assert!(requests[1].contains("parentId=t"));
+        assert!(requests[2].contains("parentId=s"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_items_in_protected_collections() -> anyhow::Result<()> {
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200).body(
+                r#"{"Items": [{"Name": "a", "Id": "1"}, {"Name": "b", "Id": "2"}, {"Name": "e1", "Id": "3", "SeriesId": "s"}], "TotalRecordCount": 3}"#,
+            ),
+            MockResponse::new(200).body(
+                r#"{"Items": [{"Name": "Favorites", "Id": "f"}], "TotalRecordCount": 1}"#,
+            ),
+            MockResponse::new(200).body(
+                r#"{"Items": [{"Name": "b", "Id": "2"}, {"Name": "Show", "Id": "s"}], "TotalRecordCount": 2}"#,
+            ),
+        ])?;
+        let config: JellyfinConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc"
+            protected_collections = ["favorites"]
+            "#,
+            server.url
+        ))?;
+        let client = JellyfinClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        let items = client.items(ItemsFilter::new().played()).await?;
+        let names: Vec<_> = items.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["a"]);
+
+        let requests = server.requests();
+        assert!(requests[1].contains("includeItemTypes=BoxSet"));
+        assert!(requests[2].contains("parentId=f"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_library() -> anyhow::Result<()> {
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![MockResponse::new(204)])?;
+        let config: JellyfinConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc"
+            "#,
+            server.url
+        ))?;
+        let client = JellyfinClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        client.refresh_library().await?;
+
+        assert!(server.requests()[0].starts_with("POST /Library/Refresh "));
+        Ok(())
+    }
 }
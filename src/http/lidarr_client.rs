@@ -0,0 +1,260 @@
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{DownloadClientKind, ResponseExt, api_url};
+use crate::config::LidarrConfig;
+use anyhow::Ok;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+/// A client for interacting with Lidarr API.
+/// https://lidarr.audio/docs/api/
+pub struct LidarrClient {
+    client: ServiceClient,
+    base_url: Url,
+    retry: RetryPolicy,
+}
+
+impl LidarrClient {
+    pub fn new(config: &LidarrConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "api/v1/")?;
+
+        let default_headers = auth_headers(&config.api_key)?;
+        let client = http
+            .service(default_headers, config.timeout)
+            .with_basic_auth(
+                config.basic_auth_username.as_ref(),
+                config.basic_auth_password.as_ref(),
+            );
+
+        Ok(Self {
+            client,
+            base_url,
+            retry: http.retry(),
+        })
+    }
+
+    /// Get the albums for a given MusicBrainz release group ID.
+    /// https://lidarr.audio/docs/api/#/Album/get_api_v1_album
+    pub async fn albums_by_foreign_id(&self, foreign_album_id: &str) -> anyhow::Result<Vec<Album>> {
+        let url = self.base_url.join("album")?;
+        let response = self
+            .client
+            .get(url)
+            .query(&[("foreignAlbumId", foreign_album_id)])
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get the history for a list of album IDs. Lidarr only supports filtering
+    /// history by a single album, so one query is made per album.
+    /// https://lidarr.audio/docs/api/#/History/get_api_v1_history
+    pub async fn history_records(
+        &self,
+        album_ids: &HashSet<u64>,
+    ) -> anyhow::Result<HashSet<HistoryRecord>> {
+        let futs = album_ids.iter().map(|id| self.album_history_records(*id));
+        let records = futures::future::try_join_all(futs).await?;
+        Ok(records.into_iter().flatten().collect())
+    }
+
+    async fn album_history_records(&self, album_id: u64) -> anyhow::Result<Vec<HistoryRecord>> {
+        let url = self.base_url.join("history")?;
+        // event type 1 = "grabbed", see docs for more info:
+        // https://github.com/Lidarr/Lidarr/blob/develop/src/NzbDrone.Core/History/EntityHistory.cs
+        let query = [("albumId", album_id), ("eventType", 1), ("pageSize", 100)];
+
+        let mut records = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let history = self
+                .client
+                .get(url.clone())
+                .query(&query)
+                .query(&[("page", page)])
+                .send_with_retry(&self.retry)
+                .await?
+                .handle_error()
+                .await?
+                .json::<History>()
+                .await?;
+
+            if history.records.is_empty() {
+                break;
+            }
+            records.extend(history.records);
+            page += 1;
+        }
+        Ok(records)
+    }
+
+    /// Delete an album by its ID and all associated files.
+    /// https://lidarr.audio/docs/api/#/Album/delete_api_v1_album__id_
+    pub async fn delete_album(&self, album_id: u64) -> anyhow::Result<()> {
+        let url = self.base_url.join("album/")?.join(&album_id.to_string())?;
+        self.client
+            .delete(url)
+            .query(&[("deleteFiles", "true")])
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+
+    /// Get the current time of the Lidarr server as reported by the `Date`
+    /// header of a system status request.
+    /// https://lidarr.audio/docs/api/#/System/get_api_v1_system_status
+    pub async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let url = self.base_url.join("system/status")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(response.server_date())
+    }
+
+    /// Get all tags.
+    pub async fn tags(&self) -> anyhow::Result<Vec<Tag>> {
+        let url = self.base_url.join("tag")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+}
+
+fn auth_headers(api_key: &str) -> Result<HeaderMap, anyhow::Error> {
+    let mut default_headers = HeaderMap::new();
+    let mut header_value = HeaderValue::from_str(api_key)?;
+    header_value.set_sensitive(true);
+    default_headers.insert("x-api-key", header_value);
+    Ok(default_headers)
+}
+
+// Responses
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(test, derive(Default))]
+pub struct Album {
+    pub id: u64,
+    pub title: String,
+    pub artist: Option<Artist>,
+    pub statistics: Option<AlbumStatistics>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(test, derive(Default))]
+pub struct AlbumStatistics {
+    pub size_on_disk: u64,
+}
+
+impl Album {
+    /// size of the album's track files, `0` if unknown
+    pub fn size_on_disk(&self) -> u64 {
+        self.statistics.as_ref().map_or(0, |s| s.size_on_disk)
+    }
+
+    /// tags are assigned to artists in Lidarr, albums inherit them
+    pub fn tags(&self) -> &[u64] {
+        self.artist
+            .as_ref()
+            .map(|a| a.tags.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+impl Debug for Album {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.artist {
+            Some(artist) => write!(f, "{} - {}({})", artist.artist_name, self.title, self.id),
+            None => write!(f, "{}({})", self.title, self.id),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(test, derive(Default))]
+pub struct Artist {
+    pub artist_name: String,
+    #[serde(default)]
+    pub tags: Vec<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct History {
+    pub records: Vec<HistoryRecord>,
+}
+
+#[derive(Deserialize, Hash, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecord {
+    pub album_id: u64,
+    pub date: DateTime<Utc>,
+    pub download_id: Option<String>,
+    pub data: Option<HistoryRecordData>,
+}
+
+impl HistoryRecord {
+    pub fn download_id_per_client(self) -> Option<(DownloadClientKind, String)> {
+        let download_id = self.download_id?;
+        let client = self.data?.download_client?;
+        Some((client, download_id))
+    }
+}
+
+#[derive(Deserialize, Hash, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecordData {
+    pub download_client: Option<DownloadClientKind>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Tag {
+    pub label: String,
+    pub id: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_album() -> anyhow::Result<()> {
+        let json = r#"[{
+            "id": 12,
+            "title": "OK Computer",
+            "foreignAlbumId": "b1392450-e666-3926-a536-22c65f834433",
+            "monitored": true,
+            "artist": {"id": 3, "artistName": "Radiohead", "tags": [1, 4]}
+        }]"#;
+        let albums: Vec<Album> = serde_json::from_str(json)?;
+        assert_eq!(albums[0].id, 12);
+        assert_eq!(albums[0].tags(), &[1, 4]);
+        assert_eq!(format!("{:?}", albums[0]), "Radiohead - OK Computer(12)");
+        Ok(())
+    }
+}
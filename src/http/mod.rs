@@ -1,21 +1,58 @@
+mod client;
 mod jellyfin_client;
+mod lidarr_client;
+#[cfg(test)]
+mod mock_server;
+mod notifiers;
+mod plex_client;
 mod radarr_client;
+mod retry;
+mod sabnzbd_client;
 mod sonarr_client;
+mod tautulli_client;
 mod torrent_clients;
+mod trakt_client;
+mod watch_history;
 
-pub use jellyfin_client::{Item, ItemsFilter, JellyfinClient, UserId};
-use log::trace;
-pub use radarr_client::{Movie, MovieEditor, RadarrClient};
-pub use sonarr_client::{Episode, SeriesInfo, SonarrClient};
+pub use client::HttpClient;
+pub use jellyfin_client::{ITEM_FIELDS, Item, ItemsFilter, JellyfinClient, User, UserId};
+#[cfg(test)]
+pub use lidarr_client::Artist;
+pub use lidarr_client::{Album, LidarrClient};
+use log::{trace, warn};
+pub use notifiers::{DiscordNotifier, Notifier, TelegramNotifier, WebhookNotifier};
+pub use plex_client::PlexClient;
+#[cfg(test)]
+pub use radarr_client::MovieCollection;
+pub use radarr_client::{Collection, Movie, MovieEditor, RadarrClient};
+pub use sabnzbd_client::SabnzbdClient;
+pub use sonarr_client::{Episode, HistoryRecord, SeriesInfo, SonarrClient};
 #[cfg(test)]
 pub use sonarr_client::{Season, SeasonStatistics, SeriesStatistics};
-pub use torrent_clients::{DelugeClient, QbittorrentClient, TorrentClient, TorrentClientKind};
+pub use tautulli_client::TautulliClient;
+pub use torrent_clients::{
+    DelugeClient, DownloadClientKind, QbittorrentClient, RtorrentClient, TorrentClient, TorrentInfo,
+};
+pub use trakt_client::TraktClient;
+pub use watch_history::{MediaServer, WatchHistoryProvider};
 
-use anyhow::bail;
-use reqwest::Response;
+use crate::logging::redact;
+use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
+use reqwest::{Response, Url, header::DATE};
+use serde::Deserialize;
+
+/// a disk as reported by the `diskspace` endpoint of Radarr and Sonarr
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskSpace {
+    pub path: String,
+    pub free_space: u64,
+}
 
 trait ResponseExt {
     async fn handle_error(self) -> anyhow::Result<Response>;
+    fn server_date(&self) -> Option<DateTime<Utc>>;
 }
 
 impl ResponseExt for Response {
@@ -28,7 +65,86 @@ impl ResponseExt for Response {
             let status = self.status();
             let url = url.clone();
             let body = self.text().await?;
-            bail!("request to {url} failed with status {status}: {body}")
+            bail!(redact(&format!(
+                "request to {url} failed with status {status}: {body}"
+            )))
         }
     }
+
+    /// parses the `Date` header of the response, i.e. the server's clock at
+    /// the time the response was generated. A missing or invalid header is
+    /// only logged, as the local clock can be used instead
+    fn server_date(&self) -> Option<DateTime<Utc>> {
+        let url = self.url();
+        let Some(header) = self.headers().get(DATE) else {
+            warn!("no `Date` header in response from {url}, using the local clock");
+            return None;
+        };
+        let date = header
+            .to_str()
+            .ok()
+            .and_then(|header| DateTime::parse_from_rfc2822(header).ok());
+        if date.is_none() {
+            warn!("invalid `Date` header {header:?} from {url}, using the local clock");
+        }
+        date.map(|date| date.with_timezone(&Utc))
+    }
+}
+
+/// max number of ids sent in a single history request. Each id is a separate
+/// query parameter, and some reverse proxies reject too long urls
+const HISTORY_IDS_PER_REQUEST: usize = 50;
+
+/// builds the url of a service API by appending `api_path` to the path of the
+/// configured base url, so that a path prefix of a service behind a reverse
+/// proxy (e.g. `http://localhost/sonarr`) is kept. Relative urls are joined to
+/// the last path segment of a url, hence the trailing slash
+fn api_url(base_url: &str, api_path: &str) -> anyhow::Result<Url> {
+    let mut url =
+        Url::parse(base_url).with_context(|| format!("invalid base url \"{base_url}\""))?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url.join(api_path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_url() -> anyhow::Result<()> {
+        let url = api_url("http://localhost:8989", "api/v3/")?;
+        assert_eq!(url.as_str(), "http://localhost:8989/api/v3/");
+
+        let url = api_url("http://localhost:8989/", "api/v3/")?;
+        assert_eq!(url.as_str(), "http://localhost:8989/api/v3/");
+
+        let url = api_url("https://host/sonarr", "api/v3/")?;
+        assert_eq!(
+            url.join("series")?.as_str(),
+            "https://host/sonarr/api/v3/series"
+        );
+
+        let url = api_url("https://host/sonarr/", "api/v3/")?;
+        assert_eq!(url.as_str(), "https://host/sonarr/api/v3/");
+
+        let url = api_url("http://localhost:8112/deluge", "json")?;
+        assert_eq!(url.as_str(), "http://localhost:8112/deluge/json");
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_url_without_api_path() -> anyhow::Result<()> {
+        let url = api_url("http://localhost:8096/emby", "")?;
+        assert_eq!(
+            url.join("Items")?.as_str(),
+            "http://localhost:8096/emby/Items"
+        );
+
+        let url = api_url("http://localhost:8096", "")?;
+        assert_eq!(url.join("Items")?.as_str(), "http://localhost:8096/Items");
+        Ok(())
+    }
 }
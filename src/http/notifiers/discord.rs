@@ -0,0 +1,185 @@
+use super::Notifier;
+use crate::cleaners::human_size;
+use crate::config::DiscordConfig;
+use crate::http::ResponseExt;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::report::RunReport;
+use async_trait::async_trait;
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+/// Discord limits the value of an embed field to this many characters
+const MAX_FIELD_LENGTH: usize = 1024;
+/// Discord allows at most this many fields in an embed
+const MAX_FIELDS: usize = 25;
+const DELETED_COLOR: u32 = 0x2ecc71;
+const DRY_RUN_COLOR: u32 = 0x95a5a6;
+
+/// Posts a summary of every run to a Discord channel via a webhook.
+/// https://discord.com/developers/docs/resources/webhook#execute-webhook
+pub struct DiscordNotifier {
+    client: ServiceClient,
+    webhook_url: Url,
+}
+
+impl DiscordNotifier {
+    pub fn new(config: &DiscordConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            webhook_url: Url::parse(&config.webhook_url)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    async fn notify(&self, report: &RunReport) -> anyhow::Result<()> {
+        let message = WebhookMessage {
+            embeds: vec![embed(report)],
+        };
+        self.client
+            .post(self.webhook_url.clone())
+            .json(&message)
+            .send()
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+}
+
+/// summarizes the report with a field per cleaner that deleted anything
+fn embed(report: &RunReport) -> Embed {
+    let (verb, color) = if report.dry_run {
+        ("would delete", DRY_RUN_COLOR)
+    } else {
+        ("deleted", DELETED_COLOR)
+    };
+    let deleted: usize = report.cleaners.iter().map(|c| c.deleted.len()).sum();
+    let size: u64 = report.cleaners.iter().map(|c| c.size_on_disk).sum();
+    let title = if report.dry_run {
+        "Sanitarr (dry run)"
+    } else {
+        "Sanitarr"
+    };
+    let fields = report
+        .cleaners
+        .iter()
+        .filter(|c| !c.deleted.is_empty())
+        .take(MAX_FIELDS)
+        .map(|c| EmbedField {
+            name: format!(
+                "{}: {verb} {} ({})",
+                c.cleaner,
+                c.deleted.len(),
+                human_size(c.size_on_disk)
+            ),
+            value: item_list(&c.deleted),
+        })
+        .collect();
+    Embed {
+        title: title.to_owned(),
+        description: format!("{verb} {deleted} item(s), {}", human_size(size)),
+        color,
+        fields,
+    }
+}
+
+/// lists the items, one per line, leaving out those that don't fit into a
+/// field
+fn item_list(items: &[String]) -> String {
+    let mut list = String::new();
+    for (i, item) in items.iter().enumerate() {
+        let line = format!("- {item}\n");
+        let more = format!("...and {} more", items.len() - i);
+        if list.len() + line.len() + more.len() > MAX_FIELD_LENGTH {
+            list.push_str(&more);
+            break;
+        }
+        list.push_str(&line);
+    }
+    list.trim_end().to_owned()
+}
+
+#[derive(Serialize)]
+struct WebhookMessage {
+    embeds: Vec<Embed>,
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    description: String,
+    color: u32,
+    fields: Vec<EmbedField>,
+}
+
+#[derive(Serialize)]
+struct EmbedField {
+    name: String,
+    value: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+    use crate::report::CleanerReport;
+
+    fn report(dry_run: bool) -> RunReport {
+        RunReport {
+            dry_run,
+            cleaners: vec![
+                CleanerReport {
+                    deleted: vec!["Movie(1)".to_owned(), "Other(2)".to_owned()],
+                    size_on_disk: 2048,
+                    ..CleanerReport::new("movies")
+                },
+                CleanerReport::new("series"),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_embed() {
+        let embed = embed(&report(true));
+        assert_eq!(embed.title, "Sanitarr (dry run)");
+        assert_eq!(embed.description, "would delete 2 item(s), 2.0 KiB");
+        assert_eq!(embed.fields.len(), 1);
+        assert_eq!(embed.fields[0].name, "movies: would delete 2 (2.0 KiB)");
+        assert_eq!(embed.fields[0].value, "- Movie(1)\n- Other(2)");
+    }
+
+    #[test]
+    fn test_item_list_truncated() {
+        let items: Vec<String> = (0..100).map(|i| format!("Movie {i:03}")).collect();
+        let list = item_list(&items);
+        assert!(list.len() <= MAX_FIELD_LENGTH);
+        assert!(list.starts_with("- Movie 000\n"));
+        assert!(list.ends_with("more"));
+    }
+
+    #[tokio::test]
+    async fn test_notify() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(204)])?;
+        let config = DiscordConfig {
+            webhook_url: format!("{}/api/webhooks/123/token", server.url),
+            timeout: None,
+        };
+        let notifier = DiscordNotifier::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        notifier.notify(&report(false)).await?;
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /api/webhooks/123/token "));
+        assert!(requests[0].contains(r#""title":"Sanitarr""#));
+        assert!(requests[0].contains(r#""name":"movies: deleted 2 (2.0 KiB)""#));
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+mod discord;
+mod telegram;
+mod webhook;
+
+use crate::report::RunReport;
+use async_trait::async_trait;
+
+pub use discord::DiscordNotifier;
+pub use telegram::TelegramNotifier;
+pub use webhook::WebhookNotifier;
+
+/// a service that is notified about the results of every run
+#[async_trait]
+pub trait Notifier {
+    /// name of the service as shown in logs
+    fn name(&self) -> &'static str;
+    async fn notify(&self, report: &RunReport) -> anyhow::Result<()>;
+}
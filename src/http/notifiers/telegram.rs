@@ -0,0 +1,168 @@
+use super::Notifier;
+use crate::cleaners::human_size;
+use crate::config::TelegramConfig;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::http::{ResponseExt, api_url};
+use crate::report::RunReport;
+use async_trait::async_trait;
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+/// Telegram limits the text of a message to this many characters
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Sends a summary of every run to a Telegram chat via the Bot API.
+/// https://core.telegram.org/bots/api#sendmessage
+pub struct TelegramNotifier {
+    client: ServiceClient,
+    url: Url,
+    chat_id: String,
+}
+
+impl TelegramNotifier {
+    pub fn new(config: &TelegramConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        // the token contains a colon, which would otherwise be taken for a
+        // URL scheme
+        let url = api_url(
+            &config.base_url,
+            &format!("./bot{}/sendMessage", config.bot_token),
+        )?;
+        Ok(Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            url,
+            chat_id: config.chat_id.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &'static str {
+        "Telegram"
+    }
+
+    async fn notify(&self, report: &RunReport) -> anyhow::Result<()> {
+        let message = SendMessage {
+            chat_id: &self.chat_id,
+            text: message(report),
+        };
+        self.client
+            .post(self.url.clone())
+            .json(&message)
+            .send()
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+}
+
+/// summarizes the report, listing the items deleted by each cleaner. Items
+/// that don't fit into a message are left out and counted instead
+fn message(report: &RunReport) -> String {
+    let verb = if report.dry_run {
+        "would delete"
+    } else {
+        "deleted"
+    };
+    let deleted: usize = report.cleaners.iter().map(|c| c.deleted.len()).sum();
+    let size: u64 = report.cleaners.iter().map(|c| c.size_on_disk).sum();
+    let title = if report.dry_run {
+        "Sanitarr (dry run)"
+    } else {
+        "Sanitarr"
+    };
+
+    let mut lines = vec![format!(
+        "{title}: {verb} {deleted} item(s), {}",
+        human_size(size)
+    )];
+    for cleaner in report.cleaners.iter().filter(|c| !c.deleted.is_empty()) {
+        lines.push(String::new());
+        lines.push(format!(
+            "{}: {verb} {} ({})",
+            cleaner.cleaner,
+            cleaner.deleted.len(),
+            human_size(cleaner.size_on_disk)
+        ));
+        lines.extend(cleaner.deleted.iter().map(|item| format!("- {item}")));
+    }
+
+    let mut text = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let left = lines[i..].iter().filter(|l| l.starts_with("- ")).count();
+        let more = format!("(+{left} more)");
+        if text.chars().count() + line.chars().count() + more.len() + 2 > MAX_MESSAGE_LENGTH {
+            text.push_str(&more);
+            break;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.trim_end().to_owned()
+}
+
+#[derive(Serialize)]
+struct SendMessage<'a> {
+    chat_id: &'a str,
+    text: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+    use crate::report::CleanerReport;
+
+    fn report(deleted: Vec<String>) -> RunReport {
+        RunReport {
+            dry_run: false,
+            cleaners: vec![CleanerReport {
+                deleted,
+                size_on_disk: 1024,
+                ..CleanerReport::new("movies")
+            }],
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let text = message(&report(vec!["Movie(1)".to_owned()]));
+        assert_eq!(
+            text,
+            "Sanitarr: deleted 1 item(s), 1.0 KiB\n\nmovies: deleted 1 (1.0 KiB)\n- Movie(1)"
+        );
+    }
+
+    #[test]
+    fn test_message_truncated() {
+        let items = (0..1000).map(|i| format!("Movie({i})")).collect();
+        let text = message(&report(items));
+        assert!(text.chars().count() <= MAX_MESSAGE_LENGTH);
+        assert!(text.contains("- Movie(0)\n"));
+        assert!(text.ends_with("more)"));
+    }
+
+    #[tokio::test]
+    async fn test_notify() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200).body(r#"{"ok": true}"#)])?;
+        let config = TelegramConfig {
+            base_url: server.url.clone(),
+            bot_token: "123:abc".to_owned(),
+            chat_id: "-100".to_owned(),
+            timeout: None,
+        };
+        let notifier = TelegramNotifier::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        notifier
+            .notify(&report(vec!["Movie(1)".to_owned()]))
+            .await?;
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /bot123:abc/sendMessage "));
+        assert!(requests[0].contains(r#""chat_id":"-100""#));
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use super::Notifier;
+use crate::config::{WebhookConfig, WebhookTrigger};
+use crate::http::ResponseExt;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::report::RunReport;
+use anyhow::Context;
+use async_trait::async_trait;
+use log::debug;
+use reqwest::Url;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Posts the report of every run as JSON to an arbitrary URL, the same one
+/// `--report-format json` prints
+pub struct WebhookNotifier {
+    client: ServiceClient,
+    url: Url,
+    trigger: WebhookTrigger,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &WebhookConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::try_from(name)
+                .with_context(|| format!("invalid webhook header name \"{name}\""))?;
+            let mut value = HeaderValue::try_from(value)
+                .with_context(|| format!("invalid value of webhook header \"{name}\""))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        Ok(Self {
+            client: http.service(headers, config.timeout),
+            url: Url::parse(&config.url)?,
+            trigger: config.send_on,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn notify(&self, report: &RunReport) -> anyhow::Result<()> {
+        let deleted = report.cleaners.iter().any(|c| !c.deleted.is_empty());
+        if self.trigger == WebhookTrigger::Deleted && !deleted {
+            debug!("nothing was deleted, not calling the webhook");
+            return Ok(());
+        }
+        self.client
+            .post(self.url.clone())
+            .json(report)
+            .send()
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+    use crate::report::CleanerReport;
+    use std::collections::HashMap;
+
+    fn notifier(server: &MockServer, send_on: WebhookTrigger) -> anyhow::Result<WebhookNotifier> {
+        let config = WebhookConfig {
+            url: format!("{}/hooks/sanitarr", server.url),
+            headers: HashMap::from([("X-Token".to_owned(), "secret".to_owned())]),
+            send_on,
+            timeout: None,
+        };
+        WebhookNotifier::new(&config, &HttpClient::new(&HttpConfig::default())?)
+    }
+
+    #[tokio::test]
+    async fn test_notify() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200)])?;
+        let report = RunReport {
+            dry_run: true,
+            cleaners: vec![CleanerReport::new("movies")],
+        };
+
+        notifier(&server, WebhookTrigger::Always)?
+            .notify(&report)
+            .await?;
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /hooks/sanitarr "));
+        assert!(requests[0].contains("x-token: secret"));
+        assert!(requests[0].contains(r#""dry_run":true"#));
+        assert!(requests[0].contains(r#""cleaner":"movies""#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notify_only_when_deleted() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200)])?;
+        let notifier = notifier(&server, WebhookTrigger::Deleted)?;
+        let mut report = RunReport {
+            dry_run: false,
+            cleaners: vec![CleanerReport::new("movies")],
+        };
+
+        notifier.notify(&report).await?;
+        assert!(server.requests().is_empty());
+
+        report.cleaners[0].deleted.push("Movie(1)".to_owned());
+        notifier.notify(&report).await?;
+        assert_eq!(server.requests().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,430 @@
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{
+    Item, ItemsFilter, ResponseExt, User, UserId, WatchHistoryProvider, api_url,
+    jellyfin_client::{ItemUserData, ProviderIds},
+};
+use crate::config::PlexConfig;
+use anyhow::Ok;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use reqwest::Url;
+use reqwest::header::{ACCEPT, HeaderMap, HeaderValue};
+use serde::Deserialize;
+
+/// A client for Plex Media Server API. Note that Plex tracks watch status per
+/// token, so the configured token should belong to the user whose watch
+/// history drives the cleanup.
+/// https://plexapi.dev/api-reference
+#[derive(Clone)]
+pub struct PlexClient {
+    client: ServiceClient,
+    base_url: Url,
+    retry: RetryPolicy,
+}
+
+impl PlexClient {
+    pub fn new(config: &PlexConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let PlexConfig {
+            base_url,
+            token,
+            timeout,
+            basic_auth_username,
+            basic_auth_password,
+        } = config;
+        let base_url = api_url(base_url, "")?;
+        let default_headers = default_headers(token)?;
+        let client = http
+            .service(default_headers, *timeout)
+            .with_basic_auth(basic_auth_username.as_ref(), basic_auth_password.as_ref());
+        Ok(Self {
+            client,
+            base_url,
+            retry: http.retry(),
+        })
+    }
+
+    /// Get all library sections.
+    /// https://plexapi.dev/api-reference/library/get-all-libraries
+    async fn sections(&self) -> anyhow::Result<Vec<Directory>> {
+        let url = self.base_url.join("library/sections")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json::<Response>()
+            .await?;
+        Ok(response.media_container.directory)
+    }
+
+    /// Get all items of the given type in a library section
+    /// https://plexapi.dev/api-reference/library/get-library-items
+    async fn section_items(&self, section: &str, kind: u8) -> anyhow::Result<Vec<Metadata>> {
+        let url = self
+            .base_url
+            .join("library/sections/")?
+            .join(&format!("{section}/all"))?;
+
+        // pagination
+        let mut items = Vec::new();
+        let limit = 100;
+
+        loop {
+            let response = self
+                .client
+                .get(url.clone())
+                .query(&[("type", kind), ("includeGuids", 1)])
+                .query(&[
+                    ("X-Plex-Container-Start", items.len()),
+                    ("X-Plex-Container-Size", limit),
+                ])
+                .send_with_retry(&self.retry)
+                .await?
+                .handle_error()
+                .await?
+                .json::<Response>()
+                .await?;
+
+            let container = response.media_container;
+            if container.metadata.is_empty() {
+                break;
+            }
+            items.extend(container.metadata);
+
+            if items.len() >= container.total_size.unwrap_or_default() {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Get items by their rating keys
+    /// https://plexapi.dev/api-reference/library/get-media-metadata
+    async fn metadata(&self, ids: &[&str]) -> anyhow::Result<Vec<Metadata>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let url = self
+            .base_url
+            .join("library/metadata/")?
+            .join(&ids.join(","))?;
+        let response = self
+            .client
+            .get(url)
+            .query(&[("includeGuids", 1)])
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json::<Response>()
+            .await?;
+        Ok(response.media_container.metadata)
+    }
+}
+
+#[async_trait]
+impl WatchHistoryProvider for PlexClient {
+    fn name(&self) -> &'static str {
+        "Plex"
+    }
+
+    /// Get all items that match the given query filter. Only item types, ids
+    /// and played status of the filter are taken into account as Plex has no
+    /// notion of favorites
+    async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
+        let metadata = match items_filter.ids {
+            Some(ids) => self.metadata(ids).await?,
+            None => {
+                let sections = self.sections().await?;
+                let kinds = items_filter.include_item_types.unwrap_or_default();
+                let mut metadata = Vec::new();
+                for (section_type, kind) in kinds.iter().filter_map(|k| plex_type(k)) {
+                    for section in sections.iter().filter(|s| s.kind == section_type) {
+                        metadata.extend(self.section_items(&section.key, kind).await?);
+                    }
+                }
+                metadata
+            }
+        };
+
+        let items = metadata
+            .into_iter()
+            .map(Item::from)
+            .filter(|item| {
+                items_filter
+                    .is_played
+                    .is_none_or(|played| item.watched() == played)
+            })
+            .collect();
+        Ok(items)
+    }
+
+    /// Get a user by it's username among the accounts of the server.
+    /// https://plexapi.dev/api-reference/server/get-server-accounts
+    async fn user(&self, user_name: &str) -> anyhow::Result<User> {
+        let url = self.base_url.join("accounts")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json::<Response>()
+            .await?;
+
+        response
+            .media_container
+            .account
+            .into_iter()
+            .find(|account| account.name == user_name)
+            .map(|account| User {
+                id: UserId(account.id.to_string()),
+                name: account.name,
+            })
+            .ok_or_else(|| anyhow::anyhow!("User {user_name} not found"))
+    }
+
+    /// Get the current time of the Plex server as reported by the `Date`
+    /// header of a server identity request.
+    /// https://plexapi.dev/api-reference/server/get-server-identity
+    async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let url = self.base_url.join("identity")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(response.server_date())
+    }
+}
+
+fn default_headers(token: &str) -> Result<HeaderMap, anyhow::Error> {
+    let mut headers = HeaderMap::new();
+    let mut header_value = HeaderValue::from_str(token)?;
+    header_value.set_sensitive(true);
+    headers.insert("X-Plex-Token", header_value);
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    Ok(headers)
+}
+
+/// maps a Jellyfin item type to a Plex library section type and Plex metadata
+/// type
+fn plex_type(item_type: &str) -> Option<(&'static str, u8)> {
+    match item_type {
+        "Movie" | "Video" => Some(("movie", 1)),
+        "Series" => Some(("show", 2)),
+        "Episode" => Some(("show", 4)),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Response {
+    media_container: MediaContainer,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct MediaContainer {
+    total_size: Option<usize>,
+    #[serde(rename = "Directory")]
+    directory: Vec<Directory>,
+    #[serde(rename = "Metadata")]
+    metadata: Vec<Metadata>,
+    #[serde(rename = "Account")]
+    account: Vec<Account>,
+}
+
+#[derive(Deserialize)]
+struct Directory {
+    key: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    rating_key: String,
+    title: String,
+    grandparent_rating_key: Option<String>,
+    grandparent_title: Option<String>,
+    parent_index: Option<u32>,
+    index: Option<u32>,
+    view_count: Option<u32>,
+    last_viewed_at: Option<i64>,
+    user_rating: Option<f32>,
+    added_at: Option<i64>,
+    originally_available_at: Option<NaiveDate>,
+    audience_rating: Option<f32>,
+    leaf_count: Option<u32>,
+    viewed_leaf_count: Option<u32>,
+    /// playback position of a partially viewed item, in milliseconds
+    view_offset: Option<u64>,
+    /// in milliseconds
+    duration: Option<u64>,
+    #[serde(rename = "Guid", default)]
+    guids: Vec<Guid>,
+    #[serde(rename = "Genre", default)]
+    genres: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Guid {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    tag: String,
+}
+
+impl Metadata {
+    fn guid(&self, scheme: &str) -> Option<String> {
+        let prefix = format!("{scheme}://");
+        self.guids
+            .iter()
+            .find_map(|g| g.id.strip_prefix(&prefix).map(ToOwned::to_owned))
+    }
+
+    /// shows are considered played when all their episodes are, other items
+    /// when viewed at least once
+    fn played(&self) -> bool {
+        match (self.leaf_count, self.viewed_leaf_count) {
+            (Some(total), Some(viewed)) => total > 0 && viewed >= total,
+            _ => self.view_count.unwrap_or_default() > 0,
+        }
+    }
+}
+
+impl From<Metadata> for Item {
+    fn from(metadata: Metadata) -> Self {
+        let provider_ids = ProviderIds {
+            tmdb: metadata.guid("tmdb"),
+            tvdb: metadata.guid("tvdb"),
+            musicbrainz_release_group: None,
+        };
+        let user_data = ItemUserData {
+            last_played_date: metadata
+                .last_viewed_at
+                .and_then(|ts| DateTime::from_timestamp(ts, 0)),
+            played: metadata.played(),
+            rating: metadata.user_rating,
+            playback_position_ticks: metadata.view_offset.map(millis_to_ticks),
+            played_percentage: None,
+            play_count: metadata.view_count.unwrap_or_default(),
+        };
+        Item {
+            name: metadata.title,
+            id: metadata.rating_key,
+            series_id: metadata.grandparent_rating_key,
+            series_name: metadata.grandparent_title,
+            index_number: metadata.index,
+            parent_index_number: metadata.parent_index,
+            provider_ids: Some(provider_ids),
+            user_data: Some(user_data),
+            genres: metadata.genres.into_iter().map(|g| g.tag).collect(),
+            community_rating: metadata.audience_rating,
+            date_created: metadata
+                .added_at
+                .and_then(|ts| DateTime::from_timestamp(ts, 0)),
+            premiere_date: metadata
+                .originally_available_at
+                .map(|date| date.and_time(NaiveTime::MIN).and_utc()),
+            run_time_ticks: metadata.duration.map(millis_to_ticks),
+        }
+    }
+}
+
+/// Plex reports durations in milliseconds, Jellyfin in ticks of 100 ns
+fn millis_to_ticks(millis: u64) -> u64 {
+    millis * 10_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_episode_into_item() -> anyhow::Result<()> {
+        let json = r#"{"MediaContainer": {"size": 1, "totalSize": 1, "Metadata": [{
+            "ratingKey": "42",
+            "title": "Pilot",
+            "type": "episode",
+            "grandparentRatingKey": "40",
+            "grandparentTitle": "Lost",
+            "parentIndex": 1,
+            "index": 2,
+            "viewCount": 1,
+            "lastViewedAt": 1700000000,
+            "audienceRating": 8.5,
+            "addedAt": 1690000000,
+            "originallyAvailableAt": "2004-09-22",
+            "Guid": [{"id": "imdb://tt123"}, {"id": "tmdb://603"}, {"id": "tvdb://81189"}],
+            "Genre": [{"tag": "Drama"}]
+        }]}}"#;
+        let response: Response = serde_json::from_str(json)?;
+        let metadata = response.media_container.metadata.into_iter().next();
+        let item = Item::from(metadata.unwrap());
+
+        assert_eq!(item.name, "Pilot");
+        assert_eq!(item.id, "42");
+        assert_eq!(item.series_id.as_deref(), Some("40"));
+        assert_eq!(item.series_name.as_deref(), Some("Lost"));
+        assert_eq!(item.parent_index_number, Some(1));
+        assert_eq!(item.index_number, Some(2));
+        assert_eq!(item.tmdb_id(), Some("603"));
+        assert_eq!(item.tvdb_id(), Some("81189"));
+        assert_eq!(item.genres, vec!["Drama"]);
+        assert_eq!(item.rating(), Some(8.5));
+        assert_eq!(item.date_created, DateTime::from_timestamp(1690000000, 0));
+        assert_eq!(
+            item.premiere_date.map(|date| date.to_rfc3339()).as_deref(),
+            Some("2004-09-22T00:00:00+00:00")
+        );
+        assert!(item.watched());
+        assert_eq!(
+            item.last_played_date(),
+            DateTime::from_timestamp(1700000000, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_played_only_when_all_episodes_viewed() {
+        let partially_watched = Metadata {
+            leaf_count: Some(10),
+            viewed_leaf_count: Some(3),
+            ..Default::default()
+        };
+        assert!(!partially_watched.played());
+
+        let fully_watched = Metadata {
+            leaf_count: Some(10),
+            viewed_leaf_count: Some(10),
+            ..Default::default()
+        };
+        assert!(fully_watched.played());
+    }
+
+    #[test]
+    fn test_plex_type() {
+        assert_eq!(plex_type("Movie"), Some(("movie", 1)));
+        assert_eq!(plex_type("Episode"), Some(("show", 4)));
+        assert_eq!(plex_type("MusicAlbum"), None);
+    }
+}
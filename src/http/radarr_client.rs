@@ -1,7 +1,11 @@
-use super::{ResponseExt, TorrentClientKind};
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{DiskSpace, DownloadClientKind, HISTORY_IDS_PER_REQUEST, ResponseExt, api_url};
+use crate::config::RadarrConfig;
 use anyhow::Ok;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
 use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::{Client, ClientBuilder, Url};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fmt::Debug;
@@ -9,21 +13,28 @@ use std::fmt::Debug;
 /// A client for interacting with Radarr API.
 /// https://radarr.video/docs/api/
 pub struct RadarrClient {
-    client: Client,
+    client: ServiceClient,
     base_url: Url,
+    retry: RetryPolicy,
 }
 
 impl RadarrClient {
-    pub fn new(base_url: &str, api_key: &str) -> anyhow::Result<Self> {
-        let mut base_url = Url::parse(base_url)?;
-        base_url.set_path("/api/v3/");
+    pub fn new(config: &RadarrConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "api/v3/")?;
 
-        let default_headers = auth_headers(api_key)?;
-        let client = ClientBuilder::new()
-            .default_headers(default_headers)
-            .build()?;
+        let default_headers = auth_headers(&config.api_key)?;
+        let client = http
+            .service(default_headers, config.timeout)
+            .with_basic_auth(
+                config.basic_auth_username.as_ref(),
+                config.basic_auth_password.as_ref(),
+            );
 
-        Ok(Self { client, base_url })
+        Ok(Self {
+            client,
+            base_url,
+            retry: http.retry(),
+        })
     }
 
     /// Get the movie IDs for a given TMDB ID.
@@ -34,7 +45,40 @@ impl RadarrClient {
             .client
             .get(url)
             .query(&[("tmdbId", tmdb_id)])
-            .send()
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get all movies in the library.
+    /// https://radarr.video/docs/api/#/Movie/get_api_v3_movie
+    pub async fn movies(&self) -> anyhow::Result<Vec<Movie>> {
+        let url = self.base_url.join("movie")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get all collections, i.e. franchises the movies of the library belong
+    /// to.
+    /// https://radarr.video/docs/api/#/Collection/get_api_v3_collection
+    pub async fn collections(&self) -> anyhow::Result<Vec<Collection>> {
+        let url = self.base_url.join("collection")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -50,33 +94,36 @@ impl RadarrClient {
         movie_ids: &HashSet<u64>,
     ) -> anyhow::Result<HashSet<HistoryRecord>> {
         let url = self.base_url.join("history")?;
-        let mut query: Vec<_> = movie_ids.iter().map(|id| ("movieIds", *id)).collect();
-        // event type 1 = "grabbed", see docs for more info:
-        // https://github.com/Radarr/Radarr/blob/develop/src/NzbDrone.Core/History/History.cs
-        query.push(("eventType", 1));
-        query.push(("pageSize", 100));
-
+        let movie_ids: Vec<_> = movie_ids.iter().copied().collect();
         let mut records = HashSet::new();
-        let mut page = 1;
 
-        loop {
-            let history = self
-                .client
-                .get(url.clone())
-                .query(&query)
-                .query(&[("page", page)])
-                .send()
-                .await?
-                .handle_error()
-                .await?
-                .json::<History>()
-                .await?;
+        for ids in movie_ids.chunks(HISTORY_IDS_PER_REQUEST) {
+            let mut query: Vec<_> = ids.iter().map(|id| ("movieIds", *id)).collect();
+            // event type 1 = "grabbed", see docs for more info:
+            // https://github.com/Radarr/Radarr/blob/develop/src/NzbDrone.Core/History/History.cs
+            query.push(("eventType", 1));
+            query.push(("pageSize", 100));
 
-            if history.records.is_empty() {
-                break;
+            let mut page = 1;
+            loop {
+                let history = self
+                    .client
+                    .get(url.clone())
+                    .query(&query)
+                    .query(&[("page", page)])
+                    .send_with_retry(&self.retry)
+                    .await?
+                    .handle_error()
+                    .await?
+                    .json::<History>()
+                    .await?;
+
+                if history.records.is_empty() {
+                    break;
+                }
+                records.extend(history.records);
+                page += 1;
             }
-            records.extend(history.records);
-            page += 1;
         }
         Ok(records)
     }
@@ -92,7 +139,7 @@ impl RadarrClient {
             .client
             .put(url.clone())
             .json(edit_request)
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -101,27 +148,112 @@ impl RadarrClient {
         Ok(res)
     }
 
-    /// Delete a movie by its ID and all associated files.
+    /// Delete a movie by its ID, along with all associated files if
+    /// `delete_files` is set. With `add_to_exclusion` the movie is also
+    /// excluded from import lists.
     /// https://radarr.video/docs/api/#/Movie/delete_api_v3_movie__id_
-    pub async fn delete_movie(&self, movie_id: u64) -> anyhow::Result<()> {
+    pub async fn delete_movie(
+        &self,
+        movie_id: u64,
+        delete_files: bool,
+        add_to_exclusion: bool,
+    ) -> anyhow::Result<()> {
         let url = self.base_url.join("movie/")?.join(&movie_id.to_string())?;
         self.client
             .delete(url)
-            .query(&[("deleteFiles", "true")])
-            .send()
+            .query(&[
+                ("deleteFiles", delete_files),
+                ("addImportExclusion", add_to_exclusion),
+            ])
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?;
         Ok(())
     }
 
+    /// Get the current time of the Radarr server as reported by the `Date`
+    /// header of a system status request.
+    /// https://radarr.video/docs/api/#/System/get_api_v3_system_status
+    pub async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let url = self.base_url.join("system/status")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(response.server_date())
+    }
+
+    /// Get all quality profiles.
+    /// https://radarr.video/docs/api/#/QualityProfile/get_api_v3_qualityprofile
+    pub async fn quality_profiles(&self) -> anyhow::Result<Vec<QualityProfile>> {
+        let url = self.base_url.join("qualityprofile")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get all items in the download queue, including stalled and failed
+    /// downloads.
+    /// https://radarr.video/docs/api/#/Queue/get_api_v3_queue
+    pub async fn queue(&self) -> anyhow::Result<Vec<QueueRecord>> {
+        let url = self.base_url.join("queue")?;
+        let mut records = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let queue = self
+                .client
+                .get(url.clone())
+                .query(&[("pageSize", 100), ("page", page)])
+                .send_with_retry(&self.retry)
+                .await?
+                .handle_error()
+                .await?
+                .json::<Queue>()
+                .await?;
+
+            if queue.records.is_empty() {
+                break;
+            }
+            records.extend(queue.records);
+            page += 1;
+        }
+        Ok(records)
+    }
+
+    /// Get the free space of the disks known to the service
+    pub async fn disk_space(&self) -> anyhow::Result<Vec<DiskSpace>> {
+        let url = self.base_url.join("diskspace")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
     /// Get all tags.
     pub async fn tags(&self) -> anyhow::Result<Vec<Tag>> {
         let url = self.base_url.join("tag")?;
         let response = self
             .client
             .get(url)
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -143,11 +275,40 @@ fn auth_headers(api_key: &str) -> Result<HeaderMap, anyhow::Error> {
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(test, derive(Default))]
 pub struct Movie {
     pub id: u64,
     pub monitored: bool,
     pub tags: Option<Vec<u64>>,
     pub title: String,
+    pub quality_profile_id: u64,
+    pub added: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub size_on_disk: u64,
+    /// folder of the movie on disk
+    pub path: Option<String>,
+    #[serde(default)]
+    pub has_file: bool,
+    /// the collection (franchise) the movie belongs to, if any
+    pub collection: Option<MovieCollection>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MovieCollection {
+    pub title: String,
+    pub tmdb_id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    pub tmdb_id: u64,
+    /// whether Radarr adds the missing movies of the collection
+    pub monitored: bool,
+    /// number of movies of the collection that are not in the library
+    #[serde(default)]
+    pub missing_movies: u32,
 }
 
 impl Debug for Movie {
@@ -165,12 +326,14 @@ pub struct History {
 #[derive(Deserialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct HistoryRecord {
+    pub movie_id: u64,
+    pub date: DateTime<Utc>,
     pub download_id: Option<String>,
     pub data: Option<HistoryRecordData>,
 }
 
 impl HistoryRecord {
-    pub fn download_id_per_client(self) -> Option<(TorrentClientKind, String)> {
+    pub fn download_id_per_client(self) -> Option<(DownloadClientKind, String)> {
         let download_id = self.download_id?;
         let client = self.data?.download_client?;
         Some((client, download_id))
@@ -180,7 +343,20 @@ impl HistoryRecord {
 #[derive(Deserialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct HistoryRecordData {
-    pub download_client: Option<TorrentClientKind>,
+    pub download_client: Option<DownloadClientKind>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Queue {
+    pub records: Vec<QueueRecord>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueRecord {
+    /// not set for downloads Radarr couldn't match to a movie
+    pub movie_id: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -190,6 +366,13 @@ pub struct Tag {
     pub id: u64,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityProfile {
+    pub name: String,
+    pub id: u64,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct MovieEditorResponse {
@@ -235,4 +418,123 @@ mod tests {
         assert_eq!(headers.len(), 1);
         assert_eq!(headers.get("x-api-key").unwrap(), "abc-key");
     }
+
+    #[tokio::test]
+    async fn test_queue_pages() -> anyhow::Result<()> {
+        use super::*;
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200).body(r#"{"records": [{"movieId": 1}, {"title": "unknown"}]}"#),
+            MockResponse::new(200).body(r#"{"records": []}"#),
+        ])?;
+        let config: RadarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc-key"
+            "#,
+            server.url
+        ))?;
+        let client = RadarrClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        let ids: Vec<_> = client
+            .queue()
+            .await?
+            .into_iter()
+            .map(|r| r.movie_id)
+            .collect();
+        assert_eq!(ids, vec![Some(1), None]);
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /api/v3/queue?pageSize=100&page=1 "));
+        assert!(requests[1].starts_with("GET /api/v3/queue?pageSize=100&page=2 "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_movie_adds_exclusion() -> anyhow::Result<()> {
+        use super::*;
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![MockResponse::new(200)])?;
+        let config: RadarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc-key"
+            "#,
+            server.url
+        ))?;
+        let client = RadarrClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        client.delete_movie(42, true, true).await?;
+
+        let requests = server.requests();
+        assert!(
+            requests[0]
+                .starts_with("DELETE /api/v3/movie/42?deleteFiles=true&addImportExclusion=true ")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_space() -> anyhow::Result<()> {
+        use super::*;
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server =
+            MockServer::start(vec![MockResponse::new(200).body(
+                r#"[{"path": "/", "label": "", "freeSpace": 1024, "totalSpace": 4096}]"#,
+            )])?;
+        let config: RadarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc-key"
+            "#,
+            server.url
+        ))?;
+        let client = RadarrClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        let disks = client.disk_space().await?;
+        assert_eq!(disks[0].path, "/");
+        assert_eq!(disks[0].free_space, 1024);
+        assert!(server.requests()[0].starts_with("GET /api/v3/diskspace "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_time() -> anyhow::Result<()> {
+        use super::*;
+        use crate::config::HttpConfig;
+        use crate::http::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200)
+                .header("Date", "Tue, 14 Nov 2023 22:13:20 GMT")
+                .body("{}"),
+            MockResponse::new(200)
+                .header("Date", "yesterday")
+                .body("{}"),
+            MockResponse::new(200).body("{}"),
+        ])?;
+        let config: RadarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc-key"
+            "#,
+            server.url
+        ))?;
+        let client = RadarrClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        assert_eq!(
+            client.server_time().await?,
+            DateTime::from_timestamp(1700000000, 0)
+        );
+        // the local clock is used instead
+        assert_eq!(client.server_time().await?, None);
+        assert_eq!(client.server_time().await?, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,184 @@
+use crate::config::HttpConfig;
+use log::warn;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+
+/// Defines how requests are retried on transient failures. Safe requests
+/// (e.g. `GET`) are retried on network errors and 5xx responses, others (e.g.
+/// `DELETE`) only when the connection couldn't be established, so that they
+/// are never executed twice. A 429 response is retried for any request as the
+/// server didn't process it
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            max_attempts: config.retry_attempts.max(1),
+            base_delay: config.retry_delay,
+        }
+    }
+
+    /// exponential backoff delay before the next attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+    }
+
+    /// delay before retrying a request that received the given response,
+    /// `None` if the response shouldn't be retried
+    fn response_delay(&self, response: &Response, safe: bool, attempt: u32) -> Option<Duration> {
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+            return Some(retry_after.unwrap_or_else(|| self.backoff(attempt)));
+        }
+        (safe && status.is_server_error()).then(|| self.backoff(attempt))
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date, only the
+/// former is supported
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// checks whether a request failed without reaching the server or, for safe
+/// requests, without getting a response
+fn is_retryable_error(error: &reqwest::Error, safe: bool) -> bool {
+    error.is_connect() || (safe && (error.is_timeout() || error.is_request()))
+}
+
+pub trait RequestBuilderExt {
+    /// sends the request retrying it on transient failures according to the
+    /// given policy
+    async fn send_with_retry(self, policy: &RetryPolicy) -> anyhow::Result<Response>;
+}
+
+impl RequestBuilderExt for RequestBuilder {
+    async fn send_with_retry(self, policy: &RetryPolicy) -> anyhow::Result<Response> {
+        let (client, request) = self.build_split();
+        let request = request?;
+        let safe = request.method().is_safe();
+
+        let mut attempt = 1;
+        loop {
+            // the last attempt (or a request with a streaming body) is
+            // executed as is, whatever the outcome
+            let Some(retry) = request
+                .try_clone()
+                .filter(|_| attempt < policy.max_attempts)
+            else {
+                return Ok(client.execute(request).await?);
+            };
+
+            let url = retry.url().clone();
+            let delay = match client.execute(retry).await {
+                Ok(response) => match policy.response_delay(&response, safe, attempt) {
+                    Some(delay) => {
+                        let status = response.status();
+                        warn!(
+                            "request to {url} failed with status {status}, retrying in {delay:?}"
+                        );
+                        delay
+                    }
+                    None => return Ok(response),
+                },
+                Err(e) if is_retryable_error(&e, safe) => {
+                    let delay = policy.backoff(attempt);
+                    warn!("request to {url} failed: {e}, retrying in {delay:?}");
+                    delay
+                }
+                Err(e) => return Err(e.into()),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock_server::{MockResponse, MockServer};
+    use reqwest::Client;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_retried_on_server_error() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            MockResponse::new(503),
+            MockResponse::new(429).header("retry-after", "0"),
+            MockResponse::new(200).body("ok"),
+        ])?;
+
+        let response = Client::new()
+            .get(&server.url)
+            .send_with_retry(&policy(3))
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.requests().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_not_retried_on_server_error() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(503), MockResponse::new(200)])?;
+
+        let response = Client::new()
+            .delete(&server.url)
+            .send_with_retry(&policy(3))
+            .await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(server.requests().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            MockResponse::new(502),
+            MockResponse::new(502),
+            MockResponse::new(200),
+        ])?;
+
+        let response = Client::new()
+            .get(&server.url)
+            .send_with_retry(&policy(2))
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(server.requests().len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::SabnzbdConfig;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::http::{ResponseExt, TorrentInfo, api_url};
+use anyhow::Ok;
+use log::debug;
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// A client for SABnzbd API. Unlike torrents there is nothing to seed, so it
+/// isn't a `TorrentClient`: "deleting" a download here only means removing its
+/// entry from the SABnzbd history (if enabled by config). The downloaded files themselves are owned
+/// by Radarr/Sonarr after import and get deleted there.
+/// https://sabnzbd.org/wiki/configuration/4.3/api
+pub struct SabnzbdClient {
+    client: ServiceClient,
+    base_url: Url,
+    api_key: String,
+    delete_history: bool,
+}
+
+impl SabnzbdClient {
+    pub fn new(config: &SabnzbdConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "api")?;
+
+        Ok(Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            base_url,
+            api_key: config.api_key.clone(),
+            delete_history: config.delete_history,
+        })
+    }
+
+    /// checks that SABnzbd is reachable and accepts the API key. A rejected
+    /// key is reported in the body of a successful response, which then has
+    /// no history in it
+    pub async fn check_connection(&self) -> anyhow::Result<()> {
+        self.client
+            .get(self.base_url.clone())
+            .query(&[
+                ("mode", "history"),
+                ("output", "json"),
+                ("limit", "1"),
+                ("apikey", &self.api_key),
+            ])
+            .send()
+            .await?
+            .handle_error()
+            .await?
+            .json::<HistoryResponse>()
+            .await
+            .map_err(|_| anyhow::anyhow!("SABnzbd rejected the API key"))?;
+        Ok(())
+    }
+
+    /// List completed downloads in SABnzbd history by their nzo ids. An empty
+    /// `nzo_ids` would list the whole history, so nothing is requested then.
+    /// https://sabnzbd.org/wiki/configuration/4.3/api#history
+    pub async fn list_history(
+        &self,
+        nzo_ids: &HashSet<String>,
+    ) -> anyhow::Result<Vec<TorrentInfo>> {
+        if nzo_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let nzo_ids = to_comma_separated(nzo_ids);
+        let response: HistoryResponse = self
+            .client
+            .get(self.base_url.clone())
+            .query(&[
+                ("mode", "history"),
+                ("output", "json"),
+                ("apikey", &self.api_key),
+                ("nzo_ids", &nzo_ids),
+            ])
+            .send()
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+
+        let downloads = response
+            .history
+            .slots
+            .into_iter()
+            .map(|s| TorrentInfo {
+                hash: s.nzo_id,
+                name: s.name,
+                ..Default::default()
+            })
+            .collect();
+        Ok(downloads)
+    }
+
+    /// Delete entries from SABnzbd history by the provided nzo ids, always
+    /// keeping the files on disk, and tell whether they were removed. An empty
+    /// `nzo_ids` would delete the whole history, so nothing is requested then.
+    /// https://sabnzbd.org/wiki/configuration/4.3/api#history_delete
+    pub async fn delete_history(&self, nzo_ids: &HashSet<String>) -> anyhow::Result<bool> {
+        if !self.delete_history {
+            debug!("removing SABnzbd history entries is disabled, skipping");
+            return Ok(false);
+        }
+        if nzo_ids.is_empty() {
+            return Ok(false);
+        }
+        let nzo_ids = to_comma_separated(nzo_ids);
+        self.client
+            .get(self.base_url.clone())
+            .query(&[
+                ("mode", "history"),
+                ("name", "delete"),
+                ("output", "json"),
+                ("del_files", "0"),
+                ("apikey", &self.api_key),
+                ("value", &nzo_ids),
+            ])
+            .send()
+            .await?
+            .handle_error()
+            .await?;
+        Ok(true)
+    }
+}
+
+fn to_comma_separated(nzo_ids: &HashSet<String>) -> String {
+    nzo_ids
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(Deserialize)]
+struct HistoryResponse {
+    history: History,
+}
+
+#[derive(Deserialize)]
+struct History {
+    slots: Vec<HistorySlot>,
+}
+
+#[derive(Deserialize)]
+struct HistorySlot {
+    nzo_id: String,
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn test_check_connection() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            MockResponse::new(200).body(r#"{"history": {"slots": []}}"#),
+            MockResponse::new(200).body(r#"{"status": false, "error": "API Key Incorrect"}"#),
+        ])?;
+        let config = SabnzbdConfig {
+            base_url: server.url.clone(),
+            api_key: "api-key".to_owned(),
+            delete_history: false,
+            timeout: None,
+        };
+        let client = SabnzbdClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        client.check_connection().await?;
+        assert!(client.check_connection().await.is_err());
+        assert!(server.requests()[0].contains("apikey=api-key"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_ids_request_nothing() -> anyhow::Result<()> {
+        let server = MockServer::start(Vec::new())?;
+        let config = SabnzbdConfig {
+            base_url: server.url.clone(),
+            api_key: "api-key".to_owned(),
+            delete_history: true,
+            timeout: None,
+        };
+        let client = SabnzbdClient::new(&config, &HttpClient::new(&HttpConfig::default())?)?;
+
+        assert!(client.list_history(&HashSet::new()).await?.is_empty());
+        assert!(!client.delete_history(&HashSet::new()).await?);
+        assert!(server.requests().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_history() -> anyhow::Result<()> {
+        let json = r#"{"history": {"noofslots": 1, "slots": [
+            {"nzo_id": "SABnzbd_nzo_p86tgx", "name": "Some.Movie.2023.1080p", "status": "Completed"}
+        ]}}"#;
+        let response: HistoryResponse = serde_json::from_str(json)?;
+        assert_eq!(response.history.slots.len(), 1);
+        assert_eq!(response.history.slots[0].name, "Some.Movie.2023.1080p");
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
-use super::{ResponseExt, TorrentClientKind};
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{DiskSpace, DownloadClientKind, HISTORY_IDS_PER_REQUEST, ResponseExt, api_url};
+use crate::config::SonarrConfig;
 use anyhow::Ok;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use reqwest::Url;
 use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::{Client, ClientBuilder, Url};
 use serde::Deserialize;
 use std::collections::HashSet;
 use std::fmt::Debug;
@@ -10,21 +13,28 @@ use std::fmt::Debug;
 /// A client for interacting with Sonarr API.
 /// https://sonarr.tv/docs/api/#v3
 pub struct SonarrClient {
-    client: Client,
+    client: ServiceClient,
     base_url: Url,
+    retry: RetryPolicy,
 }
 
 impl SonarrClient {
-    pub fn new(base_url: &str, api_key: &str) -> anyhow::Result<Self> {
-        let mut base_url = Url::parse(base_url)?;
-        base_url.set_path("/api/v3/");
-
-        let default_headers = auth_headers(api_key)?;
-        let client = ClientBuilder::new()
-            .default_headers(default_headers)
-            .build()?;
-
-        Ok(Self { client, base_url })
+    pub fn new(config: &SonarrConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "api/v3/")?;
+
+        let default_headers = auth_headers(&config.api_key)?;
+        let client = http
+            .service(default_headers, config.timeout)
+            .with_basic_auth(
+                config.basic_auth_username.as_ref(),
+                config.basic_auth_password.as_ref(),
+            );
+
+        Ok(Self {
+            client,
+            base_url,
+            retry: http.retry(),
+        })
     }
 
     /// Get the series IDs for a given TVDB ID.
@@ -35,7 +45,7 @@ impl SonarrClient {
             .client
             .get(url)
             .query(&[("tvdbId", provider_id)])
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -48,14 +58,38 @@ impl SonarrClient {
     /// https://sonarr.tv/docs/api/#v3/tag/history/GET/api/v3/history
     pub async fn history_records(
         &self,
-        movie_ids: &HashSet<u64>,
+        series_ids: &HashSet<u64>,
+    ) -> anyhow::Result<HashSet<HistoryRecord>> {
+        let series_ids: Vec<_> = series_ids.iter().collect();
+        let mut records = HashSet::new();
+        for ids in series_ids.chunks(HISTORY_IDS_PER_REQUEST) {
+            let query: Vec<_> = ids.iter().map(|id| ("seriesIds", id.to_string())).collect();
+            records.extend(self.grab_history(&query).await?);
+        }
+        Ok(records)
+    }
+
+    /// Get the history records of all episodes grabbed with the given
+    /// download id, e.g. all episodes of a season pack.
+    /// https://sonarr.tv/docs/api/#v3/tag/history/GET/api/v3/history
+    pub async fn history_records_by_download_id(
+        &self,
+        download_id: &str,
+    ) -> anyhow::Result<HashSet<HistoryRecord>> {
+        self.grab_history(&[("downloadId", download_id.to_owned())])
+            .await
+    }
+
+    /// Internal function for paging through "grabbed" history records
+    /// matching the given query
+    async fn grab_history(
+        &self,
+        query: &[(&str, String)],
     ) -> anyhow::Result<HashSet<HistoryRecord>> {
         let url = self.base_url.join("history")?;
-        let mut query: Vec<_> = movie_ids.iter().map(|id| ("seriesIds", *id)).collect();
         // event type 1 = "grabbed", see docs for more info:
         // https://github.com/Sonarr/Sonarr/blob/v5-develop/src/NzbDrone.Core/History/EpisodeHistory.cs#L37
-        query.push(("eventType", 1));
-        query.push(("pageSize", 100));
+        let defaults = [("eventType", 1), ("pageSize", 100)];
 
         let mut records = HashSet::new();
         let mut page = 1;
@@ -64,9 +98,10 @@ impl SonarrClient {
             let history = self
                 .client
                 .get(url.clone())
-                .query(&query)
+                .query(query)
+                .query(&defaults)
                 .query(&[("page", page)])
-                .send()
+                .send_with_retry(&self.retry)
                 .await?
                 .handle_error()
                 .await?
@@ -83,30 +118,131 @@ impl SonarrClient {
         Ok(records)
     }
 
-    /// Delete series by its ID and all associated files.
+    /// Get all items in the download queue, including stalled and failed
+    /// downloads.
+    /// https://sonarr.tv/docs/api/#v3/tag/queue/GET/api/v3/queue
+    pub async fn queue(&self) -> anyhow::Result<Vec<QueueRecord>> {
+        let url = self.base_url.join("queue")?;
+        let mut records = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let queue = self
+                .client
+                .get(url.clone())
+                .query(&[("pageSize", 100), ("page", page)])
+                .send_with_retry(&self.retry)
+                .await?
+                .handle_error()
+                .await?
+                .json::<Queue>()
+                .await?;
+
+            if queue.records.is_empty() {
+                break;
+            }
+            records.extend(queue.records);
+            page += 1;
+        }
+        Ok(records)
+    }
+
+    /// Delete series by its ID, along with all associated files if
+    /// `delete_files` is set. With `add_to_exclusion` the series is also added
+    /// to the import list exclusions.
     /// https://sonarr.tv/docs/api/#v3/tag/series/DELETE/api/v3/series/{id}
-    pub async fn delete_series(&self, series_id: u64) -> anyhow::Result<()> {
+    pub async fn delete_series(
+        &self,
+        series_id: u64,
+        delete_files: bool,
+        add_to_exclusion: bool,
+    ) -> anyhow::Result<()> {
         let url = self
             .base_url
             .join("series/")?
             .join(&series_id.to_string())?;
         self.client
             .delete(url)
-            .query(&[("deleteFiles", "true")])
-            .send()
+            .query(&[
+                ("deleteFiles", delete_files),
+                ("addImportListExclusion", add_to_exclusion),
+            ])
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?;
         Ok(())
     }
 
+    /// Delete an episode file by its ID. The episode itself stays in Sonarr.
+    /// https://sonarr.tv/docs/api/#v3/tag/episodefile/DELETE/api/v3/episodefile/{id}
+    pub async fn delete_episode_file(&self, episode_file_id: u64) -> anyhow::Result<()> {
+        let url = self
+            .base_url
+            .join("episodefile/")?
+            .join(&episode_file_id.to_string())?;
+        self.client
+            .delete(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(())
+    }
+
+    /// Get the current time of the Sonarr server as reported by the `Date`
+    /// header of a system status request.
+    /// https://sonarr.tv/docs/api/#v3/tag/system/GET/api/v3/system/status
+    pub async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let url = self.base_url.join("system/status")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?;
+        Ok(response.server_date())
+    }
+
+    /// Get all quality profiles.
+    /// https://sonarr.tv/docs/api/#v3/tag/qualityprofile/GET/api/v3/qualityprofile
+    pub async fn quality_profiles(&self) -> anyhow::Result<Vec<QualityProfile>> {
+        let url = self.base_url.join("qualityprofile")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get the free space of the disks known to the service
+    pub async fn disk_space(&self) -> anyhow::Result<Vec<DiskSpace>> {
+        let url = self.base_url.join("diskspace")?;
+        let response = self
+            .client
+            .get(url)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
     /// Get all tags.
     pub async fn tags(&self) -> anyhow::Result<Vec<Tag>> {
         let url = self.base_url.join("tag")?;
         let response = self
             .client
             .get(url)
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -123,7 +259,32 @@ impl SonarrClient {
             .client
             .get(url)
             .query(&[("seriesId", series_id)])
-            .send()
+            .query(&[("includeEpisodeFile", true)])
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// Get episodes by their IDs
+    /// https://sonarr.tv/docs/api/#v3/tag/episode/GET/api/v3/episode
+    pub async fn episodes_by_ids(
+        &self,
+        episode_ids: &HashSet<u64>,
+    ) -> anyhow::Result<Vec<Episode>> {
+        if episode_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let url = self.base_url.join("episode")?;
+        let query: Vec<_> = episode_ids.iter().map(|id| ("episodeIds", *id)).collect();
+        let response = self
+            .client
+            .get(url)
+            .query(&query)
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -147,7 +308,7 @@ impl SonarrClient {
             .client
             .put(url)
             .json(&request)
-            .send()
+            .send_with_retry(&self.retry)
             .await?
             .handle_error()
             .await?
@@ -172,8 +333,40 @@ pub struct SeriesInfo {
     pub title: String,
     pub id: u64,
     pub tags: Option<Vec<u64>>,
+    pub quality_profile_id: u64,
+    pub added: Option<DateTime<Utc>>,
     pub statistics: SeriesStatistics,
     pub seasons: Option<Vec<Season>>,
+    /// folder of the series on disk
+    pub path: Option<String>,
+    /// `standard`, `daily` or `anime`
+    #[serde(default)]
+    pub series_type: String,
+}
+
+impl SeriesInfo {
+    /// number of episodes of the series that have a file on disk
+    pub fn episode_file_count(&self) -> usize {
+        self.seasons
+            .iter()
+            .flatten()
+            .map(|season| season.statistics.episode_file_count)
+            .sum()
+    }
+
+    pub fn is_anime(&self) -> bool {
+        self.series_type == "anime"
+    }
+
+    /// the latest season that still has episodes to air, if any
+    pub fn airing_season(&self) -> Option<u32> {
+        self.seasons
+            .iter()
+            .flatten()
+            .filter(|season| season.statistics.next_airing.is_some())
+            .map(|season| season.season_number)
+            .max()
+    }
 }
 
 impl Debug for SeriesInfo {
@@ -191,16 +384,46 @@ pub struct SeriesStatistics {
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(test, derive(Default))]
 pub struct Episode {
     pub episode_number: u32,
     pub id: u64,
     pub monitored: bool,
     pub season_number: u32,
+    pub absolute_episode_number: Option<u32>,
+    #[serde(default)]
+    pub title: String,
+    /// air date in the series' local time
+    pub air_date: Option<NaiveDate>,
+    pub air_date_utc: Option<DateTime<Utc>>,
+    /// `0` if the episode has no file on disk
+    #[serde(default)]
+    pub episode_file_id: u64,
+    /// only included when requested with `includeEpisodeFile`
+    pub episode_file: Option<EpisodeFile>,
+}
+
+impl Episode {
+    /// size of the episode file, `0` if unknown
+    pub fn size_on_disk(&self) -> u64 {
+        self.episode_file.as_ref().map_or(0, |file| file.size)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EpisodeFile {
+    pub size: u64,
+    /// absolute path of the file
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Season {
+    pub season_number: u32,
+    pub monitored: bool,
     pub statistics: SeasonStatistics,
 }
 
@@ -221,12 +444,15 @@ pub struct History {
 #[derive(Deserialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct HistoryRecord {
+    pub episode_id: u64,
+    pub series_id: u64,
+    pub date: DateTime<Utc>,
     pub download_id: Option<String>,
     pub data: Option<HistoryRecordData>,
 }
 
 impl HistoryRecord {
-    pub fn download_id_per_client(self) -> Option<(TorrentClientKind, String)> {
+    pub fn download_id_per_client(self) -> Option<(DownloadClientKind, String)> {
         let download_id = self.download_id?;
         let client = self.data?.download_client?;
         Some((client, download_id))
@@ -236,7 +462,21 @@ impl HistoryRecord {
 #[derive(Deserialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct HistoryRecordData {
-    pub download_client: Option<TorrentClientKind>,
+    pub download_client: Option<DownloadClientKind>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Queue {
+    pub records: Vec<QueueRecord>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueRecord {
+    /// not set for downloads Sonarr couldn't match to a series
+    pub series_id: Option<u64>,
+    pub episode_id: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -246,6 +486,13 @@ pub struct Tag {
     pub id: u64,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityProfile {
+    pub name: String,
+    pub id: u64,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EpisodeMonitorResponse {
@@ -263,7 +510,21 @@ impl std::fmt::Display for EpisodeMonitorResponse {
 #[cfg(test)]
 mod tests {
     use super::HistoryRecord;
+    use crate::http::mock_server::{MockResponse, MockServer};
     use crate::http::sonarr_client::HistoryRecordData;
+    use crate::http::{HttpClient, SonarrClient};
+    use std::collections::HashSet;
+
+    fn client(server: &MockServer) -> anyhow::Result<SonarrClient> {
+        let config: crate::config::SonarrConfig = toml::from_str(&format!(
+            r#"
+            base_url = "{}"
+            api_key = "abc-key"
+            "#,
+            server.url
+        ))?;
+        SonarrClient::new(&config, &HttpClient::new(&Default::default())?)
+    }
 
     #[test]
     fn test_auth_headers() {
@@ -275,22 +536,28 @@ mod tests {
     #[test]
     fn test_download_id_and_client() {
         let history_record = HistoryRecord {
+            episode_id: 1,
+            series_id: 1,
+            date: Default::default(),
             download_id: "foo".to_owned().into(),
             data: Some(HistoryRecordData {
-                download_client: Some(crate::http::TorrentClientKind::Deluge),
+                download_client: Some(crate::http::DownloadClientKind::Deluge),
             }),
         };
         let (client, download_id) = history_record.download_id_per_client().unwrap();
-        assert!(matches!(client, crate::http::TorrentClientKind::Deluge));
+        assert!(matches!(client, crate::http::DownloadClientKind::Deluge));
         assert_eq!(download_id, "foo");
     }
 
     #[test]
     fn test_download_id_and_client_no_id() {
         let history_record = HistoryRecord {
+            episode_id: 1,
+            series_id: 1,
+            date: Default::default(),
             download_id: None,
             data: Some(HistoryRecordData {
-                download_client: Some(crate::http::TorrentClientKind::Deluge),
+                download_client: Some(crate::http::DownloadClientKind::Deluge),
             }),
         };
         assert!(history_record.download_id_per_client().is_none());
@@ -299,6 +566,9 @@ mod tests {
     #[test]
     fn test_download_id_and_client_no_data() {
         let history_record = HistoryRecord {
+            episode_id: 1,
+            series_id: 1,
+            date: Default::default(),
             download_id: "foo".to_owned().into(),
             data: None,
         };
@@ -308,6 +578,9 @@ mod tests {
     #[test]
     fn test_download_id_and_client_no_client() {
         let history_record = HistoryRecord {
+            episode_id: 1,
+            series_id: 1,
+            date: Default::default(),
             download_id: "foo".to_owned().into(),
             data: Some(HistoryRecordData {
                 download_client: None,
@@ -315,4 +588,84 @@ mod tests {
         };
         assert!(history_record.download_id_per_client().is_none());
     }
+
+    #[tokio::test]
+    async fn test_episodes_by_series_id() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200).body(
+            r#"[{
+                "id": 7,
+                "seasonNumber": 1,
+                "episodeNumber": 2,
+                "monitored": true,
+                "airDateUtc": "2024-01-01T00:00:00Z",
+                "episodeFileId": 70,
+                "episodeFile": {"size": 1024}
+            }, {
+                "id": 8,
+                "seasonNumber": 1,
+                "episodeNumber": 3,
+                "monitored": false
+            }]"#,
+        )])?;
+
+        let episodes = client(&server)?.episodes_by_series_id(3).await?;
+
+        assert_eq!(episodes.len(), 2);
+        assert_eq!(episodes[0].episode_file_id, 70);
+        assert_eq!(episodes[0].size_on_disk(), 1024);
+        assert!(episodes[0].air_date_utc.is_some());
+        assert_eq!(episodes[1].episode_file_id, 0);
+        assert_eq!(episodes[1].size_on_disk(), 0);
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /api/v3/episode?seriesId=3&includeEpisodeFile=true "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unmonitor_episodes() -> anyhow::Result<()> {
+        let server =
+            MockServer::start(vec![MockResponse::new(202).body(
+                r#"[{"id": 7, "seasonNumber": 1, "episodeNumber": 2, "monitored": false}]"#,
+            )])?;
+
+        let res = client(&server)?
+            .unmonitor_episodes(&HashSet::from([7]))
+            .await?;
+
+        assert_eq!(res[0].id, 7);
+        assert_eq!(res[0].to_string(), "s01e02");
+        let requests = server.requests();
+        assert!(requests[0].starts_with("PUT /api/v3/episode/monitor "));
+        assert!(requests[0].ends_with(r#"{"episodeIds":[7],"monitored":false}"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_episode_file() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200)])?;
+
+        client(&server)?.delete_episode_file(70).await?;
+
+        assert!(server.requests()[0].starts_with("DELETE /api/v3/episodefile/70 "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history_records_in_batches() -> anyhow::Result<()> {
+        let empty = || MockResponse::new(200).body(r#"{"records": []}"#);
+        let server = MockServer::start(vec![empty(), empty(), empty()])?;
+        let client = client(&server)?;
+
+        client.history_records(&(1..=50).collect()).await?;
+        client.history_records(&(1..=51).collect()).await?;
+
+        let id_counts: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|request| request.matches("seriesIds=").count())
+            .collect();
+        assert_eq!(id_counts, vec![50, 50, 1]);
+        Ok(())
+    }
 }
@@ -0,0 +1,464 @@
+use super::client::{HttpClient, ServiceClient};
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{
+    Item, ItemsFilter, PlexClient, ResponseExt, User, UserId, WatchHistoryProvider, api_url,
+};
+use crate::config::TautulliConfig;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+
+/// number of history rows requested per page
+const HISTORY_PAGE_SIZE: usize = 1000;
+
+/// Takes the watch history of Plex users from Tautulli, which records every
+/// playback of every user, instead of the played flags Plex keeps for the
+/// owner of the token. The library itself (items, provider ids, genres, ...)
+/// still comes from Plex.
+/// https://github.com/Tautulli/Tautulli/wiki/Tautulli-API-Reference
+pub struct TautulliClient {
+    client: ServiceClient,
+    url: Url,
+    api_key: String,
+    retry: RetryPolicy,
+    plex: PlexClient,
+}
+
+impl TautulliClient {
+    pub fn new(
+        config: &TautulliConfig,
+        plex: PlexClient,
+        http: &HttpClient,
+    ) -> anyhow::Result<Self> {
+        let TautulliConfig {
+            base_url,
+            api_key,
+            timeout,
+            basic_auth_username,
+            basic_auth_password,
+        } = config;
+        let client = http
+            .service(HeaderMap::new(), *timeout)
+            .with_basic_auth(basic_auth_username.as_ref(), basic_auth_password.as_ref());
+        Ok(Self {
+            client,
+            url: api_url(base_url, "api/v2")?,
+            api_key: api_key.clone(),
+            retry: http.retry(),
+            plex,
+        })
+    }
+
+    /// runs an API command and returns its data
+    async fn command<T: DeserializeOwned>(
+        &self,
+        cmd: &str,
+        params: &[(&str, String)],
+    ) -> anyhow::Result<T> {
+        let response = self
+            .client
+            .get(self.url.clone())
+            .query(&[("apikey", self.api_key.as_str()), ("cmd", cmd)])
+            .query(params)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json::<Response>()
+            .await?
+            .response;
+        match (response.result.as_str(), response.data) {
+            ("success", Some(data)) => Ok(serde_json::from_value(data)?),
+            _ => bail!(
+                "Tautulli command `{cmd}` failed: {}",
+                response.message.unwrap_or(response.result)
+            ),
+        }
+    }
+
+    /// Get all playbacks of a user, ungrouped.
+    /// https://github.com/Tautulli/Tautulli/wiki/Tautulli-API-Reference#get_history
+    async fn history(&self, user_id: &str) -> anyhow::Result<Vec<HistoryRow>> {
+        let mut rows = Vec::new();
+        loop {
+            let page: History = self
+                .command(
+                    "get_history",
+                    &[
+                        ("user_id", user_id.to_owned()),
+                        ("grouping", "0".to_owned()),
+                        ("start", rows.len().to_string()),
+                        ("length", HISTORY_PAGE_SIZE.to_string()),
+                    ],
+                )
+                .await?;
+            if page.data.is_empty() {
+                break;
+            }
+            rows.extend(page.data);
+            if rows.len() >= page.records_filtered {
+                break;
+            }
+        }
+        Ok(rows)
+    }
+}
+
+#[async_trait]
+impl WatchHistoryProvider for TautulliClient {
+    fn name(&self) -> &'static str {
+        "Tautulli"
+    }
+
+    /// Get the Plex items matching the filter, with their played status taken
+    /// from the history of the user the filter is for. Shows are played once
+    /// the user watched every one of their episodes, and were last played
+    /// when the last of their episodes was
+    async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
+        let Some(user_id) = items_filter.user_id else {
+            return self.plex.items(items_filter).await;
+        };
+        let is_played = items_filter.is_played;
+        let shows = items_filter
+            .include_item_types
+            .is_some_and(|types| types.contains(&"Series"));
+        let items = self.plex.items(items_filter.played_or_not()).await?;
+        let mut history = WatchHistory::new(self.history(user_id).await?);
+        if shows {
+            let filter = ItemsFilter::new()
+                .include_item_types(&["Episode"])
+                .played_or_not();
+            history.watch_shows(&self.plex.items(filter).await?);
+        }
+
+        let items = items
+            .into_iter()
+            .map(|mut item| {
+                history.apply(&mut item, shows);
+                item
+            })
+            .filter(|item| is_played.is_none_or(|played| item.watched() == played))
+            .collect();
+        Ok(items)
+    }
+
+    /// Get a user by their Plex username or Tautulli friendly name.
+    /// https://github.com/Tautulli/Tautulli/wiki/Tautulli-API-Reference#get_users
+    async fn user(&self, user_name: &str) -> anyhow::Result<User> {
+        let users: Vec<TautulliUser> = self.command("get_users", &[]).await?;
+        users
+            .into_iter()
+            .find(|user| user.username == user_name || user.friendly_name == user_name)
+            .map(|user| User {
+                id: UserId(user.user_id.to_string()),
+                name: user.username,
+            })
+            .ok_or_else(|| anyhow!("User {user_name} not found"))
+    }
+
+    /// Tautulli only reports what Plex played, so the clock of Plex counts
+    async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.plex.server_time().await
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    response: ResponseBody,
+}
+
+/// the data is only parsed once the command succeeded, as failures come with
+/// arbitrary data
+#[derive(Deserialize)]
+struct ResponseBody {
+    result: String,
+    message: Option<String>,
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct History {
+    records_filtered: usize,
+    data: Vec<HistoryRow>,
+}
+
+/// a single playback of an item
+#[derive(Deserialize)]
+struct HistoryRow {
+    #[serde(deserialize_with = "rating_key")]
+    rating_key: Option<String>,
+    /// the show of an episode
+    #[serde(deserialize_with = "rating_key", default)]
+    grandparent_rating_key: Option<String>,
+    /// when the playback stopped, as a unix timestamp
+    stopped: i64,
+    percent_complete: u32,
+    /// 1 once the playback passed the watched threshold set in Tautulli, 0.5
+    /// for a partial playback
+    watched_status: f64,
+}
+
+#[derive(Deserialize)]
+struct TautulliUser {
+    user_id: u64,
+    username: String,
+    #[serde(default)]
+    friendly_name: String,
+}
+
+/// Tautulli reports rating keys as numbers, and missing ones (e.g. the show of
+/// a movie) as empty strings
+fn rating_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let key = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::String(s) if !s.is_empty() => Some(s),
+        _ => None,
+    };
+    Ok(key)
+}
+
+/// the playbacks of a user summed up per item and per show
+#[derive(Default)]
+struct WatchHistory {
+    items: HashMap<String, Plays>,
+    /// last played date of the shows of played episodes
+    shows: HashMap<String, DateTime<Utc>>,
+    /// shows whose episodes were all watched, see [`Self::watch_shows`]
+    watched_shows: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Plays {
+    watched: u32,
+    last_played: Option<DateTime<Utc>>,
+    percent_complete: u32,
+}
+
+impl WatchHistory {
+    fn new(rows: Vec<HistoryRow>) -> Self {
+        let mut history = Self::default();
+        for row in rows {
+            let Some(key) = row.rating_key else {
+                continue;
+            };
+            let stopped = DateTime::from_timestamp(row.stopped, 0);
+            let plays = history.items.entry(key).or_default();
+            if row.watched_status >= 1.0 {
+                plays.watched += 1;
+            }
+            plays.last_played = plays.last_played.max(stopped);
+            plays.percent_complete = plays.percent_complete.max(row.percent_complete);
+            if let (Some(show), Some(stopped)) = (row.grandparent_rating_key, stopped) {
+                let last_played = history.shows.entry(show).or_insert(stopped);
+                *last_played = (*last_played).max(stopped);
+            }
+        }
+        history
+    }
+
+    /// marks the shows of the given episodes as watched if every one of their
+    /// episodes was watched
+    fn watch_shows(&mut self, episodes: &[Item]) {
+        let mut unwatched = HashSet::new();
+        let mut shows = HashSet::new();
+        for episode in episodes {
+            let Some(show) = &episode.series_id else {
+                continue;
+            };
+            shows.insert(show.clone());
+            if self.items.get(&episode.id).is_none_or(|p| p.watched == 0) {
+                unwatched.insert(show.clone());
+            }
+        }
+        self.watched_shows = shows.difference(&unwatched).cloned().collect();
+    }
+
+    /// replaces the user data of the item with the history. The completion of
+    /// partially played items is reported as their played percentage
+    fn apply(&self, item: &mut Item, is_show: bool) {
+        let Some(user_data) = item.user_data.as_mut() else {
+            return;
+        };
+        if is_show {
+            user_data.played = self.watched_shows.contains(&item.id);
+            user_data.last_played_date = self.shows.get(&item.id).copied();
+            return;
+        }
+        let plays = self.items.get(&item.id);
+        user_data.played = plays.is_some_and(|p| p.watched > 0);
+        user_data.play_count = plays.map(|p| p.watched).unwrap_or_default();
+        user_data.last_played_date = plays.and_then(|p| p.last_played);
+        user_data.playback_position_ticks = None;
+        user_data.played_percentage = plays
+            .filter(|p| p.watched == 0)
+            .map(|p| f64::from(p.percent_complete));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HttpConfig, PlexConfig};
+    use crate::http::jellyfin_client::ItemUserData;
+    use crate::http::mock_server::{MockResponse, MockServer};
+
+    fn history() -> anyhow::Result<WatchHistory> {
+        let json = r#"{"response": {"result": "success", "message": null, "data": {
+            "recordsFiltered": 4,
+            "recordsTotal": 4,
+            "data": [
+                {"rating_key": 42, "grandparent_rating_key": 40, "stopped": 1700000000,
+                 "percent_complete": 98, "watched_status": 1},
+                {"rating_key": 42, "grandparent_rating_key": 40, "stopped": 1700100000,
+                 "percent_complete": 95, "watched_status": 1},
+                {"rating_key": 43, "grandparent_rating_key": 40, "stopped": 1700200000,
+                 "percent_complete": 30, "watched_status": 0.5},
+                {"rating_key": 7, "grandparent_rating_key": "", "stopped": 1600000000,
+                 "percent_complete": 100, "watched_status": 1}
+            ]
+        }}}"#;
+        let response: Response = serde_json::from_str(json)?;
+        let history: History = serde_json::from_value(response.response.data.unwrap())?;
+        Ok(WatchHistory::new(history.data))
+    }
+
+    fn item(id: &str) -> Item {
+        Item {
+            name: id.to_owned(),
+            id: id.to_owned(),
+            series_id: None,
+            series_name: None,
+            premiere_date: None,
+            index_number: None,
+            parent_index_number: None,
+            provider_ids: None,
+            user_data: Some(ItemUserData {
+                last_played_date: None,
+                played: true,
+                rating: None,
+                playback_position_ticks: None,
+                played_percentage: None,
+                play_count: 1,
+            }),
+            genres: Vec::new(),
+            community_rating: None,
+            date_created: None,
+            run_time_ticks: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_history() -> anyhow::Result<()> {
+        let mut history = history()?;
+
+        let mut episode = item("42");
+        history.apply(&mut episode, false);
+        assert!(episode.watched());
+        assert_eq!(episode.play_count(), 2);
+        assert_eq!(
+            episode.last_played_date(),
+            DateTime::from_timestamp(1700100000, 0)
+        );
+        assert_eq!(episode.played_percentage(), None);
+
+        let mut partially_played = item("43");
+        history.apply(&mut partially_played, false);
+        assert!(!partially_played.watched());
+        assert_eq!(partially_played.played_percentage(), Some(30.0));
+
+        // played according to Plex, but not by this user
+        let mut not_played = item("8");
+        history.apply(&mut not_played, false);
+        assert!(!not_played.watched());
+        assert_eq!(not_played.last_played_date(), None);
+
+        let episode = |id: &str| Item {
+            series_id: Some("40".to_owned()),
+            ..item(id)
+        };
+        // played according to Plex, but episode 43 was only partially played
+        // by this user
+        history.watch_shows(&[episode("42"), episode("43")]);
+        let mut show = item("40");
+        history.apply(&mut show, true);
+        assert!(!show.watched());
+        assert_eq!(
+            show.last_played_date(),
+            DateTime::from_timestamp(1700200000, 0)
+        );
+
+        history.watch_shows(&[episode("42")]);
+        let mut show = item("40");
+        history.apply(&mut show, true);
+        assert!(show.watched());
+
+        // no episodes in Plex
+        let mut show = item("41");
+        history.apply(&mut show, true);
+        assert!(!show.watched());
+        Ok(())
+    }
+
+    fn client(server: &MockServer) -> anyhow::Result<TautulliClient> {
+        let http = HttpClient::new(&HttpConfig::default())?;
+        let plex = PlexClient::new(
+            &PlexConfig {
+                base_url: "http://localhost:32400".to_owned(),
+                token: "plex-token".to_owned(),
+                timeout: None,
+                basic_auth_username: None,
+                basic_auth_password: None,
+            },
+            &http,
+        )?;
+        let config = TautulliConfig {
+            base_url: server.url.clone(),
+            api_key: "tautulli-key".to_owned(),
+            timeout: None,
+            basic_auth_username: None,
+            basic_auth_password: None,
+        };
+        TautulliClient::new(&config, plex, &http)
+    }
+
+    #[tokio::test]
+    async fn test_user() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200).body(
+            r#"{"response": {"result": "success", "data": [
+                {"user_id": 1, "username": "john", "friendly_name": "John"},
+                {"user_id": 2, "username": "jane", "friendly_name": "Jane D."}
+            ]}}"#,
+        )])?;
+        let client = client(&server)?;
+
+        let user = client.user("Jane D.").await?;
+        assert_eq!(user.id.as_ref(), "2");
+        assert_eq!(user.name, "jane");
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /api/v2?apikey=tautulli-key&cmd=get_users "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_command() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![MockResponse::new(200).body(
+            r#"{"response": {"result": "error", "message": "Invalid apikey", "data": {}}}"#,
+        )])?;
+
+        let Err(error) = client(&server)?.user("john").await else {
+            panic!("expected the command to fail");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Tautulli command `get_users` failed: Invalid apikey"
+        );
+        Ok(())
+    }
+}
@@ -1,30 +1,75 @@
-use super::TorrentClient;
+use super::{TorrentClient, TorrentInfo, is_auth_error};
 use crate::config::DelugeConfig;
-use crate::http::ResponseExt;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::http::{ResponseExt, api_url};
 use anyhow::{Context, Ok, bail};
 use async_trait::async_trait;
+use log::debug;
+use reqwest::Url;
 use reqwest::header::{COOKIE, HeaderMap, HeaderValue};
-use reqwest::{Client, Url};
 use serde::Deserialize;
 use serde::de::DeserializeOwned;
 use serde_json::{Value, json};
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
 
 const SESSION_COOKIE: &str = "_session_id";
+const NOT_AUTHENTICATED_CODE: i64 = 1;
 
 pub struct DelugeClient {
-    client: Client,
+    client: ServiceClient,
     base_url: Url,
-    default_headers: HeaderMap,
+    password: String,
+    daemon_host: Option<String>,
+    /// holds the session cookie, replaced whenever the session expires
+    default_headers: RwLock<HeaderMap>,
 }
 
 impl DelugeClient {
-    pub async fn new(config: &DelugeConfig) -> anyhow::Result<Self> {
-        let mut base_url = Url::parse(&config.base_url)?;
-        base_url.set_path("/json");
+    pub async fn new(config: &DelugeConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "json")?;
 
-        let client = Client::new();
-        let session_cookie = login(&client, &base_url, &config.password).await?;
+        let client = Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            base_url,
+            password: config.password.clone(),
+            daemon_host: config.daemon_host.clone(),
+            default_headers: RwLock::new(HeaderMap::new()),
+        };
+        client.login().await?;
+        client.connect_daemon().await?;
+        Ok(client)
+    }
+
+    /// Deluge 2 WebUI is not connected to a daemon on a fresh install and
+    /// fails every torrent command with "Not connected" until a daemon host
+    /// gets selected, so connect it to the configured (or the only) one
+    async fn connect_daemon(&self) -> anyhow::Result<()> {
+        // `false` is a valid answer here, unlike for other methods
+        let connected = self
+            .send(&DelugeRequest::Connected)
+            .await?
+            .context("Deluge rejected the session right after logging in")?;
+        if connected.result == Some(Value::Bool(true)) {
+            return Ok(());
+        }
+        let hosts = self
+            .post::<Vec<DaemonHost>>(DelugeRequest::GetHosts)
+            .await?
+            .unwrap_or_default();
+        let host = select_host(&hosts, self.daemon_host.as_deref())?;
+        debug!("connecting Deluge WebUI to the daemon at {host}");
+        self.post::<Value>(DelugeRequest::Connect(&host.id))
+            .await
+            .with_context(|| format!("unable to connect Deluge WebUI to the daemon at {host}"))?;
+        Ok(())
+    }
+
+    /// Log in and store the session cookie for subsequent requests
+    async fn login(&self) -> anyhow::Result<()> {
+        let session_cookie = login(&self.client, &self.base_url, &self.password).await?;
 
         let mut default_headers = HeaderMap::new();
         let mut header_value =
@@ -32,38 +77,59 @@ impl DelugeClient {
         header_value.set_sensitive(true);
         default_headers.insert(COOKIE, header_value);
 
-        Ok(Self {
-            client,
-            base_url,
-            default_headers,
-        })
+        *self.default_headers.write().unwrap() = default_headers;
+        Ok(())
     }
 
-    /// Internal function for submitting requests to Deluge API
+    /// Internal function for submitting requests to Deluge API. If Deluge
+    /// rejects the session (e.g. because it has expired) logs in again and
+    /// retries once
     async fn post<T: DeserializeOwned>(
         &self,
         request: DelugeRequest<'_>,
     ) -> anyhow::Result<Option<T>> {
+        if let Some(response) = self.send(&request).await? {
+            return response.response();
+        }
+        debug!("Deluge session is rejected, logging in again");
+        self.login().await?;
+        self.send(&request)
+            .await?
+            .context("Deluge rejected the session right after logging in")?
+            .response()
+    }
+
+    /// sends a request to Deluge API, returns `None` if the session is rejected
+    async fn send(&self, request: &DelugeRequest<'_>) -> anyhow::Result<Option<DelugeResponse>> {
+        let headers = self.default_headers.read().unwrap().clone();
         let response = self
             .client
             .post(self.base_url.clone())
             .json(&request.to_json())
-            .headers(self.default_headers.clone())
+            .headers(headers)
             .send()
-            .await?
+            .await?;
+
+        if is_auth_error(response.status()) {
+            return Ok(None);
+        }
+        let response = response
             .handle_error()
             .await?
             .json::<DelugeResponse>()
             .await?;
 
-        response.response()
+        if response.is_unauthenticated() {
+            return Ok(None);
+        }
+        Ok(Some(response))
     }
 }
 
 #[async_trait]
 impl TorrentClient for DelugeClient {
     /// List all torrents in the client by their hashes.
-    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<TorrentInfo>> {
         let request = DelugeRequest::ListTorrents(hashes);
         let response = self
             .post::<HashMap<String, Torrent>>(request)
@@ -74,21 +140,49 @@ impl TorrentClient for DelugeClient {
             return Ok(Vec::default());
         };
 
-        Ok(result.into_values().map(|v| v.name).collect())
+        let torrents = result
+            .into_iter()
+            .map(|(hash, torrent)| TorrentInfo {
+                hash,
+                content_path: torrent.save_path.map(|path| path.join(&torrent.name)),
+                name: torrent.name,
+                ratio: Some(torrent.ratio),
+                seeding_time: Some(Duration::from_secs(torrent.seeding_time)),
+                trackers: Some(torrent.tracker_host)
+                    .filter(|host| !host.is_empty())
+                    .into_iter()
+                    .collect(),
+                size: torrent.total_size,
+            })
+            .collect();
+        Ok(torrents)
     }
 
-    /// Delete torrents by provided hashes and also delete the associated files.
-    async fn delete_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
-        let request = DelugeRequest::DeleteTorrents(hashes);
+    /// Delete torrents by provided hashes and optionally the associated files.
+    async fn delete_torrents(
+        &self,
+        hashes: &HashSet<String>,
+        delete_files: bool,
+    ) -> anyhow::Result<()> {
+        let request = DelugeRequest::DeleteTorrents(hashes, delete_files);
         self.post::<Vec<bool>>(request)
             .await
             .map_err(|e| anyhow::anyhow!("unable to delete torrents: {e}"))?;
         Ok(())
     }
+
+    /// Pause torrents by provided hashes.
+    async fn pause_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
+        let request = DelugeRequest::PauseTorrents(hashes);
+        self.post::<Value>(request)
+            .await
+            .map_err(|e| anyhow::anyhow!("unable to pause torrents: {e}"))?;
+        Ok(())
+    }
 }
 
 /// Login to Deluge api with password-only method
-async fn login(client: &Client, url: &Url, password: &str) -> Result<String, anyhow::Error> {
+async fn login(client: &ServiceClient, url: &Url, password: &str) -> Result<String, anyhow::Error> {
     let request = DelugeRequest::Login(password);
     let response = client
         .post(url.clone())
@@ -114,12 +208,49 @@ async fn login(client: &Client, url: &Url, password: &str) -> Result<String, any
     sid_cookie
 }
 
+/// picks the daemon matching `daemon_host` (`host` or `host:port`), or the
+/// only one if it isn't set
+fn select_host<'a>(
+    hosts: &'a [DaemonHost],
+    daemon_host: Option<&str>,
+) -> anyhow::Result<&'a DaemonHost> {
+    let listed = || {
+        let hosts: Vec<_> = hosts.iter().map(|h| h.to_string()).collect();
+        hosts.join(", ")
+    };
+    match daemon_host {
+        Some(daemon_host) => hosts
+            .iter()
+            .find(|h| h.matches(daemon_host))
+            .with_context(|| {
+                format!(
+                    "Deluge daemon `{daemon_host}` is not in the WebUI connection manager, available: {}",
+                    listed()
+                )
+            }),
+        None => match hosts {
+            [] => bail!(
+                "Deluge WebUI is not connected and has no daemon to connect to, add one in its connection manager"
+            ),
+            [host] => Ok(host),
+            _ => bail!(
+                "Deluge WebUI is not connected and has several daemons, set `daemon_host` to one of: {}",
+                listed()
+            ),
+        },
+    }
+}
+
 // Requests //
 
 enum DelugeRequest<'a> {
     Login(&'a str),
+    Connected,
+    GetHosts,
+    Connect(&'a str),
     ListTorrents(&'a HashSet<String>),
-    DeleteTorrents(&'a HashSet<String>),
+    DeleteTorrents(&'a HashSet<String>, bool),
+    PauseTorrents(&'a HashSet<String>),
 }
 
 impl DelugeRequest<'_> {
@@ -132,6 +263,27 @@ impl DelugeRequest<'_> {
                     "id": 1
                 }
             ),
+            DelugeRequest::Connected => json!(
+                {
+                    "method": "web.connected",
+                    "params": [],
+                    "id": 1
+                }
+            ),
+            DelugeRequest::GetHosts => json!(
+                {
+                    "method": "web.get_hosts",
+                    "params": [],
+                    "id": 1
+                }
+            ),
+            DelugeRequest::Connect(host_id) => json!(
+                {
+                    "method": "web.connect",
+                    "params": [host_id],
+                    "id": 1
+                }
+            ),
             DelugeRequest::ListTorrents(hashes) => json!(
                 {
                     "method": "core.get_torrents_status",
@@ -141,23 +293,30 @@ impl DelugeRequest<'_> {
                             "state": ["Seeding"]
                         },
                         // fields to return
-                        ["name", "state"]
+                        ["name", "state", "ratio", "seeding_time", "tracker_host", "save_path", "total_size"]
                     ],
                     "id": 1
                 }
             ),
-            DelugeRequest::DeleteTorrents(hashes) => json!(
+            DelugeRequest::DeleteTorrents(hashes, delete_files) => json!(
                 {
                     "method": "core.remove_torrents",
                     "params": [
                         // torrent hashes to delete
                         hashes_to_lower(hashes),
                         // whether to also delete torrent files
-                        true
+                        delete_files
                     ],
                     "id": 1
                 }
             ),
+            DelugeRequest::PauseTorrents(hashes) => json!(
+                {
+                    "method": "core.pause_torrents",
+                    "params": [hashes_to_lower(hashes)],
+                    "id": 1
+                }
+            ),
         }
     }
 }
@@ -174,6 +333,45 @@ fn hashes_to_lower(hashes: &HashSet<String>) -> HashSet<String> {
 #[derive(Deserialize)]
 pub struct Torrent {
     pub name: String,
+    pub ratio: f64,
+    /// seeding time in seconds
+    pub seeding_time: u64,
+    /// hostname of the current tracker, empty if there is none
+    #[serde(default)]
+    pub tracker_host: String,
+    /// directory the torrent content is saved to
+    #[serde(default)]
+    pub save_path: Option<PathBuf>,
+    /// size of all the files of the torrent in bytes
+    #[serde(default)]
+    pub total_size: Option<u64>,
+}
+
+/// a daemon of the WebUI connection manager, listed as
+/// `[id, host, port, username]`
+#[derive(Deserialize)]
+struct DaemonHost {
+    id: String,
+    host: String,
+    port: u16,
+    #[serde(default)]
+    _username: Option<Value>,
+}
+
+impl DaemonHost {
+    /// whether this is the daemon at `host` or `host:port`
+    fn matches(&self, daemon_host: &str) -> bool {
+        match daemon_host.rsplit_once(':') {
+            Some((host, port)) => self.host == host && port.parse().ok() == Some(self.port),
+            None => self.host == daemon_host,
+        }
+    }
+}
+
+impl std::fmt::Display for DaemonHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
 }
 
 #[derive(Deserialize)]
@@ -189,6 +387,14 @@ struct DelugeResponse {
 }
 
 impl DelugeResponse {
+    /// Deluge responds with a "Not authenticated" error (code 1) and a 200
+    /// status once the session expires
+    fn is_unauthenticated(&self) -> bool {
+        self.error
+            .as_ref()
+            .is_some_and(|e| e.code == NOT_AUTHENTICATED_CODE)
+    }
+
     /// processes response received from Deluge API. Throws an error if response
     /// contains a non-null `error` or it's `result` is set to `false`.
     /// Otherwise deserializes the `result` into a type `T` and returns the
@@ -207,3 +413,107 @@ impl DelugeResponse {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn test_login_again_on_rejected_session() -> anyhow::Result<()> {
+        let logged_in = r#"{"result": true, "error": null, "id": 1}"#;
+        let server = MockServer::start(vec![
+            MockResponse::new(200)
+                .header("set-cookie", "_session_id=first")
+                .body(logged_in),
+            MockResponse::new(200).body(logged_in),
+            MockResponse::new(200)
+                .body(r#"{"result": null, "error": {"message": "Not authenticated", "code": 1}, "id": 1}"#),
+            MockResponse::new(200)
+                .header("set-cookie", "_session_id=second")
+                .body(logged_in),
+            MockResponse::new(200).body(r#"{"result": {}, "error": null, "id": 1}"#),
+        ])?;
+        let config = DelugeConfig {
+            base_url: server.url.clone(),
+            password: "qwerty".to_owned(),
+            ..Default::default()
+        };
+
+        let http = HttpClient::new(&HttpConfig::default())?;
+
+        let client = DelugeClient::new(&config, &http).await?;
+        let torrents = client.list_torrents(&HashSet::new()).await?;
+        assert!(torrents.is_empty());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[1].contains("web.connected"));
+        assert!(requests[2].contains("_session_id=first"));
+        assert!(requests[3].contains("auth.login"));
+        assert!(requests[4].contains("_session_id=second"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_daemon() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            MockResponse::new(200)
+                .header("set-cookie", "_session_id=first")
+                .body(r#"{"result": true, "error": null, "id": 1}"#),
+            MockResponse::new(200).body(r#"{"result": false, "error": null, "id": 1}"#),
+            MockResponse::new(200).body(
+                r#"{"result": [["a1", "127.0.0.1", 58846, "localclient"]], "error": null, "id": 1}"#,
+            ),
+            MockResponse::new(200).body(r#"{"result": ["core.get_torrents_status"], "error": null, "id": 1}"#),
+        ])?;
+        let config = DelugeConfig {
+            base_url: server.url.clone(),
+            password: "qwerty".to_owned(),
+            ..Default::default()
+        };
+        let http = HttpClient::new(&HttpConfig::default())?;
+
+        DelugeClient::new(&config, &http).await?;
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].contains("web.connected"));
+        assert!(requests[2].contains("web.get_hosts"));
+        assert!(requests[3].contains(r#""method":"web.connect","params":["a1"]"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_host() -> anyhow::Result<()> {
+        let hosts: Vec<DaemonHost> = serde_json::from_value(json!([
+            ["a1", "127.0.0.1", 58846, "localclient"],
+            ["b2", "seedbox", 58846, "user"],
+            ["c3", "seedbox", 58847, "user"],
+        ]))?;
+        assert_eq!(select_host(&hosts, Some("127.0.0.1"))?.id, "a1");
+        assert_eq!(select_host(&hosts, Some("seedbox:58847"))?.id, "c3");
+        assert!(select_host(&hosts, Some("seedbox:1")).is_err());
+        // ambiguous without `daemon_host`
+        assert!(select_host(&hosts, None).is_err());
+        assert_eq!(select_host(&hosts[1..2], None)?.id, "b2");
+        assert!(select_host(&[], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_torrents_request() {
+        let hashes = HashSet::from(["ABC".to_owned()]);
+
+        let json = DelugeRequest::DeleteTorrents(&hashes, true).to_json();
+        assert_eq!(json["method"], "core.remove_torrents");
+        assert_eq!(json["params"], json!([["abc"], true]));
+
+        let json = DelugeRequest::DeleteTorrents(&hashes, false).to_json();
+        assert_eq!(json["params"], json!([["abc"], false]));
+
+        let json = DelugeRequest::PauseTorrents(&hashes).to_json();
+        assert_eq!(json["method"], "core.pause_torrents");
+        assert_eq!(json["params"], json!([["abc"]]));
+    }
+}
@@ -1,30 +1,100 @@
 mod deluge;
 mod qbittorrent;
+mod rtorrent;
+mod xmlrpc;
 
 use async_trait::async_trait;
+use reqwest::StatusCode;
 use serde::Deserialize;
-use std::{collections::HashSet, fmt::Display};
+use std::{collections::HashSet, fmt::Display, path::PathBuf, time::Duration};
 
 pub use deluge::DelugeClient;
 pub use qbittorrent::QbittorrentClient;
+pub use rtorrent::RtorrentClient;
 
 #[async_trait]
 pub trait TorrentClient {
-    async fn delete_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()>;
-    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<String>>;
+    /// deletes torrents by their hashes, along with the downloaded files if
+    /// `delete_files` is set
+    async fn delete_torrents(
+        &self,
+        hashes: &HashSet<String>,
+        delete_files: bool,
+    ) -> anyhow::Result<()>;
+    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<TorrentInfo>>;
+    /// pauses torrents by their hashes instead of deleting them
+    async fn pause_torrents(&self, _hashes: &HashSet<String>) -> anyhow::Result<()> {
+        anyhow::bail!("pausing torrents is not supported by this client")
+    }
+    /// adds the tag to torrents by their hashes instead of deleting them
+    async fn tag_torrents(&self, _hashes: &HashSet<String>, _tag: &str) -> anyhow::Result<()> {
+        anyhow::bail!("tagging torrents is not supported by this client")
+    }
+}
+
+/// a torrent (or any other download) as reported by a download client
+#[derive(Debug, Default, Clone)]
+pub struct TorrentInfo {
+    /// download id of the torrent. Note that the case may differ from the one
+    /// used by Radarr/Sonarr
+    pub hash: String,
+    pub name: String,
+    /// upload/download ratio, `None` if not reported by the client
+    pub ratio: Option<f64>,
+    /// time spent seeding, `None` if not reported by the client
+    pub seeding_time: Option<Duration>,
+    /// hostnames of the trackers the torrent announces to
+    pub trackers: Vec<String>,
+    /// path of the downloaded file or directory, `None` if not reported by
+    /// the client
+    pub content_path: Option<PathBuf>,
+    /// total size of the torrent content in bytes, `None` if not reported by
+    /// the client
+    pub size: Option<u64>,
+}
+
+impl TorrentInfo {
+    /// returns the first tracker of the torrent that matches any of the given
+    /// hostnames. Subdomains of a protected hostname match too
+    pub fn protected_tracker(&self, protected: &[String]) -> Option<&str> {
+        self.trackers
+            .iter()
+            .find(|tracker| {
+                protected.iter().any(|host| {
+                    tracker.eq_ignore_ascii_case(host)
+                        || tracker
+                            .to_lowercase()
+                            .ends_with(&format!(".{}", host.to_lowercase()))
+                })
+            })
+            .map(String::as_str)
+    }
+}
+
+/// checks whether the client rejected the request because of an invalid or
+/// expired session
+fn is_auth_error(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
 }
 
 const DELUGE_NAME: &str = "Deluge";
 const QBITTORRENT_NAME: &str = "qBittorrent";
+const RTORRENT_NAME: &str = "rTorrent";
+const SABNZBD_NAME: &str = "SABnzbd";
 
-#[derive(Eq, Hash, PartialEq)]
-pub enum TorrentClientKind {
+/// the download client a grab was sent to, as named in Radarr/Sonarr history
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum DownloadClientKind {
     Deluge,
     Qbittorrent,
+    Rtorrent,
+    /// a usenet client. It has nothing to seed, so it isn't a
+    /// [`TorrentClient`], see [`crate::http::SabnzbdClient`]
+    Sabnzbd,
     Other(String),
 }
 
-impl<'de> Deserialize<'de> for TorrentClientKind {
+impl<'de> Deserialize<'de> for DownloadClientKind {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -33,17 +103,21 @@ impl<'de> Deserialize<'de> for TorrentClientKind {
         match s.to_lowercase().as_str() {
             "deluge" => Ok(Self::Deluge),
             "qbittorrent" => Ok(Self::Qbittorrent),
+            "rtorrent" => Ok(Self::Rtorrent),
+            "sabnzbd" => Ok(Self::Sabnzbd),
             _ => Ok(Self::Other(s)),
         }
     }
 }
 
-impl Display for TorrentClientKind {
+impl Display for DownloadClientKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
-            TorrentClientKind::Deluge => DELUGE_NAME,
-            TorrentClientKind::Qbittorrent => QBITTORRENT_NAME,
-            TorrentClientKind::Other(s) => s,
+            DownloadClientKind::Deluge => DELUGE_NAME,
+            DownloadClientKind::Qbittorrent => QBITTORRENT_NAME,
+            DownloadClientKind::Rtorrent => RTORRENT_NAME,
+            DownloadClientKind::Sabnzbd => SABNZBD_NAME,
+            DownloadClientKind::Other(s) => s,
         };
         f.write_str(s)
     }
@@ -57,15 +131,31 @@ mod tests {
     fn test_serde() {
         #[derive(Deserialize)]
         struct Test {
-            qbittorrent: TorrentClientKind,
-            deluge: TorrentClientKind,
-            other: TorrentClientKind,
+            qbittorrent: DownloadClientKind,
+            deluge: DownloadClientKind,
+            rtorrent: DownloadClientKind,
+            sabnzbd: DownloadClientKind,
+            other: DownloadClientKind,
         }
 
-        let s = r#"{"qbittorrent":"qBittorrenT", "deluge":"deluge", "other":"foo"}"#;
+        let s = r#"{"qbittorrent":"qBittorrenT", "deluge":"deluge", "rtorrent":"rTorrent", "sabnzbd":"SABnzbd", "other":"foo"}"#;
         let test: Test = serde_json::from_str(s).unwrap();
-        assert!(matches!(test.qbittorrent, TorrentClientKind::Qbittorrent));
-        assert!(matches!(test.deluge, TorrentClientKind::Deluge));
-        assert!(matches!(test.other, TorrentClientKind::Other(s) if s == "foo"));
+        assert!(matches!(test.qbittorrent, DownloadClientKind::Qbittorrent));
+        assert!(matches!(test.deluge, DownloadClientKind::Deluge));
+        assert!(matches!(test.rtorrent, DownloadClientKind::Rtorrent));
+        assert!(matches!(test.sabnzbd, DownloadClientKind::Sabnzbd));
+        assert!(matches!(test.other, DownloadClientKind::Other(s) if s == "foo"));
+    }
+
+    #[test]
+    fn test_protected_tracker() {
+        let torrent = TorrentInfo {
+            trackers: vec!["open.tracker.org".to_owned(), "t.Private.org".to_owned()],
+            ..Default::default()
+        };
+        let protected = ["private.org".to_owned()];
+        assert_eq!(torrent.protected_tracker(&protected), Some("t.Private.org"));
+        assert_eq!(torrent.protected_tracker(&["vate.org".to_owned()]), None);
+        assert_eq!(torrent.protected_tracker(&[]), None);
     }
 }
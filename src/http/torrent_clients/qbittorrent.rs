@@ -1,30 +1,58 @@
-use super::TorrentClient;
+use super::{TorrentClient, TorrentInfo, is_auth_error};
+use crate::cleaners::try_join_limited;
 use crate::config::QbittorrentConfig;
-use crate::http::ResponseExt;
+use crate::http::client::{HttpClient, ServiceClient};
+use crate::http::{ResponseExt, api_url};
 use anyhow::Ok;
 use async_trait::async_trait;
+use log::debug;
 use reqwest::header::{COOKIE, HeaderMap, HeaderValue};
-use reqwest::{Client, Url};
+use reqwest::{RequestBuilder, Response, Url};
 use serde::Deserialize;
 use serde_json::json;
 use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
 
 pub struct QbittorrentClient {
-    client: Client,
+    client: ServiceClient,
     base_url: Url,
-    default_headers: HeaderMap,
+    username: String,
+    password: String,
+    seeding_states: Vec<String>,
+    /// trackers are only needed to keep the torrents of `protected_trackers`
+    fetch_trackers: bool,
+    max_concurrent_requests: usize,
+    /// holds the session cookie, replaced whenever the session expires
+    default_headers: RwLock<HeaderMap>,
 }
 
 impl QbittorrentClient {
-    pub async fn new(config: &QbittorrentConfig) -> anyhow::Result<Self> {
-        let mut base_url = Url::parse(&config.base_url)?;
-        base_url.set_path("/api/v2/");
+    pub async fn new(config: &QbittorrentConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let base_url = api_url(&config.base_url, "api/v2/")?;
 
-        let client = Client::new();
+        let client = Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            base_url,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            seeding_states: config.seeding_states.clone(),
+            fetch_trackers: !config.protected_trackers.is_empty(),
+            max_concurrent_requests: http.max_concurrent_requests(),
+            default_headers: RwLock::new(HeaderMap::new()),
+        };
+        client.login().await?;
+        Ok(client)
+    }
 
-        let response = client
-            .post(base_url.join("auth/login")?)
-            .form(&json!({ "username": config.username, "password": config.password }))
+    /// Log in and store the session cookie for subsequent requests.
+    /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#login
+    async fn login(&self) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(self.base_url.join("auth/login")?)
+            .form(&json!({ "username": self.username, "password": self.password }))
             .send()
             .await?
             .handle_error()
@@ -41,11 +69,43 @@ impl QbittorrentClient {
         header_value.set_sensitive(true);
         default_headers.insert(COOKIE, header_value);
 
-        Ok(Self {
-            client,
-            base_url,
-            default_headers,
-        })
+        *self.default_headers.write().unwrap() = default_headers;
+        Ok(())
+    }
+
+    /// Send a request with the session cookie. If qBittorrent rejects the
+    /// session (e.g. because it has expired) logs in again and retries once
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let retry = request.try_clone();
+        let response = request.headers(self.headers()).send().await?;
+
+        match retry {
+            Some(retry) if is_auth_error(response.status()) => {
+                debug!("qBittorrent session is rejected, logging in again");
+                self.login().await?;
+                retry
+                    .headers(self.headers())
+                    .send()
+                    .await?
+                    .handle_error()
+                    .await
+            }
+            _ => response.handle_error().await,
+        }
+    }
+
+    fn headers(&self) -> HeaderMap {
+        self.default_headers.read().unwrap().clone()
+    }
+
+    /// Get hostnames of the trackers of a torrent with a given hash.
+    /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#get-torrent-trackers
+    async fn trackers(&self, hash: &str) -> anyhow::Result<Vec<String>> {
+        let url = self.base_url.join("torrents/trackers")?;
+        let request = self.client.get(url).query(&[("hash", hash)]);
+        let response: Vec<Tracker> = self.send(request).await?.json().await?;
+
+        Ok(response.iter().filter_map(Tracker::host).collect())
     }
 }
 
@@ -53,43 +113,123 @@ impl QbittorrentClient {
 impl TorrentClient for QbittorrentClient {
     /// List all torrents in the client by their hashes.
     /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#get-torrent-list
-    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<TorrentInfo>> {
         let url = self.base_url.join("torrents/info")?;
-        let hashes = to_bar_separated_string(hashes);
-        let response: Vec<Torrent> = self
-            .client
-            .get(url)
-            .query(&[("hashes", hashes)])
-            .headers(self.default_headers.clone())
-            .send()
-            .await?
-            .handle_error()
-            .await?
-            .json()
-            .await?;
+        let mut response: Vec<Torrent> = Vec::new();
+        for hashes in hash_batches(hashes) {
+            let request = self.client.get(url.clone()).query(&[("hashes", hashes)]);
+            response.extend(self.send(request).await?.json::<Vec<Torrent>>().await?);
+        }
+        let torrents = filter_seeding(response, &self.seeding_states);
+        if !self.fetch_trackers {
+            return Ok(torrents.into_iter().map(TorrentInfo::from).collect());
+        }
 
-        Ok(response.into_iter().map(|t| t.name).collect())
+        // qBittorrent lists the trackers of one torrent per request
+        let futs = torrents.into_iter().map(|torrent| async move {
+            let trackers = self.trackers(&torrent.hash).await?;
+            Ok(TorrentInfo {
+                trackers,
+                ..TorrentInfo::from(torrent)
+            })
+        });
+        try_join_limited(futs, self.max_concurrent_requests).await
     }
 
-    /// Delete torrents by provided hashes and also delete the associated files.
+    /// Delete torrents by provided hashes and optionally the associated files.
     /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#delete-torrents
-    async fn delete_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
+    async fn delete_torrents(
+        &self,
+        hashes: &HashSet<String>,
+        delete_files: bool,
+    ) -> anyhow::Result<()> {
         let url = self.base_url.join("torrents/delete")?;
-        let hashes = to_bar_separated_string(hashes);
-        let body = &[("hashes", hashes.as_str()), ("deleteFiles", "true")];
-        self.client
-            .post(url)
-            .form(body)
-            .headers(self.default_headers.clone())
-            .send()
-            .await?
-            .handle_error()
-            .await?;
+        for hashes in hash_batches(hashes) {
+            let request = self
+                .client
+                .post(url.clone())
+                .form(&delete_form(hashes, delete_files));
+            self.send(request).await?;
+        }
+        Ok(())
+    }
+
+    /// Pause torrents by provided hashes. qBittorrent 5 renamed `pause` to
+    /// `stop` (Web API 2.11).
+    /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-5.0)#stop-torrents
+    async fn pause_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
+        let request = self.client.get(self.base_url.join("app/webapiVersion")?);
+        let version = self.send(request).await?.text().await?;
+        let url = self.base_url.join(pause_endpoint(&version))?;
+        for hashes in hash_batches(hashes) {
+            let request = self.client.post(url.clone()).form(&[("hashes", hashes)]);
+            self.send(request).await?;
+        }
+        Ok(())
+    }
+
+    /// Add a tag to torrents by provided hashes.
+    /// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#add-torrent-tags
+    async fn tag_torrents(&self, hashes: &HashSet<String>, tag: &str) -> anyhow::Result<()> {
+        let url = self.base_url.join("torrents/addTags")?;
+        for hashes in hash_batches(hashes) {
+            let request = self
+                .client
+                .post(url.clone())
+                .form(&[("hashes", hashes.as_str()), ("tags", tag)]);
+            self.send(request).await?;
+        }
         Ok(())
     }
 }
 
-fn to_bar_separated_string<'a, I>(hashes: I) -> String
+/// the endpoint pausing torrents in the given Web API version, `stop` since
+/// 2.11 and `pause` before
+fn pause_endpoint(webapi_version: &str) -> &'static str {
+    let version: Vec<u32> = webapi_version
+        .trim()
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect();
+    if version.as_slice() >= [2, 11].as_slice() {
+        "torrents/stop"
+    } else {
+        "torrents/pause"
+    }
+}
+
+/// drops torrents that are not in one of the given states, e.g. those still
+/// downloading or checking, so they are never deleted
+fn filter_seeding(torrents: Vec<Torrent>, seeding_states: &[String]) -> Vec<Torrent> {
+    torrents
+        .into_iter()
+        .filter(|t| {
+            let seeding = seeding_states.contains(&t.state);
+            if !seeding {
+                debug!(
+                    "skipping torrent \"{}\" as it is not seeding (state \"{}\")",
+                    t.name, t.state
+                );
+            }
+            seeding
+        })
+        .collect()
+}
+
+/// form body of the delete torrents request
+fn delete_form(hashes: String, delete_files: bool) -> [(&'static str, String); 2] {
+    [
+        ("hashes", hashes),
+        ("deleteFiles", delete_files.to_string()),
+    ]
+}
+
+/// max number of hashes sent in a single request, so that the query or form
+/// value doesn't exceed the size limits
+const HASHES_PER_REQUEST: usize = 100;
+
+/// splits the hashes into `|` separated batches of [HASHES_PER_REQUEST]
+fn hash_batches<'a, I>(hashes: I) -> Vec<String>
 where
     I: IntoIterator<Item = &'a String>,
 {
@@ -97,29 +237,218 @@ where
     if hashes_vec.is_empty() {
         // if there are no hashes, return "none" as the value to avoid
         // qbittorrent returning all torrents
-        return "none".to_owned();
+        return vec!["none".to_owned()];
     }
-    hashes_vec.join("|")
+    hashes_vec
+        .chunks(HASHES_PER_REQUEST)
+        .map(|batch| batch.join("|"))
+        .collect()
 }
 
 #[derive(Deserialize)]
 pub struct Torrent {
+    pub hash: String,
     pub name: String,
+    pub state: String,
+    /// `None` if not reported, e.g. by older WebUI versions
+    #[serde(default)]
+    pub ratio: Option<f64>,
+    /// seeding time in seconds, `None` if not reported, e.g. by older WebUI
+    /// versions
+    #[serde(default)]
+    pub seeding_time: Option<u64>,
+    /// absolute path of the torrent content (root directory for multi-file
+    /// torrents). Only reported by qBittorrent 4.4 and newer
+    #[serde(default)]
+    pub content_path: Option<PathBuf>,
+    /// size of all the files of the torrent, including the ones not selected
+    /// for download
+    #[serde(default)]
+    pub total_size: Option<u64>,
+}
+
+impl From<Torrent> for TorrentInfo {
+    fn from(torrent: Torrent) -> Self {
+        Self {
+            hash: torrent.hash,
+            name: torrent.name,
+            ratio: torrent.ratio,
+            seeding_time: torrent.seeding_time.map(Duration::from_secs),
+            trackers: Vec::new(),
+            content_path: torrent.content_path,
+            size: torrent.total_size,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Tracker {
+    pub url: String,
+}
+
+impl Tracker {
+    /// hostname of the tracker announce URL. qBittorrent also lists DHT, PeX
+    /// and LSD as pseudo trackers (e.g. `** [DHT] **`), these are skipped
+    fn host(&self) -> Option<String> {
+        let url = Url::parse(&self.url).ok()?;
+        url.host_str().map(ToOwned::to_owned)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::mock_server::{MockResponse, MockServer};
+
+    #[test]
+    fn test_deserialize_torrent() -> anyhow::Result<()> {
+        let json = r#"[{"hash": "abc", "name": "foo", "ratio": 1.5, "seeding_time": 3600, "state": "uploading", "total_size": 2048}]"#;
+        let torrents: Vec<Torrent> = serde_json::from_str(json)?;
+        let info = TorrentInfo::from(torrents.into_iter().next().unwrap());
+        assert_eq!(info.hash, "abc");
+        assert_eq!(info.ratio, Some(1.5));
+        assert_eq!(info.seeding_time, Some(Duration::from_secs(3600)));
+        assert_eq!(info.size, Some(2048));
+
+        // older WebUI versions leave out the seeding time
+        let json = r#"[{"hash": "abc", "name": "foo", "state": "uploading"}]"#;
+        let torrents: Vec<Torrent> = serde_json::from_str(json)?;
+        let info = TorrentInfo::from(torrents.into_iter().next().unwrap());
+        assert_eq!(info.ratio, None);
+        assert_eq!(info.seeding_time, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pause_endpoint() {
+        assert_eq!(pause_endpoint("2.11.2"), "torrents/stop");
+        assert_eq!(pause_endpoint("3.0"), "torrents/stop");
+        assert_eq!(pause_endpoint("2.9.3"), "torrents/pause");
+        assert_eq!(pause_endpoint("2.2"), "torrents/pause");
+    }
+
+    #[test]
+    fn test_tracker_host() -> anyhow::Result<()> {
+        let json = r#"[
+            {"url": "** [DHT] **", "status": 2},
+            {"url": "udp://tracker.opentrackr.org:1337/announce", "status": 2},
+            {"url": "https://tracker.private.org/abc123/announce", "status": 2}
+        ]"#;
+        let trackers: Vec<Tracker> = serde_json::from_str(json)?;
+        let hosts: Vec<String> = trackers.iter().filter_map(Tracker::host).collect();
+        assert_eq!(hosts, ["tracker.opentrackr.org", "tracker.private.org"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_login_again_on_rejected_session() -> anyhow::Result<()> {
+        let server = MockServer::start(vec![
+            MockResponse::new(200).header("set-cookie", "SID=first"),
+            MockResponse::new(403).body("Forbidden"),
+            MockResponse::new(200).header("set-cookie", "SID=second"),
+            MockResponse::new(200).body("[]"),
+        ])?;
+        let config = QbittorrentConfig {
+            base_url: server.url.clone(),
+            username: "admin".to_owned(),
+            password: "adminadmin".to_owned(),
+            ..Default::default()
+        };
+
+        let http = HttpClient::new(&HttpConfig::default())?;
+
+        let client = QbittorrentClient::new(&config, &http).await?;
+        let torrents = client.list_torrents(&HashSet::new()).await?;
+        assert!(torrents.is_empty());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].contains("SID=first"));
+        assert!(requests[2].starts_with("POST /api/v2/auth/login"));
+        assert!(requests[3].contains("SID=second"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trackers_of_protected_trackers_only() -> anyhow::Result<()> {
+        let torrents = r#"[{"hash": "a", "name": "foo", "ratio": 1.0, "seeding_time": 10, "state": "uploading"}]"#;
+        let server = MockServer::start(vec![
+            MockResponse::new(200).header("set-cookie", "SID=sid"),
+            MockResponse::new(200).body(torrents),
+        ])?;
+        let mut config = QbittorrentConfig {
+            base_url: server.url.clone(),
+            seeding_states: vec!["uploading".to_owned()],
+            ..Default::default()
+        };
+        let http = HttpClient::new(&HttpConfig::default())?;
+
+        let client = QbittorrentClient::new(&config, &http).await?;
+        let listed = client.list_torrents(&HashSet::new()).await?;
+        assert!(listed[0].trackers.is_empty());
+        assert_eq!(server.requests().len(), 2);
+
+        let server = MockServer::start(vec![
+            MockResponse::new(200).header("set-cookie", "SID=sid"),
+            MockResponse::new(200).body(torrents),
+            MockResponse::new(200).body(r#"[{"url": "https://tracker.private.org/announce"}]"#),
+        ])?;
+        config.base_url = server.url.clone();
+        config.protected_trackers = vec!["tracker.private.org".to_owned()];
+
+        let client = QbittorrentClient::new(&config, &http).await?;
+        let listed = client.list_torrents(&HashSet::new()).await?;
+        assert_eq!(listed[0].trackers, ["tracker.private.org"]);
+        assert!(server.requests()[2].starts_with("GET /api/v2/torrents/trackers?hash=a"));
+        Ok(())
+    }
+
     #[test]
-    fn test_to_bar_separated_string() {
+    fn test_filter_seeding() -> anyhow::Result<()> {
+        let json = r#"[
+            {"hash": "a", "name": "seeding", "ratio": 1.0, "seeding_time": 10, "state": "stalledUP"},
+            {"hash": "b", "name": "downloading", "ratio": 0.0, "seeding_time": 0, "state": "downloading"},
+            {"hash": "c", "name": "checking", "ratio": 0.0, "seeding_time": 0, "state": "checkingDL"}
+        ]"#;
+        let torrents: Vec<Torrent> = serde_json::from_str(json)?;
+        let states = ["uploading".to_owned(), "stalledUP".to_owned()];
+        let seeding = filter_seeding(torrents, &states);
+        assert_eq!(seeding.len(), 1);
+        assert_eq!(seeding[0].hash, "a");
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_form() {
+        let form = delete_form("hash1".to_owned(), true);
+        assert_eq!(form[0], ("hashes", "hash1".to_owned()));
+        assert_eq!(form[1], ("deleteFiles", "true".to_owned()));
+
+        let form = delete_form("hash1".to_owned(), false);
+        assert_eq!(form[1], ("deleteFiles", "false".to_owned()));
+    }
+
+    #[test]
+    fn test_hash_batches() {
         let hashes = &["hash1".to_owned(), "hash2".to_owned(), "hash3".to_owned()];
-        let result = super::to_bar_separated_string(hashes);
-        assert_eq!(result, "hash1|hash2|hash3");
+        let result = super::hash_batches(hashes);
+        assert_eq!(result, vec!["hash1|hash2|hash3"]);
     }
 
     #[test]
-    fn test_to_bar_separated_string_empty() {
+    fn test_hash_batches_empty() {
         let hashes: Vec<String> = vec![];
-        let result = super::to_bar_separated_string(&hashes);
-        assert_eq!(result, "none");
+        let result = super::hash_batches(&hashes);
+        assert_eq!(result, vec!["none"]);
+    }
+
+    #[test]
+    fn test_hash_batches_split() {
+        let hashes: Vec<String> = (0..=HASHES_PER_REQUEST).map(|i| i.to_string()).collect();
+        let result = super::hash_batches(&hashes);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].split('|').count(), HASHES_PER_REQUEST);
+        assert_eq!(result[1], HASHES_PER_REQUEST.to_string());
     }
 }
@@ -0,0 +1,300 @@
+use super::xmlrpc::{self, Value};
+use super::{TorrentClient, TorrentInfo};
+use crate::config::RtorrentConfig;
+use crate::http::ResponseExt;
+use crate::http::client::{HttpClient, ServiceClient};
+use anyhow::{Ok, bail};
+use async_trait::async_trait;
+use log::warn;
+use reqwest::Url;
+use reqwest::header::{CONTENT_TYPE, HeaderMap};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+/// A client for rTorrent's XML-RPC interface. Works with both a plain
+/// SCGI-over-HTTP mount (e.g. `/RPC2`) and the ruTorrent `httprpc` plugin
+/// (`/rutorrent/plugins/httprpc/action.php`)
+pub struct RtorrentClient {
+    client: ServiceClient,
+    url: Url,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl RtorrentClient {
+    pub fn new(config: &RtorrentConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.base_url)?;
+        Ok(Self {
+            client: http.service(HeaderMap::new(), config.timeout),
+            url,
+            username: config.username.clone(),
+            password: config.password.clone(),
+        })
+    }
+
+    /// checks that rTorrent is reachable with the configured credentials
+    pub async fn check_connection(&self) -> anyhow::Result<()> {
+        self.call("system.client_version", &[]).await?;
+        Ok(())
+    }
+
+    /// Internal function for submitting XML-RPC calls to rTorrent
+    async fn call(&self, method: &str, params: &[Value]) -> anyhow::Result<Value> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "text/xml")
+            .body(xmlrpc::method_call(method, params));
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        let body = request.send().await?.handle_error().await?.text().await?;
+        xmlrpc::parse_response(&body)
+    }
+
+    /// Calls a single-argument `d.*` method for each of the given hashes in one
+    /// `system.multicall` request. Hashes unknown to rTorrent are skipped
+    async fn multicall(
+        &self,
+        method: &str,
+        hashes: &HashSet<String>,
+    ) -> anyhow::Result<Vec<(String, Value)>> {
+        let hashes = hashes_to_upper(hashes);
+        let calls = hashes
+            .iter()
+            .map(|hash| {
+                Value::Struct(vec![
+                    ("methodName".to_owned(), Value::from(method)),
+                    (
+                        "params".to_owned(),
+                        Value::Array(vec![Value::from(hash.as_str())]),
+                    ),
+                ])
+            })
+            .collect();
+        let response = self
+            .call("system.multicall", &[Value::Array(calls)])
+            .await?;
+
+        let results = response.as_array().unwrap_or_default();
+        let values = hashes
+            .into_iter()
+            .zip(results)
+            .filter_map(|(hash, result)| {
+                // successful results are wrapped into a single-element array,
+                // failures (e.g. unknown hash) are returned as fault structs
+                let value = result.as_array()?.first()?.clone();
+                Some((hash, value))
+            })
+            .collect();
+        Ok(values)
+    }
+
+    /// Resolves the files of a torrent before it gets erased, as rTorrent
+    /// forgets them along with the torrent
+    async fn torrent_files(&self, hash: &str, download_dir: &str) -> anyhow::Result<TorrentFiles> {
+        let base_path = self.call("d.base_path", &[Value::from(hash)]).await?;
+        let directory = self.call("d.directory", &[Value::from(hash)]).await?;
+        let params = [hash, "", "f.path="].map(Value::from);
+        let files = self.call("f.multicall", &params).await?;
+        let files: Vec<&str> = files
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|file| file.as_array()?.first()?.as_str())
+            .collect();
+        TorrentFiles::new(
+            base_path.as_str().unwrap_or_default(),
+            directory.as_str().unwrap_or_default(),
+            &files,
+            download_dir,
+        )
+    }
+
+    /// Removes the files of an erased torrent one by one, then the
+    /// directories of a multi-file torrent left empty
+    async fn delete_files(&self, files: &TorrentFiles) -> anyhow::Result<()> {
+        for file in &files.files {
+            let file = file.to_string_lossy();
+            let params = ["", "rm", "-f", "--", &file].map(Value::from);
+            self.call("execute.throw", &params)
+                .await
+                .map_err(|e| anyhow::anyhow!("unable to delete file {file}: {e}"))?;
+        }
+        if let Some(dir) = &files.own_dir {
+            let dir = dir.to_string_lossy();
+            let params = [
+                "", "find", &dir, "-depth", "-type", "d", "-empty", "-delete",
+            ];
+            self.call("execute.throw", &params.map(Value::from))
+                .await
+                .map_err(|e| anyhow::anyhow!("unable to delete empty directories at {dir}: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// the files of a torrent on disk, checked to be safe to delete
+#[derive(Debug, PartialEq)]
+struct TorrentFiles {
+    /// absolute paths of the files of the torrent
+    files: Vec<PathBuf>,
+    /// directory created for a multi-file torrent, removed if it's left empty
+    own_dir: Option<PathBuf>,
+}
+
+impl TorrentFiles {
+    /// `base_path` and `directory` are what `d.base_path` and `d.directory`
+    /// report, `files` the paths reported by `f.path`, relative to
+    /// `directory`. Refuses anything outside of the torrent's own files, e.g.
+    /// an empty, relative or root directory, or a file path leading out of it
+    fn new(
+        base_path: &str,
+        directory: &str,
+        files: &[&str],
+        download_dir: &str,
+    ) -> anyhow::Result<Self> {
+        let directory = Path::new(directory);
+        if !directory.is_absolute() || directory.parent().is_none() {
+            bail!("refusing to delete files in {directory:?}, it isn't an absolute path");
+        }
+        if files.is_empty() {
+            bail!("rTorrent reported no files");
+        }
+        let files = files
+            .iter()
+            .map(|file| {
+                let path = Path::new(file);
+                if file.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+                {
+                    bail!("refusing to delete {file:?}, it isn't a path within the torrent");
+                }
+                Ok(directory.join(path))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        // a multi-file torrent is saved to a directory of its own, which is
+        // its base path
+        let own_dir = (Path::new(base_path) == directory)
+            .then(|| directory.to_path_buf())
+            .filter(|dir| {
+                let shared = dir == Path::new(download_dir);
+                if shared {
+                    warn!("{dir:?} is the download directory, keeping its directories");
+                }
+                !shared
+            });
+        Ok(Self { files, own_dir })
+    }
+}
+
+#[async_trait]
+impl TorrentClient for RtorrentClient {
+    /// List all torrents in the client by their hashes.
+    async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<TorrentInfo>> {
+        let torrents = self
+            .multicall("d.name", hashes)
+            .await
+            .map_err(|e| anyhow::anyhow!("unable to list torrents: {e}"))?
+            .into_iter()
+            .filter_map(|(hash, name)| {
+                Some(TorrentInfo {
+                    hash,
+                    name: name.as_str()?.to_owned(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Ok(torrents)
+    }
+
+    /// Delete torrents by provided hashes and optionally the associated files.
+    /// rTorrent itself never removes data on `d.erase`, so the files of each
+    /// torrent are resolved upfront and removed one by one afterwards
+    async fn delete_torrents(
+        &self,
+        hashes: &HashSet<String>,
+        delete_files: bool,
+    ) -> anyhow::Result<()> {
+        let download_dir = self.call("directory.default", &[]).await?;
+        let download_dir = download_dir.as_str().unwrap_or_default();
+        for hash in hashes_to_upper(hashes) {
+            let files = if delete_files {
+                self.torrent_files(&hash, download_dir)
+                    .await
+                    .inspect_err(|e| warn!("keeping the files of torrent {hash}: {e}"))
+                    .ok()
+            } else {
+                None
+            };
+            self.call("d.erase", &[Value::from(hash.as_str())])
+                .await
+                .map_err(|e| anyhow::anyhow!("unable to delete torrent {hash}: {e}"))?;
+            if let Some(files) = files {
+                self.delete_files(&files).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Both Radarr and Sonarr store download ids (torrent hashes) in uppercase
+/// format, which is also what rTorrent expects. The hashes are normalized
+/// anyway in case they were provided in a different case
+fn hashes_to_upper(hashes: &HashSet<String>) -> Vec<String> {
+    hashes.iter().map(|h| h.to_uppercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torrent_files() -> anyhow::Result<()> {
+        let download_dir = "/downloads";
+        let files = TorrentFiles::new(
+            "/downloads/Show.S01",
+            "/downloads/Show.S01",
+            &["e01.mkv", "subs/e01.srt"],
+            download_dir,
+        )?;
+        assert_eq!(
+            files,
+            TorrentFiles {
+                files: vec![
+                    PathBuf::from("/downloads/Show.S01/e01.mkv"),
+                    PathBuf::from("/downloads/Show.S01/subs/e01.srt"),
+                ],
+                own_dir: Some(PathBuf::from("/downloads/Show.S01")),
+            }
+        );
+
+        // a single file torrent lives in the download directory
+        let files = TorrentFiles::new(
+            "/downloads/Movie.mkv",
+            "/downloads",
+            &["Movie.mkv"],
+            download_dir,
+        )?;
+        assert_eq!(files.files, vec![PathBuf::from("/downloads/Movie.mkv")]);
+        assert_eq!(files.own_dir, None);
+
+        // a multi-file torrent saved right into the download directory
+        let files = TorrentFiles::new("/downloads", "/downloads", &["a.mkv"], download_dir)?;
+        assert_eq!(files.own_dir, None);
+
+        assert!(TorrentFiles::new("", "", &["a.mkv"], download_dir).is_err());
+        assert!(TorrentFiles::new("/", "/", &["a.mkv"], download_dir).is_err());
+        assert!(TorrentFiles::new("x", "downloads", &["a.mkv"], download_dir).is_err());
+        assert!(TorrentFiles::new("/d/x", "/d/x", &[], download_dir).is_err());
+        assert!(TorrentFiles::new("/d/x", "/d/x", &[""], download_dir).is_err());
+        assert!(TorrentFiles::new("/d/x", "/d/x", &["../y"], download_dir).is_err());
+        assert!(TorrentFiles::new("/d/x", "/d/x", &["/etc/passwd"], download_dir).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashes_to_upper() {
+        let hashes = HashSet::from(["abc123".to_owned()]);
+        assert_eq!(hashes_to_upper(&hashes), vec!["ABC123".to_owned()]);
+    }
+}
@@ -0,0 +1,391 @@
+//! A minimal XML-RPC implementation covering only what is needed to talk to
+//! rTorrent, i.e. serializing method calls and parsing method responses.
+//! https://xmlrpc.com/spec.md
+
+use anyhow::{Context, bail};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Double(f64),
+    Array(Vec<Value>),
+    Struct(Vec<(String, Value)>),
+    Nil,
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// returns a member of a struct value by its name
+    pub fn member(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn write_xml(&self, out: &mut String) {
+        out.push_str("<value>");
+        match self {
+            Value::String(s) => {
+                let _ = write!(out, "<string>{}</string>", escape(s));
+            }
+            Value::Int(i) => {
+                let _ = write!(out, "<i8>{i}</i8>");
+            }
+            Value::Bool(b) => {
+                let _ = write!(out, "<boolean>{}</boolean>", u8::from(*b));
+            }
+            Value::Double(d) => {
+                let _ = write!(out, "<double>{d}</double>");
+            }
+            Value::Array(values) => {
+                out.push_str("<array><data>");
+                for value in values {
+                    value.write_xml(out);
+                }
+                out.push_str("</data></array>");
+            }
+            Value::Struct(members) => {
+                out.push_str("<struct>");
+                for (name, value) in members {
+                    let _ = write!(out, "<member><name>{}</name>", escape(name));
+                    value.write_xml(out);
+                    out.push_str("</member>");
+                }
+                out.push_str("</struct>");
+            }
+            Value::Nil => out.push_str("<nil/>"),
+        }
+        out.push_str("</value>");
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned())
+    }
+}
+
+/// serializes a method call into an XML-RPC request body
+pub fn method_call(method: &str, params: &[Value]) -> String {
+    let mut out = String::from(r#"<?xml version="1.0"?><methodCall>"#);
+    let _ = write!(out, "<methodName>{}</methodName><params>", escape(method));
+    for param in params {
+        out.push_str("<param>");
+        param.write_xml(&mut out);
+        out.push_str("</param>");
+    }
+    out.push_str("</params></methodCall>");
+    out
+}
+
+/// parses an XML-RPC method response. Returns an error if the response
+/// contains a fault
+pub fn parse_response(xml: &str) -> anyhow::Result<Value> {
+    let mut parser = Parser::new(xml)?;
+    parser.expect_start("methodResponse")?;
+    let value = match parser.next_start()? {
+        "params" => {
+            parser.expect_start("param")?;
+            let value = parser.value()?;
+            parser.expect_end("param")?;
+            parser.expect_end("params")?;
+            value
+        }
+        "fault" => {
+            let fault = parser.value()?;
+            let code = match fault.member("faultCode") {
+                Some(Value::Int(code)) => *code,
+                _ => 0,
+            };
+            let message = fault
+                .member("faultString")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            bail!("XML-RPC fault: {message} (code {code})")
+        }
+        other => bail!("unexpected element <{other}> in XML-RPC response"),
+    };
+    parser.expect_end("methodResponse")?;
+    Ok(value)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Start(&'a str),
+    End(&'a str),
+    Empty(&'a str),
+    Text(&'a str),
+}
+
+/// a tiny pull parser supporting only the subset of XML used by XML-RPC, i.e.
+/// elements without attributes and text content
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(xml: &'a str) -> anyhow::Result<Self> {
+        let mut tokens = Vec::new();
+        let mut rest = xml;
+        while !rest.is_empty() {
+            if let Some(tag) = rest.strip_prefix('<') {
+                let end = tag.find('>').context("unterminated XML tag")?;
+                let (tag, remaining) = (&tag[..end], &tag[end + 1..]);
+                rest = remaining;
+                if tag.starts_with('?') || tag.starts_with('!') {
+                    continue;
+                }
+                if let Some(name) = tag.strip_prefix('/') {
+                    tokens.push(Token::End(name.trim()));
+                } else if let Some(name) = tag.strip_suffix('/') {
+                    tokens.push(Token::Empty(name.trim()));
+                } else {
+                    tokens.push(Token::Start(tag.trim()));
+                }
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                tokens.push(Token::Text(&rest[..end]));
+                rest = &rest[end..];
+            }
+        }
+        Ok(Self { tokens, pos: 0 })
+    }
+
+    /// returns the next token, skipping whitespace-only text
+    fn next(&mut self) -> anyhow::Result<&Token<'a>> {
+        while let Some(Token::Text(text)) = self.tokens.get(self.pos) {
+            if !text.trim().is_empty() {
+                break;
+            }
+            self.pos += 1;
+        }
+        let token = self
+            .tokens
+            .get(self.pos)
+            .context("unexpected end of XML-RPC response")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn peek_is_end(&mut self, name: &str) -> anyhow::Result<bool> {
+        let token = self.next()?;
+        let is_end = matches!(token, Token::End(n) if *n == name);
+        self.pos -= 1;
+        Ok(is_end)
+    }
+
+    fn next_start(&mut self) -> anyhow::Result<&'a str> {
+        match self.next()? {
+            Token::Start(name) => Ok(name),
+            token => bail!("expected a start tag, got {token:?}"),
+        }
+    }
+
+    fn expect_start(&mut self, name: &str) -> anyhow::Result<()> {
+        match self.next()? {
+            Token::Start(n) if *n == name => Ok(()),
+            token => bail!("expected <{name}>, got {token:?}"),
+        }
+    }
+
+    fn expect_end(&mut self, name: &str) -> anyhow::Result<()> {
+        match self.next()? {
+            Token::End(n) if *n == name => Ok(()),
+            token => bail!("expected </{name}>, got {token:?}"),
+        }
+    }
+
+    /// reads raw text up to the closing tag with the given name
+    fn text(&mut self, name: &str) -> anyhow::Result<String> {
+        let text = match self.tokens.get(self.pos) {
+            Some(Token::Text(text)) => {
+                self.pos += 1;
+                unescape(text)
+            }
+            _ => String::new(),
+        };
+        self.expect_end(name)?;
+        Ok(text)
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        match self.next()? {
+            Token::Start("value") => {}
+            Token::Empty("value") => return Ok(Value::String(String::new())),
+            token => bail!("expected <value>, got {token:?}"),
+        }
+
+        // a value without a type element is a string
+        if let Some(Token::Text(text)) = self.tokens.get(self.pos)
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::End("value")))
+        {
+            let value = Value::String(unescape(text));
+            self.pos += 2;
+            return Ok(value);
+        }
+
+        let value = match self.next()? {
+            Token::End("value") => return Ok(Value::String(String::new())),
+            Token::Empty("nil") => Value::Nil,
+            Token::Empty("string") => Value::String(String::new()),
+            Token::Start(name) => {
+                let name = *name;
+                match name {
+                    "string" => Value::String(self.text(name)?),
+                    "i4" | "i8" | "int" => Value::Int(self.text(name)?.trim().parse()?),
+                    "boolean" => Value::Bool(self.text(name)?.trim() == "1"),
+                    "double" => Value::Double(self.text(name)?.trim().parse()?),
+                    "array" => {
+                        let mut values = Vec::new();
+                        match self.next()? {
+                            Token::Start("data") => {
+                                while !self.peek_is_end("data")? {
+                                    values.push(self.value()?);
+                                }
+                                self.expect_end("data")?;
+                            }
+                            Token::Empty("data") => {}
+                            token => bail!("expected <data>, got {token:?}"),
+                        }
+                        self.expect_end("array")?;
+                        Value::Array(values)
+                    }
+                    "struct" => {
+                        let mut members = Vec::new();
+                        while !self.peek_is_end("struct")? {
+                            self.expect_start("member")?;
+                            self.expect_start("name")?;
+                            let member_name = self.text("name")?;
+                            let value = self.value()?;
+                            self.expect_end("member")?;
+                            members.push((member_name, value));
+                        }
+                        self.expect_end("struct")?;
+                        Value::Struct(members)
+                    }
+                    other => bail!("unsupported XML-RPC type <{other}>"),
+                }
+            }
+            token => bail!("unexpected token {token:?} in XML-RPC value"),
+        };
+        self.expect_end("value")?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_call() {
+        let params = [
+            Value::from(""),
+            Value::Array(vec![Value::from("A&B"), Value::Int(1)]),
+        ];
+        let actual = method_call("d.multicall2", &params);
+        let expected = concat!(
+            r#"<?xml version="1.0"?><methodCall><methodName>d.multicall2</methodName><params>"#,
+            "<param><value><string></string></value></param>",
+            "<param><value><array><data>",
+            "<value><string>A&amp;B</string></value><value><i8>1</i8></value>",
+            "</data></array></value></param>",
+            "</params></methodCall>"
+        );
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_multicall_response() -> anyhow::Result<()> {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <methodResponse>
+              <params><param><value><array><data>
+                <value><array><data><value><string>Some &amp; Name</string></value></data></array></value>
+                <value><struct>
+                  <member><name>faultCode</name><value><i4>-501</i4></value></member>
+                  <member><name>faultString</name><value><string>Could not find info-hash.</string></value></member>
+                </struct></value>
+                <value><array><data><value>plain</value><value><i8>42</i8></value></data></array></value>
+              </data></array></value></param></params>
+            </methodResponse>"#;
+
+        let value = parse_response(xml)?;
+        let results = value.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0],
+            Value::Array(vec![Value::String("Some & Name".to_owned())])
+        );
+        assert_eq!(results[1].member("faultCode"), Some(&Value::Int(-501)));
+        assert_eq!(
+            results[2],
+            Value::Array(vec![Value::String("plain".to_owned()), Value::Int(42)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_fault() {
+        let xml = r#"<?xml version="1.0"?><methodResponse><fault><value><struct>
+            <member><name>faultCode</name><value><int>-506</int></value></member>
+            <member><name>faultString</name><value><string>Method not defined</string></value></member>
+            </struct></value></fault></methodResponse>"#;
+
+        let err = parse_response(xml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "XML-RPC fault: Method not defined (code -506)"
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_values() -> anyhow::Result<()> {
+        let xml = "<methodResponse><params><param><value><array><data>\
+            <value><string/></value><value></value><value><i8>0</i8></value>\
+            </data></array></value></param></params></methodResponse>";
+
+        let value = parse_response(xml)?;
+        assert_eq!(
+            value,
+            Value::Array(vec![
+                Value::String(String::new()),
+                Value::String(String::new()),
+                Value::Int(0)
+            ])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,616 @@
+use super::client::{HttpClient, ServiceClient};
+use super::jellyfin_client::ItemUserData;
+use super::retry::{RequestBuilderExt, RetryPolicy};
+use super::{
+    ITEM_FIELDS, Item, ItemsFilter, MediaServer, ResponseExt, User, WatchHistoryProvider, api_url,
+};
+use crate::config::TraktConfig;
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell};
+
+/// the token is refreshed once it expires in less than this
+const REFRESH_BEFORE_EXPIRY: TimeDelta = TimeDelta::days(1);
+
+/// Takes the watch history from Trakt instead of the media server, e.g. to
+/// clean up items watched on other devices. Items are still listed by the
+/// media server and matched by their TMDB (movies) and TVDB (shows) ids. The
+/// OAuth token is obtained with the device flow on the first run and kept in
+/// a file, refreshing it before it expires.
+/// https://trakt.docs.apiary.io
+pub struct TraktClient {
+    client: ServiceClient,
+    base_url: Url,
+    client_id: String,
+    client_secret: String,
+    token_file: PathBuf,
+    token: Mutex<Option<Token>>,
+    /// fetched once, as every query of the media server needs it
+    history: OnceCell<TraktHistory>,
+    retry: RetryPolicy,
+    media_server: MediaServer,
+}
+
+impl TraktClient {
+    pub fn new(
+        config: &TraktConfig,
+        media_server: MediaServer,
+        http: &HttpClient,
+    ) -> anyhow::Result<Self> {
+        let TraktConfig {
+            base_url,
+            client_id,
+            client_secret,
+            token_file,
+            timeout,
+        } = config;
+        let mut headers = HeaderMap::new();
+        headers.insert("trakt-api-version", HeaderValue::from_static("2"));
+        headers.insert("trakt-api-key", HeaderValue::from_str(client_id)?);
+        Ok(Self {
+            client: http.service(headers, *timeout),
+            base_url: api_url(base_url, "")?,
+            client_id: client_id.clone(),
+            client_secret: client_secret.clone(),
+            token_file: token_file.clone(),
+            token: Mutex::default(),
+            history: OnceCell::new(),
+            retry: http.retry(),
+            media_server,
+        })
+    }
+
+    /// a valid access token, read from the token file, refreshed or obtained
+    /// with the device flow as needed
+    async fn access_token(&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        if token.is_none() {
+            *token = self.load_token().await?;
+        }
+        let valid = match token.take() {
+            Some(current) if current.expires_at() > Utc::now() + REFRESH_BEFORE_EXPIRY => current,
+            Some(expiring) => {
+                let refreshed = self.refresh(&expiring.refresh_token).await?;
+                self.save_token(&refreshed).await?;
+                refreshed
+            }
+            None => {
+                let authorized = self.authorize().await?;
+                self.save_token(&authorized).await?;
+                authorized
+            }
+        };
+        let access_token = valid.access_token.clone();
+        *token = Some(valid);
+        Ok(access_token)
+    }
+
+    async fn load_token(&self) -> anyhow::Result<Option<Token>> {
+        let path = &self.token_file;
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => {
+                Ok(Some(serde_json::from_str(&content).with_context(|| {
+                    format!("failed to parse Trakt token file {path:?}")
+                })?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read Trakt token file {path:?}")),
+        }
+    }
+
+    async fn save_token(&self, token: &Token) -> anyhow::Result<()> {
+        let path = &self.token_file;
+        tokio::fs::write(path, serde_json::to_string_pretty(token)?)
+            .await
+            .with_context(|| format!("failed to write Trakt token file {path:?}"))
+    }
+
+    /// Obtain a token with the device flow, asking the user to enter a code on
+    /// the Trakt website and polling until they do.
+    /// https://trakt.docs.apiary.io/#reference/authentication-devices
+    async fn authorize(&self) -> anyhow::Result<Token> {
+        let code: DeviceCode = self
+            .client
+            .post(self.base_url.join("oauth/device/code")?)
+            .json(&serde_json::json!({ "client_id": self.client_id }))
+            .send()
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        warn!(
+            "Trakt authorization required: visit {} and enter the code {}",
+            code.verification_url, code.user_code
+        );
+
+        let url = self.base_url.join("oauth/device/token")?;
+        let body = serde_json::json!({
+            "code": code.device_code,
+            "client_id": self.client_id,
+            "client_secret": self.client_secret,
+        });
+        let mut interval = Duration::from_secs(code.interval);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(code.expires_in);
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(interval).await;
+            let response = self.client.post(url.clone()).json(&body).send().await?;
+            match response.status() {
+                // not entered yet
+                StatusCode::BAD_REQUEST => {}
+                StatusCode::TOO_MANY_REQUESTS => interval += Duration::from_secs(1),
+                _ => {
+                    let token = response.handle_error().await?.json().await?;
+                    info!("authorized with Trakt");
+                    return Ok(token);
+                }
+            }
+        }
+        bail!("Trakt authorization timed out, the code wasn't entered in time")
+    }
+
+    /// Exchange the refresh token for a new token.
+    /// https://trakt.docs.apiary.io/#reference/authentication-oauth/get-token/exchange-refresh_token-for-access_token
+    async fn refresh(&self, refresh_token: &str) -> anyhow::Result<Token> {
+        let token = self
+            .client
+            .post(self.base_url.join("oauth/token")?)
+            .json(&serde_json::json!({
+                "refresh_token": refresh_token,
+                "client_id": self.client_id,
+                "client_secret": self.client_secret,
+                "redirect_uri": "urn:ietf:wg:oauth:2.0:oob",
+                "grant_type": "refresh_token",
+            }))
+            .send()
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        info!("refreshed the Trakt token");
+        Ok(token)
+    }
+
+    /// Get everything the user has watched.
+    /// https://trakt.docs.apiary.io/#reference/sync/get-watched
+    async fn history(&self) -> anyhow::Result<&TraktHistory> {
+        self.history
+            .get_or_try_init(|| async {
+                let access_token = self.access_token().await?;
+                let movies: Vec<WatchedMovie> = self.watched("movies", &access_token).await?;
+                let shows: Vec<WatchedShow> = self.watched("shows", &access_token).await?;
+                Ok(TraktHistory::new(movies, shows))
+            })
+            .await
+    }
+
+    async fn watched<T: serde::de::DeserializeOwned>(
+        &self,
+        kind: &str,
+        access_token: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let url = self.base_url.join("sync/watched/")?.join(kind)?;
+        let watched = self
+            .client
+            .get(url)
+            .bearer_auth(access_token)
+            .send_with_retry(&self.retry)
+            .await?
+            .handle_error()
+            .await?
+            .json()
+            .await?;
+        Ok(watched)
+    }
+
+    /// TVDB ids of the series of the given episodes, keyed by the series ids
+    /// of the media server
+    async fn series_tvdb_ids(
+        &self,
+        episodes: &[Item],
+        user_id: Option<&str>,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let mut series_ids: Vec<&str> = episodes
+            .iter()
+            .filter_map(|ep| ep.series_id.as_deref())
+            .collect();
+        series_ids.sort_unstable();
+        series_ids.dedup();
+        if series_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut filter = ItemsFilter::new()
+            .ids(&series_ids)
+            .include_item_types(&["Series"])
+            .fields(ITEM_FIELDS);
+        if let Some(user_id) = user_id {
+            filter = filter.user_id(user_id);
+        }
+        let series = self.media_server.items(filter).await?;
+        Ok(series
+            .into_iter()
+            .filter_map(|s| Some((s.id.clone(), s.tvdb_id()?.to_owned())))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl WatchHistoryProvider for TraktClient {
+    fn name(&self) -> &'static str {
+        "Trakt"
+    }
+
+    /// Get the media server items matching the filter, with their played
+    /// status taken from Trakt. Shows keep the played status reported by the
+    /// media server, only their last played date comes from Trakt. Other
+    /// items (e.g. albums) are left as they are
+    async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>> {
+        let types = items_filter.include_item_types.unwrap_or_default();
+        let kind = if types.contains(&"Series") {
+            MediaKind::Show
+        } else if types.contains(&"Episode") {
+            MediaKind::Episode
+        } else if types.contains(&"Movie") || types.contains(&"Video") {
+            MediaKind::Movie
+        } else {
+            return self.media_server.items(items_filter).await;
+        };
+        let is_played = items_filter.is_played;
+        let user_id = items_filter.user_id;
+        let items = self
+            .media_server
+            .items(items_filter.played_or_not())
+            .await?;
+        let history = self.history().await?;
+        let series_tvdb_ids = match kind {
+            MediaKind::Episode => self.series_tvdb_ids(&items, user_id).await?,
+            _ => HashMap::new(),
+        };
+
+        let items = items
+            .into_iter()
+            .map(|mut item| {
+                history.apply(&mut item, kind, &series_tvdb_ids);
+                item
+            })
+            .filter(|item| is_played.is_none_or(|played| item.watched() == played))
+            .collect();
+        Ok(items)
+    }
+
+    /// Trakt has a single user, so users are still those of the media server
+    async fn user(&self, user_name: &str) -> anyhow::Result<User> {
+        self.media_server.user(user_name).await
+    }
+
+    async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.media_server.server_time().await
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    /// seconds since `created_at`
+    expires_in: i64,
+    /// unix timestamp
+    created_at: i64,
+}
+
+impl Token {
+    fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.created_at + self.expires_in, 0).unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    /// seconds
+    expires_in: u64,
+    /// seconds to wait between polls
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+struct WatchedMovie {
+    plays: u32,
+    last_watched_at: DateTime<Utc>,
+    movie: Media,
+}
+
+#[derive(Deserialize)]
+struct WatchedShow {
+    last_watched_at: DateTime<Utc>,
+    show: Media,
+    #[serde(default)]
+    seasons: Vec<WatchedSeason>,
+}
+
+#[derive(Deserialize)]
+struct WatchedSeason {
+    number: u32,
+    episodes: Vec<WatchedEpisode>,
+}
+
+#[derive(Deserialize)]
+struct WatchedEpisode {
+    number: u32,
+    plays: u32,
+    last_watched_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct Media {
+    ids: MediaIds,
+}
+
+#[derive(Deserialize)]
+struct MediaIds {
+    tmdb: Option<u64>,
+    tvdb: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum MediaKind {
+    Movie,
+    Show,
+    Episode,
+}
+
+/// number of plays and the last play of an item
+type Plays = (u32, DateTime<Utc>);
+
+/// what the user has watched, keyed by TMDB ids of movies and TVDB ids of
+/// shows
+#[derive(Default)]
+struct TraktHistory {
+    movies: HashMap<String, Plays>,
+    shows: HashMap<String, DateTime<Utc>>,
+    /// keyed by the TVDB id of the show, the season and the episode number
+    episodes: HashMap<(String, u32, u32), Plays>,
+}
+
+impl TraktHistory {
+    fn new(movies: Vec<WatchedMovie>, shows: Vec<WatchedShow>) -> Self {
+        let mut history = Self::default();
+        for watched in movies {
+            if let Some(tmdb) = watched.movie.ids.tmdb {
+                let plays = (watched.plays, watched.last_watched_at);
+                history.movies.insert(tmdb.to_string(), plays);
+            }
+        }
+        for watched in shows {
+            let Some(tvdb) = watched.show.ids.tvdb.map(|id| id.to_string()) else {
+                continue;
+            };
+            history.shows.insert(tvdb.clone(), watched.last_watched_at);
+            for season in watched.seasons {
+                for episode in season.episodes {
+                    let key = (tvdb.clone(), season.number, episode.number);
+                    let plays = (episode.plays, episode.last_watched_at);
+                    history.episodes.insert(key, plays);
+                }
+            }
+        }
+        history
+    }
+
+    /// replaces the user data of the item with the history
+    fn apply(&self, item: &mut Item, kind: MediaKind, series_tvdb_ids: &HashMap<String, String>) {
+        let user_data = item.user_data.get_or_insert_with(ItemUserData::default);
+        let plays = match kind {
+            MediaKind::Show => {
+                let last_played = item
+                    .provider_ids
+                    .as_ref()
+                    .and_then(|ids| self.shows.get(ids.tvdb.as_deref()?));
+                user_data.last_played_date = last_played.copied();
+                return;
+            }
+            MediaKind::Movie => item
+                .provider_ids
+                .as_ref()
+                .and_then(|ids| self.movies.get(ids.tmdb.as_deref()?)),
+            MediaKind::Episode => item
+                .series_id
+                .as_ref()
+                .and_then(|series_id| series_tvdb_ids.get(series_id))
+                .zip(item.parent_index_number.zip(item.index_number))
+                .and_then(|(tvdb, (season, episode))| {
+                    self.episodes.get(&(tvdb.clone(), season, episode))
+                }),
+        };
+        user_data.played = plays.is_some_and(|(count, _)| *count > 0);
+        user_data.play_count = plays.map(|(count, _)| *count).unwrap_or_default();
+        user_data.last_played_date = plays.map(|(_, last)| *last);
+        user_data.playback_position_ticks = None;
+        user_data.played_percentage = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http::jellyfin_client::ProviderIds;
+    use crate::http::mock_server::{MockResponse, MockServer};
+    use std::sync::Arc;
+
+    fn history() -> anyhow::Result<TraktHistory> {
+        let movies = serde_json::from_str(
+            r#"[{"plays": 2, "last_watched_at": "2024-01-02T20:00:00.000Z",
+                 "movie": {"title": "Heat", "ids": {"trakt": 1, "tmdb": 949, "imdb": "tt0113277"}}}]"#,
+        )?;
+        let shows = serde_json::from_str(
+            r#"[{"plays": 1, "last_watched_at": "2024-02-01T20:00:00.000Z",
+                 "show": {"title": "Lost", "ids": {"trakt": 2, "tvdb": 73739}},
+                 "seasons": [{"number": 1, "episodes": [
+                     {"number": 2, "plays": 1, "last_watched_at": "2024-02-01T20:00:00.000Z"}
+                 ]}]}]"#,
+        )?;
+        Ok(TraktHistory::new(movies, shows))
+    }
+
+    fn item(id: &str, tmdb: Option<&str>, tvdb: Option<&str>) -> Item {
+        Item {
+            name: id.to_owned(),
+            id: id.to_owned(),
+            series_id: None,
+            series_name: None,
+            premiere_date: None,
+            index_number: None,
+            parent_index_number: None,
+            provider_ids: Some(ProviderIds {
+                tmdb: tmdb.map(ToOwned::to_owned),
+                tvdb: tvdb.map(ToOwned::to_owned),
+                musicbrainz_release_group: None,
+            }),
+            user_data: None,
+            genres: Vec::new(),
+            community_rating: None,
+            date_created: None,
+            run_time_ticks: None,
+        }
+    }
+
+    fn date(s: &str) -> Option<DateTime<Utc>> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_apply_history() -> anyhow::Result<()> {
+        let history = history()?;
+        let no_series = HashMap::new();
+
+        let mut movie = item("1", Some("949"), None);
+        history.apply(&mut movie, MediaKind::Movie, &no_series);
+        assert!(movie.watched());
+        assert_eq!(movie.play_count(), 2);
+        assert_eq!(movie.last_played_date(), date("2024-01-02T20:00:00Z"));
+
+        let mut unwatched = item("2", Some("603"), None);
+        history.apply(&mut unwatched, MediaKind::Movie, &no_series);
+        assert!(!unwatched.watched());
+
+        let mut show = item("40", None, Some("73739"));
+        history.apply(&mut show, MediaKind::Show, &no_series);
+        assert_eq!(show.last_played_date(), date("2024-02-01T20:00:00Z"));
+
+        let series = HashMap::from([("40".to_owned(), "73739".to_owned())]);
+        let episode = |number| Item {
+            series_id: Some("40".to_owned()),
+            parent_index_number: Some(1),
+            index_number: Some(number),
+            ..item("42", None, None)
+        };
+        let mut watched = episode(2);
+        history.apply(&mut watched, MediaKind::Episode, &series);
+        assert!(watched.watched());
+        let mut not_watched = episode(3);
+        history.apply(&mut not_watched, MediaKind::Episode, &series);
+        assert!(!not_watched.watched());
+        Ok(())
+    }
+
+    fn client(server: &MockServer, token_file: PathBuf) -> anyhow::Result<TraktClient> {
+        let http = HttpClient::new(&HttpConfig::default())?;
+        let config = TraktConfig {
+            base_url: server.url.clone(),
+            client_id: "client-id".to_owned(),
+            client_secret: "client-secret".to_owned(),
+            token_file,
+            timeout: None,
+        };
+        let media_server = Arc::new(crate::http::PlexClient::new(
+            &crate::config::PlexConfig {
+                base_url: "http://localhost:32400".to_owned(),
+                token: "plex-token".to_owned(),
+                timeout: None,
+                basic_auth_username: None,
+                basic_auth_password: None,
+            },
+            &http,
+        )?);
+        TraktClient::new(&config, media_server, &http)
+    }
+
+    #[tokio::test]
+    async fn test_device_flow() -> anyhow::Result<()> {
+        let token_file =
+            std::env::temp_dir().join(format!("sanitarr-trakt-{}.json", std::process::id()));
+        let _ = tokio::fs::remove_file(&token_file).await;
+        let expires_in = 7776000;
+        let token = format!(
+            r#"{{"access_token": "access", "token_type": "bearer", "expires_in": {expires_in},
+                "refresh_token": "refresh", "scope": "public", "created_at": {}}}"#,
+            Utc::now().timestamp()
+        );
+        let server = MockServer::start(vec![
+            MockResponse::new(200).body(
+                r#"{"device_code": "device", "user_code": "ABCD1234",
+                    "verification_url": "https://trakt.tv/activate",
+                    "expires_in": 600, "interval": 0}"#,
+            ),
+            // pending until the code is entered
+            MockResponse::new(400),
+            MockResponse::new(200).body(&token),
+        ])?;
+
+        let client = client(&server, token_file.clone())?;
+        assert_eq!(client.access_token().await?, "access");
+        // cached from now on
+        assert_eq!(client.access_token().await?, "access");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].starts_with("POST /oauth/device/code "));
+        assert!(requests[2].contains(r#""code":"device""#));
+        let saved: Token = serde_json::from_str(&tokio::fs::read_to_string(&token_file).await?)?;
+        assert_eq!(saved.refresh_token, "refresh");
+        tokio::fs::remove_file(&token_file).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_expiring_token() -> anyhow::Result<()> {
+        let token_file = std::env::temp_dir().join(format!(
+            "sanitarr-trakt-refresh-{}.json",
+            std::process::id()
+        ));
+        let expiring = Token {
+            access_token: "old".to_owned(),
+            refresh_token: "refresh".to_owned(),
+            expires_in: 3600,
+            created_at: Utc::now().timestamp(),
+        };
+        tokio::fs::write(&token_file, serde_json::to_string(&expiring)?).await?;
+        let refreshed = format!(
+            r#"{{"access_token": "new", "refresh_token": "refresh2", "expires_in": 7776000,
+                "created_at": {}}}"#,
+            Utc::now().timestamp()
+        );
+        let server = MockServer::start(vec![MockResponse::new(200).body(&refreshed)])?;
+
+        let client = client(&server, token_file.clone())?;
+        assert_eq!(client.access_token().await?, "new");
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /oauth/token "));
+        assert!(requests[0].contains(r#""refresh_token":"refresh""#));
+        tokio::fs::remove_file(&token_file).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+use super::{Item, ItemsFilter, User};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+/// A media server (or any other service) that knows what a user has watched.
+/// Cleaners query items through it without knowing which server is behind
+#[async_trait]
+pub trait WatchHistoryProvider {
+    /// a human readable name of the provider, used for logging
+    fn name(&self) -> &'static str;
+
+    /// Get all items that match the given query filter
+    async fn items(&self, items_filter: ItemsFilter<'_>) -> anyhow::Result<Vec<Item>>;
+
+    /// Get a user by it's username (not id). Throws an error if the user not
+    /// found
+    async fn user(&self, user_name: &str) -> anyhow::Result<User>;
+
+    /// Get the current time of the server, `None` if it didn't report a valid
+    /// one
+    async fn server_time(&self) -> anyhow::Result<Option<DateTime<Utc>>>;
+}
+
+/// a shared [`WatchHistoryProvider`] that can be passed to several cleaners
+pub type MediaServer = Arc<dyn WatchHistoryProvider + Send + Sync>;
@@ -1,22 +1,107 @@
-use log::LevelFilter;
+use anyhow::Context;
+use clap::ValueEnum;
+use fern::colors::{Color, ColoredLevelConfig};
+use log::{LevelFilter, Record};
+use std::fmt::Arguments;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
 
+/// names of query parameters, headers and fields whose values are secret.
+/// Longer names go first as `token` is a part of several of them
+const SECRET_KEYS: &[&str] = &[
+    "x-emby-token",
+    "x-plex-token",
+    "x-api-key",
+    "client_secret",
+    "refresh_token",
+    "access_token",
+    "bot_token",
+    "api_key",
+    "apikey",
+    "password",
+    "token",
+];
+
+const REDACTED: &str = "***";
+
+/// number of rotated log files kept besides the current one
+const ROTATED_LOG_FILES: usize = 3;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Text,
+    /// one JSON object per line, e.g. for Loki or ELK
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+    /// when stdout is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// the colors of the levels of the text lines printed to stdout, `None`
+    /// if they aren't colorized
+    fn colors(self) -> Option<ColoredLevelConfig> {
+        self.enabled().then(|| {
+            ColoredLevelConfig::new()
+                .error(Color::Red)
+                .warn(Color::Yellow)
+                .info(Color::Green)
+                .debug(Color::Blue)
+                .trace(Color::Magenta)
+        })
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                std::io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+        }
+    }
+}
+
 /// setup logging for the application including line format as well as the main
-/// log level and per-target log levels (if provided)
-pub fn setup_logging(level: LoggingSettings) -> anyhow::Result<()> {
-    let mut cfg = fern::Dispatch::new()
-        .level(level.root_level)
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{timestamp} [{level}] {message}",
-                timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                level = record.level(),
-                message = message,
-            ))
+/// log level and per-target log levels (if provided). Logs go to stdout and, if
+/// given, to a log file rotated at `log_file_max_size`. Only the lines printed
+/// to stdout are colorized
+pub fn setup_logging(
+    level: LoggingSettings,
+    format: LogFormat,
+    color: ColorChoice,
+    log_file: Option<&Path>,
+    log_file_max_size: u64,
+) -> anyhow::Result<()> {
+    let colors = color.colors();
+    let stdout = fern::Dispatch::new()
+        .format(move |out, message, record| {
+            out.finish(format_args!("{}", line(message, record, format, colors)))
         })
         .chain(std::io::stdout());
+    let mut cfg = fern::Dispatch::new().level(level.root_level).chain(stdout);
+    if let Some(path) = log_file {
+        let file = RotatingFile::open(path, log_file_max_size)?;
+        let file = fern::Dispatch::new()
+            .format(move |out, message, record| {
+                out.finish(format_args!("{}", line(message, record, format, None)))
+            })
+            .chain(Box::new(file) as Box<dyn Write + Send>);
+        cfg = cfg.chain(file);
+    }
 
     for (log_target, level) in level.other_levels {
         cfg = cfg.level_for(log_target, level);
@@ -25,6 +110,223 @@ pub fn setup_logging(level: LoggingSettings) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn line(
+    message: &Arguments,
+    record: &Record,
+    format: LogFormat,
+    colors: Option<ColoredLevelConfig>,
+) -> String {
+    let message = redact(&message.to_string());
+    match format {
+        LogFormat::Text => text_line(&message, record, colors),
+        LogFormat::Json => json_line(message, record),
+    }
+}
+
+fn text_line(message: &str, record: &Record, colors: Option<ColoredLevelConfig>) -> String {
+    let cleaner = cleaner_name(record.target())
+        .map(|name| format!("[{name}] "))
+        .unwrap_or_default();
+    let level = match colors {
+        Some(colors) => colors.color(record.level()).to_string(),
+        None => record.level().to_string(),
+    };
+    format!(
+        "{timestamp} [{level}] {cleaner}{message}",
+        timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+    )
+}
+
+fn json_line(message: String, record: &Record) -> String {
+    serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message,
+    })
+    .to_string()
+}
+
+/// A log file that is rotated once it grows over `max_size`: the file is
+/// renamed to `<file>.1`, the previous `<file>.1` to `<file>.2` and so on, the
+/// oldest one being removed
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open log file {path:?}"))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..ROTATED_LOG_FILES).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    /// called after every log line, so lines are never split between files
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+/// obscures secrets in a log line or an error message, so that logs can be
+/// shared safely: api keys and tokens in urls (e.g. `?apikey=...`), headers
+/// and bodies echoed back by a server (e.g. `MediaBrowser Token="..."`), as
+/// well as the tokens in Telegram bot and Discord webhook urls
+pub fn redact(text: &str) -> String {
+    let text = redact_key_values(text);
+    let text = redact_path_segment(&text, "/bot", 0);
+    redact_path_segment(&text, "/webhooks/", 1)
+}
+
+/// replaces the values of [`SECRET_KEYS`] given as `key=value`, `key: value`
+/// or `"key": "value"`
+fn redact_key_values(text: &str) -> String {
+    let lowercase = text.to_ascii_lowercase();
+    let bytes = lowercase.as_bytes();
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let at_word_start = i == 0 || !is_word_byte(bytes[i - 1]);
+        let key = SECRET_KEYS
+            .iter()
+            .find(|key| at_word_start && bytes[i..].starts_with(key.as_bytes()));
+        let Some(key) = key else {
+            i += 1;
+            continue;
+        };
+        let mut j = i + key.len();
+        if j < bytes.len() && is_word_byte(bytes[j]) {
+            i = j;
+            continue;
+        }
+        j = skip(bytes, j, b"\"'");
+        j = skip(bytes, j, b" ");
+        if j >= bytes.len() || !matches!(bytes[j], b'=' | b':') {
+            i = j.max(i + 1);
+            continue;
+        }
+        j = skip(bytes, j + 1, b" ");
+        j = skip(bytes, j, b"\"'");
+        let end = value_end(bytes, j, b"");
+        if end > j {
+            redacted.push_str(&text[copied..j]);
+            redacted.push_str(REDACTED);
+            copied = end;
+        }
+        i = end.max(i + 1);
+    }
+    redacted.push_str(&text[copied..]);
+    redacted
+}
+
+/// replaces the path segment `skip` segments after each `marker`, e.g. the
+/// token of `/webhooks/{id}/{token}` or the rest of `/bot{token}`
+fn redact_path_segment(text: &str, marker: &str, skip: usize) -> String {
+    let bytes = text.as_bytes();
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut search_from = 0;
+    while let Some(found) = text[search_from..].find(marker) {
+        let mut start = search_from + found + marker.len();
+        for _ in 0..skip {
+            let end = value_end(bytes, start, b"/?#");
+            if bytes.get(end) != Some(&b'/') {
+                break;
+            }
+            start = end + 1;
+        }
+        let end = value_end(bytes, start, b"/?#");
+        // Telegram bot tokens look like `123456:abc...`
+        let is_secret = skip > 0 || text[start..end].contains(':');
+        if is_secret && end > start {
+            redacted.push_str(&text[copied..start]);
+            redacted.push_str(REDACTED);
+            copied = end;
+        }
+        search_from = end.max(start);
+    }
+    redacted.push_str(&text[copied..]);
+    redacted
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-'
+}
+
+/// index of the first byte from `start` on that is not one of `bytes`
+fn skip(text: &[u8], start: usize, bytes: &[u8]) -> usize {
+    let mut i = start;
+    while i < text.len() && bytes.contains(&text[i]) {
+        i += 1;
+    }
+    i
+}
+
+/// index of the byte ending a value starting at `start`, i.e. whitespace,
+/// a quote, a separator or one of `extra`
+fn value_end(text: &[u8], start: usize, extra: &[u8]) -> usize {
+    let mut i = start;
+    while i < text.len()
+        && !text[i].is_ascii_whitespace()
+        && !b"\"'&,;)}".contains(&text[i])
+        && !extra.contains(&text[i])
+    {
+        i += 1;
+    }
+    i
+}
+
+/// name of the cleaner a log record comes from, e.g. `movies` for the
+/// `sanitarr::cleaners::movies` target. Cleaners run concurrently, so their
+/// log lines are prefixed with it to stay readable when interleaved
+fn cleaner_name(target: &str) -> Option<&str> {
+    let name = target.strip_prefix("sanitarr::cleaners::")?;
+    (name != "utils").then_some(name)
+}
+
 #[derive(Debug, Clone)]
 /// Represents the logging settings for the application, including the root log
 /// level and specific log levels for other modules. This allows to separately
@@ -39,6 +341,22 @@ pub struct LoggingSettings {
     pub other_levels: Vec<(String, LevelFilter)>,
 }
 
+impl LoggingSettings {
+    /// the settings of `-v` (debug) and `-vv` (trace), raising the level of
+    /// sanitarr's own logs only
+    pub fn verbosity(verbose: u8) -> Self {
+        let level = match verbose {
+            0 => return Self::default(),
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        Self {
+            root_level: DEFAULT_LEVEL,
+            other_levels: vec![("sanitarr".to_owned(), level)],
+        }
+    }
+}
+
 impl Default for LoggingSettings {
     fn default() -> Self {
         Self {
@@ -77,6 +395,110 @@ impl FromStr for LoggingSettings {
 mod test_super {
     use super::*;
 
+    #[test]
+    fn test_cleaner_name() {
+        assert_eq!(cleaner_name("sanitarr::cleaners::movies"), Some("movies"));
+        assert_eq!(
+            cleaner_name("sanitarr::cleaners::episodes"),
+            Some("episodes")
+        );
+        assert_eq!(cleaner_name("sanitarr::cleaners::utils"), None);
+        assert_eq!(cleaner_name("sanitarr::cleaners"), None);
+        assert_eq!(cleaner_name("sanitarr::http::sonarr_client"), None);
+    }
+
+    #[test]
+    fn test_text_line_color() {
+        let record = Record::builder()
+            .level(log::Level::Error)
+            .target("sanitarr::cleaners::movies")
+            .build();
+        let plain = text_line("failed", &record, ColorChoice::Never.colors());
+        assert!(plain.ends_with(" [ERROR] [movies] failed"));
+        let colored = text_line("failed", &record, ColorChoice::Always.colors());
+        assert!(colored.ends_with(" [\x1b[31mERROR\x1b[0m] [movies] failed"));
+    }
+
+    #[test]
+    fn test_json_line() -> anyhow::Result<()> {
+        let record = Record::builder()
+            .level(log::Level::Warn)
+            .target("sanitarr::cleaners::movies")
+            .build();
+        let line: serde_json::Value =
+            serde_json::from_str(&json_line("said \"hi\"".to_owned(), &record))?;
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "sanitarr::cleaners::movies");
+        assert_eq!(line["message"], "said \"hi\"");
+        assert!(line["timestamp"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotating_file() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sanitarr-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("sanitarr.log");
+
+        let mut file = RotatingFile::open(&path, 10)?;
+        for line in [
+            "zeroth line\n",
+            "first line\n",
+            "second line\n",
+            "third\n",
+            "fourth line\n",
+        ] {
+            file.write_all(line.as_bytes())?;
+            file.flush()?;
+        }
+
+        assert_eq!(std::fs::read_to_string(&path)?, "");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("sanitarr.log.1"))?,
+            "third\nfourth line\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("sanitarr.log.2"))?,
+            "second line\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("sanitarr.log.3"))?,
+            "first line\n"
+        );
+        assert!(!dir.join("sanitarr.log.4").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("request to http://sab/api?mode=queue&apikey=s3cr3t&output=json failed"),
+            "request to http://sab/api?mode=queue&apikey=***&output=json failed"
+        );
+        assert_eq!(
+            redact(r#"Authorization: MediaBrowser Client="sanitarr", Token="abc123""#),
+            r#"Authorization: MediaBrowser Client="sanitarr", Token="***""#
+        );
+        assert_eq!(
+            redact(r#"{"Api_Key": "abc", "name": "foo"}"#),
+            r#"{"Api_Key": "***", "name": "foo"}"#
+        );
+        assert_eq!(redact("X-Plex-Token: abc"), "X-Plex-Token: ***");
+        assert_eq!(
+            redact("request to https://api.telegram.org/bot123:abc/sendMessage failed"),
+            "request to https://api.telegram.org/bot***/sendMessage failed"
+        );
+        assert_eq!(
+            redact("request to https://discord.com/api/webhooks/123/abc?wait=true failed"),
+            "request to https://discord.com/api/webhooks/123/***?wait=true failed"
+        );
+        let harmless = "keeping 2 tokens: foo, see /bottom/ and /webhooks/";
+        assert_eq!(redact(harmless), harmless);
+        assert_eq!(redact("deleting Amélie (2001)"), "deleting Amélie (2001)");
+        assert_eq!(redact("Åsa token=ключ é"), "Åsa token=*** é");
+    }
+
     #[test]
     fn test_deser_single_log_level() {
         let raw_str = "debug";
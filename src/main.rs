@@ -1,45 +1,469 @@
+use anyhow::Context;
 use clap::Parser;
-use cleaners::{MoviesCleaner, SeriesCleaner};
-use cli::Cli;
-use http::JellyfinClient;
-use services::DownloadService;
+use cleaners::{
+    CleanupMode, EpisodesCleaner, MoviesCleaner, MusicCleaner, SeriesCleaner, TvCleaner, Users,
+};
+use cli::{Cli, Command, PENDING_EXIT_CODE};
+use config::{Config, LidarrConfig, RadarrConfig, SonarrConfig};
+use daemon::Schedule;
+use http::{
+    HttpClient, JellyfinClient, MediaServer, PlexClient, TautulliClient, TraktClient,
+    WatchHistoryProvider,
+};
+use log::{error, info, warn};
+use report::RunReport;
+use services::{
+    Clock, Confirmation, DeletionLimit, DownloadService, Health, Metrics, NotificationService,
+    StateStore,
+};
+use std::sync::Arc;
+use std::time::Instant;
 
 mod cleaners;
 mod cli;
 mod config;
+mod daemon;
 mod http;
 mod logging;
+mod prompt;
+mod report;
 mod services;
+mod validate;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
-    logging::setup_logging(args.log_level)?;
+    logging::setup_logging(
+        args.logging_settings(),
+        args.log_format,
+        args.color,
+        args.log_file.as_deref(),
+        args.log_file_max_size,
+    )?;
 
-    let config = config::Config::load(&args.config).await?;
+    if let Some(Command::Init { path }) = &args.command {
+        let path = match path {
+            Some(path) => path,
+            None => args.config_path()?,
+        };
+        Config::write_example(path)?;
+        info!("wrote an example config to {path:?}, edit it before running sanitarr");
+        return Ok(());
+    }
+    let mut config = Config::load(args.config_path()?).await?;
+    // a one-off check, even if a schedule is configured
+    if let Some(Command::Validate) = args.command {
+        return run(&args, config, CleanupMode::DryRun, &Metrics::default())
+            .await
+            .map(|_| ());
+    }
+    let command = args.command.clone().unwrap_or_default();
+    let schedule = match (args.interval, config.schedule.take()) {
+        (Some(_), _) if matches!(command, Command::Simulate { .. }) => {
+            anyhow::bail!("`simulate` runs once, it can't be combined with `--interval`")
+        }
+        // `review` is refused as a daemon by `cleanup_mode`
+        (Some(interval), _) => Some(Schedule::Interval(interval)),
+        // one-off commands ignore the configured schedule
+        (None, Some(cron)) if command.is_cleanup() => Some(Schedule::Cron {
+            cron: Box::new(cron),
+            timezone: config.timezone,
+        }),
+        (None, _) => None,
+    };
+    let mode = args.cleanup_mode(schedule.is_some(), prompt::is_terminal())?;
 
-    let jellyfin_client = JellyfinClient::new(&config.jellyfin)?;
-    let download_service = DownloadService::new(config.download_clients).await?;
-    let user = jellyfin_client.user(&config.username).await?;
+    match schedule {
+        Some(schedule) => {
+            if args.fail_if_pending {
+                warn!("`--fail-if-pending` is ignored when running as a daemon");
+            }
+            let metrics = Metrics::default();
+            if let Some(addr) = args.metrics_addr {
+                metrics.serve(addr)?;
+            }
+            let health = Health::new(schedule.period());
+            if let Some(addr) = args.health_addr {
+                health.serve(addr)?;
+            }
+            let (args, metrics, health) = (&args, &metrics, &health);
+            // the first run uses the config loaded above, the later ones
+            // reload it, so that a running daemon picks up its changes
+            let mut loaded = Some(config);
+            daemon::run(schedule, move || {
+                let loaded = loaded.take();
+                async move {
+                    let started = Instant::now();
+                    let result = async {
+                        let config = match loaded {
+                            Some(config) => config,
+                            None => Config::load(args.config_path()?).await?,
+                        };
+                        run(args, config, mode, metrics).await
+                    }
+                    .await;
+                    metrics.record_run(started.elapsed());
+                    health.record_run(result.is_ok());
+                    result.map(|_| ())
+                }
+            })
+            .await
+        }
+        None => {
+            if args.metrics_addr.is_some() {
+                warn!("`--metrics-addr` is ignored when not running as a daemon");
+            }
+            if args.health_addr.is_some() {
+                warn!("`--health-addr` is ignored when not running as a daemon");
+            }
+            let pending = run(&args, config, mode, &Metrics::default()).await?;
+            if args.fail_if_pending && mode == CleanupMode::DryRun && pending {
+                info!("items are pending deletion, exiting with code {PENDING_EXIT_CODE}");
+                std::process::exit(PENDING_EXIT_CODE);
+            }
+            Ok(())
+        }
+    }
+}
 
-    let movies_cleaner = MoviesCleaner::new(
-        config.radarr,
-        jellyfin_client.clone(),
-        download_service.clone(),
-        &user.id,
-    )?;
+/// runs a single cleanup with the given config as selected by the command and
+/// returns whether anything was (or would be) deleted, counting the deletions
+/// in `metrics`
+async fn run(
+    args: &Cli,
+    mut config: Config,
+    mode: CleanupMode,
+    metrics: &Metrics,
+) -> anyhow::Result<bool> {
+    if let Some(since) = args.since {
+        config.override_retention_period(since);
+    }
+    if let Some(Command::Validate) = args.command {
+        validate::validate(&config).await?;
+        return Ok(false);
+    }
 
-    let series_cleaner = SeriesCleaner::new(
-        config.sonarr,
-        jellyfin_client.clone(),
-        download_service.clone(),
-        &user.id,
-    )?;
+    let http = HttpClient::new(&config.http)?;
+    let media_server = media_server(&config, &http)?;
+    let library_refresh = library_refresh(&config, &http)?;
+    let mut user_ids = Vec::new();
+    for username in config.usernames() {
+        user_ids.push(media_server.user(username).await?.id);
+    }
+    let download_service = DownloadService::new(config.download_clients, &http).await?;
+    let notification_service = NotificationService::new(&config.notifications, &http)?;
+    let state = StateStore::load(config.state_db.as_deref(), config.grace_period).await?;
 
-    tokio::try_join!(
-        movies_cleaner.cleanup(args.force_delete),
-        series_cleaner.cleanup(args.force_delete),
-    )?;
+    let mut clock = Clock::new(&config.clock);
+    if let Some(media_server_time) = media_server.server_time().await? {
+        clock.check_skew(media_server.name(), media_server_time);
+        if config.clock.use_server_time {
+            clock.sync_with(media_server_time);
+        }
+    }
+
+    let cleaners = Cleaners {
+        http,
+        media_server,
+        download_service,
+        users: Users {
+            ids: user_ids,
+            policy: config.watched_policy,
+            watched_threshold: config.watched_threshold,
+            min_play_count: config.min_play_count,
+            retention_anchor: config.retention_anchor,
+        },
+        clock,
+        deletion_limit: DeletionLimit::new(args.max_delete, args.max_free),
+        state,
+        ignore_confirm_threshold: args.ignore_confirm_threshold,
+    };
+
+    // deletions are limited and confirmed at once, after all the cleaners
+    // asked for it
+    let confirm_threshold = config
+        .confirm_threshold
+        .filter(|_| !args.ignore_confirm_threshold);
+    let (confirmation, prompt) =
+        Confirmation::channel(cleaners.deletion_limit.clone(), confirm_threshold);
+    let results = match args.command.clone().unwrap_or_default() {
+        Command::Simulate {
+            radarr_retention,
+            sonarr_retention,
+        } => {
+            let movies_cleaner = cleaners.movies(config.radarr)?;
+            let series_cleaner = cleaners.tv(config.sonarr)?;
+            let (movies_delta, series_delta) = tokio::try_join!(
+                movies_cleaner.simulate(radarr_retention),
+                series_cleaner.simulate(sonarr_retention),
+            )?;
+            info!("movies: {movies_delta}");
+            info!("series: {series_delta}");
+            return Ok(false);
+        }
+        Command::Validate => unreachable!("validated before connecting to the services"),
+        Command::Init { .. } => unreachable!("handled before loading the config"),
+        Command::Movies => {
+            let cleaner = cleaners.movies(config.radarr)?;
+            vec![
+                prompt
+                    .run_alongside(cleaner.cleanup(mode, confirmation))
+                    .await,
+            ]
+        }
+        Command::Series => {
+            let cleaner = cleaners.series(config.sonarr)?;
+            vec![
+                prompt
+                    .run_alongside(cleaner.cleanup(mode, confirmation))
+                    .await,
+            ]
+        }
+        Command::Episodes => {
+            let cleaner = cleaners.episodes(config.sonarr)?;
+            vec![
+                prompt
+                    .run_alongside(cleaner.cleanup(mode, confirmation))
+                    .await,
+            ]
+        }
+        Command::Music => {
+            let lidarr = config.lidarr.context("`lidarr` is not configured")?;
+            let cleaner = cleaners.music(lidarr)?;
+            vec![
+                prompt
+                    .run_alongside(cleaner.cleanup(mode, confirmation))
+                    .await,
+            ]
+        }
+        command @ (Command::All | Command::Review) => {
+            let movies_cleaner = cleaners.movies(config.radarr)?;
+            let series_cleaner = cleaners.tv(config.sonarr)?;
+            let music_cleaner = config
+                .lidarr
+                .map(|lidarr| cleaners.music(lidarr))
+                .transpose()?;
+
+            let movies_cleanup = movies_cleaner.cleanup(mode, confirmation.clone());
+            let series_cleanup = series_cleaner.cleanup(mode, confirmation.clone());
+            // the last `Confirmation` is dropped along with the closure
+            // without a music cleaner
+            let music_cleanup = music_cleaner
+                .as_ref()
+                .map(|music_cleaner| music_cleaner.cleanup(mode, confirmation));
+            let music_cleanup = async {
+                match music_cleanup {
+                    Some(music_cleanup) => Some(music_cleanup.await),
+                    None => None,
+                }
+            };
+            // a failing cleaner doesn't cancel the others, as they may be
+            // deleting items already
+            let cleanups =
+                async { Ok(tokio::join!(movies_cleanup, series_cleanup, music_cleanup)) };
+            let (movies_result, series_result, music_result) = match command {
+                Command::Review => prompt.review_alongside(cleanups).await?,
+                _ => prompt.run_alongside(cleanups).await?,
+            };
+            [Some(movies_result), Some(series_result), music_result]
+                .into_iter()
+                .flatten()
+                .collect()
+        }
+    };
+
+    // what the other cleaners did is processed before the first failure is
+    // returned
+    let mut reports = Vec::new();
+    let mut failure = None;
+    for result in results {
+        match result {
+            Ok(report) => reports.push(report),
+            Err(e) if failure.is_none() => failure = Some(e),
+            Err(e) => error!("{e:#}"),
+        }
+    }
+    let mut report = RunReport {
+        dry_run: mode == CleanupMode::DryRun,
+        cleaners: reports,
+    };
+    let download_ids = report.download_ids();
+    let downloads = if report.dry_run {
+        cleaners.download_service.list(&download_ids).await
+    } else {
+        cleaners.download_service.delete(&download_ids).await
+    };
+    report.set_torrents(&downloads.torrents);
+    // the items are gone already, failing download clients are reported
+    // along with the cleaners once the run is recorded
+    for e in downloads.failures {
+        match failure {
+            None => failure = Some(e),
+            Some(_) => error!("{e:#}"),
+        }
+    }
+    metrics.record_deleted(&report);
+    // dry runs leave the state as it was, pending items included. Neither
+    // failing keeps the others from being notified of the run
+    let saved = if report.dry_run {
+        Ok(())
+    } else {
+        cleaners.state.save().await
+    };
+    let written = report
+        .write(args.report_format, args.report_file.as_deref())
+        .await;
+    for e in [saved, written].into_iter().filter_map(Result::err) {
+        match failure {
+            None => failure = Some(e),
+            Some(_) => error!("{e:#}"),
+        }
+    }
+    notification_service.notify(&report).await;
+    if let Some(media_server) = library_refresh {
+        refresh_library(&media_server, &report).await;
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    report.check_failed()?;
+
+    Ok(!report.is_empty())
+}
+
+/// builds a client for Jellyfin (or Emby) if it is configured to scan its
+/// libraries after a cleanup
+fn library_refresh(config: &Config, http: &HttpClient) -> anyhow::Result<Option<JellyfinClient>> {
+    match (&config.jellyfin, &config.emby) {
+        (Some(jellyfin), _) if jellyfin.refresh_after_cleanup => {
+            Ok(Some(JellyfinClient::new(jellyfin, http)?))
+        }
+        (None, Some(emby)) if emby.refresh_after_cleanup => {
+            Ok(Some(JellyfinClient::emby(emby, http)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// starts a library scan after a cleanup that deleted anything. A failure is
+/// only logged, as the items are gone already and the next scheduled scan
+/// picks them up anyway
+async fn refresh_library(media_server: &JellyfinClient, report: &RunReport) {
+    if report.dry_run || report.is_empty() {
+        return;
+    }
+    match media_server.refresh_library().await {
+        Ok(()) => info!("started a library scan in {}", media_server.name()),
+        Err(e) => warn!(
+            "unable to start a library scan in {}: {e:#}",
+            media_server.name()
+        ),
+    }
+}
+
+/// builds a client for the media server configured as a source of watch history
+fn media_server(config: &Config, http: &HttpClient) -> anyhow::Result<MediaServer> {
+    let media_server: MediaServer = match (&config.jellyfin, &config.emby, &config.plex) {
+        (Some(jellyfin), _, _) => Arc::new(JellyfinClient::new(jellyfin, http)?),
+        (None, Some(emby), _) => Arc::new(JellyfinClient::emby(emby, http)?),
+        (None, None, Some(plex)) => {
+            let plex = PlexClient::new(plex, http)?;
+            match &config.tautulli {
+                Some(tautulli) => Arc::new(TautulliClient::new(tautulli, plex, http)?),
+                None => Arc::new(plex),
+            }
+        }
+        (None, None, None) => anyhow::bail!("no media server configured"),
+    };
+    match &config.trakt {
+        Some(trakt) => Ok(Arc::new(TraktClient::new(trakt, media_server, http)?)),
+        None => Ok(media_server),
+    }
+}
+
+/// everything the cleaners share, so that only the cleaners selected by the
+/// command get built. Downloads are removed once all the cleaners are done,
+/// so that a torrent shared by several of them is only deleted once
+struct Cleaners {
+    http: HttpClient,
+    media_server: MediaServer,
+    download_service: DownloadService,
+    users: Users,
+    clock: Clock,
+    deletion_limit: DeletionLimit,
+    state: StateStore,
+    ignore_confirm_threshold: bool,
+}
+
+impl Cleaners {
+    /// the `confirm_threshold` of a service overriding the global one. None
+    /// with `--ignore-confirm-threshold`
+    fn confirm_threshold(&self, service: Option<usize>) -> Option<usize> {
+        service.filter(|_| !self.ignore_confirm_threshold)
+    }
+
+    fn movies(&self, mut config: RadarrConfig) -> anyhow::Result<MoviesCleaner> {
+        config.confirm_threshold = self.confirm_threshold(config.confirm_threshold);
+        MoviesCleaner::new(
+            config,
+            &self.http,
+            self.media_server.clone(),
+            &self.users,
+            self.clock,
+            self.deletion_limit.clone(),
+            self.state.clone(),
+        )
+    }
+
+    /// series or episodes cleaner, depending on `sonarr.cleanup_mode`
+    fn tv(&self, mut config: SonarrConfig) -> anyhow::Result<TvCleaner> {
+        config.confirm_threshold = self.confirm_threshold(config.confirm_threshold);
+        TvCleaner::new(
+            config,
+            &self.http,
+            self.media_server.clone(),
+            &self.users,
+            self.clock,
+            self.deletion_limit.clone(),
+            self.state.clone(),
+        )
+    }
+
+    fn series(&self, mut config: SonarrConfig) -> anyhow::Result<SeriesCleaner> {
+        config.confirm_threshold = self.confirm_threshold(config.confirm_threshold);
+        SeriesCleaner::new(
+            config,
+            &self.http,
+            self.media_server.clone(),
+            &self.users,
+            self.clock,
+            self.deletion_limit.clone(),
+            self.state.clone(),
+        )
+    }
+
+    fn episodes(&self, mut config: SonarrConfig) -> anyhow::Result<EpisodesCleaner> {
+        config.confirm_threshold = self.confirm_threshold(config.confirm_threshold);
+        EpisodesCleaner::new(
+            config,
+            &self.http,
+            self.media_server.clone(),
+            &self.users,
+            self.clock,
+            self.deletion_limit.clone(),
+            self.state.clone(),
+        )
+    }
 
-    Ok(())
+    fn music(&self, mut config: LidarrConfig) -> anyhow::Result<MusicCleaner> {
+        config.confirm_threshold = self.confirm_threshold(config.confirm_threshold);
+        MusicCleaner::new(
+            config,
+            &self.http,
+            self.media_server.clone(),
+            &self.users,
+            self.clock,
+            self.state.clone(),
+        )
+    }
 }
@@ -0,0 +1,83 @@
+mod review;
+
+use dialoguer::{Confirm, MultiSelect};
+use std::io::IsTerminal;
+use tokio::sync::Mutex;
+
+/// cleaners run concurrently, so this lock makes sure only one prompt is
+/// rendered in the terminal at a time
+static PROMPT_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// whether sanitarr runs in a terminal, where prompts can be answered. They
+/// are rendered to stderr, so stdout may still be redirected
+pub fn is_terminal() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// the items a cleaner asks to select from
+pub struct Group {
+    /// what the items are, e.g. "movies from Radarr"
+    pub what: String,
+    /// the label of every item along with its details, e.g. when it was last
+    /// played
+    pub items: Vec<(String, String)>,
+}
+
+/// presents a terminal multi-select for every group in turn, with all the
+/// items checked, and returns the indices of those the user left selected
+pub async fn select(groups: Vec<Group>) -> anyhow::Result<Vec<Vec<usize>>> {
+    let _guard = PROMPT_LOCK.lock().await;
+
+    let selected = tokio::task::spawn_blocking(move || {
+        groups
+            .into_iter()
+            .map(|group| {
+                let labels: Vec<_> = group
+                    .items
+                    .iter()
+                    .map(|(label, details)| format!("{label}  [{details}]"))
+                    .collect();
+                MultiSelect::new()
+                    .with_prompt(format!("Select {} to delete", group.what))
+                    .defaults(&vec![true; labels.len()])
+                    .items(&labels)
+                    .interact()
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await??;
+    Ok(selected)
+}
+
+/// shows the items of all the groups on a single screen where each can be
+/// checked or unchecked, all of them checked to begin with, and returns the
+/// indices of those checked once the user confirms. Nothing is selected if
+/// the user quits instead
+pub async fn review(groups: Vec<Group>) -> anyhow::Result<Vec<Vec<usize>>> {
+    let _guard = PROMPT_LOCK.lock().await;
+
+    tokio::task::spawn_blocking(move || review::run(groups)).await?
+}
+
+/// lists the items about to be deleted, grouped by what they are (e.g.
+/// "movies from Radarr"), along with the number of downloads that may be
+/// removed with them and asks the user to confirm. Declines by default
+pub async fn confirm(groups: Vec<(String, Vec<String>)>, downloads: usize) -> anyhow::Result<bool> {
+    let _guard = PROMPT_LOCK.lock().await;
+
+    let mut prompt = "The following items will be deleted:".to_owned();
+    for (what, labels) in &groups {
+        prompt.push_str(&format!("\n{what}:"));
+        for label in labels {
+            prompt.push_str(&format!("\n  - {label}"));
+        }
+    }
+    prompt.push_str(&format!(
+        "\nalong with up to {downloads} download(s) in the download clients. Continue?"
+    ));
+    let confirmed = tokio::task::spawn_blocking(move || {
+        Confirm::new().with_prompt(prompt).default(false).interact()
+    })
+    .await??;
+    Ok(confirmed)
+}
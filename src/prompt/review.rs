@@ -0,0 +1,255 @@
+use super::Group;
+use ratatui::{
+    Frame, Terminal,
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListState, Paragraph},
+};
+use std::io;
+
+/// runs the review screen until the user confirms the checked items or quits,
+/// see [`super::review`]
+pub fn run(groups: Vec<Group>) -> anyhow::Result<Vec<Vec<usize>>> {
+    let mut review = Review::new(groups);
+    let _screen = Screen::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+    loop {
+        terminal.draw(|frame| review.render(frame))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => review.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => review.move_cursor(1),
+            KeyCode::PageUp => review.move_cursor(-10),
+            KeyCode::PageDown => review.move_cursor(10),
+            KeyCode::Char(' ') => review.toggle(),
+            KeyCode::Char('a') => review.toggle_all(),
+            KeyCode::Enter => return Ok(review.selected()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(review.nothing_selected());
+            }
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(review.nothing_selected()),
+            _ => {}
+        }
+    }
+}
+
+/// the terminal in raw mode showing the alternate screen, restored when
+/// dropped, including when the review panics
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let screen = Self;
+        execute!(io::stderr(), EnterAlternateScreen)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stderr(), LeaveAlternateScreen, cursor::Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// a line of the review screen
+#[derive(Debug, PartialEq)]
+enum Row {
+    /// the header of a group, by its index
+    Group(usize),
+    /// an item, by the index of its group and its index in the group
+    Item(usize, usize),
+}
+
+/// the items under review along with whether each of them is checked, and
+/// the row the cursor is on
+struct Review {
+    groups: Vec<Group>,
+    checked: Vec<Vec<bool>>,
+    rows: Vec<Row>,
+    cursor: usize,
+}
+
+impl Review {
+    fn new(groups: Vec<Group>) -> Self {
+        let checked = groups.iter().map(|g| vec![true; g.items.len()]).collect();
+        let rows = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, g)| {
+                let items = (0..g.items.len()).map(move |item| Row::Item(group, item));
+                std::iter::once(Row::Group(group)).chain(items)
+            })
+            .collect();
+        Self {
+            groups,
+            checked,
+            rows,
+            cursor: 0,
+        }
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        let last = self.rows.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(by).min(last);
+    }
+
+    /// checks or unchecks the item under the cursor. On the header of a group
+    /// all its items are checked, or unchecked if all of them are already
+    fn toggle(&mut self) {
+        match self.rows.get(self.cursor) {
+            Some(Row::Group(group)) => {
+                let checked = &mut self.checked[*group];
+                let check = checked.contains(&false);
+                checked.fill(check);
+            }
+            Some(Row::Item(group, item)) => {
+                let checked = &mut self.checked[*group][*item];
+                *checked = !*checked;
+            }
+            None => {}
+        }
+    }
+
+    /// checks all the items, or unchecks them if all of them are already
+    fn toggle_all(&mut self) {
+        let check = self.checked.iter().flatten().any(|checked| !checked);
+        for checked in &mut self.checked {
+            checked.fill(check);
+        }
+    }
+
+    /// the indices of the checked items of every group
+    fn selected(&self) -> Vec<Vec<usize>> {
+        self.checked
+            .iter()
+            .map(|checked| {
+                let checked = checked.iter().enumerate();
+                checked
+                    .filter_map(|(i, checked)| checked.then_some(i))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn nothing_selected(&self) -> Vec<Vec<usize>> {
+        vec![Vec::new(); self.groups.len()]
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [list_area, help_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let lines = self.rows.iter().map(|row| match *row {
+            Row::Group(group) => {
+                let checked = self.checked[group].iter().filter(|c| **c).count();
+                let total = self.checked[group].len();
+                let what = &self.groups[group].what;
+                Line::from(format!("{what} ({checked}/{total} checked)")).bold()
+            }
+            Row::Item(group, item) => {
+                let (label, details) = &self.groups[group].items[item];
+                let check = if self.checked[group][item] { "x" } else { " " };
+                Line::from(vec![
+                    Span::raw(format!("  [{check}] {label}  ")),
+                    Span::raw(details.as_str()).dark_gray(),
+                ])
+            }
+        });
+        let list = List::new(lines)
+            .block(Block::bordered().title(" Items to delete "))
+            .highlight_style(Style::new().reversed());
+        let mut state = ListState::default().with_selected(Some(self.cursor));
+        frame.render_stateful_widget(list, list_area, &mut state);
+
+        let checked = self.checked.iter().flatten().filter(|c| **c).count();
+        let help = format!(
+            "↑/↓ move  space check  a check all  enter delete {checked} checked item(s)  q quit without deleting"
+        );
+        frame.render_widget(Paragraph::new(help), help_area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    fn review() -> Review {
+        let item = |label: &str| (label.to_owned(), "never played, 1.0 GiB".to_owned());
+        Review::new(vec![
+            Group {
+                what: "movies from Radarr".to_owned(),
+                items: vec![item("Heat"), item("Ronin")],
+            },
+            Group {
+                what: "series from Sonarr".to_owned(),
+                items: vec![item("Lost")],
+            },
+        ])
+    }
+
+    #[test]
+    fn test_toggle() {
+        let mut review = review();
+        assert_eq!(review.selected(), vec![vec![0, 1], vec![0]]);
+
+        // unchecks "Ronin"
+        review.move_cursor(2);
+        review.toggle();
+        assert_eq!(review.selected(), vec![vec![0], vec![0]]);
+        // the movies header checks all the movies again, then unchecks them
+        review.move_cursor(-2);
+        review.toggle();
+        assert_eq!(review.selected(), vec![vec![0, 1], vec![0]]);
+        review.toggle();
+        assert_eq!(review.selected(), vec![vec![], vec![0]]);
+        // the cursor stays on the last row
+        review.move_cursor(10);
+        assert_eq!(review.rows[review.cursor], Row::Item(1, 0));
+        review.toggle();
+        assert_eq!(review.selected(), review.nothing_selected());
+
+        review.toggle_all();
+        assert_eq!(review.selected(), vec![vec![0, 1], vec![0]]);
+        review.toggle_all();
+        assert_eq!(review.selected(), review.nothing_selected());
+    }
+
+    #[test]
+    fn test_render() -> anyhow::Result<()> {
+        let mut review = review();
+        review.move_cursor(1);
+        review.toggle();
+        let mut terminal = Terminal::new(TestBackend::new(100, 8))?;
+        terminal.draw(|frame| review.render(frame))?;
+
+        let buffer = terminal.backend().buffer();
+        let lines: Vec<String> = (0..buffer.area.height)
+            .map(|y| {
+                let cells = (0..buffer.area.width).map(|x| buffer[(x, y)].symbol());
+                cells.collect::<String>().trim_end().to_owned()
+            })
+            .collect();
+        assert!(lines[1].starts_with("│movies from Radarr (1/2 checked)"));
+        assert!(lines[2].starts_with("│  [ ] Heat  never played, 1.0 GiB"));
+        assert!(lines[3].starts_with("│  [x] Ronin  never played, 1.0 GiB"));
+        assert!(lines[4].starts_with("│series from Sonarr (1/1 checked)"));
+        assert!(lines[7].contains("enter delete 2 checked item(s)"));
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use crate::cleaners::human_size;
+use crate::http::{DownloadClientKind, TorrentInfo};
+use anyhow::{Context, bail};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display},
+    path::Path,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// A summary of what a run did (or would do in dry-run mode)
+#[derive(Serialize)]
+pub struct RunReport {
+    pub dry_run: bool,
+    pub cleaners: Vec<CleanerReport>,
+}
+
+impl RunReport {
+    /// download ids of all the cleaners merged per client, so that a download
+    /// shared by several cleaners is only handled once
+    pub fn download_ids(&self) -> HashMap<DownloadClientKind, HashSet<String>> {
+        let mut merged: HashMap<DownloadClientKind, HashSet<String>> = HashMap::new();
+        for report in &self.cleaners {
+            for (kind, ids) in &report.download_ids {
+                merged.entry(kind.clone()).or_default().extend(ids.clone());
+            }
+        }
+        merged
+    }
+
+    /// whether anything (items or torrents) was deleted, or would be deleted
+    /// in dry-run mode
+    pub fn is_empty(&self) -> bool {
+        self.cleaners
+            .iter()
+            .all(|c| c.deleted.is_empty() && c.torrents.is_empty())
+    }
+
+    /// records the listed or deleted torrents in the reports of the cleaners
+    /// they belong to
+    pub fn set_torrents(&mut self, torrents: &[TorrentInfo]) {
+        for report in &mut self.cleaners {
+            report.torrents = torrents
+                .iter()
+                .filter(|torrent| {
+                    report
+                        .download_ids
+                        .values()
+                        .flatten()
+                        .any(|id| torrent.hash.eq_ignore_ascii_case(id))
+                })
+                .map(|torrent| torrent.name.clone())
+                .collect();
+        }
+    }
+
+    /// fails listing the items that couldn't be deleted, if any
+    pub fn check_failed(&self) -> anyhow::Result<()> {
+        let failed: Vec<&str> = self
+            .cleaners
+            .iter()
+            .flat_map(|c| &c.failed)
+            .map(|f| f.item.as_str())
+            .collect();
+        if !failed.is_empty() {
+            bail!(
+                "failed to delete {} item(s): {}",
+                failed.len(),
+                failed.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// renders the report in the given format and writes it to `file` or to
+    /// stdout if not set
+    pub async fn write(&self, format: ReportFormat, file: Option<&Path>) -> anyhow::Result<()> {
+        let rendered = match format {
+            ReportFormat::Text => self.to_string(),
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+        };
+        match file {
+            Some(path) => tokio::fs::write(path, rendered)
+                .await
+                .with_context(|| format!("failed to write report to {path:?}"))?,
+            None => println!("{rendered}"),
+        }
+        Ok(())
+    }
+}
+
+impl Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = if self.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        for (i, report) in self.cleaners.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}: {} considered, {verb} {} ({}), skipped {}, {} torrent(s)",
+                report.cleaner,
+                report.considered,
+                report.deleted.len(),
+                human_size(report.size_on_disk),
+                report.skipped.len(),
+                report.torrents.len()
+            )?;
+            for item in &report.deleted {
+                write!(f, "\n  - {item}")?;
+            }
+            for skipped in &report.skipped {
+                write!(f, "\n  ~ {} ({})", skipped.item, skipped.reason)?;
+            }
+            for failed in &report.failed {
+                write!(f, "\n  ! {} ({})", failed.item, failed.error)?;
+            }
+            for torrent in &report.torrents {
+                write!(f, "\n  * {torrent}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// results of a single cleaner. In dry-run mode `deleted` and `torrents` hold
+/// the items that would be deleted
+#[derive(Serialize, Debug, Default)]
+pub struct CleanerReport {
+    pub cleaner: &'static str,
+    /// number of items checked for eligibility
+    pub considered: usize,
+    pub deleted: Vec<String>,
+    /// items that were eligible for deletion but were left in place
+    pub skipped: Vec<SkippedItem>,
+    /// items whose deletion failed
+    pub failed: Vec<FailedItem>,
+    /// names of the torrents removed from the download clients
+    pub torrents: Vec<String>,
+    /// download ids of the deleted items per client. The downloads are
+    /// removed once all the cleaners are done
+    #[serde(skip)]
+    pub download_ids: HashMap<DownloadClientKind, HashSet<String>>,
+    /// disk space taken by the deleted items, in bytes
+    pub size_on_disk: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkippedItem {
+    pub item: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailedItem {
+    pub item: String,
+    pub error: String,
+}
+
+impl CleanerReport {
+    pub fn new(cleaner: &'static str) -> Self {
+        Self {
+            cleaner,
+            ..Default::default()
+        }
+    }
+
+    /// records the items of `before` missing from `after` as skipped for the
+    /// given reason
+    pub fn skip_missing<T: Debug>(&mut self, before: &[String], after: &[T], reason: &str) {
+        let after = labels(after);
+        self.skipped.extend(
+            before
+                .iter()
+                .filter(|item| !after.contains(item))
+                .map(|item| SkippedItem {
+                    item: item.clone(),
+                    reason: reason.to_owned(),
+                }),
+        );
+    }
+
+    /// records all the given items as skipped for the given reason
+    pub fn skip_all<T: Debug>(&mut self, items: &[T], reason: &str) {
+        self.skip_missing::<T>(&labels(items), &[], reason);
+    }
+}
+
+/// labels of items as they are listed in logs and reports
+pub fn labels<T: Debug>(items: &[T]) -> Vec<String> {
+    items.iter().map(|item| format!("{item:?}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_missing() {
+        let mut report = CleanerReport::new("movies");
+        let before = labels(&["a", "b", "c"]);
+        report.skip_missing(&before, &["a", "c"], "not selected");
+        report.skip_all(&["c"], "not confirmed");
+
+        let skipped: Vec<_> = report
+            .skipped
+            .iter()
+            .map(|s| (s.item.as_str(), s.reason.as_str()))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![("\"b\"", "not selected"), ("\"c\"", "not confirmed")]
+        );
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut report = RunReport {
+            dry_run: true,
+            cleaners: vec![CleanerReport::new("movies"), CleanerReport::new("series")],
+        };
+        assert!(report.is_empty());
+        report.cleaners[1].torrents.push("series.mkv".to_owned());
+        assert!(!report.is_empty());
+    }
+
+    #[test]
+    fn test_failed_items() {
+        let mut report = RunReport {
+            dry_run: false,
+            cleaners: vec![cleaner_report("movies", &["a"])],
+        };
+        assert!(report.check_failed().is_ok());
+
+        report.cleaners[0].failed.push(FailedItem {
+            item: "Movie(1)".to_owned(),
+            error: "not found".to_owned(),
+        });
+        // the cleaner only reports the downloads of the items it deleted
+        assert_eq!(report.download_ids().len(), 1);
+        assert!(report.to_string().contains("\n  ! Movie(1) (not found)"));
+        let error = report.check_failed().unwrap_err().to_string();
+        assert_eq!(error, "failed to delete 1 item(s): Movie(1)");
+    }
+
+    fn cleaner_report(cleaner: &'static str, ids: &[&str]) -> CleanerReport {
+        let ids = ids.iter().map(|id| id.to_string()).collect();
+        CleanerReport {
+            download_ids: HashMap::from([(DownloadClientKind::Qbittorrent, ids)]),
+            ..CleanerReport::new(cleaner)
+        }
+    }
+
+    #[test]
+    fn test_torrents_shared_between_cleaners() {
+        let mut report = RunReport {
+            dry_run: false,
+            cleaners: vec![
+                cleaner_report("movies", &["a", "b"]),
+                cleaner_report("series", &["b", "c"]),
+            ],
+        };
+        let download_ids = report.download_ids();
+        let expected: HashSet<String> = ["a", "b", "c"].map(str::to_owned).into();
+        assert_eq!(download_ids.len(), 1);
+        assert_eq!(download_ids[&DownloadClientKind::Qbittorrent], expected);
+
+        let torrents = ["A", "B", "C"].map(|hash| TorrentInfo {
+            hash: hash.to_owned(),
+            name: format!("torrent {hash}"),
+            ..Default::default()
+        });
+        report.set_torrents(&torrents);
+        assert_eq!(report.cleaners[0].torrents, vec!["torrent A", "torrent B"]);
+        assert_eq!(report.cleaners[1].torrents, vec!["torrent B", "torrent C"]);
+    }
+
+    #[test]
+    fn test_json_report() -> anyhow::Result<()> {
+        let report = RunReport {
+            dry_run: true,
+            cleaners: vec![CleanerReport {
+                cleaner: "movies",
+                considered: 2,
+                deleted: vec!["Movie(1)".to_owned()],
+                skipped: vec![SkippedItem {
+                    item: "Other(2)".to_owned(),
+                    reason: "not selected".to_owned(),
+                }],
+                torrents: vec!["movie.mkv".to_owned()],
+                size_on_disk: 1024,
+                ..Default::default()
+            }],
+        };
+        let json: serde_json::Value = serde_json::to_value(&report)?;
+        assert_eq!(json["dry_run"], true);
+        assert_eq!(json["cleaners"][0]["cleaner"], "movies");
+        assert_eq!(json["cleaners"][0]["deleted"][0], "Movie(1)");
+        assert_eq!(json["cleaners"][0]["skipped"][0]["reason"], "not selected");
+        assert_eq!(json["cleaners"][0]["torrents"][0], "movie.mkv");
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use crate::config::ClockConfig;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, warn};
+use std::time::Duration;
+
+/// A reference clock used for computing retention dates. By default it's the
+/// local clock, but it can be shifted to match the clock of the media server
+/// (which is the one producing `last_played_date` values) when the two differ
+#[derive(Clone, Copy)]
+pub struct Clock {
+    offset: TimeDelta,
+    max_skew: Duration,
+}
+
+impl Clock {
+    pub fn new(config: &ClockConfig) -> Self {
+        Self {
+            offset: TimeDelta::zero(),
+            max_skew: config.max_skew,
+        }
+    }
+
+    /// current time according to this clock
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset
+    }
+
+    /// use the given server time as the reference for [`Clock::now`]
+    pub fn sync_with(&mut self, server_time: DateTime<Utc>) {
+        self.offset = skew(server_time, Utc::now());
+        debug!("using server clock as reference, offset is {}", self.offset);
+    }
+
+    /// compares the given server time to the local clock and logs a warning if
+    /// the difference exceeds the configured threshold. Returns the skew
+    pub fn check_skew(&self, service: &str, server_time: DateTime<Utc>) -> TimeDelta {
+        let skew = skew(server_time, Utc::now());
+        if exceeds(skew, self.max_skew) {
+            warn!(
+                "clock of {service} differs from the local clock by {}s, retention periods may \
+                 not behave as expected. Check time settings on both hosts",
+                skew.num_seconds()
+            );
+        } else {
+            debug!("clock skew with {service} is {}s", skew.num_seconds());
+        }
+        skew
+    }
+}
+
+/// the difference between the server and the local clock. Positive values mean
+/// the server is ahead
+fn skew(server_time: DateTime<Utc>, local_time: DateTime<Utc>) -> TimeDelta {
+    server_time - local_time
+}
+
+fn exceeds(skew: TimeDelta, max_skew: Duration) -> bool {
+    skew.abs().to_std().unwrap_or_default() > max_skew
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew_within_threshold() {
+        let local = Utc::now();
+        let server = local + Duration::from_secs(30);
+        assert!(!exceeds(skew(server, local), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_skew_exceeds_threshold_server_behind() {
+        let local = Utc::now();
+        let server = local - Duration::from_secs(3600);
+        let skew = skew(server, local);
+        assert_eq!(skew.num_seconds(), -3600);
+        assert!(exceeds(skew, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_synced_clock_follows_server() {
+        let mut clock = Clock::new(&ClockConfig::default());
+        clock.sync_with(Utc::now() + Duration::from_secs(600));
+        let delta = clock.now() - Utc::now();
+        assert!((delta.num_seconds() - 600).abs() <= 1);
+    }
+}
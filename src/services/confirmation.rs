@@ -0,0 +1,516 @@
+use crate::{
+    prompt::{self, Group},
+    services::DeletionLimit,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::fmt::{self, Debug};
+use tokio::sync::{mpsc, oneshot};
+
+/// Lets the cleaners of a run ask the user which items to delete and to
+/// confirm their deletions, while the user is asked only once all of them
+/// gathered their items. The items of all the cleaners are weighed against
+/// the [`DeletionLimit`] and the global `confirm_threshold` at once too. Every cleaner gets its own clone and
+/// either asks with it or drops it, e.g. when it has nothing to delete, see
+/// [`ConfirmationPrompt`]
+#[derive(Clone)]
+pub struct Confirmation {
+    selections: Option<mpsc::UnboundedSender<Selection>>,
+    limits: Option<mpsc::UnboundedSender<Limit>>,
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+/// Prompts for the selections and deletions asked for through
+/// [`Confirmation`] once every cleaner has either asked or dropped its
+/// `Confirmation`
+pub struct ConfirmationPrompt {
+    selections: mpsc::UnboundedReceiver<Selection>,
+    limits: mpsc::UnboundedReceiver<Limit>,
+    requests: mpsc::UnboundedReceiver<Request>,
+    deletion_limit: DeletionLimit,
+    /// the global `confirm_threshold`, `None` with `--ignore-confirm-threshold`
+    confirm_threshold: Option<usize>,
+    /// whether the deletion can be confirmed in a terminal, see
+    /// [`prompt::is_terminal`]
+    terminal: bool,
+}
+
+struct Selection {
+    group: Group,
+    selected: oneshot::Sender<Vec<usize>>,
+}
+
+struct Limit {
+    items: Vec<Weighed>,
+    kept: oneshot::Sender<Vec<usize>>,
+}
+
+/// an item weighed against the [`DeletionLimit`], along with the cleaner's
+/// limit request it comes from
+struct Weighed {
+    limit: usize,
+    index: usize,
+    label: String,
+    last_played: Option<DateTime<Utc>>,
+    size: u64,
+}
+
+impl Debug for Weighed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+struct Request {
+    what: String,
+    labels: Vec<String>,
+    downloads: usize,
+    /// whether the user has to confirm the deletion, whatever the global
+    /// `confirm_threshold`
+    ask: bool,
+    /// whether the service overrides the global `confirm_threshold`, so that
+    /// its items don't count towards it
+    own_threshold: bool,
+    /// whether the deletion was confirmed, or why it was refused
+    confirmed: oneshot::Sender<Result<bool, String>>,
+}
+
+impl Confirmation {
+    pub fn channel(
+        deletion_limit: DeletionLimit,
+        confirm_threshold: Option<usize>,
+    ) -> (Self, ConfirmationPrompt) {
+        let (selections, selection_receiver) = mpsc::unbounded_channel();
+        let (limits, limit_receiver) = mpsc::unbounded_channel();
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let confirmation = Self {
+            selections: Some(selections),
+            limits: Some(limits),
+            requests,
+        };
+        let prompt = ConfirmationPrompt {
+            selections: selection_receiver,
+            limits: limit_receiver,
+            requests: receiver,
+            deletion_limit,
+            confirm_threshold,
+            terminal: prompt::is_terminal(),
+        };
+        (confirmation, prompt)
+    }
+
+    /// asks the user which of the given items (e.g. "movies from Radarr") to
+    /// delete, each shown along with its details (e.g. when it was last
+    /// played), once the other cleaners gathered their items too. All of them
+    /// are selected to begin with. Can be asked only once, the deletion has to
+    /// be confirmed still
+    pub async fn select<T: Debug>(
+        &mut self,
+        what: &str,
+        items: Vec<T>,
+        details: impl Fn(&T) -> String,
+    ) -> anyhow::Result<Vec<T>> {
+        let selections = self
+            .selections
+            .take()
+            .context("the items to delete were selected already")?;
+        let group = Group {
+            what: what.to_owned(),
+            items: items
+                .iter()
+                .map(|item| (format!("{item:?}"), details(item)))
+                .collect(),
+        };
+        let (selected, answer) = oneshot::channel();
+        let selection = Selection { group, selected };
+        selections
+            .send(selection)
+            .ok()
+            .context("the confirmation prompt is not running")?;
+        // the prompt waits for every cleaner to be done gathering its items
+        drop(selections);
+        let selected = answer
+            .await
+            .context("nothing was selected as the confirmation prompt failed")?;
+        let selected = items
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, item)| selected.contains(&i).then_some(item))
+            .collect();
+        Ok(selected)
+    }
+
+    /// keeps as many of the given items as the [`DeletionLimit`] allows, once
+    /// the items of the other cleaners are known too. Items last played the
+    /// longest ago are kept first, whichever cleaner they come from. Can be
+    /// asked only once, after the items were selected
+    pub async fn limit<T: Debug>(
+        &mut self,
+        items: Vec<T>,
+        last_played: impl Fn(&T) -> Option<DateTime<Utc>>,
+        size: impl Fn(&T) -> u64,
+    ) -> anyhow::Result<Vec<T>> {
+        // nothing is selected anymore
+        self.selections = None;
+        let limits = self
+            .limits
+            .take()
+            .context("the items to delete were limited already")?;
+        let weighed = items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| Weighed {
+                limit: 0,
+                index,
+                label: format!("{item:?}"),
+                last_played: last_played(item),
+                size: size(item),
+            })
+            .collect();
+        let (kept, answer) = oneshot::channel();
+        let limit = Limit {
+            items: weighed,
+            kept,
+        };
+        limits
+            .send(limit)
+            .ok()
+            .context("the confirmation prompt is not running")?;
+        // the prompt waits for every cleaner to be done selecting its items
+        drop(limits);
+        let kept = answer
+            .await
+            .context("the deletion limit wasn't applied as the confirmation prompt failed")?;
+        let kept = items
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, item)| kept.contains(&i).then_some(item))
+            .collect();
+        Ok(kept)
+    }
+
+    /// hands the deletion of the given items over, along with those of the
+    /// other cleaners, and waits for it to be confirmed. The user is asked if
+    /// `ask` is set for any of the cleaners or if the items of the cleaners
+    /// without their `own_threshold` are more than the global
+    /// `confirm_threshold`. Above it, the deletion is refused without a
+    /// terminal to confirm it in
+    pub async fn confirm(
+        self,
+        what: &str,
+        labels: Vec<String>,
+        downloads: usize,
+        ask: bool,
+        own_threshold: bool,
+    ) -> anyhow::Result<bool> {
+        let (confirmed, answer) = oneshot::channel();
+        let request = Request {
+            what: what.to_owned(),
+            labels,
+            downloads,
+            ask,
+            own_threshold,
+            confirmed,
+        };
+        self.requests
+            .send(request)
+            .ok()
+            .context("the confirmation prompt is not running")?;
+        // the prompt waits for every `Confirmation` to be gone
+        drop(self);
+        answer
+            .await
+            .context("the deletion wasn't confirmed as the confirmation prompt failed")?
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+impl ConfirmationPrompt {
+    /// runs the given cleanups, prompting for the selections and deletions
+    /// they ask for once all of them did ask or gave up on asking. The
+    /// `Confirmation`s handed to the cleanups must be the only ones left
+    pub async fn run_alongside<T>(
+        self,
+        cleanups: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let prompt = self.answer(
+            async |groups| prompt::select(groups).await,
+            async |groups, downloads| prompt::confirm(groups, downloads).await,
+        );
+        let (result, ()) = tokio::try_join!(cleanups, prompt)?;
+        Ok(result)
+    }
+
+    /// like [`Self::run_alongside`], but the items of all the cleanups are
+    /// selected on a single review screen, see [`prompt::review`]. Confirming
+    /// the selection there confirms the deletion as well
+    pub async fn review_alongside<T>(
+        self,
+        cleanups: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let prompt = self.answer(
+            async |groups| prompt::review(groups).await,
+            async |_, _| Ok(true),
+        );
+        let (result, ()) = tokio::try_join!(cleanups, prompt)?;
+        Ok(result)
+    }
+
+    /// waits for all the selections and answers them at once with `select`,
+    /// if there are any, then weighs all the items to limit against the
+    /// [`DeletionLimit`] at once, then waits for all the requests and answers
+    /// them with a single `ask`, if any of them has to be confirmed
+    async fn answer(
+        mut self,
+        select: impl AsyncFnOnce(Vec<Group>) -> anyhow::Result<Vec<Vec<usize>>>,
+        ask: impl AsyncFnOnce(Vec<(String, Vec<String>)>, usize) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        let mut groups = Vec::new();
+        let mut senders = Vec::new();
+        while let Some(selection) = self.selections.recv().await {
+            groups.push(selection.group);
+            senders.push(selection.selected);
+        }
+        if !groups.is_empty() {
+            let selected = select(groups).await?;
+            for (sender, selected) in senders.into_iter().zip(selected) {
+                // the cleaner is gone if it failed meanwhile
+                let _ = sender.send(selected);
+            }
+        }
+
+        let mut limits = Vec::new();
+        while let Some(limit) = self.limits.recv().await {
+            limits.push(limit);
+        }
+        let mut kept = vec![Vec::new(); limits.len()];
+        let mut items = Vec::new();
+        let mut senders = Vec::new();
+        for (i, limit) in limits.into_iter().enumerate() {
+            items.extend(
+                limit
+                    .items
+                    .into_iter()
+                    .map(|item| Weighed { limit: i, ..item }),
+            );
+            senders.push(limit.kept);
+        }
+        let items = self
+            .deletion_limit
+            .select(items, |item| item.last_played, |item| item.size);
+        for item in items {
+            kept[item.limit].push(item.index);
+        }
+        for (sender, kept) in senders.into_iter().zip(kept) {
+            // the cleaner is gone if it failed meanwhile
+            let _ = sender.send(kept);
+        }
+
+        let mut requests = Vec::new();
+        while let Some(request) = self.requests.recv().await {
+            requests.push(request);
+        }
+        if requests.is_empty() {
+            return Ok(());
+        }
+        let mut asking = requests.iter().any(|r| r.ask);
+        let count = requests
+            .iter()
+            .filter(|r| !r.own_threshold)
+            .map(|r| r.labels.len())
+            .sum::<usize>();
+        match self
+            .confirm_threshold
+            .filter(|&threshold| count > threshold)
+        {
+            Some(threshold) if asking => warn!(
+                "{count} items are about to be deleted, more than `confirm_threshold` ({threshold})"
+            ),
+            Some(threshold) if self.terminal => {
+                warn!(
+                    "{count} items are about to be deleted, more than `confirm_threshold` ({threshold}), which has to be confirmed even with `--yes`"
+                );
+                asking = true;
+            }
+            Some(threshold) => {
+                let refusal = format!(
+                    "refusing to delete {count} items, more than `confirm_threshold` ({threshold}). Check the watch history reported by the media server, then confirm the deletion in a terminal or pass `--ignore-confirm-threshold`"
+                );
+                for request in requests {
+                    let answer = if request.own_threshold {
+                        Ok(true)
+                    } else {
+                        Err(refusal.clone())
+                    };
+                    // the cleaner is gone if it failed meanwhile
+                    let _ = request.confirmed.send(answer);
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
+        let confirmed = if asking {
+            let downloads = requests.iter().map(|r| r.downloads).sum();
+            let groups = requests
+                .iter()
+                .map(|r| (r.what.clone(), r.labels.clone()))
+                .collect();
+            ask(groups, downloads).await?
+        } else {
+            true
+        };
+        for request in requests {
+            // the cleaner is gone if it failed meanwhile
+            let _ = request.confirmed.send(Ok(confirmed));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_single_prompt() -> anyhow::Result<()> {
+        let (confirmation, prompt) = Confirmation::channel(DeletionLimit::new(None, None), None);
+        let asked = Mutex::new(Vec::new());
+        let ask = async |groups: Vec<(String, Vec<String>)>, downloads| {
+            asked.lock().unwrap().push((groups, downloads));
+            Ok(true)
+        };
+        let movies =
+            confirmation
+                .clone()
+                .confirm("movies", vec!["Heat".to_owned()], 1, true, false);
+        let series =
+            confirmation
+                .clone()
+                .confirm("series", vec!["Lost".to_owned()], 2, true, false);
+        // a cleaner with nothing to delete
+        drop(confirmation);
+
+        let select = async |_| panic!("nothing to select");
+        let (movies, series, ()) = tokio::try_join!(movies, series, prompt.answer(select, ask))?;
+        assert!(movies && series);
+        let asked = asked.into_inner().unwrap();
+        assert_eq!(asked.len(), 1);
+        let (groups, downloads) = &asked[0];
+        assert_eq!(groups.len(), 2);
+        assert_eq!(*downloads, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nothing_to_confirm() -> anyhow::Result<()> {
+        let (confirmation, prompt) = Confirmation::channel(DeletionLimit::new(None, None), None);
+        drop(confirmation);
+        prompt
+            .answer(
+                async |_| panic!("nothing to select"),
+                async |_, _| panic!("nothing to confirm"),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_select_before_confirming() -> anyhow::Result<()> {
+        let (confirmation, prompt) = Confirmation::channel(DeletionLimit::new(None, None), None);
+        let prompts = Mutex::new(Vec::new());
+        let select = async |groups: Vec<Group>| {
+            prompts
+                .lock()
+                .unwrap()
+                .extend(groups.iter().map(|g| g.what.clone()));
+            // keeps only the first item of every group
+            Ok(groups.iter().map(|_| vec![0]).collect())
+        };
+        let ask = async |groups: Vec<(String, Vec<String>)>, _| {
+            // every group was selected at once before the single confirmation
+            assert_eq!(prompts.lock().unwrap().len(), 2);
+            assert_eq!(groups.len(), 2);
+            Ok(true)
+        };
+        let cleanup = |mut confirmation: Confirmation,
+                       what: &'static str,
+                       items: Vec<&'static str>| async move {
+            let selected = confirmation.select(what, items, |_| String::new()).await?;
+            let labels = selected.iter().map(ToString::to_string).collect();
+            let confirmed = confirmation.confirm(what, labels, 0, true, false).await?;
+            anyhow::Ok((selected, confirmed))
+        };
+        let movies = cleanup(confirmation.clone(), "movies", vec!["Heat", "Ronin"]);
+        let series = cleanup(confirmation, "series", vec!["Lost"]);
+
+        let (movies, series, ()) = tokio::try_join!(movies, series, prompt.answer(select, ask))?;
+        assert_eq!(movies, (vec!["Heat"], true));
+        assert_eq!(series, (vec!["Lost"], true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limit_across_cleaners() -> anyhow::Result<()> {
+        let (confirmation, prompt) = Confirmation::channel(DeletionLimit::new(Some(2), None), None);
+        let days_ago = |days: i64| Some(Utc::now() - chrono::Duration::days(days));
+        let cleanup = |mut confirmation: Confirmation, items: Vec<(&'static str, i64)>| async move {
+            confirmation
+                .limit(items, |(_, days)| days_ago(*days), |_| 1)
+                .await
+        };
+        let movies = cleanup(confirmation.clone(), vec![("Heat", 10), ("Ronin", 30)]);
+        let series = cleanup(confirmation, vec![("Lost", 20)]);
+
+        let select = async |_| panic!("nothing to select");
+        let ask = async |_, _| panic!("nothing to confirm");
+        let (movies, series, ()) = tokio::try_join!(movies, series, prompt.answer(select, ask))?;
+        // the two oldest items, whichever cleaner they come from
+        assert_eq!(movies, vec![("Ronin", 30)]);
+        assert_eq!(series, vec![("Lost", 20)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_confirm_threshold_across_cleaners() -> anyhow::Result<()> {
+        let run = async |terminal: bool| {
+            let (confirmation, mut prompt) =
+                Confirmation::channel(DeletionLimit::new(None, None), Some(2));
+            prompt.terminal = terminal;
+            let asked = Mutex::new(false);
+            let ask = async |_, _| {
+                *asked.lock().unwrap() = true;
+                Ok(true)
+            };
+            let labels = |labels: &[&str]| labels.iter().map(ToString::to_string).collect();
+            // each cleaner is below the threshold with `--yes`, all of them
+            // together aren't
+            let movies =
+                confirmation
+                    .clone()
+                    .confirm("movies", labels(&["Heat", "Ronin"]), 0, false, false);
+            let series = confirmation
+                .clone()
+                .confirm("series", labels(&["Lost"]), 0, false, false);
+            // overrides the threshold, so it doesn't count towards it
+            let albums = confirmation.confirm("albums", labels(&["Blue", "Kid A"]), 0, false, true);
+            let select = async |_| panic!("nothing to select");
+            let (movies, series, albums, ()) = tokio::join!(movies, series, albums, async {
+                prompt.answer(select, ask).await.unwrap()
+            });
+            (movies, series, albums, asked.into_inner().unwrap())
+        };
+
+        let (movies, series, albums, asked) = run(true).await;
+        assert!(movies? && series? && albums?);
+        assert!(asked);
+
+        // refused without a terminal to confirm in
+        let (movies, series, albums, asked) = run(false).await;
+        assert!(movies.is_err() && series.is_err());
+        assert!(albums?);
+        assert!(!asked);
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::cleaners::human_size;
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+/// Caps the number of items and the number of bytes deleted in a single run.
+/// The limits are shared by all cleaners, whose items are weighed against
+/// them at once (see [`crate::services::Confirmation`]). It also keeps track
+/// of the space the cleaners are about to free on each disk, so that they
+/// don't each delete enough to reach `min_free_space` on a disk they share
+#[derive(Clone)]
+pub struct DeletionLimit {
+    /// `None` if not limited
+    max_items: Option<usize>,
+    /// `None` if not limited
+    max_bytes: Option<u64>,
+    /// bytes being freed per disk path
+    freeing: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DeletionLimit {
+    pub fn new(max_delete: Option<usize>, max_free: Option<u64>) -> Self {
+        Self {
+            max_items: max_delete,
+            max_bytes: max_free,
+            freeing: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// lets `select` pick the items to delete from the given disk knowing how
+    /// many bytes other cleaners are already freeing on it, and adds the bytes
+    /// the picked items free. The disk stays locked meanwhile, so concurrent
+    /// cleaners take turns
+    pub fn free_space_on<T>(&self, disk: &str, select: impl FnOnce(u64) -> (T, u64)) -> T {
+        let mut freeing = self.freeing.lock().unwrap();
+        let freeing = freeing.entry(disk.to_owned()).or_default();
+        let (items, freed) = select(*freeing);
+        *freeing += freed;
+        items
+    }
+
+    /// keeps as many of the given items as the limits allow. Items last
+    /// played the longest ago are preferred, never played ones come first
+    pub fn select<T, F, S>(&self, mut items: Vec<T>, last_played: F, size: S) -> Vec<T>
+    where
+        T: Debug,
+        F: Fn(&T) -> Option<DateTime<Utc>>,
+        S: Fn(&T) -> u64,
+    {
+        let total_size: u64 = items.iter().map(&size).sum();
+        let items_fit = self.max_items.is_none_or(|max| items.len() <= max);
+        let bytes_fit = self.max_bytes.is_none_or(|max| total_size <= max);
+        if items_fit && bytes_fit {
+            return items;
+        }
+
+        items.sort_by_key(|item| last_played(item));
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut limit = "--max-delete";
+        for item in &items {
+            if self.max_items.is_some_and(|max| count >= max) {
+                break;
+            }
+            let item_size = size(item);
+            if self.max_bytes.is_some_and(|max| bytes + item_size > max) {
+                limit = "--max-free";
+                break;
+            }
+            count += 1;
+            bytes += item_size;
+        }
+        let skipped = items.split_off(count);
+        warn!(
+            "`{limit}` limit reached after {} item(s) ({}), skipping {}: {skipped:?}",
+            count,
+            human_size(bytes),
+            skipped.len()
+        );
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn days_ago(days: u64) -> Option<DateTime<Utc>> {
+        Some(Utc::now() - Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let limit = DeletionLimit::new(None, None);
+        let items = limit.select(vec![1, 2, 3], |_| None, |_| 1);
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_keeps_oldest_items() {
+        let limit = DeletionLimit::new(Some(2), None);
+        let last_played = |item: &u64| match item {
+            1 => days_ago(10),
+            2 => days_ago(30),
+            _ => None,
+        };
+        let items = limit.select(vec![1, 2, 3], last_played, |_| 1);
+        assert_eq!(items, vec![3, 2]);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let limit = DeletionLimit::new(None, Some(100));
+        let last_played = |item: &u64| days_ago(*item);
+        // sizes are the items themselves, the oldest (largest) ones go first
+        let items = limit.select(vec![10, 30, 40, 50], last_played, |item| *item);
+        assert_eq!(items, vec![50, 40]);
+    }
+
+    #[test]
+    fn test_free_space_shared_per_disk() {
+        let limit = DeletionLimit::new(None, None);
+        assert_eq!(limit.free_space_on("/data", |freeing| (freeing, 30)), 0);
+        assert_eq!(
+            limit
+                .clone()
+                .free_space_on("/data", |freeing| (freeing, 20)),
+            30
+        );
+        assert_eq!(limit.free_space_on("/media", |freeing| (freeing, 0)), 0);
+        assert_eq!(limit.free_space_on("/data", |freeing| (freeing, 0)), 50);
+    }
+
+    #[test]
+    fn test_count_and_size_limits() {
+        let limit = DeletionLimit::new(Some(2), Some(100));
+        let items = limit.select(vec![10, 20], |_| None, |item| *item);
+        assert_eq!(items, vec![10, 20]);
+
+        let items = limit.select(vec![10, 20, 30], |_| None, |item| *item);
+        assert_eq!(items, vec![10, 20]);
+        let items = limit.select(vec![60, 50], |_| None, |item| *item);
+        assert_eq!(items, vec![60]);
+    }
+}
@@ -1,79 +1,463 @@
-use crate::config::DownloadClientsConfig;
-use crate::http::{DelugeClient, QbittorrentClient, TorrentClient, TorrentClientKind};
-use log::{debug, error, info};
+use crate::cleaners::human_size;
+use crate::config::{DownloadClientsConfig, TorrentAction};
+use crate::http::{
+    DelugeClient, DownloadClientKind, HttpClient, QbittorrentClient, RtorrentClient, SabnzbdClient,
+    TorrentClient, TorrentInfo,
+};
+use anyhow::Context;
+use log::{debug, error, info, warn};
 use std::{
     collections::{HashMap, HashSet},
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 /// This is a high level service that interacts with various Download clients,
 /// that you define in a config file, through their API
 #[derive(Clone)]
-pub struct DownloadService(Arc<HashMap<TorrentClientKind, GenericClient>>);
+pub struct DownloadService {
+    clients: Arc<HashMap<DownloadClientKind, GenericClient>>,
+    keep_rules: Arc<HashMap<DownloadClientKind, KeepRules>>,
+    /// grabs sent to SABnzbd are only looked up in (and removed from) its
+    /// history, as there is nothing to seed
+    sabnzbd: Option<Arc<SabnzbdClient>>,
+}
 
 type GenericClient = Box<dyn TorrentClient + Send + Sync>;
 
+/// the torrents listed or deleted in a run, along with the failures of the
+/// clients that couldn't be processed
+#[derive(Default)]
+pub struct Processed {
+    pub torrents: Vec<TorrentInfo>,
+    pub failures: Vec<anyhow::Error>,
+}
+
+impl Processed {
+    fn add(&mut self, result: anyhow::Result<Vec<TorrentInfo>>) {
+        match result {
+            Ok(torrents) => self.torrents.extend(torrents),
+            Err(e) => self.failures.push(e),
+        }
+    }
+}
+
+/// name of the tag added to torrents with `torrent_action = "tag"`
+const TAG: &str = "sanitarr";
+
+/// per client rules that prevent torrents from being deleted
+#[derive(Clone, Debug, Default)]
+pub struct KeepRules {
+    pub seed_goal: SeedGoal,
+    /// hostnames of trackers whose torrents are never deleted
+    pub protected_trackers: Vec<String>,
+    /// only remove torrents from the client, keeping the downloaded files
+    pub keep_files: bool,
+    /// keep the downloaded files of a torrent if any of them is hardlinked
+    pub keep_hardlinked_files: bool,
+    /// pause or tag the torrents instead of deleting them
+    pub action: TorrentAction,
+}
+
+/// seeding thresholds a torrent has to reach before it may be deleted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SeedGoal {
+    pub min_ratio: Option<f64>,
+    pub min_seed_time: Option<Duration>,
+}
+
+impl SeedGoal {
+    /// checks whether the torrent has reached all the configured thresholds.
+    /// Values not reported by the client are considered unmet
+    fn is_met(&self, torrent: &TorrentInfo) -> bool {
+        let ratio_met = self
+            .min_ratio
+            .is_none_or(|min| torrent.ratio.is_some_and(|ratio| ratio >= min));
+        let time_met = self
+            .min_seed_time
+            .is_none_or(|min| torrent.seeding_time.is_some_and(|time| time >= min));
+        ratio_met && time_met
+    }
+}
+
+impl KeepRules {
+    /// checks whether the torrent may be deleted, logging the reason if it
+    /// has to be kept
+    fn allow_deletion(&self, torrent: &TorrentInfo, kind: &DownloadClientKind) -> bool {
+        if let Some(tracker) = torrent.protected_tracker(&self.protected_trackers) {
+            info!(
+                "retaining torrent \"{}\" in \"{kind}\" as it belongs to a protected tracker \"{tracker}\"",
+                torrent.name
+            );
+            return false;
+        }
+        if !self.seed_goal.is_met(torrent) {
+            info!(
+                "retaining torrent \"{}\" in \"{kind}\" as it hasn't reached its seeding goal yet (ratio: {:?}, seeding time: {:?})",
+                torrent.name, torrent.ratio, torrent.seeding_time
+            );
+            return false;
+        }
+        true
+    }
+
+    /// checks whether deleting the torrent frees the space of its files, i.e.
+    /// it is neither paused nor tagged, and its files are deleted along with it
+    fn frees_space(&self, torrent: &TorrentInfo) -> bool {
+        self.action == TorrentAction::Delete && self.delete_files(torrent)
+    }
+
+    /// checks whether the files of the torrent should be deleted along with it
+    fn delete_files(&self, torrent: &TorrentInfo) -> bool {
+        if self.keep_files {
+            return false;
+        }
+        if !self.keep_hardlinked_files {
+            return true;
+        }
+        let Some(path) = &torrent.content_path else {
+            warn!(
+                "content path of torrent \"{}\" is unknown, keeping its files",
+                torrent.name
+            );
+            return false;
+        };
+        match has_hardlinks(path) {
+            Ok(false) => true,
+            Ok(true) => {
+                info!(
+                    "keeping files of torrent \"{}\" as some of them are hardlinked",
+                    torrent.name
+                );
+                false
+            }
+            Err(e) => {
+                warn!("unable to check hardlinks at {path:?}, keeping the files: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// checks whether a file, or any file within a directory, has more than one
+/// hard link
+#[cfg(unix)]
+fn has_hardlinks(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            if has_hardlinks(&entry?.path())? {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+    Ok(metadata.is_file() && metadata.nlink() > 1)
+}
+
+#[cfg(not(unix))]
+fn has_hardlinks(_path: &Path) -> std::io::Result<bool> {
+    Err(std::io::Error::other(
+        "hardlink detection is only supported on unix",
+    ))
+}
+
+/// the sum of the sizes of the torrents, `None` if no client reported any
+fn total_size(torrents: &[TorrentInfo]) -> Option<u64> {
+    torrents
+        .iter()
+        .filter_map(|t| t.size)
+        .reduce(|total, size| total + size)
+}
+
+/// writes the space deleting the torrents would free to the log, along with
+/// the number of torrents whose files would be kept
+fn log_freed_space(freeing: &[TorrentInfo], keeping_files: usize) {
+    let mut not_counted = Vec::new();
+    let unknown = freeing.iter().filter(|t| t.size.is_none()).count();
+    if unknown > 0 {
+        not_counted.push(format!("{unknown} torrent(s) of unknown size"));
+    }
+    if keeping_files > 0 {
+        not_counted.push(format!("{keeping_files} torrent(s) whose files are kept"));
+    }
+    match total_size(freeing) {
+        Some(size) if not_counted.is_empty() => {
+            info!("deleting the torrents would free {}", human_size(size));
+        }
+        Some(size) => info!(
+            "deleting the torrents would free {}, not counting {}",
+            human_size(size),
+            not_counted.join(" and ")
+        ),
+        None if keeping_files > 0 && unknown == 0 => {
+            info!("deleting the torrents would free no space, as all their files are kept");
+        }
+        None => {}
+    }
+}
+
 impl DownloadService {
-    pub async fn new(cfg: DownloadClientsConfig) -> anyhow::Result<Self> {
-        let mut clients: HashMap<TorrentClientKind, GenericClient> = HashMap::new();
+    pub async fn new(cfg: DownloadClientsConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        let mut keep_rules = HashMap::new();
 
         if let Some(qbittorrent_cfg) = cfg.qbittorrent {
-            let client = QbittorrentClient::new(&qbittorrent_cfg).await?;
-            clients.insert(TorrentClientKind::Qbittorrent, Box::new(client));
+            let client = QbittorrentClient::new(&qbittorrent_cfg, http).await?;
+            clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+            let rules = KeepRules {
+                seed_goal: SeedGoal {
+                    min_ratio: qbittorrent_cfg.min_seed_ratio,
+                    min_seed_time: qbittorrent_cfg.min_seed_time,
+                },
+                protected_trackers: qbittorrent_cfg.protected_trackers,
+                keep_files: !qbittorrent_cfg.delete_files,
+                keep_hardlinked_files: qbittorrent_cfg.detect_hardlinks,
+                action: qbittorrent_cfg.torrent_action,
+            };
+            keep_rules.insert(DownloadClientKind::Qbittorrent, rules);
         }
 
         if let Some(deluge_cfg) = cfg.deluge {
-            let client = DelugeClient::new(&deluge_cfg).await?;
-            clients.insert(TorrentClientKind::Deluge, Box::new(client));
+            let client = DelugeClient::new(&deluge_cfg, http).await?;
+            clients.insert(DownloadClientKind::Deluge, Box::new(client));
+            let rules = KeepRules {
+                seed_goal: SeedGoal {
+                    min_ratio: deluge_cfg.min_seed_ratio,
+                    min_seed_time: deluge_cfg.min_seed_time,
+                },
+                protected_trackers: deluge_cfg.protected_trackers,
+                keep_files: !deluge_cfg.delete_files,
+                keep_hardlinked_files: deluge_cfg.detect_hardlinks,
+                action: deluge_cfg.torrent_action,
+            };
+            keep_rules.insert(DownloadClientKind::Deluge, rules);
         }
 
-        Ok(Self(Arc::new(clients)))
+        if let Some(rtorrent_cfg) = cfg.rtorrent {
+            let client = RtorrentClient::new(&rtorrent_cfg, http)?;
+            clients.insert(DownloadClientKind::Rtorrent, Box::new(client));
+            let rules = KeepRules {
+                keep_files: !rtorrent_cfg.delete_files,
+                ..Default::default()
+            };
+            keep_rules.insert(DownloadClientKind::Rtorrent, rules);
+        }
+
+        let sabnzbd = cfg
+            .sabnzbd
+            .map(|sabnzbd_cfg| SabnzbdClient::new(&sabnzbd_cfg, http))
+            .transpose()?;
+
+        Ok(Self {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(keep_rules),
+            sabnzbd: sabnzbd.map(Arc::new),
+        })
     }
 
-    /// queries each torrent client API and retrieves torrents names. Then
-    /// writes the output to the log
-    pub async fn list(
-        &self,
-        hashes: &HashMap<TorrentClientKind, HashSet<String>>,
-    ) -> anyhow::Result<()> {
+    /// connects to each of the configured clients, logging in where they
+    /// require it, and tells for each of them whether it succeeded
+    pub async fn check_connections(
+        cfg: &DownloadClientsConfig,
+        http: &HttpClient,
+    ) -> Vec<(DownloadClientKind, anyhow::Result<()>)> {
+        let mut results = Vec::new();
+        if let Some(qbittorrent_cfg) = &cfg.qbittorrent {
+            let result = QbittorrentClient::new(qbittorrent_cfg, http).await;
+            results.push((DownloadClientKind::Qbittorrent, result.map(|_| ())));
+        }
+        if let Some(deluge_cfg) = &cfg.deluge {
+            let result = DelugeClient::new(deluge_cfg, http).await;
+            results.push((DownloadClientKind::Deluge, result.map(|_| ())));
+        }
+        if let Some(rtorrent_cfg) = &cfg.rtorrent {
+            let result = async {
+                RtorrentClient::new(rtorrent_cfg, http)?
+                    .check_connection()
+                    .await
+            };
+            results.push((DownloadClientKind::Rtorrent, result.await));
+        }
+        if let Some(sabnzbd_cfg) = &cfg.sabnzbd {
+            let result = async {
+                SabnzbdClient::new(sabnzbd_cfg, http)?
+                    .check_connection()
+                    .await
+            };
+            results.push((DownloadClientKind::Sabnzbd, result.await));
+        }
+        results
+    }
+
+    /// queries each torrent client API and retrieves the torrents by the given
+    /// hashes that deleting would remove, applying the keep rules of their
+    /// client. Then writes their names, and the space deleting them would
+    /// free, to the log. A failing client doesn't stop the others from being
+    /// queried
+    pub async fn list(&self, hashes: &HashMap<DownloadClientKind, HashSet<String>>) -> Processed {
+        let mut processed = Processed::default();
+        let mut freeing = Vec::new();
+        let mut keeping_files = 0;
         for (kind, hashes) in hashes {
-            let Some(client) = self.get_client(kind) else {
-                error!("unable to list torrents {hashes:?}, no client \"{kind}\" is configured");
-                continue;
+            let listed = self
+                .list_from(kind, hashes)
+                .await
+                .with_context(|| format!("unable to list the downloads in \"{kind}\""));
+            if let Ok(torrents) = &listed {
+                let rules = self.keep_rules.get(kind).cloned().unwrap_or_default();
+                for torrent in torrents {
+                    if rules.frees_space(torrent) {
+                        freeing.push(torrent.clone());
+                    } else {
+                        keeping_files += 1;
+                    }
+                }
+            }
+            processed.add(listed);
+        }
+        log_freed_space(&freeing, keeping_files);
+        processed
+    }
+
+    async fn list_from(
+        &self,
+        kind: &DownloadClientKind,
+        hashes: &HashSet<String>,
+    ) -> anyhow::Result<Vec<TorrentInfo>> {
+        if *kind == DownloadClientKind::Sabnzbd {
+            let Some(sabnzbd) = &self.sabnzbd else {
+                error!("unable to list downloads {hashes:?}, no client \"{kind}\" is configured");
+                return Ok(Vec::new());
             };
-            let names = client.list_torrents(hashes).await?;
-            info!("found the following torrents for deletion: {names:?}");
+            let downloads = sabnzbd.list_history(hashes).await?;
+            let names: Vec<&str> = downloads.iter().map(|d| d.name.as_str()).collect();
+            info!("found the following downloads in \"{kind}\" history: {names:?}");
+            return Ok(downloads);
         }
-        Ok(())
+        let Some(client) = self.get_client(kind) else {
+            error!("unable to list torrents {hashes:?}, no client \"{kind}\" is configured");
+            return Ok(Vec::new());
+        };
+        let rules = self.keep_rules.get(kind).cloned().unwrap_or_default();
+        let torrents: Vec<_> = client
+            .list_torrents(hashes)
+            .await?
+            .into_iter()
+            .filter(|t| rules.allow_deletion(t, kind))
+            .collect();
+        let names: Vec<&str> = torrents.iter().map(|t| t.name.as_str()).collect();
+        info!("found the following torrents for deletion: {names:?}");
+        Ok(torrents)
     }
 
     /// queries each torrent client API and deletes torrents by the given
-    /// hashes.
-    pub async fn delete(
+    /// hashes, or pauses or tags them if the client is configured to. A
+    /// failing client doesn't stop the others from being processed
+    pub async fn delete(&self, hashes: &HashMap<DownloadClientKind, HashSet<String>>) -> Processed {
+        let mut processed = Processed::default();
+        for (kind, hashes) in hashes {
+            let deleted = self
+                .delete_from(kind, hashes)
+                .await
+                .with_context(|| format!("unable to delete the downloads from \"{kind}\""));
+            processed.add(deleted);
+        }
+        processed
+    }
+
+    /// deletes (or pauses or tags) the torrents of a single client, returning
+    /// the deleted ones
+    async fn delete_from(
         &self,
-        hashes: &HashMap<TorrentClientKind, HashSet<String>>,
-    ) -> anyhow::Result<()> {
-        if hashes.is_empty() {
-            return Ok(());
+        kind: &DownloadClientKind,
+        hashes: &HashSet<String>,
+    ) -> anyhow::Result<Vec<TorrentInfo>> {
+        if *kind == DownloadClientKind::Sabnzbd {
+            return self.delete_sabnzbd_history(hashes).await;
         }
-        for (kind, hashes) in hashes {
-            let Some(client) = self.get_client(kind) else {
-                error!("unable to delete torrents {hashes:?}, no client \"{kind}\" is configured");
+        let Some(client) = self.get_client(kind) else {
+            error!("unable to delete torrents {hashes:?}, no client \"{kind}\" is configured");
+            return Ok(Vec::new());
+        };
+        let torrents = client.list_torrents(hashes).await?;
+        let rules = self.keep_rules.get(kind).cloned().unwrap_or_default();
+        let deletable: Vec<_> = torrents
+            .into_iter()
+            .filter(|t| rules.allow_deletion(t, kind))
+            .collect();
+
+        if deletable.is_empty() {
+            debug!("no torrents to delete for a given client \"{kind}\", skipping");
+            return Ok(Vec::new());
+        }
+        if rules.action != TorrentAction::Delete {
+            let hashes: HashSet<String> = hashes
+                .iter()
+                .filter(|h| deletable.iter().any(|t| t.hash.eq_ignore_ascii_case(h)))
+                .cloned()
+                .collect();
+            let names: Vec<&str> = deletable.iter().map(|t| t.name.as_str()).collect();
+            if rules.action == TorrentAction::Pause {
+                client.pause_torrents(&hashes).await?;
+                info!("paused torrents {names:?} in \"{kind}\" instead of deleting them");
+            } else {
+                client.tag_torrents(&hashes, TAG).await?;
+                info!(
+                    "tagged torrents {names:?} in \"{kind}\" with \"{TAG}\" instead of deleting them"
+                );
+            }
+            return Ok(Vec::new());
+        }
+
+        // torrents are deleted in up to two batches: with and without files
+        let mut batches: HashMap<bool, (HashSet<String>, Vec<&TorrentInfo>)> = HashMap::new();
+        for torrent in &deletable {
+            let Some(hash) = hashes.iter().find(|h| torrent.hash.eq_ignore_ascii_case(h)) else {
                 continue;
             };
-            let names = client.list_torrents(hashes).await?;
-            if names.is_empty() {
-                debug!("no torrents to delete for a given client \"{kind}\", skipping");
+            let (hashes, torrents) = batches.entry(rules.delete_files(torrent)).or_default();
+            hashes.insert(hash.clone());
+            torrents.push(torrent);
+        }
+        let mut deleted = Vec::new();
+        for (delete_files, (hashes, torrents)) in batches {
+            client.delete_torrents(&hashes, delete_files).await?;
+            let names: Vec<&str> = torrents.iter().map(|t| t.name.as_str()).collect();
+            if delete_files {
+                info!("deleted torrents {names:?} and their files from \"{kind}\"");
             } else {
-                client.delete_torrents(hashes).await?;
-                info!("deleted torrents {names:?} from \"{kind}\"");
+                info!("deleted torrents {names:?} from \"{kind}\", keeping their files");
             }
+            deleted.extend(torrents.into_iter().cloned());
         }
-        Ok(())
+        Ok(deleted)
     }
 
-    fn get_client(&self, kind: &TorrentClientKind) -> Option<&GenericClient> {
-        self.0.get(kind)
+    /// removes the grabs from SABnzbd history, returning the removed ones
+    async fn delete_sabnzbd_history(
+        &self,
+        nzo_ids: &HashSet<String>,
+    ) -> anyhow::Result<Vec<TorrentInfo>> {
+        let Some(sabnzbd) = &self.sabnzbd else {
+            error!("unable to delete downloads {nzo_ids:?}, no client \"SABnzbd\" is configured");
+            return Ok(Vec::new());
+        };
+        let downloads = sabnzbd.list_history(nzo_ids).await?;
+        let ids = downloads.iter().map(|d| d.hash.clone()).collect();
+        if !sabnzbd.delete_history(&ids).await? {
+            return Ok(Vec::new());
+        }
+        let names: Vec<&str> = downloads.iter().map(|d| d.name.as_str()).collect();
+        info!("removed downloads {names:?} from \"SABnzbd\" history");
+        Ok(downloads)
+    }
+
+    fn get_client(&self, kind: &DownloadClientKind) -> Option<&GenericClient> {
+        self.clients.get(kind)
     }
 }
 
@@ -86,6 +470,9 @@ mod tests {
     struct MockTorrentClient {
         listed_hashes: Arc<Mutex<HashSet<String>>>,
         deleted_hashes: Arc<Mutex<HashSet<String>>>,
+        /// hashes of torrents deleted along with their files
+        deleted_files: Arc<Mutex<HashSet<String>>>,
+        paused_hashes: Arc<Mutex<HashSet<String>>>,
     }
 
     impl MockTorrentClient {
@@ -93,52 +480,85 @@ mod tests {
             Self {
                 listed_hashes: Arc::new(Mutex::new(HashSet::new())),
                 deleted_hashes: Arc::new(Mutex::new(HashSet::new())),
+                deleted_files: Arc::new(Mutex::new(HashSet::new())),
+                paused_hashes: Arc::new(Mutex::new(HashSet::new())),
             }
         }
     }
 
     #[async_trait]
     impl TorrentClient for MockTorrentClient {
-        async fn list_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+        async fn list_torrents(
+            &self,
+            hashes: &HashSet<String>,
+        ) -> anyhow::Result<Vec<TorrentInfo>> {
             let mut listed_hashes = self.listed_hashes.lock().unwrap();
             listed_hashes.clear();
             listed_hashes.extend(hashes.clone());
 
-            let response = ["foo", "bar", "baz"]
-                .into_iter()
-                .map(ToOwned::to_owned)
+            let response = hashes
+                .iter()
+                .map(|hash| TorrentInfo {
+                    hash: hash.to_uppercase(),
+                    name: format!("torrent {hash}"),
+                    ratio: Some(if hash == "d" { 0.5 } else { 2.0 }),
+                    seeding_time: None,
+                    trackers: vec![format!("tracker-{hash}.org")],
+                    content_path: None,
+                    size: None,
+                })
                 .collect();
             Ok(response)
         }
 
-        async fn delete_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
+        async fn delete_torrents(
+            &self,
+            hashes: &HashSet<String>,
+            delete_files: bool,
+        ) -> anyhow::Result<()> {
             let mut deleted_hashes = self.deleted_hashes.lock().unwrap();
             deleted_hashes.extend(hashes.clone());
+            if delete_files {
+                self.deleted_files.lock().unwrap().extend(hashes.clone());
+            }
+            Ok(())
+        }
+
+        async fn pause_torrents(&self, hashes: &HashSet<String>) -> anyhow::Result<()> {
+            self.paused_hashes.lock().unwrap().extend(hashes.clone());
             Ok(())
         }
     }
 
+    fn service(clients: HashMap<DownloadClientKind, GenericClient>) -> DownloadService {
+        DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::new()),
+            sabnzbd: None,
+        }
+    }
+
     #[tokio::test]
     async fn test_download_service() -> anyhow::Result<()> {
         let client = MockTorrentClient::new();
         let listed_hashes = client.listed_hashes.clone();
         let deleted_hashes = client.deleted_hashes.clone();
 
-        let mut clients: HashMap<TorrentClientKind, GenericClient> = HashMap::new();
-        clients.insert(TorrentClientKind::Qbittorrent, Box::new(client));
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
 
-        let service = DownloadService(Arc::new(clients));
+        let service = service(clients);
 
         let listed = HashSet::from(["a".to_string(), "b".to_string(), "c".to_string()]);
-        let listed_map = HashMap::from([(TorrentClientKind::Qbittorrent, listed.clone())]);
+        let listed_map = HashMap::from([(DownloadClientKind::Qbittorrent, listed.clone())]);
 
-        service.list(&listed_map).await?;
+        assert!(service.list(&listed_map).await.failures.is_empty());
         assert_eq!(*listed_hashes.lock().unwrap(), listed);
 
         let deleted = HashSet::from(["d".to_string(), "e".to_string(), "f".to_string()]);
-        let deleted_map = HashMap::from([(TorrentClientKind::Qbittorrent, deleted.clone())]);
+        let deleted_map = HashMap::from([(DownloadClientKind::Qbittorrent, deleted.clone())]);
 
-        service.delete(&deleted_map).await?;
+        assert!(service.delete(&deleted_map).await.failures.is_empty());
 
         assert_eq!(*listed_hashes.lock().unwrap(), deleted);
         assert_eq!(*deleted_hashes.lock().unwrap(), deleted);
@@ -152,25 +572,314 @@ mod tests {
         let listed_hashes = client.listed_hashes.clone();
         let deleted_hashes = client.deleted_hashes.clone();
 
-        let mut clients: HashMap<TorrentClientKind, GenericClient> = HashMap::new();
-        clients.insert(TorrentClientKind::Qbittorrent, Box::new(client));
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
 
-        let service = DownloadService(Arc::new(clients));
+        let service = service(clients);
 
         let listed = HashSet::from(["a".to_string(), "b".to_string(), "c".to_string()]);
-        let listed_map = HashMap::from([(TorrentClientKind::Deluge, listed)]);
+        let listed_map = HashMap::from([(DownloadClientKind::Deluge, listed)]);
 
-        service.list(&listed_map).await?;
+        assert!(service.list(&listed_map).await.failures.is_empty());
         assert!(listed_hashes.lock().unwrap().is_empty());
 
         let deleted = HashSet::from(["d".to_string(), "e".to_string(), "f".to_string()]);
-        let deleted_map = HashMap::from([(TorrentClientKind::Deluge, deleted)]);
+        let deleted_map = HashMap::from([(DownloadClientKind::Deluge, deleted)]);
 
-        service.delete(&deleted_map).await?;
+        assert!(service.delete(&deleted_map).await.failures.is_empty());
 
         assert!(listed_hashes.lock().unwrap().is_empty());
         assert!(deleted_hashes.lock().unwrap().is_empty());
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_download_service_retains_torrents_below_seed_goal() -> anyhow::Result<()> {
+        let client = MockTorrentClient::new();
+        let deleted_hashes = client.deleted_hashes.clone();
+
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+
+        let rules = KeepRules {
+            seed_goal: SeedGoal {
+                min_ratio: Some(1.0),
+                min_seed_time: None,
+            },
+            ..Default::default()
+        };
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::from([(DownloadClientKind::Qbittorrent, rules)])),
+            sabnzbd: None,
+        };
+
+        let deleted = HashSet::from(["d".to_string(), "e".to_string(), "f".to_string()]);
+        let deleted_map = HashMap::from([(DownloadClientKind::Qbittorrent, deleted)]);
+
+        assert!(service.delete(&deleted_map).await.failures.is_empty());
+
+        let expected = HashSet::from(["e".to_string(), "f".to_string()]);
+        assert_eq!(*deleted_hashes.lock().unwrap(), expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_service_retains_protected_trackers() -> anyhow::Result<()> {
+        let client = MockTorrentClient::new();
+        let deleted_hashes = client.deleted_hashes.clone();
+
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+
+        let rules = KeepRules {
+            protected_trackers: vec!["tracker-e.org".to_owned()],
+            ..Default::default()
+        };
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::from([(DownloadClientKind::Qbittorrent, rules)])),
+            sabnzbd: None,
+        };
+
+        let deleted = HashSet::from(["d".to_string(), "e".to_string(), "f".to_string()]);
+        let deleted_map = HashMap::from([(DownloadClientKind::Qbittorrent, deleted)]);
+
+        assert!(service.delete(&deleted_map).await.failures.is_empty());
+
+        let expected = HashSet::from(["d".to_string(), "f".to_string()]);
+        assert_eq!(*deleted_hashes.lock().unwrap(), expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_service_lists_deletable_torrents() -> anyhow::Result<()> {
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(
+            DownloadClientKind::Qbittorrent,
+            Box::new(MockTorrentClient::new()),
+        );
+        let rules = KeepRules {
+            seed_goal: SeedGoal {
+                min_ratio: Some(1.0),
+                min_seed_time: None,
+            },
+            ..Default::default()
+        };
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::from([(DownloadClientKind::Qbittorrent, rules)])),
+            sabnzbd: None,
+        };
+
+        let hashes = HashSet::from(["d".to_string(), "e".to_string()]);
+        let hashes_map = HashMap::from([(DownloadClientKind::Qbittorrent, hashes)]);
+
+        // "d" hasn't reached its seeding goal, so it wouldn't be deleted
+        let listed = service.list(&hashes_map).await.torrents;
+        let names: Vec<&str> = listed.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["torrent e"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frees_space() {
+        let torrent = TorrentInfo {
+            hash: "A".to_owned(),
+            name: "torrent a".to_owned(),
+            ratio: None,
+            seeding_time: None,
+            trackers: Vec::new(),
+            content_path: None,
+            size: Some(1024),
+        };
+        assert!(KeepRules::default().frees_space(&torrent));
+        let keeping_files = KeepRules {
+            keep_files: true,
+            ..Default::default()
+        };
+        assert!(!keeping_files.frees_space(&torrent));
+        // the files of hardlinked torrents are kept if their path is unknown
+        let keeping_hardlinked = KeepRules {
+            keep_hardlinked_files: true,
+            ..Default::default()
+        };
+        assert!(!keeping_hardlinked.frees_space(&torrent));
+        let pausing = KeepRules {
+            action: TorrentAction::Pause,
+            ..Default::default()
+        };
+        assert!(!pausing.frees_space(&torrent));
+    }
+
+    #[tokio::test]
+    async fn test_download_service_keeps_files() -> anyhow::Result<()> {
+        let client = MockTorrentClient::new();
+        let deleted_hashes = client.deleted_hashes.clone();
+        let deleted_files = client.deleted_files.clone();
+
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+
+        let rules = KeepRules {
+            keep_files: true,
+            ..Default::default()
+        };
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::from([(DownloadClientKind::Qbittorrent, rules)])),
+            sabnzbd: None,
+        };
+
+        let deleted = HashSet::from(["d".to_string(), "e".to_string()]);
+        let deleted_map = HashMap::from([(DownloadClientKind::Qbittorrent, deleted.clone())]);
+
+        assert!(service.delete(&deleted_map).await.failures.is_empty());
+
+        assert_eq!(*deleted_hashes.lock().unwrap(), deleted);
+        assert!(deleted_files.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_service_pauses_torrents() -> anyhow::Result<()> {
+        let client = MockTorrentClient::new();
+        let deleted_hashes = client.deleted_hashes.clone();
+        let paused_hashes = client.paused_hashes.clone();
+
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+
+        let rules = KeepRules {
+            seed_goal: SeedGoal {
+                min_ratio: Some(1.0),
+                min_seed_time: None,
+            },
+            action: TorrentAction::Pause,
+            ..Default::default()
+        };
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            keep_rules: Arc::new(HashMap::from([(DownloadClientKind::Qbittorrent, rules)])),
+            sabnzbd: None,
+        };
+
+        let hashes = HashSet::from(["d".to_string(), "e".to_string()]);
+        let hashes_map = HashMap::from([(DownloadClientKind::Qbittorrent, hashes)]);
+
+        let deleted = service.delete(&hashes_map).await.torrents;
+
+        // "d" hasn't reached its seeding goal
+        assert_eq!(
+            *paused_hashes.lock().unwrap(),
+            HashSet::from(["e".to_string()])
+        );
+        assert!(deleted_hashes.lock().unwrap().is_empty());
+        assert!(deleted.is_empty());
+
+        let tagging = DownloadService {
+            keep_rules: Arc::new(HashMap::from([(
+                DownloadClientKind::Qbittorrent,
+                KeepRules {
+                    action: TorrentAction::Tag,
+                    ..Default::default()
+                },
+            )])),
+            ..service
+        };
+        // the mock doesn't support tags
+        assert_eq!(tagging.delete(&hashes_map).await.failures.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_service_failing_client() {
+        let client = MockTorrentClient::new();
+        let deleted_hashes = client.deleted_hashes.clone();
+
+        let mut clients: HashMap<DownloadClientKind, GenericClient> = HashMap::new();
+        clients.insert(DownloadClientKind::Qbittorrent, Box::new(client));
+        clients.insert(
+            DownloadClientKind::Deluge,
+            Box::new(MockTorrentClient::new()),
+        );
+        let service = DownloadService {
+            clients: Arc::new(clients),
+            // the mock doesn't support tags
+            keep_rules: Arc::new(HashMap::from([(
+                DownloadClientKind::Deluge,
+                KeepRules {
+                    action: TorrentAction::Tag,
+                    ..Default::default()
+                },
+            )])),
+            sabnzbd: None,
+        };
+
+        let hashes_map = HashMap::from([
+            (
+                DownloadClientKind::Qbittorrent,
+                HashSet::from(["e".to_string()]),
+            ),
+            (DownloadClientKind::Deluge, HashSet::from(["f".to_string()])),
+        ]);
+        let processed = service.delete(&hashes_map).await;
+
+        // the other client is processed anyway
+        assert_eq!(processed.failures.len(), 1);
+        assert_eq!(processed.torrents.len(), 1);
+        assert_eq!(
+            *deleted_hashes.lock().unwrap(),
+            HashSet::from(["e".to_string()])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_has_hardlinks() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sanitarr-hardlinks-{}", std::process::id()));
+        let torrent_dir = dir.join("torrent");
+        std::fs::create_dir_all(&torrent_dir)?;
+        let file = torrent_dir.join("episode.mkv");
+        std::fs::write(&file, "foo")?;
+
+        assert!(!has_hardlinks(&torrent_dir)?);
+
+        std::fs::hard_link(&file, dir.join("library.mkv"))?;
+        assert!(has_hardlinks(&torrent_dir)?);
+        assert!(has_hardlinks(&file)?);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_total_size() {
+        let torrent = |size| TorrentInfo {
+            size,
+            ..Default::default()
+        };
+        assert_eq!(total_size(&[]), None);
+        assert_eq!(total_size(&[torrent(None)]), None);
+        let torrents = [torrent(Some(1000)), torrent(None), torrent(Some(24))];
+        assert_eq!(total_size(&torrents), Some(1024));
+    }
+
+    #[test]
+    fn test_seed_goal_unknown_values_are_unmet() {
+        let torrent = TorrentInfo::default();
+        assert!(SeedGoal::default().is_met(&torrent));
+
+        let goal = SeedGoal {
+            min_ratio: None,
+            min_seed_time: Some(Duration::from_secs(60)),
+        };
+        assert!(!goal.is_met(&torrent));
+    }
 }
@@ -0,0 +1,66 @@
+use anyhow::Context;
+use log::{debug, info};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// a response of an endpoint: the status line and a plain text body
+pub(super) type Response = (&'static str, String);
+
+pub(super) const OK: &str = "200 OK";
+pub(super) const NOT_FOUND: &str = "404 Not Found";
+pub(super) const SERVICE_UNAVAILABLE: &str = "503 Service Unavailable";
+
+/// serves `GET <path>` requests at `addr` from a background thread, replying
+/// to them with `respond` and to other paths with 404. Returns the address it
+/// listens on
+pub(super) fn serve<F>(
+    addr: SocketAddr,
+    path: &'static str,
+    respond: F,
+) -> anyhow::Result<SocketAddr>
+where
+    F: Fn() -> Response + Send + 'static,
+{
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("failed to listen on {addr}"))?;
+    let addr = listener.local_addr()?;
+    info!("serving http://{addr}{path}");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(stream, path, &respond) {
+                debug!("failed to serve {path}: {e}");
+            }
+        }
+    });
+    Ok(addr)
+}
+
+fn handle(
+    mut stream: TcpStream,
+    path: &str,
+    respond: &impl Fn() -> Response,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers aren't needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let requested = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = if requested == path {
+        respond()
+    } else {
+        (NOT_FOUND, String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes())
+}
@@ -0,0 +1,108 @@
+use super::endpoint::{self, Response};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tracks the runs of a daemon for liveness and readiness probes: healthy
+/// while the last run succeeded and the next one completes in time, i.e.
+/// within twice the time between runs
+#[derive(Clone)]
+pub struct Health {
+    last_run: Arc<Mutex<LastRun>>,
+    /// how long a run may take to complete after the previous one
+    max_age: Duration,
+}
+
+struct LastRun {
+    /// when the last run completed, or the daemon started before that
+    at: Instant,
+    /// `None` until the first run completes
+    succeeded: Option<bool>,
+}
+
+impl Health {
+    /// `period` is the time between runs
+    pub fn new(period: Duration) -> Self {
+        Self {
+            last_run: Arc::new(Mutex::new(LastRun {
+                at: Instant::now(),
+                succeeded: None,
+            })),
+            max_age: period * 2,
+        }
+    }
+
+    pub fn record_run(&self, succeeded: bool) {
+        *self.last_run.lock().unwrap() = LastRun {
+            at: Instant::now(),
+            succeeded: Some(succeeded),
+        };
+    }
+
+    /// 200 if healthy, 503 along with the reason otherwise
+    fn status(&self, now: Instant) -> Response {
+        let last_run = self.last_run.lock().unwrap();
+        let age = now.saturating_duration_since(last_run.at);
+        if last_run.succeeded == Some(false) {
+            (
+                endpoint::SERVICE_UNAVAILABLE,
+                "the last run failed".to_owned(),
+            )
+        } else if age > self.max_age {
+            let age = humantime::format_duration(Duration::from_secs(age.as_secs()));
+            let message = format!("no run completed in {age}");
+            (endpoint::SERVICE_UNAVAILABLE, message)
+        } else {
+            (endpoint::OK, "ok".to_owned())
+        }
+    }
+
+    /// serves the status at `http://<addr>/health` in the background and
+    /// returns the address it listens on
+    pub fn serve(&self, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let health = self.clone();
+        endpoint::serve(addr, "/health", move || health.status(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let hour = Duration::from_secs(60 * 60);
+        let health = Health::new(hour);
+        let now = Instant::now();
+        // waiting for the first run
+        assert_eq!(health.status(now).0, endpoint::OK);
+        assert_eq!(
+            health.status(now + hour * 3),
+            (
+                endpoint::SERVICE_UNAVAILABLE,
+                "no run completed in 3h".to_owned()
+            )
+        );
+
+        health.record_run(false);
+        let now = Instant::now();
+        assert_eq!(health.status(now).0, endpoint::SERVICE_UNAVAILABLE);
+        health.record_run(true);
+        assert_eq!(health.status(now).0, endpoint::OK);
+        assert_eq!(health.status(now + hour).0, endpoint::OK);
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> anyhow::Result<()> {
+        let health = Health::new(Duration::from_secs(60));
+        let addr = health.serve("127.0.0.1:0".parse()?)?;
+
+        let response = reqwest::get(format!("http://{addr}/health")).await?;
+        assert_eq!(response.status(), 200);
+        health.record_run(false);
+        let response = reqwest::get(format!("http://{addr}/health")).await?;
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.text().await?, "the last run failed");
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use super::endpoint;
+use crate::report::RunReport;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Counts what sanitarr deleted over the runs of a daemon and serves the
+/// counters to Prometheus at `/metrics`. Dry runs count towards the run
+/// durations only
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    /// keyed by the kind of item, e.g. `movie`
+    items_deleted: BTreeMap<&'static str, u64>,
+    torrents_deleted: u64,
+    bytes_freed: u64,
+    runs: u64,
+    run_duration_seconds: f64,
+}
+
+impl Metrics {
+    /// counts the items and torrents deleted by a run
+    pub fn record_deleted(&self, report: &RunReport) {
+        if report.dry_run {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        for cleaner in &report.cleaners {
+            *counters
+                .items_deleted
+                .entry(item_type(cleaner.cleaner))
+                .or_default() += cleaner.deleted.len() as u64;
+            counters.bytes_freed += cleaner.size_on_disk;
+        }
+        // a torrent shared by several cleaners is listed by each of them
+        let torrents: HashSet<&String> = report.cleaners.iter().flat_map(|c| &c.torrents).collect();
+        counters.torrents_deleted += torrents.len() as u64;
+    }
+
+    /// counts a run, whether it succeeded or not
+    pub fn record_run(&self, duration: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.runs += 1;
+        counters.run_duration_seconds += duration.as_secs_f64();
+    }
+
+    /// the counters in the Prometheus text format
+    fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (sample, value) in samples {
+                let _ = writeln!(text, "{sample} {value}");
+            }
+        };
+        let items: Vec<_> = counters
+            .items_deleted
+            .iter()
+            .map(|(kind, count)| {
+                let sample = format!("sanitarr_items_deleted_total{{type=\"{kind}\"}}");
+                (sample, count.to_string())
+            })
+            .collect();
+        metric(
+            "sanitarr_items_deleted_total",
+            "counter",
+            "Items deleted from the *arr services.",
+            &items,
+        );
+        metric(
+            "sanitarr_torrents_deleted_total",
+            "counter",
+            "Torrents deleted from the download clients.",
+            &[(
+                "sanitarr_torrents_deleted_total".to_owned(),
+                counters.torrents_deleted.to_string(),
+            )],
+        );
+        metric(
+            "sanitarr_bytes_freed_total",
+            "counter",
+            "Disk space taken by the deleted items, in bytes.",
+            &[(
+                "sanitarr_bytes_freed_total".to_owned(),
+                counters.bytes_freed.to_string(),
+            )],
+        );
+        metric(
+            "sanitarr_run_duration_seconds",
+            "summary",
+            "Duration of the cleanup runs.",
+            &[
+                (
+                    "sanitarr_run_duration_seconds_sum".to_owned(),
+                    counters.run_duration_seconds.to_string(),
+                ),
+                (
+                    "sanitarr_run_duration_seconds_count".to_owned(),
+                    counters.runs.to_string(),
+                ),
+            ],
+        );
+        text
+    }
+
+    /// serves the metrics at `http://<addr>/metrics` in the background and
+    /// returns the address it listens on
+    pub fn serve(&self, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let metrics = self.clone();
+        endpoint::serve(addr, "/metrics", move || (endpoint::OK, metrics.render()))
+    }
+}
+
+/// the `type` label of the items deleted by a cleaner
+fn item_type(cleaner: &'static str) -> &'static str {
+    match cleaner {
+        "movies" => "movie",
+        "episodes" => "episode",
+        "albums" => "album",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::CleanerReport;
+
+    fn report(dry_run: bool) -> RunReport {
+        let mut movies = CleanerReport::new("movies");
+        movies.deleted = vec!["Heat(1)".to_owned(), "Alien(2)".to_owned()];
+        movies.size_on_disk = 3000;
+        movies.torrents = vec!["Heat.1995".to_owned()];
+        let mut series = CleanerReport::new("series");
+        series.deleted = vec!["Lost(3)".to_owned()];
+        series.size_on_disk = 500;
+        series.torrents = vec!["Heat.1995".to_owned(), "Lost.S01".to_owned()];
+        RunReport {
+            dry_run,
+            cleaners: vec![movies, series],
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_deleted(&report(false));
+        metrics.record_deleted(&report(true));
+        metrics.record_run(Duration::from_millis(1500));
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE sanitarr_items_deleted_total counter\n"));
+        assert!(text.contains("sanitarr_items_deleted_total{type=\"movie\"} 2\n"));
+        assert!(text.contains("sanitarr_items_deleted_total{type=\"series\"} 1\n"));
+        assert!(text.contains("sanitarr_torrents_deleted_total 2\n"));
+        assert!(text.contains("sanitarr_bytes_freed_total 3500\n"));
+        assert!(text.contains("sanitarr_run_duration_seconds_sum 1.5\n"));
+        assert!(text.contains("sanitarr_run_duration_seconds_count 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> anyhow::Result<()> {
+        let metrics = Metrics::default();
+        metrics.record_deleted(&report(false));
+        let addr = metrics.serve("127.0.0.1:0".parse()?)?;
+
+        let response = reqwest::get(format!("http://{addr}/metrics")).await?;
+        assert_eq!(response.status(), 200);
+        assert!(
+            response
+                .text()
+                .await?
+                .contains("sanitarr_bytes_freed_total 3500")
+        );
+
+        let response = reqwest::get(format!("http://{addr}/other")).await?;
+        assert_eq!(response.status(), 404);
+        Ok(())
+    }
+}
@@ -1,3 +1,20 @@
+mod clock;
+mod confirmation;
+mod deletion_limit;
 mod download_service;
+mod endpoint;
+mod health;
+mod metrics;
+mod notification_service;
+mod state_store;
+mod trash;
 
+pub use clock::Clock;
+pub use confirmation::Confirmation;
+pub use deletion_limit::DeletionLimit;
 pub use download_service::DownloadService;
+pub use health::Health;
+pub use metrics::Metrics;
+pub use notification_service::NotificationService;
+pub use state_store::StateStore;
+pub use trash::Trash;
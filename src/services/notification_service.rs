@@ -0,0 +1,41 @@
+use crate::config::NotificationsConfig;
+use crate::http::{DiscordNotifier, HttpClient, Notifier, TelegramNotifier, WebhookNotifier};
+use crate::report::RunReport;
+use log::{info, warn};
+use std::sync::Arc;
+
+type GenericNotifier = Box<dyn Notifier + Send + Sync>;
+
+/// Sends the report of a run to all the configured notifiers. A failure to
+/// notify is only logged, so that it doesn't fail the run
+#[derive(Clone)]
+pub struct NotificationService {
+    notifiers: Arc<Vec<GenericNotifier>>,
+}
+
+impl NotificationService {
+    pub fn new(cfg: &NotificationsConfig, http: &HttpClient) -> anyhow::Result<Self> {
+        let mut notifiers: Vec<GenericNotifier> = Vec::new();
+        if let Some(discord_cfg) = &cfg.discord {
+            notifiers.push(Box::new(DiscordNotifier::new(discord_cfg, http)?));
+        }
+        if let Some(telegram_cfg) = &cfg.telegram {
+            notifiers.push(Box::new(TelegramNotifier::new(telegram_cfg, http)?));
+        }
+        if let Some(webhook_cfg) = &cfg.webhook {
+            notifiers.push(Box::new(WebhookNotifier::new(webhook_cfg, http)?));
+        }
+        Ok(Self {
+            notifiers: Arc::new(notifiers),
+        })
+    }
+
+    pub async fn notify(&self, report: &RunReport) {
+        for notifier in self.notifiers.iter() {
+            match notifier.notify(report).await {
+                Ok(()) => info!("sent the report to {}", notifier.name()),
+                Err(e) => warn!("unable to send the report to {}: {e:#}", notifier.name()),
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::report::{CleanerReport, labels};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::info;
+use rusqlite::{Connection, params};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Keeps track of what earlier runs did in an SQLite database, so that items
+/// are not processed again. Without a database nothing is kept between runs
+#[derive(Clone, Default)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    /// how long items have to stay eligible before they are deleted
+    grace_period: Option<Duration>,
+    state: Arc<Mutex<State>>,
+}
+
+/// items of every cleaner keyed by their ids in the *arr
+type ItemsById = BTreeMap<String, BTreeMap<u64, DateTime<Utc>>>;
+
+#[derive(Default)]
+struct State {
+    /// when items were deleted
+    deleted: ItemsById,
+    /// since when items waiting for the grace period have been eligible
+    pending: ItemsById,
+    /// when items seen but not yet eligible for deletion were last played,
+    /// keyed by their ids in the media server
+    last_played: BTreeMap<String, BTreeMap<String, DateTime<Utc>>>,
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS deleted (
+        cleaner TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        at TEXT NOT NULL,
+        PRIMARY KEY (cleaner, id)
+    );
+    CREATE TABLE IF NOT EXISTS pending (
+        cleaner TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        since TEXT NOT NULL,
+        PRIMARY KEY (cleaner, id)
+    );
+    CREATE TABLE IF NOT EXISTS last_played (
+        cleaner TEXT NOT NULL,
+        item TEXT NOT NULL,
+        last_played TEXT NOT NULL,
+        PRIMARY KEY (cleaner, item)
+    );
+";
+
+impl State {
+    fn read(db: &Connection) -> rusqlite::Result<Self> {
+        let mut state = Self::default();
+        for (table, items) in [
+            ("deleted", &mut state.deleted),
+            ("pending", &mut state.pending),
+        ] {
+            let mut query = db.prepare(&format!("SELECT * FROM {table}"))?;
+            let rows = query.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            for row in rows {
+                let (cleaner, id, at): (String, u64, DateTime<Utc>) = row?;
+                items.entry(cleaner).or_default().insert(id, at);
+            }
+        }
+        let mut query = db.prepare("SELECT * FROM last_played")?;
+        let rows = query.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for row in rows {
+            let (cleaner, item, at): (String, String, DateTime<Utc>) = row?;
+            state
+                .last_played
+                .entry(cleaner)
+                .or_default()
+                .insert(item, at);
+        }
+        Ok(state)
+    }
+
+    /// replaces everything in the database with this state
+    fn write(&self, db: &mut Connection) -> rusqlite::Result<()> {
+        let tx = db.transaction()?;
+        for (table, items) in [("deleted", &self.deleted), ("pending", &self.pending)] {
+            tx.execute(&format!("DELETE FROM {table}"), [])?;
+            let mut insert = tx.prepare(&format!("INSERT INTO {table} VALUES (?1, ?2, ?3)"))?;
+            for (cleaner, items) in items {
+                for (id, at) in items {
+                    insert.execute(params![cleaner, id, at])?;
+                }
+            }
+        }
+        tx.execute("DELETE FROM last_played", [])?;
+        {
+            let mut insert = tx.prepare("INSERT INTO last_played VALUES (?1, ?2, ?3)")?;
+            for (cleaner, items) in &self.last_played {
+                for (item, at) in items {
+                    insert.execute(params![cleaner, item, at])?;
+                }
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// opens the database, creating it and its tables if they don't exist yet
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let db = Connection::open(path)?;
+    db.execute_batch(SCHEMA)?;
+    Ok(db)
+}
+
+impl StateStore {
+    /// reads the state from the given database, starting afresh if it doesn't
+    /// exist yet
+    pub async fn load(path: Option<&Path>, grace_period: Option<Duration>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self {
+                grace_period,
+                ..Self::default()
+            });
+        };
+        let db_path = path.to_owned();
+        let state = tokio::task::spawn_blocking(move || State::read(&open(&db_path)?))
+            .await?
+            .with_context(|| format!("failed to read state database {path:?}"))?;
+        Ok(Self {
+            path: Some(path.to_owned()),
+            grace_period,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// writes the state back to its database, if any
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let state = self.state.clone();
+        let db_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut db = open(&db_path)?;
+            state.lock().unwrap().write(&mut db)
+        })
+        .await?
+        .with_context(|| format!("failed to write state database {path:?}"))
+    }
+
+    /// forgets the items of the cleaner that no longer exist, i.e. that
+    /// aren't among the `existing` ones it found in the *arr, e.g. once their
+    /// deletion shows up there
+    pub fn prune(&self, cleaner: &str, existing: &HashSet<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            deleted, pending, ..
+        } = &mut *state;
+        for items in [deleted, pending] {
+            if let Some(items) = items.get_mut(cleaner) {
+                items.retain(|id, _| existing.contains(id));
+            }
+        }
+    }
+
+    /// drops the items deleted by an earlier run, e.g. when the deletion
+    /// hasn't shown up in the *arr yet
+    pub fn without_deleted<T: Debug>(
+        &self,
+        items: Vec<T>,
+        id: impl Fn(&T) -> u64,
+        report: &mut CleanerReport,
+    ) -> Vec<T> {
+        let state = self.state.lock().unwrap();
+        let Some(deleted) = state.deleted.get(report.cleaner) else {
+            return items;
+        };
+        let before = labels(&items);
+        let items: Vec<T> = items
+            .into_iter()
+            .filter(|item| match deleted.get(&id(item)) {
+                Some(at) => {
+                    info!("{item:?} was already deleted at {at}, skipping");
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        report.skip_missing(&before, &items, "already deleted by an earlier run");
+        items
+    }
+
+    /// keeps the items that have been eligible for deletion for the whole
+    /// grace period, counting from the first run that found them eligible.
+    /// Items missing from `items` are no longer eligible and start over
+    pub fn after_grace_period<T: Debug>(
+        &self,
+        items: Vec<T>,
+        id: impl Fn(&T) -> u64,
+        now: DateTime<Utc>,
+        report: &mut CleanerReport,
+    ) -> Vec<T> {
+        let Some(grace_period) = self.grace_period else {
+            return items;
+        };
+        let mut state = self.state.lock().unwrap();
+        let pending = state.pending.entry(report.cleaner.to_owned()).or_default();
+        let eligible: HashSet<u64> = items.iter().map(&id).collect();
+        pending.retain(|item, _| eligible.contains(item));
+
+        let mut waiting = Vec::new();
+        let items = items
+            .into_iter()
+            .filter(|item| {
+                let since = *pending.entry(id(item)).or_insert(now);
+                if since <= now - grace_period {
+                    return true;
+                }
+                info!("{item:?} is pending deletion since {since}");
+                waiting.push(format!("{item:?}"));
+                false
+            })
+            .collect();
+        report.skip_missing(&waiting, &[] as &[T], "pending deletion for `grace_period`");
+        items
+    }
+
+    /// whether the item, given its id in the media server, was last played at
+    /// `last_played` already when the cleaner last saw it not yet eligible
+    /// for deletion, i.e. it wasn't played since
+    pub fn unchanged(&self, cleaner: &str, item: &str, last_played: DateTime<Utc>) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .last_played
+            .get(cleaner)
+            .and_then(|items| items.get(item))
+            .is_some_and(|at| *at == last_played)
+    }
+
+    /// records when the items the cleaner saw not yet eligible for deletion
+    /// were last played, given their ids in the media server. Items left out
+    /// are forgotten, e.g. as they became eligible
+    pub fn record_not_yet_eligible(
+        &self,
+        cleaner: &str,
+        items: impl IntoIterator<Item = (String, DateTime<Utc>)>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .last_played
+            .insert(cleaner.to_owned(), items.into_iter().collect());
+    }
+
+    /// records the items deleted by the cleaner, given their ids
+    pub fn record_deleted(
+        &self,
+        cleaner: &str,
+        ids: impl IntoIterator<Item = u64>,
+        now: DateTime<Utc>,
+    ) {
+        let ids: HashSet<u64> = ids.into_iter().collect();
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.pending.get_mut(cleaner) {
+            pending.retain(|id, _| !ids.contains(id));
+        }
+        let deleted = state.deleted.entry(cleaner.to_owned()).or_default();
+        deleted.extend(ids.into_iter().map(|id| (id, now)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SubsecRound;
+
+    fn id(item: &(&str, u64)) -> u64 {
+        item.1
+    }
+
+    #[test]
+    fn test_without_deleted() {
+        let store = StateStore::default();
+        store.record_deleted("movies", [1], Utc::now());
+
+        let mut report = CleanerReport::new("movies");
+        // renamed in the *arr meanwhile
+        let items = store.without_deleted(vec![("A", 1), ("b", 2)], id, &mut report);
+        assert_eq!(items, vec![("b", 2)]);
+        assert_eq!(report.skipped.len(), 1);
+
+        let mut report = CleanerReport::new("series");
+        let items = store.without_deleted(vec![("a", 1)], id, &mut report);
+        assert_eq!(items, vec![("a", 1)]);
+    }
+
+    #[test]
+    fn test_prune() {
+        let store = StateStore::default();
+        store.record_deleted("movies", [1, 2], Utc::now());
+        store.record_deleted("series", [1], Utc::now());
+
+        // the deletion of 1 showed up in Radarr
+        store.prune("movies", &HashSet::from([2, 3]));
+        let state = store.state.lock().unwrap();
+        assert_eq!(state.deleted["movies"].keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(state.deleted["series"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_load_and_save() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("sanitarr-state-{}.db", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let store = StateStore::load(Some(&path), None).await?;
+        let played = Utc::now().trunc_subsecs(0);
+        store.record_deleted("movies", [1], Utc::now());
+        store.record_not_yet_eligible("episodes", [("series-1".to_owned(), played)]);
+        store.save().await?;
+        // saving again replaces what was saved
+        store.save().await?;
+
+        let store = StateStore::load(Some(&path), None).await?;
+        let mut report = CleanerReport::new("movies");
+        assert!(
+            store
+                .without_deleted(vec![("a", 1)], id, &mut report)
+                .is_empty()
+        );
+        assert!(store.unchanged("episodes", "series-1", played));
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let store = StateStore::default();
+        let played = Utc::now();
+        store.record_not_yet_eligible("episodes", [("a".to_owned(), played)]);
+        assert!(store.unchanged("episodes", "a", played));
+        // played again since
+        assert!(!store.unchanged("episodes", "a", played + chrono::Duration::hours(1)));
+        assert!(!store.unchanged("series", "a", played));
+
+        // "a" became eligible meanwhile
+        store.record_not_yet_eligible("episodes", [("b".to_owned(), played)]);
+        assert!(!store.unchanged("episodes", "a", played));
+    }
+
+    #[test]
+    fn test_after_grace_period() {
+        let store = StateStore {
+            grace_period: Some(Duration::from_secs(24 * 60 * 60)),
+            ..StateStore::default()
+        };
+        let now = Utc::now();
+        let hours = |h: i64| now + chrono::Duration::hours(h);
+
+        let mut report = CleanerReport::new("movies");
+        assert!(
+            store
+                .after_grace_period(vec![("a", 1), ("b", 2)], id, now, &mut report)
+                .is_empty()
+        );
+        assert_eq!(report.skipped.len(), 2);
+
+        // "b" is no longer eligible, so it starts over
+        let mut report = CleanerReport::new("movies");
+        let items = store.after_grace_period(vec![("a", 1)], id, hours(12), &mut report);
+        assert!(items.is_empty());
+
+        let mut report = CleanerReport::new("movies");
+        let items = store.after_grace_period(vec![("a", 1), ("b", 2)], id, hours(24), &mut report);
+        assert_eq!(items, vec![("a", 1)]);
+        assert_eq!(report.skipped.len(), 1);
+
+        // deleted items are no longer pending
+        store.record_deleted("movies", [1, 2], hours(24));
+        assert!(store.state.lock().unwrap().pending["movies"].is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::config::DeletionMode;
+use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// lists everything moved into the trash directory
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Moves the files of deleted items into a trash directory instead of deleting
+/// them, keeping their original paths below it (e.g. `/movies/Heat (1995)`
+/// goes to `<dir>/movies/Heat (1995)`). Every move is recorded in a manifest
+/// in the trash directory, so that the files can be reviewed and restored
+#[derive(Clone)]
+pub struct Trash {
+    dir: PathBuf,
+    /// serializes updates of the manifest by concurrent deletions
+    manifest: Arc<Mutex<()>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrashEntry {
+    item: String,
+    original_path: PathBuf,
+    trash_path: PathBuf,
+    trashed_at: DateTime<Utc>,
+}
+
+impl Trash {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            manifest: Arc::default(),
+        }
+    }
+
+    /// the trash of a service, if its deletion mode is `trash`
+    pub fn of(mode: DeletionMode, dir: Option<PathBuf>) -> Option<Self> {
+        match mode {
+            DeletionMode::Delete => None,
+            DeletionMode::Trash => dir.map(Self::new),
+        }
+    }
+
+    /// moves the file or directory of the item into the trash and records it
+    /// in the manifest. Returns where it was moved to
+    pub fn move_item<T: Debug>(
+        &self,
+        item: &T,
+        path: &Path,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<PathBuf> {
+        let trash_path = self.trash_path(path);
+        if trash_path.exists() {
+            bail!("{trash_path:?} is already in the trash");
+        }
+        if let Some(parent) = trash_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {parent:?}"))?;
+        }
+        move_path(path, &trash_path)
+            .with_context(|| format!("failed to move {path:?} to {trash_path:?}"))?;
+        info!("moved {item:?} from {path:?} to {trash_path:?}");
+
+        self.record(TrashEntry {
+            item: format!("{item:?}"),
+            original_path: path.to_owned(),
+            trash_path: trash_path.clone(),
+            trashed_at: now,
+        })?;
+        Ok(trash_path)
+    }
+
+    /// moves the file or directory back from the trash to where `move_item`
+    /// took it from and drops it from the manifest, e.g. when the item failed
+    /// to be removed from its service
+    pub fn restore(&self, trash_path: &Path, original_path: &Path) -> anyhow::Result<()> {
+        move_path(trash_path, original_path)
+            .with_context(|| format!("failed to move {trash_path:?} back to {original_path:?}"))?;
+        info!("moved {trash_path:?} back to {original_path:?}");
+        self.update(|entries| entries.retain(|e| e.trash_path != trash_path))
+    }
+
+    /// the original path below the trash directory
+    fn trash_path(&self, path: &Path) -> PathBuf {
+        let relative: PathBuf = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        self.dir.join(relative)
+    }
+
+    fn record(&self, entry: TrashEntry) -> anyhow::Result<()> {
+        self.update(|entries| entries.push(entry))
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<TrashEntry>)) -> anyhow::Result<()> {
+        let _lock = self.manifest.lock().unwrap();
+        let path = self.dir.join(MANIFEST_FILE);
+        let mut entries: Vec<TrashEntry> = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse trash manifest {path:?}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read trash manifest {path:?}"));
+            }
+        };
+        change(&mut entries);
+        std::fs::write(&path, serde_json::to_string_pretty(&entries)?)
+            .with_context(|| format!("failed to write trash manifest {path:?}"))
+    }
+}
+
+/// renames the file or directory, falling back to copying and removing it if
+/// the trash is on another file system
+fn move_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_recursively(from, to)?;
+            if from.is_dir() {
+                std::fs::remove_dir_all(from)
+            } else {
+                std::fs::remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_item() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("sanitarr-trash-{}", std::process::id()));
+        let movie = root.join("movies/Heat (1995)");
+        std::fs::create_dir_all(&movie)?;
+        std::fs::write(movie.join("heat.mkv"), "movie")?;
+
+        let trash = Trash::new(root.join("trash"));
+        let moved = trash.move_item(&"Heat(1)", &movie, Utc::now())?;
+
+        assert!(!movie.exists());
+        assert_eq!(moved, trash.trash_path(&movie));
+        assert!(moved.ends_with("movies/Heat (1995)"));
+        assert_eq!(std::fs::read_to_string(moved.join("heat.mkv"))?, "movie");
+
+        let manifest = std::fs::read_to_string(root.join("trash").join(MANIFEST_FILE))?;
+        let entries: Vec<TrashEntry> = serde_json::from_str(&manifest)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].item, "\"Heat(1)\"");
+        assert_eq!(entries[0].original_path, movie);
+
+        // nothing is overwritten in the trash
+        std::fs::create_dir_all(&movie)?;
+        assert!(trash.move_item(&"Heat(1)", &movie, Utc::now()).is_err());
+        assert!(movie.exists());
+
+        std::fs::remove_dir(&movie)?;
+        trash.restore(&moved, &movie)?;
+        assert_eq!(std::fs::read_to_string(movie.join("heat.mkv"))?, "movie");
+        assert!(!moved.exists());
+        let manifest = std::fs::read_to_string(root.join("trash").join(MANIFEST_FILE))?;
+        assert_eq!(serde_json::from_str::<Vec<TrashEntry>>(&manifest)?.len(), 0);
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_recursively() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("sanitarr-copy-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("from/Season 01"))?;
+        std::fs::write(root.join("from/Season 01/e01.mkv"), "episode")?;
+
+        copy_recursively(&root.join("from"), &root.join("to"))?;
+        assert_eq!(
+            std::fs::read_to_string(root.join("to/Season 01/e01.mkv"))?,
+            "episode"
+        );
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
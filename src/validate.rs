@@ -0,0 +1,60 @@
+use crate::config::Config;
+use crate::http::{HttpClient, LidarrClient, RadarrClient, SonarrClient};
+use crate::media_server;
+use crate::services::DownloadService;
+use anyhow::bail;
+use log::{error, info};
+
+/// checks the connection to every configured service, authenticating where
+/// they require it, and logs which of them succeeded. Nothing gets deleted.
+/// Fails if any of the services is unreachable
+pub async fn validate(config: &Config) -> anyhow::Result<()> {
+    let http = HttpClient::new(&config.http)?;
+    let mut results: Vec<(String, anyhow::Result<()>)> = Vec::new();
+
+    match media_server(config, &http) {
+        Ok(media_server) => {
+            for username in config.usernames() {
+                let result = media_server.user(username).await.map(|_| ());
+                results.push((
+                    format!("{} user \"{username}\"", media_server.name()),
+                    result,
+                ));
+            }
+        }
+        Err(e) => results.push(("media server".to_owned(), Err(e))),
+    }
+
+    let radarr = async { RadarrClient::new(&config.radarr, &http)?.tags().await };
+    results.push(("Radarr".to_owned(), radarr.await.map(|_| ())));
+    let sonarr = async { SonarrClient::new(&config.sonarr, &http)?.tags().await };
+    results.push(("Sonarr".to_owned(), sonarr.await.map(|_| ())));
+    if let Some(lidarr_config) = &config.lidarr {
+        let lidarr = async { LidarrClient::new(lidarr_config, &http)?.tags().await };
+        results.push(("Lidarr".to_owned(), lidarr.await.map(|_| ())));
+    }
+
+    for (kind, result) in DownloadService::check_connections(&config.download_clients, &http).await
+    {
+        results.push((kind.to_string(), result));
+    }
+
+    let mut failed = 0;
+    for (service, result) in &results {
+        match result {
+            Ok(()) => info!("{service}: ok"),
+            Err(e) => {
+                failed += 1;
+                error!("{service}: {e:#}");
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} services are unreachable", results.len());
+    }
+    info!(
+        "the config is valid and all {} services are reachable",
+        results.len()
+    );
+    Ok(())
+}